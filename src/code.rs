@@ -4,19 +4,116 @@ use ropey::iter::Lines;
 use ropey::Rope;
 use ropey::RopeSlice;
 use tree_sitter::InputEdit;
+use std::cell::{Cell, RefCell};
 use std::cmp::min;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::fs;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
 use tree_sitter::{Node, Parser, Point, Query, QueryCursor, TextProvider};
 
 use crate::config::{Config, Language};
+use crate::search::SearchOptions;
 use crate::utils::{self, hex_to_color};
 use strfmt::strfmt;
 use log2::*;
 
+/// Node kinds of functions, classes and other named scopes of the supported languages.
+const SCOPE_KINDS: [&str; 20] = [
+    "function_item", "impl_item", "struct_item", "trait_item", "enum_item", "mod_item",
+    "function_definition", "class_definition", "function_declaration", "method_declaration",
+    "method_definition", "class_declaration", "interface_declaration", "enum_declaration",
+    "constructor_declaration", "object_declaration", "type_declaration",
+    "class_specifier", "struct_specifier", "namespace_definition",
+];
+
+/// Node kind prefixes of control flow blocks.
+const BLOCK_KINDS: [&str; 6] = ["if", "for", "while", "loop", "match", "switch"];
+
+/// Lock files written by this process, removed on exit even if the buffers are not dropped.
+static HELD_LOCKS: std::sync::Mutex<Vec<PathBuf>> = std::sync::Mutex::new(Vec::new());
+
+/// Removes the lock files of this process, for the panic and ctrl+c paths.
+pub fn remove_held_locks() {
+    if let Ok(locks) = HELD_LOCKS.try_lock() {
+        for path in locks.iter() { let _ = fs::remove_file(path); }
+    }
+}
+
+pub const STDIN_FILE_NAME: &str = "[stdin]";
+const BOM: char = '\u{feff}';
+
+/// Line diffs with more old by new lines than this mark the whole changed region modified.
+const DIFF_LINES_LIMIT: usize = 1_000_000;
+
+/// Changes from the old to the new lines by lcs of the region between the common prefix and suffix.
+fn diff_lines(old: &[u64], new: &[u64]) -> HashMap<usize, ChangeKind> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b).count();
+    let (a, b) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    // ops: 0 equal, 1 delete, 2 insert
+    let mut ops = vec![];
+    if a.len() * b.len() <= DIFF_LINES_LIMIT {
+        let w = b.len() + 1;
+        let mut lcs = vec![0u32; (a.len() + 1) * w];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * w + j] = if a[i] == b[j] { lcs[(i + 1) * w + j + 1] + 1 }
+                    else { lcs[(i + 1) * w + j].max(lcs[i * w + j + 1]) };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] { ops.push(0); i += 1; j += 1; }
+            else if j == b.len() || (i < a.len() && lcs[(i + 1) * w + j] >= lcs[i * w + j + 1]) { ops.push(1); i += 1; }
+            else { ops.push(2); j += 1; }
+        }
+    } else {
+        ops.extend(std::iter::repeat(1).take(a.len()));
+        ops.extend(std::iter::repeat(2).take(b.len()));
+    }
+    ops.push(0); // flushes the last run
+
+    let mut changes = HashMap::new();
+    let last = new.len().saturating_sub(1);
+    let (mut row, mut deleted, mut inserted) = (prefix, 0, 0);
+    for op in ops {
+        match op {
+            1 => deleted += 1,
+            2 => inserted += 1,
+            _ => {
+                let from = row - inserted;
+                for (k, r) in (from..row).enumerate() {
+                    changes.insert(r, if k < deleted { ChangeKind::Modified } else { ChangeKind::Added });
+                }
+                if inserted == 0 && deleted > 0 {
+                    changes.entry(row.min(last)).or_insert(ChangeKind::Deleted);
+                }
+                row += 1;
+                (deleted, inserted) = (0, 0);
+                continue;
+            }
+        }
+        if op == 2 { row += 1; }
+    }
+    changes
+}
+
+/// Recently edited rows kept per file.
+const RECENT_LINES_LIMIT: usize = 10;
+/// Edits this close to a remembered row count as the same location.
+const RECENT_LINES_DISTANCE: usize = 5;
+
+/// Opening and closing chars inserted together with `auto_pairs`.
+const PAIRS: [(char, char); 5] = [('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')];
+
 pub struct Code {
     pub file_name: String,
     pub abs_path: String,
@@ -30,9 +127,65 @@ pub struct Code {
     r: usize, c: usize, x: usize, y: usize,
     lang_conf: Option<Language>,
     line2runneble: HashMap<usize, Runnable>,
+    /// The tree is only edited, not parsed, till a background parse is set with `set_tree`.
+    parse_deferred: bool,
+    /// Counts edits, a background parse of an older text is dropped.
+    edit_version: usize,
+    /// Edited since the runnables were found, their rows are only shifted till `refresh_runnables`.
+    runnables_dirty: bool,
     query_test: Option<tree_sitter::Query>,
+    /// Changes not yet written to the swap file.
+    swap_dirty: bool,
+    /// The lock file of this buffer was written by this process.
+    locked: bool,
+    /// Opened while another red holds the lock, saving is refused.
+    pub read_only: bool,
+    /// History length at the last undo checkpoint.
+    checkpoint: usize,
+    /// Undo groups started and not ended yet.
+    open_groups: usize,
+    /// Cached first line with trailing whitespace, reset on edit.
+    trailing_ws: Cell<Option<Option<usize>>>,
+    /// Recently edited rows, most recent last.
+    recent_lines: Vec<usize>,
+    /// Line hashes of the text as loaded or last saved.
+    snapshot: Vec<u64>,
+    /// Cached line changes against the snapshot, reset on edit.
+    line_changes: RefCell<Option<Rc<HashMap<usize, ChangeKind>>>>,
+    /// Line ending written on save, the text holds `\n` only.
+    pub eol: EolStyle,
+    /// Encoding the file is read and saved with, one of `utils::ENCODINGS`.
+    pub encoding: String,
+    /// Rows with the less common line ending on load, saving writes them with `eol`.
+    mixed_line_endings: Vec<usize>,
+    /// Highlight captures of the rows around the last drawn ones, reset on edit.
+    highlights: RefCell<Option<HighlightWindow>>,
+    /// Modification time of the file as loaded, saved or reloaded.
+    mtime: Option<SystemTime>,
+}
+
+/// Captures of the rows from..to as (start_byte, end_byte, start_row, end_row, capture index),
+/// scrolling inside the rows reuses them.
+struct HighlightWindow {
+    from: usize,
+    to: usize,
+    captures: Vec<(usize, usize, usize, usize, usize)>,
+}
+
+/// Line ending of a file, the most common one on load.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EolStyle { Lf, Crlf }
+
+impl EolStyle {
+    pub fn name(&self) -> &'static str {
+        match self { EolStyle::Lf => "lf", EolStyle::Crlf => "crlf" }
+    }
 }
 
+/// Line change against the saved file, shown in the gutter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind { Added, Modified, Deleted }
+
 impl Code {
     pub fn new() -> Self {
         Self {
@@ -48,7 +201,24 @@ impl Code {
             r: 0, c: 0, x: 0, y: 0,
             lang_conf: None,
             line2runneble: HashMap::new(),
+            runnables_dirty: false,
+            parse_deferred: false,
+            edit_version: 0,
             query_test: None,
+            swap_dirty: false,
+            locked: false,
+            read_only: false,
+            checkpoint: 0,
+            open_groups: 0,
+            trailing_ws: Cell::new(None),
+            recent_lines: Vec::new(),
+            snapshot: Vec::new(),
+            line_changes: RefCell::new(None),
+            eol: EolStyle::Lf,
+            encoding: "utf-8".to_string(),
+            mixed_line_endings: Vec::new(),
+            highlights: RefCell::new(None),
+            mtime: None,
         }
     }
 
@@ -63,8 +233,34 @@ impl Code {
         let text = Rope::from_reader(BufReader::new(file))?;
         let abs_path = utils::abs_file(path);
         let file_name = utils::get_file_name(path);
+        let lang = Self::detect_lang(path, conf);
+
+        Ok(Self::from_text(text, file_name, abs_path, lang, conf))
+    }
+
+    /// Reads the file decoding it with the given encoding instead of utf-8.
+    pub fn from_file_with_encoding(path: &str, encoding: &str, conf: &Config) -> std::io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let text = utils::decode(&bytes, encoding).ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidData, format!("file is not valid {}", encoding)
+        ))?;
+        let abs_path = utils::abs_file(path);
+        let file_name = utils::get_file_name(path);
+        let lang = Self::detect_lang(path, conf);
+
+        let mut code = Self::from_text(Rope::from_str(&text), file_name, abs_path, lang, conf);
+        code.encoding = encoding.to_string();
+        Ok(code)
+    }
 
-        let mut lang = match detect_lang::from_path(path) {
+    /// Scratch buffer with text read from stdin, not bound to a file.
+    pub fn from_stdin(text: &str, lang: Option<String>, conf: &Config) -> Self {
+        let lang = lang.unwrap_or("text".to_string());
+        Self::from_text(Rope::from_str(text), STDIN_FILE_NAME.to_string(), String::new(), lang, conf)
+    }
+
+    fn detect_lang(path: &str, conf: &Config) -> String {
+        match detect_lang::from_path(path) {
             Some(lang) => lang.id().to_lowercase(),
             None => {
                 // find lang by ext from config
@@ -73,8 +269,12 @@ impl Code {
                     None => "text".to_string(),
                 }
             }
-        };
+        }
+    }
 
+    fn from_text(text: Rope, file_name: String, abs_path: String, mut lang: String, conf: &Config) -> Self {
+        let (text, eol, mixed_line_endings) = Self::split_line_endings(text);
+        let mtime = Self::read_mtime(&abs_path);
         let lang_conf = conf.language.iter().find(|l| l.name == lang);
         let lang_conf = lang_conf.map(|lc| (*lc).clone());
 
@@ -101,7 +301,7 @@ impl Code {
         };
 
         if lang == "text".to_string() {
-            return Ok(Self {
+            let mut this = Self {
                 text,
                 file_name,
                 abs_path,
@@ -114,8 +314,27 @@ impl Code {
                 r: 0, c: 0, x: 0, y: 0,
                 lang_conf,
                 line2runneble: HashMap::new(),
+                runnables_dirty: false,
+                parse_deferred: false,
+                edit_version: 0,
                 query_test: None,
-            })
+                swap_dirty: false,
+                locked: false,
+                read_only: false,
+                checkpoint: 0,
+                open_groups: 0,
+                trailing_ws: Cell::new(None),
+                recent_lines: Vec::new(),
+                snapshot: Vec::new(),
+                line_changes: RefCell::new(None),
+                eol,
+                encoding: "utf-8".to_string(),
+                mixed_line_endings,
+                highlights: RefCell::new(None),
+                mtime,
+            };
+            this.take_snapshot();
+            return this;
         }
 
         let mut parser = Parser::new();
@@ -159,11 +378,43 @@ impl Code {
             r: 0, c: 0, x: 0, y: 0,
             lang_conf,
             line2runneble: HashMap::new(),
-            query_test
+            runnables_dirty: false,
+            parse_deferred: false,
+            edit_version: 0,
+            query_test,
+            swap_dirty: false,
+            locked: false,
+            read_only: false,
+            checkpoint: 0,
+            open_groups: 0,
+            trailing_ws: Cell::new(None),
+            recent_lines: Vec::new(),
+            snapshot: Vec::new(),
+            line_changes: RefCell::new(None),
+            eol,
+            encoding: "utf-8".to_string(),
+            mixed_line_endings,
+            highlights: RefCell::new(None),
+            mtime,
         };
 
         this.update_runnables();
-        Ok(this)
+        this.take_snapshot();
+        this
+    }
+
+    pub fn is_scratch(&self) -> bool {
+        self.abs_path.is_empty()
+    }
+
+    /// Binds the buffer to a new file path, used by save as.
+    pub fn set_path(&mut self, path: &str) {
+        let abs_path = std::env::current_dir()
+            .map(|dir| dir.join(path))
+            .unwrap_or(Path::new(path).to_path_buf());
+        self.abs_path = abs_path.to_string_lossy().to_string();
+        self.file_name = utils::get_file_name(path);
+        self.changed = true;
     }
     pub fn set_lang(&mut self, lang:String, conf: &Config) {
         self.lang = lang;
@@ -181,19 +432,300 @@ impl Code {
         (self.r.clone(), self.c.clone(), self.y.clone(), self.x.clone())
     }
 
+    /// Parent dir of the file when it does not exist yet.
+    pub fn missing_dir(&self) -> Option<PathBuf> {
+        let dir = Path::new(&self.abs_path).parent()?;
+        match dir.as_os_str().is_empty() || dir.exists() {
+            true => None,
+            false => Some(dir.to_path_buf()),
+        }
+    }
+
+    /// Creates the missing parent dirs of the file, like `mkdir -p`.
+    pub fn create_dir(&self) -> std::io::Result<()> {
+        match self.missing_dir() {
+            Some(dir) => fs::create_dir_all(dir),
+            None => Ok(()),
+        }
+    }
+
     pub fn save_file(&mut self) -> std::io::Result<()> {
         if !self.changed { return Ok(()); }
 
-        let file = File::create(&self.abs_path)?;
-        let saved = self.text.write_to(BufWriter::new(file));
-        self.changed = false;
+        let saved = self.write_file(Path::new(&self.abs_path));
+        if saved.is_ok() {
+            self.changed = false;
+            self.remove_swap();
+            self.take_snapshot();
+            self.mixed_line_endings.clear();
+            self.mtime = Self::read_mtime(&self.abs_path);
+        }
         saved
     }
 
+    /// Writes the text with the file line ending and encoding to the path,
+    /// fails without writing if the encoding has no bytes for some char.
+    pub fn write_file(&self, path: &Path) -> std::io::Result<()> {
+        if self.encoding != "utf-8" {
+            let text = match self.eol {
+                EolStyle::Lf => self.text.to_string(),
+                EolStyle::Crlf => self.text.to_string().replace('\n', "\r\n"),
+            };
+            let bytes = utils::encode(&text, &self.encoding).ok_or(std::io::Error::new(
+                std::io::ErrorKind::InvalidData, format!("text can not be saved as {}", self.encoding)
+            ))?;
+            return fs::write(path, bytes);
+        }
+
+        let file = File::create(path)?;
+        match self.eol {
+            EolStyle::Lf => self.text.write_to(BufWriter::new(file)),
+            EolStyle::Crlf => {
+                let mut writer = BufWriter::new(file);
+                self.text.chunks()
+                    .try_for_each(|chunk| writer.write_all(chunk.replace('\n', "\r\n").as_bytes()))
+                    .and_then(|_| writer.flush())
+            },
+        }
+    }
+
+    fn read_mtime(path: &str) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// The file changed on disk since it was loaded, saved or reloaded.
+    pub fn modified_on_disk(&self) -> bool {
+        if self.is_scratch() { return false; }
+        match (self.mtime, Self::read_mtime(&self.abs_path)) {
+            (Some(known), Some(disk)) => known != disk,
+            _ => false,
+        }
+    }
+
+    /// Takes the version on disk as seen, so it is not reported as modified again.
+    pub fn keep_disk_change(&mut self) {
+        self.mtime = Self::read_mtime(&self.abs_path);
+    }
+
+    /// Replaces the text with the file on disk as one undoable change, the buffer becomes saved.
+    pub fn reload_file(&mut self) -> std::io::Result<()> {
+        let mtime = Self::read_mtime(&self.abs_path);
+        let bytes = fs::read(&self.abs_path)?;
+        let text = match self.encoding.as_str() {
+            "utf-8" => String::from_utf8(bytes).ok(), // a BOM stays in the text as on open
+            encoding => utils::decode(&bytes, encoding),
+        }.ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidData, format!("file is not valid {}", self.encoding)
+        ))?;
+        let (text, eol, mixed_line_endings) = Self::split_line_endings(Rope::from_str(&text));
+
+        self.replace_all(&text.to_string());
+        self.eol = eol;
+        self.mixed_line_endings = mixed_line_endings;
+        self.changed = false;
+        self.remove_swap();
+        self.take_snapshot();
+        self.mtime = mtime;
+        Ok(())
+    }
+
+    fn line_hashes(&self) -> Vec<u64> {
+        self.text.lines().map(|line| {
+            let mut hasher = DefaultHasher::new();
+            line.chars().filter(|c| *c != '\n' && *c != '\r').for_each(|c| c.hash(&mut hasher));
+            hasher.finish()
+        }).collect()
+    }
+
+    /// Marks the current text as the saved version for `line_changes`.
+    fn take_snapshot(&mut self) {
+        self.snapshot = self.line_hashes();
+        self.line_changes.replace(None);
+    }
+
+    /// Added, modified and deleted rows against the saved file,
+    /// a deletion is marked on the row after it.
+    /// Cached until the next edit or save, the gutter and minimap ask every frame.
+    pub fn line_changes(&self) -> Rc<HashMap<usize, ChangeKind>> {
+        if let Some(changes) = self.line_changes.borrow().as_ref() {
+            return changes.clone();
+        }
+        let changes = Rc::new(diff_lines(&self.snapshot, &self.line_hashes()));
+        self.line_changes.replace(Some(changes.clone()));
+        changes
+    }
+
+    /// Removes blank lines after the last non-blank line, keeping its newline.
+    /// Returns the removed (row, col, row1, col1) range.
+    pub fn trim_eof_blank_lines(&mut self) -> Option<(usize, usize, usize, usize)> {
+        let last = self.text.len_lines() - 1;
+        let (row, col) = match (0..=last).rev().find(|&r| !self.text.line(r).to_string().trim().is_empty()) {
+            Some(r) if r < last && self.text.line(r).chars().last() == Some('\n') => (r + 1, 0),
+            Some(_) => return None,
+            None => (0, 0),
+        };
+
+        let col1 = self.line_len(last);
+        if (row, col) == (last, col1) { return None; }
+
+        self.remove_text(row, col, last, col1);
+        Some((row, col, last, col1))
+    }
+
+    /// Removes spaces and tabs at the line ends, returns the removed (row, col, col1) ranges.
+    pub fn trim_trailing_whitespace(&mut self) -> Vec<(usize, usize, usize)> {
+        let mut trimmed = vec![];
+        let first = match self.first_trailing_whitespace() {
+            Some(row) => row,
+            None => return trimmed,
+        };
+
+        for row in first..self.text.len_lines() {
+            let line = self.text.line(row).to_string();
+            let content = line.trim_end_matches(|c| c == '\n' || c == '\r');
+            let kept = content.trim_end_matches(|c| c == ' ' || c == '\t');
+            if kept.len() == content.len() { continue; }
+
+            let (col, col1) = (kept.chars().count(), content.chars().count());
+            self.remove_text(row, col, row, col1);
+            trimmed.push((row, col, col1));
+        }
+        trimmed
+    }
+
+    /// Adds a newline at the end if missing, returns the (row, col) it was inserted at.
+    pub fn ensure_final_newline(&mut self) -> Option<(usize, usize)> {
+        if self.has_final_newline() { return None; }
+
+        let row = self.text.len_lines() - 1;
+        let col = self.line_len(row);
+        self.insert_text("\n", row, col);
+        Some((row, col))
+    }
+
+    /// Swap file next to the file, e.g. `.main.rs.red.swp`.
+    fn swap_path(&self) -> Option<PathBuf> {
+        if self.is_scratch() { return None; }
+        let path = Path::new(&self.abs_path);
+        let name = path.file_name()?.to_string_lossy();
+        Some(path.with_file_name(format!(".{}.red.swp", name)))
+    }
+
+    /// Writes unsaved changes to the swap file.
+    pub fn write_swap(&mut self) -> std::io::Result<()> {
+        if !self.changed || !self.swap_dirty { return Ok(()); }
+        let path = match self.swap_path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let file = File::create(path)?;
+        self.text.write_to(BufWriter::new(file))?;
+        self.swap_dirty = false;
+        Ok(())
+    }
+
+    pub fn remove_swap(&mut self) {
+        if let Some(path) = self.swap_path() {
+            let _ = fs::remove_file(path);
+        }
+        self.swap_dirty = self.changed;
+    }
+
+    /// Lock file next to the file, e.g. `.main.rs.red.lock`, holds the pid of the red editing it.
+    fn lock_path(&self) -> Option<PathBuf> {
+        if self.is_scratch() { return None; }
+        let path = Path::new(&self.abs_path);
+        let name = path.file_name()?.to_string_lossy();
+        Some(path.with_file_name(format!(".{}.red.lock", name)))
+    }
+
+    /// Pid of another running red holding the lock, a lock of an ended process is stale.
+    pub fn lock_owner(&self) -> Option<u32> {
+        let pid = fs::read_to_string(self.lock_path()?).ok()?.trim().parse::<u32>().ok()?;
+        if pid == std::process::id() || !utils::process_alive(pid) { return None; }
+        Some(pid)
+    }
+
+    /// Writes the lock file with the pid of this process, taking over any previous lock.
+    pub fn lock(&mut self) -> std::io::Result<()> {
+        let path = match self.lock_path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        fs::write(&path, std::process::id().to_string())?;
+        HELD_LOCKS.lock().unwrap().push(path);
+        self.locked = true;
+        self.read_only = false;
+        Ok(())
+    }
+
+    /// Takes over the lock of the buffer of the same file this one replaces.
+    pub fn take_lock(&mut self, old: &mut Code) {
+        self.locked = std::mem::take(&mut old.locked);
+        self.read_only = old.read_only;
+    }
+
+    /// Removes the lock file if this process wrote it.
+    pub fn unlock(&mut self) {
+        if !self.locked { return; }
+        if let Some(path) = self.lock_path() {
+            let _ = fs::remove_file(&path);
+            HELD_LOCKS.lock().unwrap().retain(|p| *p != path);
+        }
+        self.locked = false;
+    }
+
+    /// Swap file exists and is newer than the file itself.
+    pub fn has_newer_swap(&self) -> bool {
+        let swap = match self.swap_path() {
+            Some(swap) => swap,
+            None => return false,
+        };
+        let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+        match (modified(&swap), modified(Path::new(&self.abs_path))) {
+            (Some(swap), Some(file)) => swap >= file,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+
+    /// Replaces the text with the swap file content, as one undoable change.
+    pub fn recover_swap(&mut self) -> std::io::Result<()> {
+        let path = match self.swap_path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let text = fs::read_to_string(path)?;
+        self.replace_all(&text);
+        Ok(())
+    }
+
+    /// Replaces the whole text as one undoable change.
+    fn replace_all(&mut self, text: &str) {
+        self.history.push(Change {
+            start: 0, operation: Operation::Start,
+            text: "".to_string(), row:0, column:0
+        });
+        let last = self.len_lines() - 1;
+        self.remove_text(0, 0, last, self.line_len(last));
+        self.insert_text(text, 0, 0);
+        self.history.push(Change {
+            start: 0, operation: Operation::End,
+            text: "".to_string(), row:0, column:0
+        });
+    }
+
     fn insert(&mut self, text: &str, from: usize) {
         let offset_byte = self.text.char_to_byte(from);
+        let row = self.text.char_to_line(from);
         self.text.insert(from, text);
+        self.shift_runnables(row, row, text.matches('\n').count() as isize);
+        self.shift_recent_lines(row, row, text.matches('\n').count() as isize);
         self.changed = true;
+        self.swap_dirty = true;
+        self.trailing_ws.set(None);
+        self.line_changes.replace(None);
+        self.highlights.replace(None);
 
         let total_bytes: usize = text.chars().map(|ch| ch.len_utf8()).sum();
         let edit = tree_sitter::InputEdit {
@@ -210,6 +742,7 @@ impl Code {
     pub fn insert_text(&mut self, text: &str, row: usize, column: usize) {
         let from = self.text.line_to_char(row) + column;
         self.insert(text, from);
+        self.remember_line(row);
 
         self.history.push(Change {
             start: from,
@@ -219,6 +752,33 @@ impl Code {
         });
     }
 
+    /// Remembers the edited row, a remembered row close to it is replaced.
+    fn remember_line(&mut self, row: usize) {
+        self.recent_lines.retain(|r| r.abs_diff(row) > RECENT_LINES_DISTANCE);
+        self.recent_lines.push(row);
+        if self.recent_lines.len() > RECENT_LINES_LIMIT { self.recent_lines.remove(0); }
+    }
+
+    /// Moves remembered rows below an edit of row..=row_end by delta,
+    /// rows inside a removed range move to its start.
+    fn shift_recent_lines(&mut self, row: usize, row_end: usize, delta: isize) {
+        if delta == 0 { return; }
+        for line in self.recent_lines.iter_mut() {
+            if *line > row_end { *line = (*line as isize + delta) as usize; }
+            else if *line > row { *line = row; }
+        }
+    }
+
+    /// Recently edited rows, most recent first.
+    pub fn recent_lines(&self) -> Vec<usize> {
+        let last = self.len_lines() - 1;
+        let mut lines = vec![];
+        for row in self.recent_lines.iter().rev().map(|r| (*r).min(last)) {
+            if !lines.contains(&row) { lines.push(row); }
+        }
+        lines
+    }
+
     pub fn insert_char(&mut self, c: char, row: usize, column: usize) {
         self.insert_text(&c.to_string(), row, column);
     }
@@ -238,9 +798,16 @@ impl Code {
     fn remove(&mut self, from: usize, to: usize) {
         let from_byte = self.text.char_to_byte(from);
         let to_byte = self.text.char_to_byte(to);
+        let (row, row_end) = (self.text.char_to_line(from), self.text.char_to_line(to));
 
         self.text.remove(from..to);
+        self.shift_runnables(row, row_end, row as isize - row_end as isize);
+        self.shift_recent_lines(row, row_end, row as isize - row_end as isize);
         self.changed = true;
+        self.swap_dirty = true;
+        self.trailing_ws.set(None);
+        self.line_changes.replace(None);
+        self.highlights.replace(None);
 
         let edit = tree_sitter::InputEdit {
             start_byte: from_byte,
@@ -267,6 +834,7 @@ impl Code {
         });
 
         self.remove(from, to);
+        self.remember_line(row);
     }
 
     pub fn remove_char(&mut self, row: usize, column: usize) {
@@ -274,19 +842,57 @@ impl Code {
     }
 
     fn apply_edit(&mut self, edit: InputEdit) {
+        self.edit_version += 1;
         match self.tree.as_mut() {
             Some(tree) => {
                 tree.edit(&edit);
-                self.tree_parse();
-                self.update_runnables();
+                if !self.parse_deferred { self.tree_parse(); }
+                self.runnables_dirty = true;
             },
             None => return,
         }
     }
+
+    /// Inserts like `insert_text` but leaves parsing to `background_parse`,
+    /// the text is shown at once and highlighted after the parse.
+    pub fn insert_text_deferred(&mut self, text: &str, row: usize, column: usize) {
+        if self.parser.is_some() { self.parse_deferred = true; }
+        self.insert_text(text, row, column);
+    }
+
+    /// A parse of the current text to run off the editor task, None if no parse is deferred.
+    /// Returns the edit version it parses and the job.
+    pub fn background_parse(&self) -> Option<(usize, impl FnOnce() -> Option<tree_sitter::Tree> + Send)> {
+        if !self.parse_deferred { return None; }
+        let language = self.parser.as_ref()?.language()?;
+        let text = self.text.to_string();
+        let old_tree = self.tree.clone();
+
+        Some((self.edit_version, move || {
+            let start = std::time::Instant::now();
+            let mut parser = Parser::new();
+            parser.set_language(language).ok()?;
+            let tree = parser.parse(text, old_tree.as_ref());
+            debug!("background tree parse {:?}", start.elapsed());
+            tree
+        }))
+    }
+
+    /// Takes the tree of a background parse, false if the text was edited after it started.
+    pub fn set_tree(&mut self, tree: tree_sitter::Tree, version: usize) -> bool {
+        if !self.parse_deferred || version != self.edit_version { return false; }
+        self.tree = Some(tree);
+        self.parse_deferred = false;
+        self.highlights.replace(None);
+        self.runnables_dirty = true;
+        true
+    }
     fn tree_parse(&mut self) {
         if let Some(parser) = &mut self.parser {
+            let start = std::time::Instant::now();
             let text = self.text.to_string();
             self.tree = parser.parse(text, self.tree.as_ref());
+            debug!("tree parse {:?}", start.elapsed());
         }
     }
 
@@ -344,6 +950,207 @@ impl Code {
         self.text.line_to_byte(line)
     }
 
+    /// First line ending with spaces or tabs, scanned once per edit.
+    pub fn first_trailing_whitespace(&self) -> Option<usize> {
+        if let Some(cached) = self.trailing_ws.get() { return cached; }
+
+        let line = self.text.lines().position(|line| {
+            let line = line.to_string();
+            let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+            line.ends_with(' ') || line.ends_with('\t')
+        });
+        self.trailing_ws.set(Some(line));
+        line
+    }
+
+    pub fn has_bom(&self) -> bool {
+        self.text.len_chars() > 0 && self.text.char(0) == BOM
+    }
+
+    /// Rows ending with \r\n in a mostly \n file or the other way around, as of load.
+    pub fn mixed_line_endings(&self) -> &[usize] {
+        &self.mixed_line_endings
+    }
+
+    /// Finds the most common line ending and the rows with the other one,
+    /// returns the text with `\r\n` turned into `\n`.
+    fn split_line_endings(text: Rope) -> (Rope, EolStyle, Vec<usize>) {
+        if !text.chunks().any(|chunk| chunk.contains('\r')) { return (text, EolStyle::Lf, vec![]); }
+
+        let crlf: Vec<bool> = text.lines()
+            .filter(|line| line.len_chars() > 0 && line.char(line.len_chars() - 1) == '\n')
+            .map(|line| line.len_chars() > 1 && line.char(line.len_chars() - 2) == '\r')
+            .collect();
+        let crlf_count = crlf.iter().filter(|c| **c).count();
+        if crlf_count == 0 { return (text, EolStyle::Lf, vec![]); }
+
+        let minority = crlf_count * 2 <= crlf.len(); // crlf rows on a tie
+        let eol = if minority { EolStyle::Lf } else { EolStyle::Crlf };
+        let mixed = crlf.iter().enumerate().filter(|(_, c)| **c == minority).map(|(r, _)| r).collect();
+        (Rope::from_str(&text.to_string().replace("\r\n", "\n")), eol, mixed)
+    }
+
+    /// Removes the BOM and forgets the mixed line endings, saving writes every row with `eol`,
+    /// returns false if there was nothing to fix.
+    pub fn normalize_line_endings(&mut self) -> bool {
+        let bom = self.has_bom();
+        if self.mixed_line_endings.is_empty() && !bom { return false; }
+
+        if bom { self.remove_text(0, 0, 0, 1); }
+        self.mixed_line_endings.clear();
+        self.changed = true;
+        true
+    }
+
+    pub fn has_final_newline(&self) -> bool {
+        let len = self.text.len_chars();
+        len == 0 || self.text.char(len - 1) == '\n'
+    }
+
+    /// Returns (lines, words, chars, bytes) for the given char range.
+    pub fn text_stats(&self, from: usize, to: usize) -> (usize, usize, usize, usize) {
+        let slice = self.text.slice(from..to);
+        let mut words = 0;
+        let mut in_word = false;
+        for ch in slice.chars() {
+            if ch.is_whitespace() { in_word = false; }
+            else if !in_word { in_word = true; words += 1; }
+        }
+        let lines = match slice.chars().last() {
+            None => 0,
+            Some('\n') => slice.len_lines() - 1,
+            Some(_) => slice.len_lines(),
+        };
+        (lines, words, slice.len_chars(), slice.len_bytes())
+    }
+
+    /// Replaces the range with text, recorded as a remove and an insert.
+    pub fn replace_text(&mut self, row: usize, col: usize, row1: usize, col1: usize, text: &str) {
+        if (row, col) != (row1, col1) { self.remove_text(row, col, row1, col1); }
+        if !text.is_empty() { self.insert_text(text, row, col); }
+    }
+
+    /// Pads lines from..=to so the first delimiter on each lines up in one column,
+    /// lines without it are untouched. Returns replaced (row, col, col1, spaces).
+    pub fn align_on(&mut self, from: usize, to: usize, delimiter: &str) -> Vec<(usize, usize, usize, String)> {
+        if delimiter.is_empty() { return vec![]; }
+
+        // (row, end of text before the delimiter, delimiter column) in chars
+        let found: Vec<(usize, usize, usize)> = (from..=to.min(self.len_lines() - 1))
+            .filter_map(|r| {
+                let line = self.text.line(r).to_string();
+                let byte = line.find(delimiter)?;
+                let col = line[..byte].chars().count();
+                let end = line[..byte].trim_end().chars().count();
+                Some((r, end, col))
+            })
+            .collect();
+
+        let column = match found.iter().map(|(_, end, _)| *end).max() {
+            Some(end) => end + 1,
+            None => return vec![],
+        };
+
+        let replaced: Vec<(usize, usize, usize, String)> = found.into_iter()
+            .filter(|(_, _, col)| *col != column)
+            .map(|(r, end, col)| (r, end, col, " ".repeat(column - end)))
+            .collect();
+
+        if replaced.is_empty() { return replaced; }
+
+        self.undo_group_start();
+        for (r, c, c1, spaces) in &replaced {
+            self.replace_text(*r, *c, *r, *c1, spaces);
+        }
+        self.undo_group_end();
+
+        replaced
+    }
+
+    /// Comments out the lines, or uncomments them if all are commented, in one undo group.
+    /// The comment goes at the smallest indent, blank lines are skipped.
+    /// Returns the replaced (row, col, col1, text), each on its own row.
+    pub fn toggle_comment_lines(&mut self, from: usize, to: usize) -> Vec<(usize, usize, usize, String)> {
+        let comment = match self.get_lang_comment() {
+            Some(comment) => comment,
+            None => return vec![],
+        };
+
+        // (row, first non whitespace column) of non blank lines
+        let lines: Vec<(usize, usize)> = (from..=to.min(self.len_lines() - 1))
+            .filter_map(|r| Some((r, self.find_first_non_whitespace(r, usize::MAX)?)))
+            .filter(|(r, _)| self.text.line(*r).chars().any(|c| !c.is_whitespace()))
+            .collect();
+
+        let commented = |r: usize, i: usize| self.text.line(r).chars().skip(i)
+            .take(comment.chars().count()).eq(comment.chars());
+        let uncomment = !lines.is_empty() && lines.iter().all(|(r, i)| commented(*r, *i));
+
+        let replaced: Vec<(usize, usize, usize, String)> = match uncomment {
+            true => lines.iter().map(|(r, i)| (*r, *i, i + comment.chars().count(), String::new())).collect(),
+            false => {
+                let indent = lines.iter().map(|(_, i)| *i).min().unwrap_or(0);
+                lines.iter().map(|(r, _)| (*r, indent, indent, comment.clone())).collect()
+            }
+        };
+
+        if replaced.is_empty() { return replaced; }
+
+        self.undo_group_start();
+        for (r, c, c1, text) in &replaced {
+            self.replace_text(*r, *c, *r, *c1, text);
+        }
+        self.undo_group_end();
+
+        replaced
+    }
+
+    /// Starts a group of changes undone at once, must be closed by `undo_group_end`.
+    pub fn undo_group_start(&mut self) {
+        self.history.push(Change {
+            start: 0, operation: Operation::Start,
+            text: "".to_string(), row:0, column:0
+        });
+        self.open_groups += 1;
+    }
+
+    /// Closes the group, an empty one is dropped so undo does not stop at it.
+    /// A group already closed by a checkpoint is left as is.
+    pub fn undo_group_end(&mut self) {
+        if self.open_groups == 0 { return; }
+        self.open_groups -= 1;
+        if matches!(self.history.last(), Some(Change { operation: Operation::Start, .. })) {
+            self.history.pop();
+            self.checkpoint = min(self.checkpoint, self.history.len());
+            return;
+        }
+        self.history.push(Change {
+            start: 0, operation: Operation::End,
+            text: "".to_string(), row:0, column:0
+        });
+    }
+
+    /// Ends the groups left open, an unmatched start would make undo take the whole history.
+    fn close_undo_groups(&mut self) {
+        while self.open_groups > 0 { self.undo_group_end(); }
+    }
+
+    /// Char column of a UTF-16 column of the row, as LSP counts them, None past the line end.
+    pub fn utf16_to_char(&self, row: usize, utf16: usize) -> Option<usize> {
+        let line = self.text.get_line(row)?;
+        let len = self.line_len(row);
+        let mut units = 0;
+        let col = line.chars().take(len).take_while(|c| { units += c.len_utf16(); units <= utf16 }).count();
+        match col == len && units < utf16 {
+            true => None,
+            false => Some(col),
+        }
+    }
+
+    pub fn byte_offset(&self, row: usize, col: usize) -> usize {
+        self.text.char_to_byte(self.text.line_to_char(row) + col)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.text.len_chars() == 0
     }
@@ -365,22 +1172,31 @@ impl Code {
     }
 
     fn position_to_point(text: &RopeSlice, pos: usize) -> (usize, usize) {
-        let byte = text.char_to_byte(pos);
         let line = text.char_to_line(pos);
-        let line_start_byte = text.line_to_byte(line);
-        let col = byte - line_start_byte;
-
-        (line, col)
+        (line, pos - text.line_to_char(line))
     }
 
-    pub fn search(&self, substring: &str) -> Vec<(usize, usize)> {
-
+    /// Matches of the pattern as (line, column, length) in chars, errors with the reason of an invalid regex.
+    pub fn search(&self, pattern: &str, options: &SearchOptions) -> Result<Vec<(usize, usize, usize)>, String> {
         let rope_slice = &self.text.slice(0..);
-        let result: Vec<(usize, usize)> = SearchIter::from_rope_slice(rope_slice, substring)
-            .map(|(pos, _)| Self::position_to_point(rope_slice, pos))
-            .collect();
+        if options.is_plain() {
+            let len = pattern.chars().count();
+            return Ok(SearchIter::from_rope_slice(rope_slice, pattern)
+                .map(|(pos, _)| Self::position_to_point(rope_slice, pos))
+                .map(|(line, column)| (line, column, len))
+                .collect());
+        }
 
-        result
+        let matcher = options.matcher(pattern)?;
+        let text = self.text.to_string();
+        Ok(matcher.find_iter(&text)
+            .filter(|m| !m.is_empty())
+            .map(|m| {
+                let (from, to) = (self.text.byte_to_char(m.start()), self.text.byte_to_char(m.end()));
+                let (line, column) = Self::position_to_point(rope_slice, from);
+                (line, column, to - from)
+            })
+            .collect())
     }
 
     pub fn find_substring(&self, line:usize, substring: &str) -> Option<usize> {
@@ -407,6 +1223,190 @@ impl Code {
             None => None,
         }
     }
+    /// Column of the unclosed `(` of the call the column is in, on the same line.
+    pub fn call_start(&self, row: usize, col: usize) -> Option<usize> {
+        let chars: Vec<char> = self.text.get_line(row)?.chars().take(col).collect();
+        let mut depth = 0;
+        for (i, ch) in chars.iter().enumerate().rev() {
+            match ch {
+                ')' => depth += 1,
+                '(' if depth == 0 => return Some(i),
+                '(' => depth -= 1,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Closing char to insert after typing `ch` at the column, e.g. `)` for `(`.
+    /// Only before whitespace or a closing char, never inside strings and comments.
+    pub fn closing_pair(&self, row: usize, col: usize, ch: char) -> Option<char> {
+        let close = PAIRS.iter().find(|(open, _)| *open == ch)?.1;
+        let line = self.text.get_line(row)?;
+        let next = line.chars().nth(col).filter(|c| *c != '\n' && *c != '\r');
+        let prev = if col > 0 { line.chars().nth(col - 1) } else { None };
+
+        if next.map_or(false, |n| !n.is_whitespace() && !")]},;".contains(n)) { return None; }
+        if ch == '"' || ch == '\'' {
+            if prev.map_or(false, |p| p.is_alphanumeric() || p == ch) { return None; }
+            if ch == '\'' && self.lang == "rust" { return None; } // lifetimes and chars
+        }
+        if self.is_in_string_or_comment(row, col) { return None; }
+        Some(close)
+    }
+
+    /// Indentation level of a new line split at the column, one more after a block opener
+    /// like `{` or python `:`, and whether the closing char after the column goes to its own line.
+    /// Languages without a grammar keep the line level.
+    pub fn enter_indent(&self, row: usize, col: usize) -> (usize, bool) {
+        const INDENT_AFTER: [&str; 3] = ["{", "(", "["];
+
+        let level = self.indentation_level(row);
+        if self.tree.is_none() { return (level, false); }
+
+        let line: Vec<char> = match self.text.get_line(row) {
+            Some(line) => line.chars().filter(|c| *c != '\n' && *c != '\r').collect(),
+            None => return (level, false),
+        };
+        let col = col.min(line.len());
+        let before: String = line[..col].iter().collect();
+        let before = before.trim_end();
+        let after: String = line[col..].iter().collect();
+
+        let custom = self.lang_conf.as_ref().and_then(|conf| conf.indent_after.as_ref());
+        let opens = match custom {
+            Some(openers) => openers.iter().find(|o| before.ends_with(o.as_str())).map(|o| o.chars().count()),
+            None => INDENT_AFTER.iter().find(|o| before.ends_with(*o)).map(|o| o.chars().count()),
+        };
+        let opens = opens.map_or(false, |len| {
+            !self.is_in_string_or_comment(row, before.chars().count() - len)
+        });
+        if !opens { return (level, false); }
+
+        let split = PAIRS.iter()
+            .any(|(open, close)| before.ends_with(*open) && after.trim_start().starts_with(*close));
+        (level + 1, split)
+    }
+
+    /// Indentation level of the line opening the block closed by the char at the column,
+    /// when only whitespace is before it, e.g. for a typed `}`.
+    pub fn closing_indent(&self, row: usize, col: usize) -> Option<usize> {
+        if !self.is_only_indentation_before(row, col) { return None; }
+
+        let root = self.tree.as_ref()?.root_node();
+        let byte = self.byte_offset(row, col);
+        let node = root.descendant_for_byte_range(byte, byte + 1)?;
+        if !matches!(node.kind(), "}" | ")" | "]") { return None; }
+
+        let open_row = node.parent()?.start_position().row;
+        if open_row == row { return None; }
+        Some(self.indentation_level(open_row))
+    }
+
+    /// Cursor is between an opening and its closing char, like `(|)`.
+    pub fn is_empty_pair(&self, row: usize, col: usize) -> bool {
+        if col == 0 { return false; }
+        let line = match self.text.get_line(row) { Some(line) => line, None => return false };
+        let (prev, next) = (line.chars().nth(col - 1), line.chars().nth(col));
+        PAIRS.iter().any(|(open, close)| prev == Some(*open) && next == Some(*close))
+    }
+
+    fn is_in_string_or_comment(&self, row: usize, col: usize) -> bool {
+        let root = match self.tree.as_ref() { Some(tree) => tree.root_node(), None => return false };
+        let byte = self.byte_offset(row, col);
+        let mut node = root.descendant_for_byte_range(byte.saturating_sub(1), byte);
+
+        while let Some(n) = node {
+            let inside = n.start_byte() < byte && byte < n.end_byte();
+            let line_comment_end = n.kind().contains("comment") && byte == n.end_byte()
+                && !(byte >= 2 && self.text.byte_slice(byte - 2..byte) == "*/");
+            if (n.kind().contains("string") || n.kind().contains("comment")) && inside
+                || line_comment_end {
+                return true;
+            }
+            node = n.parent();
+        }
+        false
+    }
+
+    /// Closing tag for the opening tag ended by `>` right before the column, e.g. `</div>`.
+    /// Uses the tree for html and jsx, the line text for xml files.
+    pub fn closing_tag_at(&self, row: usize, col: usize) -> Option<String> {
+        const VOID_ELEMENTS: [&str; 14] = [
+            "area", "base", "br", "col", "embed", "hr", "img",
+            "input", "link", "meta", "param", "source", "track", "wbr",
+        ];
+
+        let line = self.text.get_line(row)?.to_string();
+        let before: String = line.chars().take(col).collect();
+        if col == 0 || !before.ends_with('>') { return None; }
+
+        let is_xml = ["xml", "xhtml", "svg"].iter()
+            .any(|ext| self.abs_path.ends_with(&format!(".{}", ext)));
+
+        let name = match self.lang.as_str() {
+            "html" | "javascript" => self.opening_tag_name(self.byte_offset(row, col - 1))?,
+            _ if is_xml => {
+                let inner = &before[before.rfind('<')? + 1..before.len() - 1];
+                if inner.starts_with(['/', '!', '?']) || inner.ends_with('/') { return None; }
+                let name: String = inner.chars().take_while(|c| !c.is_whitespace()).collect();
+                let valid = name.chars().all(|c| c.is_alphanumeric() || "-_:.".contains(c));
+                if name.is_empty() || !valid { return None; }
+                name
+            }
+            _ => return None,
+        };
+
+        if self.lang == "html" && VOID_ELEMENTS.contains(&name.to_lowercase().as_str()) {
+            return None;
+        }
+
+        let closing = format!("</{}>", name);
+        let after: String = line.chars().skip(col).collect();
+        if after.starts_with(&closing) { return None; }
+        Some(closing)
+    }
+
+    /// Name of the html start tag or jsx opening element which `>` is at the byte.
+    fn opening_tag_name(&self, byte: usize) -> Option<String> {
+        let root = self.tree.as_ref()?.root_node();
+        let mut node = root.descendant_for_byte_range(byte, byte + 1);
+
+        while let Some(n) = node {
+            if n.end_byte() == byte + 1 {
+                let name = match n.kind() {
+                    "start_tag" => (0..n.named_child_count())
+                        .filter_map(|i| n.named_child(i))
+                        .find(|c| c.kind() == "tag_name"),
+                    "jsx_opening_element" => n.child_by_field_name("name"),
+                    _ => None,
+                };
+                if let Some(name) = name {
+                    return Some(self.text.byte_slice(name.start_byte()..name.end_byte()).to_string());
+                }
+            }
+            node = n.parent();
+        }
+        None
+    }
+
+    /// Comment start column and the marker to continue it with, e.g. `/// `,
+    /// when the line starts with a line comment.
+    pub fn comment_prefix(&self, line: usize) -> Option<(usize, String)> {
+        let comment = &self.lang_conf.as_ref()?.comment;
+        let text = self.text.get_line(line)?.to_string();
+        let start = text.len() - text.trim_start().len();
+        let rest = text[start..].strip_prefix(comment.as_str())?;
+
+        // doc comments like `///`, `//!` or `##`
+        let last = comment.chars().last()?;
+        let marker_len = rest.find(|c| c != last && c != '!').unwrap_or(rest.len());
+        let mut prefix = format!("{}{}", comment, &rest[..marker_len]);
+        if rest[marker_len..].starts_with(' ') { prefix.push(' '); }
+
+        Some((text[..start].chars().count(), prefix))
+    }
+
     pub fn get_lang_conf(&self) -> Option<&Language> {
         self.lang_conf.as_ref()
     }
@@ -442,10 +1442,50 @@ impl Code {
             None => None,
         }
     }
-    pub fn is_only_indentation_before(&self, r: usize, c: usize) -> bool {
-        if r >= self.text.len_lines() || c == 0 { return false; }
+    /// Language chars that are part of a word, besides letters, digits and `_`.
+    pub fn word_chars(&self) -> &str {
+        self.lang_conf.as_ref().and_then(|conf| conf.word_chars.as_deref()).unwrap_or("")
+    }
+    /// Reindents a multi-line block pasted at (r, c) to the indentation of that line.
+    /// The block's common leading indentation is replaced by the target one.
+    pub fn reindent_paste(&self, text: &str, r: usize, c: usize) -> String {
+        if !text.contains('\n') { return text.to_string(); }
+
+        let line = self.text.line(r).to_string();
+        let before: String = line.chars().take(c).collect();
+        let target: String = if before.trim().is_empty() { before }
+            else { line.chars().take_while(|c| *c == ' ' || *c == '\t').collect() };
+
+        // leading tabs become the language indent, so widths are comparable
+        let indent = self.indent_string();
+        let normalize = |l: &str| -> String {
+            let tabs = l.chars().take_while(|c| *c == '\t').count();
+            match &indent {
+                Some(indent) if tabs > 0 => format!("{}{}", indent.repeat(tabs), &l[tabs..]),
+                _ => l.to_string(),
+            }
+        };
+        let lines: Vec<String> = text.split('\n').map(|l| normalize(l)).collect();
+        let leading = |l: &str| l.chars().take_while(|c| *c == ' ' || *c == '\t').count();
 
-        let line = self.text.line(r);
+        // a first line copied from the middle of a line has no indentation of its own
+        let skip_first = leading(&lines[0]) == 0;
+        let common = lines.iter().enumerate()
+            .filter(|(i, l)| !(*i == 0 && skip_first) && !l.trim().is_empty())
+            .map(|(_, l)| leading(l))
+            .min().unwrap_or(0);
+
+        lines.iter().enumerate().map(|(i, l)| {
+            if i == 0 { l.chars().skip(common.min(leading(l))).collect() }
+            else if l.trim().is_empty() { String::new() }
+            else { format!("{}{}", target, l.chars().skip(common).collect::<String>()) }
+        }).collect::<Vec<String>>().join("\n")
+    }
+
+    pub fn is_only_indentation_before(&self, r: usize, c: usize) -> bool {
+        if r >= self.text.len_lines() || c == 0 { return false; }
+
+        let line = self.text.line(r);
 
         let mut col = 0;
         for ch in line.chars() {
@@ -473,7 +1513,7 @@ impl Code {
     }
 
     /// calculates color ranges from line number `from` to `to`
-    /// returns colors vectors of (start_byte, end_byte, color): (usize, usize, Color)
+    /// returns colors vectors of (start_byte, end_byte, start_row, end_row, color)
     pub fn colors(
         &self,
         from: usize,
@@ -485,6 +1525,40 @@ impl Code {
             Some(q) => q, None => return Vec::new(),
         };
 
+        // the rows of a screen above and below are queried too, scrolling reuses them
+        let cached = matches!(self.highlights.borrow().as_ref(), Some(w) if w.from <= from && to <= w.to);
+        if !cached {
+            let margin = to - from;
+            let (wfrom, wto) = (from.saturating_sub(margin), to + margin);
+            let captures = self.captures(wfrom, wto);
+            self.highlights.replace(Some(HighlightWindow { from: wfrom, to: wto, captures }));
+        }
+
+        let palette: Vec<Color> = query.capture_names().iter()
+            .map(|capture_name| {
+                let name = capture_name.split('.').next().unwrap_or(capture_name);
+                theme.get(name).map(|s| hex_to_color(s)).unwrap_or(Color::Reset)
+            })
+            .collect();
+
+        let from_byte = self.text.line_to_byte(from.min(self.text.len_lines()));
+        let to_byte = self.text.line_to_byte(to.min(self.text.len_lines()));
+        let highlights = self.highlights.borrow();
+        highlights.iter()
+            .flat_map(|w| w.captures.iter())
+            .filter(|(start, end, _, _, _)| *end > from_byte && *start < to_byte)
+            .map(|&(start, end, start_row, end_row, index)| (start, end, start_row, end_row, palette[index]))
+            .filter(|(_, _, _, _, color)| *color != Color::Reset)
+            .collect()
+    }
+
+    /// Runs the highlight query on the rows from..to,
+    /// returns (start_byte, end_byte, start_row, end_row, capture index).
+    fn captures(&self, from: usize, to: usize) -> Vec<(usize, usize, usize, usize, usize)> {
+        let query = match self.query.as_ref() {
+            Some(q) => q, None => return Vec::new(),
+        };
+
         let mut query_cursor = QueryCursor::new();
         let start_index = self.text.line_to_byte(from);
         let max_index = self.text.len_lines();
@@ -497,27 +1571,41 @@ impl Code {
 
         matches
             .flat_map(|m| m.captures)
-            .map(|capture| {
-                let capture_index = capture.index as usize;
-                let capture_name = &query.capture_names()[capture_index];
-                let name = capture_name.split('.').next().unwrap_or(capture_name);
-                let theme_colors = theme.get(name);
-                let color = theme_colors
-                    .map(|s| hex_to_color(s))
-                    .unwrap_or(Color::Reset);
-                // let text = self.text.byte_slice(capture.node.start_byte()..capture.node.end_byte()).as_str();
-                (
-                    capture.node.start_byte(),
-                    capture.node.end_byte(),
-                    capture.node.start_position().row,
-                    capture.node.end_position().row,
-                    color,
-                )
-            })
-            .filter(|(_, _, _, _, color)| *color != Color::Reset)
+            .map(|capture| (
+                capture.node.start_byte(),
+                capture.node.end_byte(),
+                capture.node.start_position().row,
+                capture.node.end_position().row,
+                capture.index as usize,
+            ))
             .collect()
     }
 
+    /// Keeps runnables below an edit of the rows row..=row_end on their lines,
+    /// the ones on removed rows are dropped.
+    fn shift_runnables(&mut self, row: usize, row_end: usize, delta: isize) {
+        if delta == 0 || self.line2runneble.is_empty() { return; }
+
+        let runnables: Vec<(usize, Runnable)> = self.line2runneble.drain().collect();
+        for (line, mut runnable) in runnables {
+            if line > row && line <= row_end { continue; }
+            if line > row_end { runnable.row = (line as isize + delta) as usize; }
+            self.line2runneble.insert(runnable.row, runnable);
+        }
+    }
+
+    /// Finds the runnables again if the text changed since the last time,
+    /// true when it did.
+    pub fn refresh_runnables(&mut self) -> bool {
+        if !self.runnables_dirty { return false; }
+        self.runnables_dirty = false;
+
+        let start = std::time::Instant::now();
+        self.update_runnables();
+        debug!("update runnables {:?}", start.elapsed());
+        true
+    }
+
     fn update_runnables(&mut self) {
         if self.lang_conf.is_none() {return; }
 
@@ -609,6 +1697,110 @@ impl Code {
         Some(path)
     }
 
+    /// Enclosing scopes (functions, classes, ...) started above the row, outer first.
+    /// Returns start row and the first line of each scope.
+    pub fn scopes_at(&self, row: usize) -> Vec<(usize, String)> {
+        let root = match self.tree.as_ref() {
+            Some(tree) => tree.root_node(),
+            None => return vec![],
+        };
+        let point = Point { row, column: 0 };
+        let mut node = root.named_descendant_for_point_range(point, point);
+
+        let mut scopes = vec![];
+        while let Some(n) = node {
+            let start = n.start_position().row;
+            let is_scope = SCOPE_KINDS.contains(&n.kind());
+            if is_scope && start < row && n.end_position().row >= row {
+                scopes.push((start, self.signature(start)));
+            }
+            node = n.parent();
+        }
+        scopes.dedup_by_key(|(start, _)| *start);
+        scopes.reverse();
+        scopes
+    }
+
+    /// First line of a scope without the opening brace.
+    fn signature(&self, row: usize) -> String {
+        let line = self.text.line(row).to_string();
+        line.trim().trim_end_matches('{').trim_end().to_string()
+    }
+
+    /// Signatures of the scopes and blocks of at least `min_lines` lines by the row of
+    /// their closing token, for the rows from..=to. The outer scope wins on a shared row.
+    pub fn closing_contexts(&self, from: usize, to: usize, min_lines: usize) -> HashMap<usize, String> {
+        let mut contexts = HashMap::new();
+        let root = match self.tree.as_ref() {
+            Some(tree) => tree.root_node(),
+            None => return contexts,
+        };
+
+        let mut nodes = vec![root];
+        while let Some(node) = nodes.pop() {
+            let (start, end) = (node.start_position().row, node.end_position().row);
+            if end < from || start > to { continue; }
+
+            let kind = node.kind();
+            let is_scope = SCOPE_KINDS.contains(&kind)
+                || BLOCK_KINDS.iter().any(|k| kind.starts_with(k));
+            if is_scope && (from..=to).contains(&end) && end + 1 >= start + min_lines && !contexts.contains_key(&end) {
+                let closing = self.text.line(end).to_string();
+                let closing = closing.trim_start();
+                if closing.starts_with(['}', ')', ']']) || closing.starts_with("end") {
+                    contexts.insert(end, self.signature(start));
+                }
+            }
+
+            let mut cursor = node.walk();
+            let children: Vec<Node> = node.named_children(&mut cursor).collect();
+            nodes.extend(children.into_iter().rev()); // outer first, then in order
+        }
+        contexts
+    }
+
+    /// Ranges of functions/blocks enclosing the position, inner first.
+    /// Node kinds come from the language `scope` config. Columns are chars.
+    pub fn function_ranges_at(&self, row: usize, column: usize) -> Vec<(Point, Point)> {
+        const DEFAULT_KINDS: [&str; 12] = [
+            "function_item", "closure_expression", "function_definition",
+            "function_declaration", "method_declaration", "method_definition",
+            "constructor_declaration", "arrow_function", "function_expression",
+            "func_literal", "lambda", "lambda_literal",
+        ];
+
+        let kinds: Vec<String> = match self.lang_conf.as_ref().and_then(|lc| lc.scope.clone()) {
+            Some(kinds) => kinds,
+            None => DEFAULT_KINDS.iter().map(|k| k.to_string()).collect(),
+        };
+
+        let root = match self.tree.as_ref() {
+            Some(tree) => tree.root_node(),
+            None => return vec![],
+        };
+        if row >= self.text.len_lines() { return vec![]; }
+
+        // tree-sitter points count bytes, the editor counts chars
+        let line_start = self.text.line_to_char(row);
+        let column = column.min(self.text.line(row).len_chars());
+        let byte_column = self.text.char_to_byte(line_start + column) - self.text.line_to_byte(row);
+        let point = Point { row, column: byte_column };
+        let mut node = root.named_descendant_for_point_range(point, point);
+
+        let to_chars = |p: Point| {
+            let byte = (self.text.line_to_byte(p.row) + p.column).min(self.text.len_bytes());
+            Point { row: p.row, column: self.text.byte_to_char(byte) - self.text.line_to_char(p.row) }
+        };
+
+        let mut ranges = vec![];
+        while let Some(n) = node {
+            if kinds.iter().any(|k| k == n.kind()) {
+                ranges.push((to_chars(n.start_position()), to_chars(n.end_position())));
+            }
+            node = n.parent();
+        }
+        ranges
+    }
 }
 
 pub struct NodePath {
@@ -932,6 +2124,34 @@ mod code_rope_search_tests {
     }
 }
 
+#[cfg(test)]
+mod code_search_options_tests {
+    use crate::code::Code;
+    use crate::search::SearchOptions;
+    use ropey::Rope;
+
+    #[test]
+    fn test_search_options() {
+        let mut code = Code::new();
+        code.text = Rope::from_str("let päth = Path::new();\nlet path2 = path;\n");
+
+        let plain = SearchOptions::default();
+        assert_eq!(code.search("path", &plain).unwrap(), vec![(1, 4, 4), (1, 12, 4)]);
+        assert_eq!(code.search("= P", &plain).unwrap(), vec![(0, 9, 3)]); // char columns
+
+        let case_insensitive = SearchOptions { case_insensitive: true, ..plain };
+        assert_eq!(code.search("path", &case_insensitive).unwrap(), vec![(0, 11, 4), (1, 4, 4), (1, 12, 4)]);
+
+        let whole_word = SearchOptions { whole_word: true, ..plain };
+        assert_eq!(code.search("path", &whole_word).unwrap(), vec![(1, 12, 4)]);
+
+        let regex = SearchOptions { regex: true, ..plain };
+        assert_eq!(code.search(r"p.th\d?", &regex).unwrap(), vec![(0, 4, 4), (1, 4, 5), (1, 12, 4)]);
+        assert_eq!(code.search("^let", &regex).unwrap().len(), 2);
+        assert!(code.search("(path", &regex).is_err());
+    }
+}
+
 // Enum to represent different types of operations
 #[derive(Debug)]
 pub enum Operation {
@@ -954,40 +2174,69 @@ pub struct Change {
 #[derive(Debug, Default)]
 pub struct MultipleChange {
     pub changes: Vec<Change>,
+    /// (row, col, row1, col1, text) of each step in the order applied, for lsp.
+    pub edits: Vec<(usize, usize, usize, usize, String)>,
 }
 
 impl Code {
     pub fn undo(&mut self) -> Option<MultipleChange> {
+        self.close_undo_groups();
         let mut multiple_change = MultipleChange::default();
-        let mut end = false;
-        let mut multiple = false;
+        let mut depth = 0; // groups can be nested by checkpoints
 
-        while !end {
+        let result = loop {
             match self.history.pop() {
-                None => return None,
+                None => break None,
                 Some(change) => {
                     match change.operation {
                         Operation::Insert => {
                             let from = change.start;
                             let to = from + change.text.chars().count();
+                            let (r, c) = self.char_to_position(from);
+                            let (r1, c1) = self.char_to_position(to);
+                            multiple_change.edits.push((r, c, r1, c1, String::new()));
                             self.remove(from, to);
                             multiple_change.changes.push(change);
-                            if !multiple { return Some(multiple_change) }
                         },
                         Operation::Remove => {
+                            let (r, c) = self.char_to_position(change.start);
+                            multiple_change.edits.push((r, c, r, c, change.text.clone()));
                             self.insert(&change.text, change.start);
                             multiple_change.changes.push(change);
-                            if !multiple { return Some(multiple_change) }
                         }
-                        Operation::End => multiple = true,
-                        Operation::Start => end = true,
+                        Operation::End => depth += 1,
+                        Operation::Start => depth -= 1,
                     }
+                    if depth == 0 { break Some(multiple_change) }
                 }
             }
+        };
 
-        }
+        self.checkpoint = self.history.len();
+        result
+    }
 
-        Some(multiple_change)
+    /// (row, col) of a char index.
+    fn char_to_position(&self, idx: usize) -> (usize, usize) {
+        let row = self.text.char_to_line(idx);
+        (row, idx - self.text.line_to_char(row))
+    }
+
+    /// Groups changes made since the last checkpoint, so they are undone together.
+    pub fn undo_checkpoint(&mut self) {
+        self.close_undo_groups();
+        let from = min(self.checkpoint, self.history.len());
+        if self.history.len() - from > 1 {
+            self.history.insert(from, Change {
+                start: 0, operation: Operation::Start,
+                text: "".to_string(), row:0, column:0
+            });
+            self.history.push(Change {
+                start: 0, operation: Operation::End,
+                text: "".to_string(), row:0, column:0
+            });
+        }
+        self.checkpoint = self.history.len();
     }
 }
 
@@ -1012,6 +2261,75 @@ mod code_undo_tests {
         println!("{:?}", buffer.history);
 
     }
+
+    #[test]
+    fn test_code_undo_checkpoint() {
+        let mut buffer = Code::new();
+
+        buffer.insert_text("hello", 0, 0);
+        buffer.insert_text(" ", 0, 5);
+        buffer.undo_checkpoint();
+        buffer.insert_text("world", 0, 6);
+        buffer.insert_text("\nfoo\n", 0, 11);
+        buffer.move_line_down(0); // nested group
+        buffer.undo_checkpoint();
+
+        buffer.undo();
+        assert_eq!(buffer.text.to_string(), "hello ");
+        buffer.undo();
+        assert_eq!(buffer.text.to_string(), "");
+        assert!(buffer.undo().is_none());
+    }
+
+    #[test]
+    fn test_code_undo_edits() {
+        let mut buffer = Code::from_str("hello\nworld\na");
+        buffer.move_line_down(0);
+        assert_eq!(buffer.text.to_string(), "world\nhello\na");
+
+        let edits = buffer.undo().unwrap().edits;
+        assert_eq!(edits, vec![
+            (1, 0, 1, 5, "".to_string()),
+            (1, 0, 1, 0, "world".to_string()),
+            (0, 0, 0, 5, "".to_string()),
+            (0, 0, 0, 0, "hello".to_string()),
+        ]);
+
+        // replaying the edits on the moved text gives the undone text
+        let mut replay = Code::from_str("world\nhello\na");
+        for (r, c, r1, c1, text) in edits {
+            replay.remove_text(r, c, r1, c1);
+            replay.insert_text(&text, r, c);
+        }
+        assert_eq!(replay.text.to_string(), buffer.text.to_string());
+    }
+
+    #[test]
+    fn test_code_undo_open_group() {
+        let mut buffer = Code::new();
+        buffer.insert_text("hello", 0, 0);
+        buffer.undo_checkpoint();
+        buffer.undo_group_start(); // never ended, e.g. an early return
+        buffer.insert_text(" world", 0, 5);
+        buffer.undo_checkpoint();
+        buffer.undo_group_end();
+
+        buffer.undo();
+        assert_eq!(buffer.text.to_string(), "hello");
+        buffer.undo();
+        assert_eq!(buffer.text.to_string(), "");
+    }
+
+    #[test]
+    fn test_code_undo_empty_group() {
+        let mut buffer = Code::new();
+        buffer.insert_text("hello", 0, 0);
+        buffer.undo_group_start();
+        buffer.undo_group_end(); // nothing changed, e.g. a save without trimming
+
+        buffer.undo();
+        assert_eq!(buffer.text.to_string(), "");
+    }
 }
 
 
@@ -1120,6 +2438,767 @@ mod code_move_line_test {
     }
 }
 
+#[cfg(test)]
+mod code_stats_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_text_stats() {
+        let code = Code::from_str("hello world\nпривет  мир\n");
+        let len = code.text.len_chars();
+        assert_eq!(code.text_stats(0, len), (2, 4, 24, 33));
+        assert_eq!(code.text_stats(0, 5), (1, 1, 5, 5));
+        assert_eq!(code.text_stats(0, 0), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_whitespace_indicators() {
+        let mut code = Code::from_str("fn main() {\n    let a = 1; \n}");
+        assert_eq!(code.first_trailing_whitespace(), Some(1));
+        assert!(!code.has_final_newline());
+
+        code.remove_text(1, 14, 1, 15);
+        code.insert_text("\n", 2, 1);
+        assert_eq!(code.first_trailing_whitespace(), None);
+        assert!(code.has_final_newline());
+    }
+}
+
+#[cfg(test)]
+mod code_paste_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_reindent_paste() {
+        let code = Code::from_str("fn main() {\n    \n}\n");
+        let block = "if a {\n    b();\n\n}";
+        assert_eq!(code.reindent_paste(block, 1, 4), "if a {\n        b();\n\n    }");
+
+        let block = "        if a {\n            b();\n        }";
+        assert_eq!(code.reindent_paste(block, 1, 4), "if a {\n        b();\n    }");
+
+        assert_eq!(code.reindent_paste("one line", 1, 4), "one line");
+    }
+}
+
+#[cfg(test)]
+mod code_align_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_align_on() {
+        let mut code = Code::from_str("a = 1\nlong_name    = 2\nno delimiter\nmid= 3\n");
+        let replaced = code.align_on(0, 3, "=");
+        assert_eq!(replaced.len(), 3);
+        assert_eq!(code.text.to_string(), "a         = 1\nlong_name = 2\nno delimiter\nmid       = 3\n");
+
+        assert!(code.align_on(0, 3, "=").is_empty());
+        assert!(code.align_on(0, 3, "=>").is_empty());
+    }
+}
+
+#[cfg(test)]
+mod code_trim_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_trim_eof_blank_lines() {
+        let mut code = Code::from_str("fn main() {}\n\n  \n\n");
+        assert_eq!(code.trim_eof_blank_lines(), Some((1, 0, 4, 0)));
+        assert_eq!(code.text.to_string(), "fn main() {}\n");
+        assert_eq!(code.trim_eof_blank_lines(), None);
+
+        let mut code = Code::from_str("fn main() {}");
+        assert_eq!(code.trim_eof_blank_lines(), None);
+
+        let mut code = Code::from_str("a\n\n ");
+        assert_eq!(code.trim_eof_blank_lines(), Some((1, 0, 2, 1)));
+        assert_eq!(code.text.to_string(), "a\n");
+    }
+
+    #[test]
+    fn test_trim_trailing_whitespace() {
+        let mut code = Code::from_str("fn main() {  \n\tlet a = 1;\t \r\n}\n \n");
+        code.undo_group_start();
+        assert_eq!(code.trim_trailing_whitespace(), vec![(0, 11, 13), (1, 11, 13), (3, 0, 1)]);
+        code.undo_group_end();
+        assert_eq!(code.text.to_string(), "fn main() {\n\tlet a = 1;\r\n}\n\n");
+        assert!(code.trim_trailing_whitespace().is_empty());
+
+        code.undo();
+        assert_eq!(code.text.to_string(), "fn main() {  \n\tlet a = 1;\t \r\n}\n \n");
+    }
+
+    #[test]
+    fn test_ensure_final_newline() {
+        let mut code = Code::from_str("a\nb");
+        assert_eq!(code.ensure_final_newline(), Some((1, 1)));
+        assert_eq!(code.text.to_string(), "a\nb\n");
+        assert_eq!(code.ensure_final_newline(), None);
+
+        let mut code = Code::from_str("");
+        assert_eq!(code.ensure_final_newline(), None);
+    }
+}
+
+#[cfg(test)]
+mod code_recent_lines_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_recent_lines() {
+        let mut code = Code::from_str(&"line\n".repeat(100)); // inserted at row 0
+
+        code.insert_text("a", 10, 0);
+        code.insert_text("b", 30, 0);
+        code.insert_text("c", 12, 0); // near 10
+        code.remove_text(40, 0, 40, 1);
+        assert_eq!(code.recent_lines(), vec![40, 12, 30, 0]);
+
+        for row in 0..12 { code.insert_text("d", row * 6, 0); }
+        assert_eq!(code.recent_lines().len(), 10);
+        assert_eq!(code.recent_lines()[0], 66);
+    }
+
+    #[test]
+    fn test_recent_lines_shift() {
+        let mut code = Code::from_str(&"line\n".repeat(100));
+        code.insert_text("a", 50, 0);
+        code.insert_text("b", 70, 0);
+
+        code.insert_text("new\nnew\n", 10, 0);
+        assert_eq!(code.recent_lines(), vec![10, 72, 52, 0]);
+
+        code.remove_text(52, 4, 60, 0);
+        assert_eq!(code.recent_lines(), vec![52, 10, 64, 0]);
+    }
+}
+
+#[cfg(test)]
+mod code_line_endings_tests {
+    use crate::code::{Code, EolStyle};
+    use ropey::Rope;
+
+    fn load(text: &str) -> Code {
+        let config = crate::config::get();
+        Code::from_text(Rope::from_str(text), "a.txt".to_string(), String::new(), "text".to_string(), &config)
+    }
+
+    #[test]
+    fn test_mixed_line_endings() {
+        let mut code = load("\u{feff}a\r\nb\r\nc\nd\r\n");
+        assert_eq!(code.text.to_string(), "\u{feff}a\nb\nc\nd\n");
+        assert_eq!(code.eol, EolStyle::Crlf);
+        assert!(code.has_bom());
+        assert_eq!(code.mixed_line_endings(), &[2]);
+
+        assert!(code.normalize_line_endings());
+        assert_eq!(code.text.to_string(), "a\nb\nc\nd\n");
+        assert!(!code.has_bom() && code.mixed_line_endings().is_empty());
+        assert!(!code.normalize_line_endings());
+
+        let code = load("a\r\nb\n");
+        assert_eq!(code.eol, EolStyle::Lf); // lf on a tie
+        assert_eq!(code.mixed_line_endings(), &[0]);
+
+        let code = load("a\rb\n");
+        assert_eq!((code.eol, code.text.to_string()), (EolStyle::Lf, "a\rb\n".to_string()));
+    }
+
+    #[test]
+    fn test_save_keeps_crlf() {
+        let path = std::env::temp_dir().join("red_crlf_test.txt");
+        let mut code = load("a\r\nb\nc\r\n");
+        code.set_path(path.to_str().unwrap());
+        code.insert_text("x\n", 1, 0);
+        code.save_file().unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\r\nx\r\nb\r\nc\r\n");
+        assert!(code.mixed_line_endings().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_file() {
+        let path = std::env::temp_dir().join("red_reload_test.txt");
+        let mut code = load("a\nb\n");
+        code.set_path(path.to_str().unwrap());
+        code.save_file().unwrap();
+        assert!(!code.modified_on_disk());
+
+        std::fs::write(&path, "a\r\nc\r\n").unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(code.modified_on_disk());
+
+        code.insert_text("x", 0, 0);
+        code.reload_file().unwrap();
+        assert_eq!((code.text.to_string(), code.eol), ("a\nc\n".to_string(), EolStyle::Crlf));
+        assert!(!code.changed && !code.modified_on_disk());
+
+        std::fs::write(&path, b"caf\xe9\n").unwrap(); // latin1
+        let later = later + std::time::Duration::from_secs(10);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(code.reload_file().is_err());
+        code.keep_disk_change();
+        assert!(!code.modified_on_disk());
+
+        code.encoding = "latin1".to_string();
+        code.reload_file().unwrap();
+        assert_eq!(code.text.to_string(), "café\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod code_utf16_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_utf16_to_char() {
+        let code = Code::from_str("let 🦀 = x;\nab\n");
+        assert_eq!(code.utf16_to_char(0, 4), Some(4));
+        assert_eq!(code.utf16_to_char(0, 6), Some(5)); // the crab is two units
+        assert_eq!(code.utf16_to_char(0, 11), Some(10));
+        assert_eq!(code.utf16_to_char(0, 12), None);
+        assert_eq!(code.utf16_to_char(1, 2), Some(2));
+        assert_eq!(code.utf16_to_char(5, 0), None);
+    }
+}
+
+#[cfg(test)]
+mod code_line_changes_tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use crate::code::{ChangeKind, Code};
+
+    #[test]
+    fn test_line_changes() {
+        let mut code = Code::from_str("a\nb\nc\nd\n");
+        code.take_snapshot();
+        assert!(code.line_changes().is_empty());
+
+        code.insert_text("x", 1, 1); // b -> bx
+        code.insert_text("new\n", 3, 0); // before d
+        code.remove_text(0, 0, 1, 0); // a deleted
+        assert_eq!(code.text.to_string(), "bx\nc\nnew\nd\n");
+        assert_eq!(*code.line_changes(), HashMap::from([
+            (0, ChangeKind::Modified), (2, ChangeKind::Added),
+        ]));
+
+        code.remove_text(2, 0, 4, 0); // new and d
+        assert_eq!(*code.line_changes(), HashMap::from([
+            (0, ChangeKind::Modified), (2, ChangeKind::Deleted),
+        ]));
+    }
+
+    #[test]
+    fn test_line_changes_cached() {
+        let mut code = Code::from_str("a\nb\n");
+        code.take_snapshot();
+        code.insert_text("x", 0, 1);
+
+        let changes = code.line_changes();
+        assert!(Rc::ptr_eq(&changes, &code.line_changes())); // no diff until the next edit
+
+        code.insert_text("y", 1, 1);
+        assert!(!Rc::ptr_eq(&changes, &code.line_changes()));
+        assert_eq!(code.line_changes().len(), 2);
+
+        code.take_snapshot(); // saved
+        assert!(code.line_changes().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod code_encoding_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_save_with_encoding() {
+        let dir = std::env::temp_dir().join("red_encoding_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        std::fs::write(&path, b"caf\xe9\n").unwrap();
+
+        let config = crate::config::get();
+        let mut code = Code::from_file_with_encoding(path.to_str().unwrap(), "latin1", &config).unwrap();
+        code.insert_text("é", 1, 0);
+        code.save_file().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"caf\xe9\n\xe9");
+
+        code.insert_text("€", 1, 1); // not in latin1
+        assert!(code.save_file().is_err());
+        assert!(code.changed);
+        assert_eq!(std::fs::read(&path).unwrap(), b"caf\xe9\n\xe9");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod code_swap_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_swap_write_recover() {
+        let dir = std::env::temp_dir().join("red_swap_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        std::fs::write(&path, "hello\n").unwrap();
+
+        let config = crate::config::get();
+        let mut code = Code::from_file(path.to_str().unwrap(), &config).unwrap();
+        assert!(!code.has_newer_swap());
+
+        code.insert_text("world ", 0, 0);
+        code.write_swap().unwrap();
+        assert!(dir.join(".file.txt.red.swp").exists());
+
+        let mut reopened = Code::from_file(path.to_str().unwrap(), &config).unwrap();
+        assert!(reopened.has_newer_swap());
+        reopened.recover_swap().unwrap();
+        assert_eq!(reopened.text.to_string(), "world hello\n");
+
+        reopened.save_file().unwrap();
+        assert!(!dir.join(".file.txt.red.swp").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod code_lock_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_lock_owner() {
+        let dir = std::env::temp_dir().join("red_lock_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        std::fs::write(&path, "hello\n").unwrap();
+        let lock = dir.join(".file.txt.red.lock");
+
+        let config = crate::config::get();
+        let mut code = Code::from_file(path.to_str().unwrap(), &config).unwrap();
+        assert_eq!(code.lock_owner(), None);
+
+        code.lock().unwrap();
+        assert!(lock.exists());
+        assert_eq!(code.lock_owner(), None); // own lock
+
+        let mut other = std::process::Command::new("sleep").arg("5").spawn().unwrap();
+        std::fs::write(&lock, other.id().to_string()).unwrap();
+        assert_eq!(code.lock_owner(), Some(other.id()));
+
+        other.kill().unwrap();
+        other.wait().unwrap();
+        assert_eq!(code.lock_owner(), None); // stale
+
+        let mut reopened = Code::from_file(path.to_str().unwrap(), &config).unwrap();
+        reopened.take_lock(&mut code);
+        code.unlock(); // no longer its lock
+        assert!(lock.exists());
+
+        reopened.unlock();
+        assert!(!lock.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod code_save_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_create_missing_dir() {
+        let dir = std::env::temp_dir().join("red_save_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("a").join("b").join("file.txt");
+
+        let mut code = Code::new();
+        code.set_path(path.to_str().unwrap());
+        code.insert_text("hello\n", 0, 0);
+        assert_eq!(code.missing_dir(), Some(dir.join("a").join("b")));
+        assert!(code.save_file().is_err());
+
+        code.create_dir().unwrap();
+        assert_eq!(code.missing_dir(), None);
+        code.save_file().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod code_deferred_parse_tests {
+    use crate::code::Code;
+    use ropey::Rope;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_large_paste_parsed_in_background() {
+        let config = crate::config::get();
+        let mut code = Code::from_text(
+            Rope::from_str("fn main() {}\n"), "a.rs".to_string(), String::new(), "rust".to_string(), &config
+        );
+        let theme = HashMap::from([("keyword".to_string(), "#ff0000".to_string())]);
+        let block: String = (0..20000).map(|i| format!("fn f{}() {{}}\n", i)).collect();
+
+        assert!(code.background_parse().is_none());
+        code.insert_text_deferred(&block, 1, 0);
+        assert_eq!(code.len_lines(), 20002);
+        assert!(code.colors(1, 3, &theme).is_empty()); // not parsed yet
+
+        // an edit while parsing drops the stale tree
+        let (version, parse) = code.background_parse().unwrap();
+        code.insert_text("\n", 0, 0);
+        assert!(!code.set_tree(parse().unwrap(), version));
+
+        let (version, parse) = code.background_parse().unwrap();
+        assert!(code.set_tree(parse().unwrap(), version));
+        assert!(code.background_parse().is_none());
+        assert_eq!(code.colors(2, 3, &theme).len(), 1);
+        assert!(code.refresh_runnables());
+    }
+}
+
+#[cfg(test)]
+mod code_runnables_tests {
+    use crate::code::Code;
+    use ropey::Rope;
+
+    #[test]
+    fn test_runnables_shift_until_refresh() {
+        let config = crate::config::get();
+        let text = "fn main() {}\n\nfn test_a() {}\n";
+        let mut code = Code::from_text(
+            Rope::from_str(text), "a.rs".to_string(), String::new(), "rust".to_string(), &config
+        );
+        assert!(code.is_runnable(0) && code.is_runnable(2));
+
+        code.insert_text("\n\n", 1, 0);
+        assert!(code.is_runnable(0) && code.is_runnable(4) && !code.is_runnable(2));
+
+        code.remove_text(1, 0, 3, 0);
+        assert!(code.is_runnable(2));
+
+        code.insert_text("fn test_b() {}\n", 1, 0);
+        assert!(!code.is_runnable(1));
+        assert!(code.refresh_runnables());
+        assert!(code.is_runnable(1) && code.is_runnable(3));
+        assert!(!code.refresh_runnables());
+    }
+}
+
+#[cfg(test)]
+mod code_highlight_tests {
+    use crate::code::Code;
+    use ropey::Rope;
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    fn large_rust_file(functions: usize) -> Code {
+        let config = crate::config::get();
+        let text: String = (0..functions)
+            .map(|i| format!("/// Doc {i}\nfn f{i}(a: u8) -> String {{\n    let s = \"{i}\";\n    s.to_string() // done\n}}\n\n"))
+            .collect();
+        Code::from_text(Rope::from_str(&text), "a.rs".to_string(), String::new(), "rust".to_string(), &config)
+    }
+
+    fn theme() -> HashMap<String, String> {
+        ["keyword", "function", "string", "comment", "type", "variable"].iter()
+            .map(|name| (name.to_string(), "#ff0000".to_string()))
+            .collect()
+    }
+
+    /// Colors of the rows straight from the query, as drawn before the cache.
+    fn uncached_colors(code: &Code, from: usize, to: usize, theme: &HashMap<String, String>) -> Vec<(usize, usize)> {
+        code.highlights.replace(None);
+        code.colors(from, to, theme).iter().map(|c| (c.0, c.1)).collect()
+    }
+
+    #[test]
+    fn test_colors_cache() {
+        let mut code = large_rust_file(100);
+        let theme = theme();
+
+        for y in [0, 1, 2, 40, 39, 300] {
+            let cached: Vec<(usize, usize)> = code.colors(y, y + 30, &theme).iter().map(|c| (c.0, c.1)).collect();
+            assert!(!cached.is_empty());
+            assert_eq!(cached, uncached_colors(&code, y, y + 30, &theme));
+        }
+
+        code.colors(0, 30, &theme);
+        code.insert_text("// ", 1, 0); // the edit resets the cache
+        let colors: Vec<(usize, usize)> = code.colors(0, 30, &theme).iter().map(|c| (c.0, c.1)).collect();
+        assert_eq!(colors, uncached_colors(&code, 0, 30, &theme));
+    }
+
+    #[test]
+    #[ignore = "slow, run with --ignored"]
+    fn test_colors_scroll_bench() {
+        let code = large_rust_file(300);
+        let theme = theme();
+        let (height, rows) = (50, code.len_lines() - 50);
+
+        let start = Instant::now();
+        for y in 0..rows { uncached_colors(&code, y, y + height, &theme); }
+        let before = start.elapsed().as_secs_f64();
+
+        code.highlights.replace(None);
+        let start = Instant::now();
+        for y in 0..rows { code.colors(y, y + height, &theme); }
+        let after = start.elapsed().as_secs_f64();
+
+        // scroll 1751 rows: query every row 2.388s, cached 0.137s (debug build)
+        assert!(after < before, "cached {:.3}s, query every row {:.3}s", after, before);
+    }
+}
+
+#[cfg(test)]
+mod code_scopes_tests {
+    use crate::code::Code;
+    use ropey::Rope;
+
+    #[test]
+    fn test_scopes_at() {
+        let config = crate::config::get();
+        let text = "impl Foo {\n    fn bar() {\n        let a = 1;\n    }\n}\n";
+        let code = Code::from_text(
+            Rope::from_str(text), "a.rs".to_string(), String::new(), "rust".to_string(), &config
+        );
+
+        let scopes: Vec<String> = code.scopes_at(2).into_iter().map(|(_, s)| s).collect();
+        assert_eq!(scopes, vec!["impl Foo", "fn bar()"]);
+        assert!(code.scopes_at(0).is_empty());
+
+        // a struct literal is no scope though its kind has "struct" in it
+        let text = "fn main() {\n    let a = Foo {\n        x: 1,\n    };\n}\n";
+        let code = Code::from_text(
+            Rope::from_str(text), "a.rs".to_string(), String::new(), "rust".to_string(), &config
+        );
+        let scopes: Vec<String> = code.scopes_at(2).into_iter().map(|(_, s)| s).collect();
+        assert_eq!(scopes, vec!["fn main()"]);
+    }
+
+    #[test]
+    fn test_closing_contexts() {
+        let config = crate::config::get();
+        let text = "impl Foo {\n    fn bar() {\n        if a {\n            b();\n        }\n    }\n}\n";
+        let code = Code::from_text(
+            Rope::from_str(text), "a.rs".to_string(), String::new(), "rust".to_string(), &config
+        );
+
+        let contexts = code.closing_contexts(0, 6, 0);
+        assert_eq!(contexts.get(&4).map(String::as_str), Some("if a"));
+        assert_eq!(contexts.get(&5).map(String::as_str), Some("fn bar()"));
+        assert_eq!(contexts.get(&6).map(String::as_str), Some("impl Foo"));
+        assert_eq!(code.closing_contexts(0, 6, 4).len(), 2);
+        assert!(code.closing_contexts(0, 3, 0).is_empty());
+    }
+
+    #[test]
+    fn test_function_ranges_at() {
+        let config = crate::config::get();
+        let text = "impl Foo {\n    fn bar() {\n        let f = |x| x + 1;\n    }\n}\n";
+        let code = Code::from_text(
+            Rope::from_str(text), "a.rs".to_string(), String::new(), "rust".to_string(), &config
+        );
+
+        let ranges: Vec<_> = code.function_ranges_at(2, 19).into_iter()
+            .map(|(s, e)| ((s.row, s.column), (e.row, e.column))).collect();
+        assert_eq!(ranges, vec![((2, 16), (2, 25)), ((1, 4), (3, 5))]);
+        assert_eq!(code.function_ranges_at(2, 8).len(), 1);
+        assert!(code.function_ranges_at(0, 0).is_empty());
+
+        // columns are chars, not bytes
+        let text = "fn a() {\n    let s = \"ёжик\"; let f = |x| x;\n}\n";
+        let code = Code::from_text(
+            Rope::from_str(text), "a.rs".to_string(), String::new(), "rust".to_string(), &config
+        );
+        let ranges: Vec<_> = code.function_ranges_at(1, 30).into_iter()
+            .map(|(s, e)| ((s.row, s.column), (e.row, e.column))).collect();
+        assert_eq!(ranges, vec![((1, 28), (1, 33)), ((0, 0), (2, 1))]);
+    }
+}
+
+#[cfg(test)]
+mod code_call_tests {
+    use crate::code::Code;
+
+    #[test]
+    fn test_call_start() {
+        let code = Code::from_str("let a = add(1, mul(2, 3), 4);\n");
+        assert_eq!(code.call_start(0, 12), Some(11));
+        assert_eq!(code.call_start(0, 20), Some(18));
+        assert_eq!(code.call_start(0, 26), Some(11));
+        assert_eq!(code.call_start(0, 29), None);
+        assert_eq!(code.call_start(0, 5), None);
+    }
+}
+
+#[cfg(test)]
+mod code_closing_tag_tests {
+    use crate::code::Code;
+    use ropey::Rope;
+
+    fn code(text: &str, path: &str, lang: &str) -> Code {
+        let config = crate::config::get();
+        Code::from_text(Rope::from_str(text), path.to_string(), path.to_string(), lang.to_string(), &config)
+    }
+
+    #[test]
+    fn test_closing_tag_jsx() {
+        let code = code("const a = <div className=\"x\">;\nconst b = <br/>;\nlet c = 1 > 0;\n", "/a.js", "javascript");
+        assert_eq!(code.closing_tag_at(0, 29), Some("</div>".to_string()));
+        assert_eq!(code.closing_tag_at(0, 28), None);
+        assert_eq!(code.closing_tag_at(1, 15), None);
+        assert_eq!(code.closing_tag_at(2, 11), None);
+    }
+
+    #[test]
+    fn test_closing_tag_xml() {
+        let code = code("<note id=\"1\"></note>\n<to>\n<br/>\n<?xml?>\n", "/a.xml", "text");
+        assert_eq!(code.closing_tag_at(0, 13), None);
+        assert_eq!(code.closing_tag_at(1, 4), Some("</to>".to_string()));
+        assert_eq!(code.closing_tag_at(2, 5), None);
+        assert_eq!(code.closing_tag_at(3, 7), None);
+
+        let code = self::code("<to>\n", "/a.txt", "text");
+        assert_eq!(code.closing_tag_at(0, 4), None);
+    }
+}
+
+#[cfg(test)]
+mod code_pairs_tests {
+    use crate::code::Code;
+    use ropey::Rope;
+
+    fn code(text: &str, lang: &str) -> Code {
+        let config = crate::config::get();
+        Code::from_text(Rope::from_str(text), "/a".to_string(), "/a".to_string(), lang.to_string(), &config)
+    }
+
+    #[test]
+    fn test_closing_pair() {
+        let code = code("let a = foo;\nlet s = \"abc\";\n// note\nb = \n", "rust");
+        assert_eq!(code.closing_pair(0, 11, '('), Some(')'));
+        assert_eq!(code.closing_pair(0, 8, '('), None); // before a word
+        assert_eq!(code.closing_pair(0, 11, '\''), None); // rust lifetimes
+        assert_eq!(code.closing_pair(1, 10, '['), None); // in a string
+        assert_eq!(code.closing_pair(2, 7, '{'), None); // in a comment
+        assert_eq!(code.closing_pair(3, 1, '"'), None); // after a word
+        assert_eq!(code.closing_pair(3, 4, '"'), Some('"'));
+        assert_eq!(code.closing_pair(3, 4, 'x'), None);
+    }
+
+    #[test]
+    fn test_is_empty_pair() {
+        let code = code("f()\n[ ]\n\"\"\n", "text");
+        assert!(code.is_empty_pair(0, 2));
+        assert!(!code.is_empty_pair(0, 1));
+        assert!(!code.is_empty_pair(1, 1));
+        assert!(code.is_empty_pair(2, 1));
+    }
+}
+
+#[cfg(test)]
+mod code_indent_tests {
+    use crate::code::Code;
+    use ropey::Rope;
+
+    fn code(text: &str, lang: &str) -> Code {
+        let config = crate::config::get();
+        Code::from_text(Rope::from_str(text), "/a".to_string(), "/a".to_string(), lang.to_string(), &config)
+    }
+
+    #[test]
+    fn test_enter_indent_rust() {
+        let code = code("fn main() {\n    let s = \"{\";\n    foo({});\n    // {\n    bar();\n}\n", "rust");
+        assert_eq!(code.enter_indent(0, 11), (1, false));
+        assert_eq!(code.enter_indent(0, 10), (0, false)); // before the brace
+        assert_eq!(code.enter_indent(1, 14), (1, false)); // in a string
+        assert_eq!(code.enter_indent(2, 9), (2, true)); // between braces
+        assert_eq!(code.enter_indent(3, 8), (1, false)); // in a comment
+        assert_eq!(code.enter_indent(4, 10), (1, false));
+    }
+
+    #[test]
+    fn test_enter_indent_python() {
+        let code = code("def main():\n    if x:  \n        pass\n", "python");
+        assert_eq!(code.enter_indent(0, 11), (1, false));
+        assert_eq!(code.enter_indent(1, 11), (2, false));
+        assert_eq!(code.enter_indent(2, 12), (2, false));
+
+        let code = self::code("def main():\n", "text");
+        assert_eq!(code.enter_indent(0, 11), (0, false)); // no grammar
+    }
+
+    #[test]
+    fn test_closing_indent() {
+        let code = code("fn main() {\n    if x {\n        }\n    }\n", "rust");
+        assert_eq!(code.closing_indent(2, 8), Some(1));
+        assert_eq!(code.closing_indent(3, 4), Some(0));
+        assert_eq!(code.closing_indent(1, 9), None);
+    }
+}
+
+#[cfg(test)]
+mod code_comment_tests {
+    use crate::code::Code;
+    use ropey::Rope;
+
+    fn code(text: &str, file_name: &str, lang: &str) -> Code {
+        let config = crate::config::get();
+        Code::from_text(
+            Rope::from_str(text), file_name.to_string(), String::new(), lang.to_string(), &config
+        )
+    }
+
+    #[test]
+    fn test_comment_prefix_rust() {
+        let code = code("// note\n    /// doc\n//!crate\nlet a = 1; // tail\n//\n", "a.rs", "rust");
+        assert_eq!(code.comment_prefix(0), Some((0, "// ".to_string())));
+        assert_eq!(code.comment_prefix(1), Some((4, "/// ".to_string())));
+        assert_eq!(code.comment_prefix(2), Some((0, "//!".to_string())));
+        assert_eq!(code.comment_prefix(3), None);
+        assert_eq!(code.comment_prefix(4), Some((0, "//".to_string())));
+    }
+
+    #[test]
+    fn test_comment_prefix_python() {
+        let code = code("def f():\n    # step one\n    ## section\n    x = 1\n", "a.py", "python");
+        assert_eq!(code.comment_prefix(0), None);
+        assert_eq!(code.comment_prefix(1), Some((4, "# ".to_string())));
+        assert_eq!(code.comment_prefix(2), Some((4, "## ".to_string())));
+        assert_eq!(code.comment_prefix(3), None);
+    }
+
+    #[test]
+    fn test_toggle_comment_lines() {
+        let mut code = code("fn main() {\n    let a = 1;\n\n  // b\n}\n", "a.rs", "rust");
+
+        let replaced = code.toggle_comment_lines(1, 3);
+        assert_eq!(replaced, vec![(1, 2, 2, "//".to_string()), (3, 2, 2, "//".to_string())]);
+        assert_eq!(code.text.to_string(), "fn main() {\n  //  let a = 1;\n\n  //// b\n}\n");
+
+        code.toggle_comment_lines(1, 3);
+        assert_eq!(code.text.to_string(), "fn main() {\n    let a = 1;\n\n  // b\n}\n");
+
+        code.toggle_comment_lines(3, 3);
+        assert_eq!(code.text.to_string(), "fn main() {\n    let a = 1;\n\n   b\n}\n");
+
+        code.undo();
+        assert_eq!(code.text.to_string(), "fn main() {\n    let a = 1;\n\n  // b\n}\n");
+    }
+}
+
 #[cfg(test)]
 mod code_indentation_tests {
     use crate::code::Code;
@@ -1,11 +1,88 @@
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 // Define a struct to represent the TOML configuration
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub theme: String,
     pub left_panel_width: Option<usize>,
+    /// Seconds between swap file writes, 0 disables swap files.
+    pub swap_interval: Option<u64>,
+    /// Milliseconds without input before an undo checkpoint, 0 undoes every change separately.
+    pub undo_checkpoint_interval: Option<u64>,
+    /// Line numbers mode: absolute (default), relative or none.
+    pub line_numbers: Option<String>,
+    /// Pin enclosing function/class in a row above the text while scrolling, default false.
+    pub sticky_scroll: Option<bool>,
+    /// Remove blank lines at the end of the file on save, default false.
+    pub trim_eof_blank_lines: Option<bool>,
+    /// Remove spaces and tabs at the line ends on save, default false.
+    pub trim_trailing_whitespace: Option<bool>,
+    /// Add a newline at the end of the file on save if missing, default false.
+    pub ensure_final_newline: Option<bool>,
+    /// Reindent pasted multi-line blocks to the cursor line, default false.
+    pub smart_paste: Option<bool>,
+    /// Continue a line comment on Enter, default false.
+    pub comment_continuation: Option<bool>,
+    /// Insert closing brackets, quotes and html tags while typing, default false.
+    pub auto_pairs: Option<bool>,
+    /// Dir names hidden in the tree and skipped by search, added to the defaults.
+    pub ignore_dirs: Option<Vec<String>>,
+    /// File names hidden in the tree, added to the defaults.
+    pub ignore_files: Option<Vec<String>>,
+    /// File extensions skipped by search, added to the defaults.
+    pub ignore_exts: Option<Vec<String>>,
+    /// Skip paths matched by .gitignore files in the tree and global search, default true.
+    pub gitignore: Option<bool>,
+    /// Expand the tree to the active file and select it when the buffer changes, default false.
+    pub reveal_active_file: Option<bool>,
+    /// Project dirs to look for the file under cursor in, besides the file dir and project root.
+    pub path: Option<Vec<String>>,
+    /// Status line format, variables are listed in the readme,
+    /// default "{lsp}{position} {lang} {file}{modified}{indicators}{modes}".
+    pub status_line: Option<String>,
+    /// Columns of a tab stop, default the language indent width.
+    pub tab_width: Option<usize>,
+    /// Draw tabs as → and trailing spaces as ·, default false.
+    pub render_whitespace: Option<bool>,
+    /// Overview of the file in a column at the right, default false.
+    pub minimap: Option<bool>,
+    /// Most frames drawn per second, redraws in between are coalesced, default 0 (no limit).
+    pub max_fps: Option<u64>,
+    /// Show the signature of a long scope after its closing brace, default false.
+    pub closing_context: Option<bool>,
+    /// Lines a scope needs to get its closing context, default 20.
+    pub closing_context_lines: Option<usize>,
+    /// Mark a BOM and lines with the less common line ending, default false.
+    pub line_ending_warnings: Option<bool>,
+    /// Ms without keys or clicks before the cursor word highlight, semantic tokens and blame update, default 300.
+    pub idle_delay: Option<u64>,
+    /// Highlight the visible occurrences of the word under the cursor, `hlcolor` in the theme, default true.
+    pub highlight_word: Option<bool>,
+    /// Reopen the files of the last session when started without a file: ask (default), auto or off.
+    pub restore_session: Option<String>,
+    /// Create missing parent dirs of a saved file: ask (default), auto or off.
+    pub create_dirs: Option<String>,
+    /// Max text width in zen mode, default 100.
+    pub zen_width: Option<usize>,
+    /// Max lsp completion items shown, the best matches are kept, default 100.
+    pub completion_limit: Option<usize>,
+    /// Show snippet completion items, default true.
+    pub completion_snippets: Option<bool>,
+    /// Show plain text completion items, default true.
+    pub completion_text: Option<bool>,
+    /// Soft wrap long lines, default false.
+    pub wrap: Option<bool>,
+    /// Wrapped rows start at the indentation of their line, default true.
+    pub wrap_keep_indent: Option<bool>,
+    /// Extra columns wrapped rows are shifted by, default 2.
+    pub wrap_indent: Option<usize>,
+    /// Snippets by language name then trigger, `Tab` after a trigger expands its template,
+    /// `${1:default}` stops are visited with `Tab`.
+    pub snippets: Option<HashMap<String, HashMap<String, String>>>,
+    /// Key bindings by action name, e.g. save = "ctrl+s", see keys.rs for actions.
+    pub keys: Option<HashMap<String, String>>,
     pub language: Vec<Language>,
 }
 
@@ -19,6 +96,12 @@ pub struct Language {
     pub executable: Option<bool>,
     pub exec:       Option<String>,
     pub exectest:   Option<String>,
+    /// Tree-sitter node kinds selected as the current function/scope.
+    pub scope:      Option<Vec<String>>,
+    /// Line endings that indent the next line, default `{`, `(` and `[`.
+    pub indent_after: Option<Vec<String>>,
+    /// Chars kept inside words for word movement and selection, e.g. `-` in css.
+    pub word_chars: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -27,12 +110,36 @@ pub struct IndentConfig {
     pub unit:  String,
 }
 
-pub fn get() -> Config {
+/// Path of the config.toml in RED_HOME.
+pub fn path() -> PathBuf {
     let red_home = option_env!("RED_HOME").expect("RED_HOME must be set!");
-    let config_path = Path::new(red_home).join("config.toml");
-    let toml_str = std::fs::read_to_string(config_path).expect("Unable to read config.toml file");
-    let config: Config = toml::from_str(&toml_str).expect("Unable to parse TOML");
-    config
+    Path::new(red_home).join("config.toml")
+}
+
+impl Config {
+    /// Theme file path, relative theme paths are in RED_HOME.
+    pub fn theme_path(&self) -> PathBuf {
+        let path = Path::new(&self.theme);
+        if path.is_absolute() { return path.to_path_buf(); }
+        let red_home = option_env!("RED_HOME").unwrap_or("./");
+        Path::new(red_home).join(path)
+    }
+}
+
+pub fn get() -> Config {
+    load().unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Reads and checks config.toml, errors with the reason.
+pub fn load() -> Result<Config, String> {
+    let toml_str = std::fs::read_to_string(path())
+        .map_err(|e| format!("Unable to read config.toml file: {}", e))?;
+    let config: Config = toml::from_str(&toml_str)
+        .map_err(|e| format!("Unable to parse config.toml: {}", e))?;
+    if let Err(e) = crate::keys::keymap(config.keys.as_ref()) {
+        return Err(format!("Invalid [keys] in config.toml: {}", e));
+    }
+    Ok(config)
 }
 
 #[cfg(test)]
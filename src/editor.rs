@@ -1,17 +1,18 @@
 use std::cmp::Ordering;
 // editor.rs
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{stdout, Write};
-use std::path::Path;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
 use std::{fs, time};
-use log2::debug;
+use log2::{debug, error};
 
 use crossterm::event::{
     DisableMouseCapture, EnableMouseCapture, Event, EventStream, KeyCode, KeyEvent, KeyModifiers,
     MouseButton, MouseEvent, MouseEventKind, KeyEventKind
 };
 use crossterm::style::Print;
+use strfmt::strfmt;
 use crossterm::terminal::{
     disable_raw_mode, enable_raw_mode, ClearType, EnterAlternateScreen, LeaveAlternateScreen,
 };
@@ -19,24 +20,30 @@ use crossterm::{
     cursor,
     cursor::position,
     event, execute, queue,
-    style::{Color, SetBackgroundColor as BColor, SetForegroundColor as FColor},
+    style::{Attribute, Color, SetAttribute, SetBackgroundColor as BColor, SetForegroundColor as FColor},
     terminal,
 };
 
 use futures::{future::FutureExt, select, StreamExt};
+use unicode_width::UnicodeWidthChar;
 
-use crate::code::{Code, NodePath, Runnable};
-use crate::config::Config;
+use crate::code::{self, ChangeKind, Code, EolStyle, NodePath, Runnable};
+use crate::config::{self, Config};
+use crate::keys::{self, Action, KeyMap};
 use crate::search::search::FileSearchResult;
-use crate::search::{Search, SearchResult};
+use crate::search::{Search, SearchOptions, SearchResult};
 use crate::lsp::{self, Lsp};
-use crate::lsp::lsp_messages::{CompletionItem, Diagnostic, DiagnosticParams, HoverResult, ReferencesResult};
+use crate::lsp::lsp_messages::{self,
+    CompletionItem, Diagnostic, DiagnosticParams, HoverResult, ReferencesResult, SemanticToken, TextEdit, PrepareRename,
+    DIAGNOSTIC_TAG_DEPRECATED, DIAGNOSTIC_TAG_UNNECESSARY, COMPLETION_KIND_SNIPPET, COMPLETION_KIND_TEXT,
+};
 
 use crate::process::Process;
 use crate::selection::Selection;
 use crate::utils::{CursorHistory, CursorPosition};
 use crate::{search, utils};
 use crate::tree;
+use crate::git::{self, DiffKind, DiffLine};
 
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::{Child, Command};
@@ -44,9 +51,49 @@ use tokio::sync::mpsc::Receiver;
 use tokio::sync::Mutex;
 
 use std::sync::Arc;
+use std::sync::atomic::{self, AtomicBool};
 
 use copypasta::{ClipboardContext, ClipboardProvider};
 
+/// Columns of the minimap.
+const MINIMAP_WIDTH: usize = 2;
+/// Most cursors put at once at the occurrences of a selection.
+const MAX_OCCURRENCE_CURSORS: usize = 1000;
+/// Pause in typing before the global search runs on the new pattern.
+const GLOBAL_SEARCH_DEBOUNCE: time::Duration = time::Duration::from_millis(150);
+/// Pause in typing before the collected edits are sent to the language servers.
+const LSP_CHANGES_DEBOUNCE: time::Duration = time::Duration::from_millis(50);
+/// Bytes of a paste above which the text is shown before it is parsed.
+const LARGE_PASTE: usize = 256 * 1024;
+/// Most project files offered by the quick open.
+const QUICK_OPEN_FILES: usize = 50_000;
+/// Most matches kept by a picker while typing.
+const LIST_MATCHES: usize = 500;
+
+/// Line numbers display mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineNumbers { Absolute, Relative, Hidden }
+
+impl LineNumbers {
+    fn from_config(value: Option<&String>) -> Self {
+        match value.map(|v| v.as_str()) {
+            Some("relative") => LineNumbers::Relative,
+            Some("none") => LineNumbers::Hidden,
+            _ => LineNumbers::Absolute,
+        }
+    }
+    fn next(self) -> Self {
+        match self {
+            LineNumbers::Absolute => LineNumbers::Relative,
+            LineNumbers::Relative => LineNumbers::Hidden,
+            LineNumbers::Hidden => LineNumbers::Absolute,
+        }
+    }
+    fn width(self) -> usize {
+        if self == LineNumbers::Hidden { 0 } else { 5 }
+    }
+}
+
 /// Represents a text editor.
 pub struct Editor {
     /// Config from config.toml.
@@ -57,6 +104,8 @@ pub struct Editor {
     height: usize,
     /// Terminal width.
     width: usize,
+    /// Right edge of the text area, narrower than the terminal in zen mode.
+    text_area_width: usize,
 
     /// Cursor row.
     r: usize,
@@ -71,8 +120,23 @@ pub struct Editor {
     lp_width: usize,
     /// Line number width.
     ln_width: usize,
+    line_numbers: LineNumbers,
     /// Line number to text space.
     lns_width: usize,
+    /// Git blame of the current file per line, shown at the cursor line when on.
+    blame: Option<Vec<String>>,
+    /// Mark a BOM and rows with the less common line ending.
+    line_ending_warnings: bool,
+    /// Draw tabs as `→` and trailing spaces as `·`.
+    render_whitespace: bool,
+    /// Overview of the whole file in a column at the right.
+    minimap: bool,
+    /// Dimmed signature of a long scope after its closing brace.
+    closing_context: bool,
+    /// Set while the terminal is in raw mode and the alternate screen.
+    terminal: Option<TerminalGuard>,
+    /// Zen mode, saved left panel width and line numbers to restore.
+    zen: Option<(usize, LineNumbers)>,
 
     /// Update screen flag.
     upd: bool,
@@ -87,8 +151,16 @@ pub struct Editor {
     scolor: Color,
     /// Color for selection.
     selcolor: Color,
+    /// Color for occurrences of the selected word.
+    occolor: Color,
+    /// background of the occurrences of the word under the cursor
+    hlcolor: Color,
     /// Color for errors.
     ecolor: Color,
+    /// Gutter colors for lines added, modified and deleted since the last save.
+    addcolor: Color,
+    modcolor: Color,
+    delcolor: Color,
 
     /// Mouse selection range.
     selection: Selection,
@@ -98,17 +170,45 @@ pub struct Editor {
 
     /// lsp servers for a language
     lang2lsp: HashMap<String,Arc<Mutex<Lsp>>>,
-    lsp_status: Arc<Mutex<String>>,
+    /// server state or progress by language, e.g. `rust-analyzer: Indexing 42%`
+    lsp_status: HashMap<String, String>,
+    lsp_status_sender: Option<tokio::sync::mpsc::UnboundedSender<(String, String)>>,
 
     /// diagnostics or errors to inline display
     diagnostics: Arc<Mutex<HashMap<String, DiagnosticParams>>>,
     diagnostics_sender: Option<tokio::sync::mpsc::Sender<DiagnosticParams>>,
 
+    /// lsp semantic tokens by file path, layered over tree-sitter colors
+    semantic_tokens: HashMap<String, Vec<SemanticToken>>,
+    semantic_sender: Option<tokio::sync::mpsc::Sender<(String, Vec<SemanticToken>)>>,
+    /// semantic tokens are requested when idle
+    semantic_pending: bool,
+    /// trees of background parses by file path and edit version
+    parse_sender: Option<tokio::sync::mpsc::UnboundedSender<(String, usize, tree_sitter::Tree)>>,
+    /// when the idle work is due, one debounce after the last key, click or change
+    idle_due: Option<tokio::time::Instant>,
+    /// when the edits collected by the language servers are sent, requests send them right away
+    lsp_changes_due: Option<tokio::time::Instant>,
+    /// when the next undo checkpoint is taken, `undo_checkpoint_interval` after the last input
+    undo_due: Option<tokio::time::Instant>,
+    /// when the last frame was drawn, with `max_fps` the next one waits for the frame interval
+    last_frame: Option<tokio::time::Instant>,
+    /// when the coalesced redraw is drawn
+    frame_due: Option<tokio::time::Instant>,
+    /// blame of the file is reloaded when idle
+    blame_pending: bool,
+    /// word under the cursor, its visible occurrences are highlighted
+    cursor_word: Option<String>,
+
     /// tree view
     tree_view: tree::TreeView,
 
     /// opened text buffers
     codes: HashMap<String, Code>,
+    /// Files from the command line after the first one, opened as buffers on start.
+    more_files: Vec<String>,
+    /// Paths of the buffers by last use, the active one first.
+    recent_buffers: VecDeque<String>,
 
     /// search
     search: Search,
@@ -122,40 +222,124 @@ pub struct Editor {
     is_lp_focused: bool,
 
     node_path: Option<NodePath>,
+
+    /// Transient message shown near the status line until the next input.
+    message: Option<String>,
+
+    /// Typed chars replace the char under the cursor, toggled by Insert.
+    overwrite: bool,
+
+    /// Row with errors under the mouse, their full messages are shown in a popup.
+    hovered_diagnostic: Option<usize>,
+    /// Range of the selection dragged with the mouse, its text moves to where it is dropped.
+    text_drag: Option<((usize, usize), (usize, usize))>,
+
+    /// Long lines continue on the next screen rows instead of horizontal scroll.
+    wrap: bool,
+
+    /// Secondary cursors (row, col), edits are applied at them and the main cursor.
+    cursors: Vec<(usize, usize)>,
+    /// Length of the occurrence selected before every cursor, 0 after the first edit.
+    cursors_selection_len: usize,
+    /// Selection the occurrence selection was extended from, restored by Esc.
+    occurrence_origin: Option<((usize, usize), (usize, usize))>,
+    /// Tab stops of the expanded snippet left to visit, (char index, len).
+    snippet_stops: Vec<(usize, usize)>,
+    /// Start and end of the current stop and chars of the text when the cursor jumped to it,
+    /// edits at the stop shift the ones after it.
+    snippet_current: (usize, usize, usize),
+
+    /// Key bindings, defaults overridden by the `[keys]` config table.
+    keymap: KeyMap,
+    /// Modification times of config.toml and the theme file, a change reloads the config.
+    config_mtimes: (Option<SystemTime>, Option<SystemTime>),
+
+    /// Signature help popup: column of the call `(`, signature label and active parameter range.
+    signature: Option<(usize, String, Option<(usize, usize)>)>,
 }
 
 impl Editor {
     pub fn new(dir: String, config: Config) -> Self {
+        let line_numbers = LineNumbers::from_config(config.line_numbers.as_ref());
+        let wrap = config.wrap.unwrap_or(false);
+        let line_ending_warnings = config.line_ending_warnings.unwrap_or(false);
+        let render_whitespace = config.render_whitespace.unwrap_or(false);
+        let closing_context = config.closing_context.unwrap_or(false);
+        let minimap = config.minimap.unwrap_or(false);
+        let keymap = keys::keymap(config.keys.as_ref()).expect("keys are checked on config load");
+        let mut tree_view = tree::TreeView::new(".".to_string());
+        tree_view.set_ignore(utils::Ignore::from_config(&config));
         Editor {
             config,
             code: Code::new(),
             height: 0,
             width: 0,
-            ln_width: 5,
+            text_area_width: 0,
+            ln_width: line_numbers.width(),
+            line_numbers,
             lns_width: 5,
+            zen: None,
+            blame: None,
+            line_ending_warnings,
+            render_whitespace,
+            closing_context,
+            minimap,
+            terminal: None,
             r: 0, c: 0, x: 0, y: 0,
             lncolor: Color::Reset,
             scolor: Color::Reset,
             selcolor: Color::Reset,
+            occolor: Color::Reset,
+            hlcolor: Color::Reset,
             ecolor: Color::Reset,
+            addcolor: Color::Reset,
+            modcolor: Color::Reset,
+            delcolor: Color::Reset,
             upd: true,
             upd_next: false,
             theme: HashMap::new(),
             selection: Selection::new(),
             process: Process::new(),
             lang2lsp: HashMap::new(),
-            lsp_status: Arc::new(Mutex::new(String::new())),
+            lsp_status: HashMap::new(),
+            lsp_status_sender: None,
             diagnostics: Arc::new(Mutex::new(HashMap::new())),
             diagnostics_sender: None,
-            tree_view: tree::TreeView::new(".".to_string()),
+            semantic_tokens: HashMap::new(),
+            semantic_sender: None,
+            parse_sender: None,
+            semantic_pending: false,
+            idle_due: None,
+            undo_due: None,
+            lsp_changes_due: None,
+            last_frame: None,
+            frame_due: None,
+            blame_pending: false,
+            cursor_word: None,
+            tree_view,
             lp_width: 0,
             codes: HashMap::new(),
+            more_files: Vec::new(),
+            recent_buffers: VecDeque::new(),
             search:Search::new(),
             overlay_lines: HashSet::new(),
             cursor_history: CursorHistory::new(),
             cursor_history_undo: CursorHistory::new(),
             is_lp_focused: false,
             node_path: None,
+            message: None,
+            overwrite: false,
+            hovered_diagnostic: None,
+            text_drag: None,
+            wrap,
+            cursors: Vec::new(),
+            cursors_selection_len: 0,
+            occurrence_origin: None,
+            snippet_stops: Vec::new(),
+            snippet_current: (0, 0, 0),
+            keymap,
+            config_mtimes: (None, None),
+            signature: None,
         }
     }
 
@@ -167,11 +351,225 @@ impl Editor {
                 self.code = code;
                 self.r = 0; self.c = 0; self.y = 0; self.x = 0;
                 self.selection.clean();
+                self.restore_cursor_position();
             }
             Err(e) => {},
         }
     }
 
+    /// Files to open as buffers on start, behind the loaded one.
+    pub fn add_files(&mut self, paths: Vec<String>) {
+        self.more_files = paths.iter()
+            .filter(|p| Path::new(p).is_file())
+            .map(|p| utils::abs_file(p))
+            .collect();
+    }
+
+    async fn open_more_files(&mut self) {
+        let active = self.code.abs_path.clone();
+        for path in std::mem::take(&mut self.more_files) {
+            if path != active { self.open_file(&path).await; }
+        }
+        if !active.is_empty() { self.open_file(&active).await; }
+    }
+
+    /// Lists the other open buffers, modified ones marked with `*`, and switches to the chosen one.
+    async fn switch_buffer(&mut self) {
+        let mut paths: Vec<String> = self.codes.keys().filter(|p| !p.is_empty()).cloned().collect();
+        if paths.is_empty() {
+            self.set_message("no other buffers".to_string());
+            return;
+        }
+        paths.sort();
+
+        let dir = format!("{}/", utils::current_dir());
+        let options: Vec<String> = paths.iter().map(|path| {
+            let modified = if self.codes[path].changed { " *" } else { "" };
+            format!("{}{}", path.strip_prefix(&dir).unwrap_or(path), modified)
+        }).collect();
+
+        let path = match self.select_from_list("buffers", &options).await {
+            Some(index) => paths[index].clone(),
+            None => return,
+        };
+
+        self.cursor_history.push(CursorPosition{
+            filename: self.code.abs_path.clone(),
+            row: self.r, col: self.c, y: self.y, x: self.x,
+        });
+        self.cursor_history_undo.clear();
+        self.tree_view.set_active(path.clone());
+        self.open_file(&path).await;
+        self.upd = true;
+        self.tree_view.upd = true;
+    }
+
+    /// Closes the current buffer, asking to save changes first, and shows the next open one
+    /// or the logo if none is left.
+    async fn close_buffer(&mut self) {
+        if self.code.file_name.is_empty() { return; }
+
+        if self.code.changed {
+            let question = format!("save changes to {}? (y/n): ", self.code.file_name);
+            match self.prompt(&question, "").await.as_deref().map(str::trim) {
+                Some("y") | Some("Y") => {
+                    self.save().await;
+                    if self.code.changed { return; } // not saved, e.g. read-only
+                },
+                Some("n") | Some("N") => {},
+                _ => return,
+            }
+        }
+
+        let path = self.code.abs_path.clone();
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            lsp.lock().await.did_close(&path);
+        }
+        self.diagnostics.lock().await.remove(&format!("file://{}", path));
+        self.semantic_tokens.remove(&path);
+        self.save_cursor_position();
+        self.code.remove_swap();
+        self.code.unlock();
+
+        self.codes.remove(""); // the empty buffer before the first file was opened
+        self.recent_buffers.retain(|p| *p != path);
+        let next = self.recent_buffers.iter().find(|p| self.codes.contains_key(*p)).cloned()
+            .or_else(|| self.codes.keys().min().cloned());
+        let mut code = next.and_then(|next| self.codes.remove(&next)).unwrap_or_else(Code::new);
+        (self.r, self.c, self.y, self.x) = code.get_cursor_position();
+        self.code = code;
+
+        self.selection.clean();
+        self.clear_cursors();
+        self.schedule_blame();
+        match self.code.is_scratch() {
+            true => { self.open_left_panel(); self.tree_view.set_width(self.lp_width); },
+            false => self.tree_view.set_active(self.code.abs_path.clone()),
+        }
+        self.upd = true;
+        self.tree_view.upd = true;
+    }
+
+    /// Switches to the buffer that was active before the current one.
+    async fn alternate_buffer(&mut self) {
+        let current = self.code.abs_path.clone();
+        let path = match self.recent_buffers.iter().find(|p| **p != current && self.codes.contains_key(*p)) {
+            Some(path) => path.clone(),
+            None => {
+                self.set_message("no other buffers".to_string());
+                return;
+            }
+        };
+
+        self.cursor_history.push(CursorPosition{
+            filename: current,
+            row: self.r, col: self.c, y: self.y, x: self.x,
+        });
+        self.cursor_history_undo.clear();
+        self.tree_view.set_active(path.clone());
+        self.open_file(&path).await;
+        self.upd = true;
+    }
+
+    /// Puts the cursor at the 1-based line and column, scrolled into view on start.
+    pub fn set_position(&mut self, line: usize, col: Option<usize>) {
+        self.r = line.saturating_sub(1).min(self.code.len_lines().saturating_sub(1));
+        self.c = col.unwrap_or(1).saturating_sub(1);
+        self.fit_cursor();
+        self.y = 0; self.x = 0;
+    }
+
+    /// Remembers the open files of the project dir and their cursors for the next start.
+    fn save_session(&mut self) {
+        let path = match utils::session_path() { Some(path) => path, None => return };
+
+        let mut files = vec![];
+        for code in self.codes.values_mut().filter(|c| !c.is_scratch()) {
+            let (row, col, y, x) = code.get_cursor_position();
+            let position = CursorPosition { filename: code.abs_path.clone(), row, col, y, x };
+            if let Some(positions) = utils::cursor_positions_path() {
+                let _ = utils::save_cursor_position(&positions, position);
+            }
+            files.push(code.abs_path.clone());
+        }
+        if !self.code.is_scratch() { files.push(self.code.abs_path.clone()); }
+
+        let session = utils::Session {
+            files,
+            active: self.code.abs_path.clone(),
+            root: self.tree_view.root_path().to_string(),
+        };
+        if let Err(e) = utils::save_session(&path, &utils::current_dir(), session) {
+            debug!("cant save session: {}", e);
+        }
+    }
+
+    /// Reopens the files of the last session in the dir, asks first unless `restore_session` is auto.
+    async fn restore_session(&mut self) {
+        let mode = self.config.restore_session.as_deref().unwrap_or("ask");
+        if mode == "off" { return; }
+        let path = match utils::session_path() { Some(path) => path, None => return };
+        let session = match utils::load_session(&path, &utils::current_dir()) {
+            Some(session) => session,
+            None => return,
+        };
+
+        if mode != "auto" {
+            let question = format!("reopen {} files of the last session? (y/n): ", session.files.len());
+            match self.prompt(&question, "").await.as_deref().map(str::trim) {
+                Some("y") | Some("Y") => {},
+                _ => return,
+            }
+        }
+
+        for file in session.files.iter().filter(|f| **f != session.active) {
+            self.open_file(file).await;
+        }
+        if !session.root.is_empty() && session.root != self.tree_view.root_path() {
+            self.tree_view.set_root(&session.root);
+        }
+        self.open_file(&session.active).await;
+        self.tree_view.set_active(session.active.clone());
+        self.is_lp_focused = false;
+        self.upd = true;
+    }
+
+    /// Moves the cursor to where it was when the file was last left.
+    fn restore_cursor_position(&mut self) {
+        let path = match utils::cursor_positions_path() { Some(path) => path, None => return };
+        let positions = utils::load_cursor_positions(&path);
+        let position = positions.into_iter().rev().find(|p| p.filename == self.code.abs_path);
+
+        if let Some(p) = position {
+            self.r = p.row.min(self.code.len_lines().saturating_sub(1));
+            self.c = p.col;
+            self.fit_cursor();
+            self.y = p.y.min(self.r);
+            self.x = p.x.min(self.c);
+        }
+    }
+
+    /// Remembers the cursor of the current file for the next session.
+    fn save_cursor_position(&self) {
+        if self.code.is_scratch() { return; }
+        let path = match utils::cursor_positions_path() { Some(path) => path, None => return };
+
+        let position = CursorPosition {
+            filename: self.code.abs_path.clone(),
+            row: self.r, col: self.c, y: self.y, x: self.x,
+        };
+        if let Err(e) = utils::save_cursor_position(&path, position) {
+            debug!("cant save cursor position: {}", e);
+        }
+    }
+
+    /// Opens text read from stdin as a scratch buffer.
+    pub fn load_stdin(&mut self, text: &str, lang: Option<String>) {
+        self.code = Code::from_stdin(text, lang, &self.config);
+        self.r = 0; self.c = 0; self.y = 0; self.x = 0;
+        self.selection.clean();
+    }
+
     pub fn open_left_panel(&mut self) {
         self.lp_width = self.config.left_panel_width.unwrap_or(25);
         self.is_lp_focused = true;
@@ -187,6 +585,7 @@ impl Editor {
     }
 
     pub fn init(&mut self) {
+        self.terminal = Some(TerminalGuard);
         execute!(stdout(), EnterAlternateScreen).expect("Could not EnterAlternateScreen");
         execute!(stdout(), EnableMouseCapture).expect("Could not EnableMouseCapture");
         enable_raw_mode().expect("Could not turn on Raw mode");
@@ -199,46 +598,63 @@ impl Editor {
         self.configure_theme();
     }
 
+    /// Restores the terminal, ignores errors since it runs in panic and signal handlers.
     pub fn deinit() {
-        disable_raw_mode().expect("Unable to disable_raw_mode");
-        execute!(stdout(), LeaveAlternateScreen).expect("Unable to LeaveAlternateScreen");
-        execute!(stdout(), DisableMouseCapture).expect("Unable DisableMouseCapture");
-        queue!(stdout(), cursor::Show).expect("Unable to show cursor");
+        let _ = disable_raw_mode();
+        let _ = execute!(stdout(),
+            LeaveAlternateScreen, DisableMouseCapture,
+            cursor::SetCursorStyle::DefaultUserShape, cursor::Show
+        );
     }
 
     pub fn handle_panic(&self) {
-        let default_panic = std::panic::take_hook();
         std::panic::set_hook(Box::new(move |info| {
+            if let Some(task) = utils::background_task() {
+                // only the task ends, e.g. the lsp reader on a broken message
+                error!("{} task panicked: {}", task, info);
+                return;
+            }
+            // restore the terminal first, so the message is not lost in the alternate screen
             Self::deinit();
-            default_panic(info);
+            code::remove_held_locks();
+            let backtrace = std::backtrace::Backtrace::force_capture();
+            eprintln!("{}\n\nbacktrace:\n{}", info, backtrace);
             std::process::exit(1);
         }));
 
         ctrlc::set_handler(move || {
             Self::deinit();
+            code::remove_held_locks();
+            std::process::exit(130);
         })
         .expect("Error setting Ctrl-C handler");
     }
 
-    fn configure_theme(&mut self) {
-        let theme_path = &self.config.theme;
-        let path = Path::new(theme_path);
+    fn load_theme(config: &Config) -> Result<HashMap<String, String>, String> {
+        let theme_content = fs::read_to_string(config.theme_path())
+            .map_err(|e| format!("Failed to read theme path file: {}", e))?;
+        let theme_yaml = serde_yaml::from_str(&theme_content)
+            .map_err(|e| format!("Failed to parse theme yaml file: {}", e))?;
+        Ok(utils::yaml_to_map(theme_yaml))
+    }
 
-        let theme_path = if path.is_absolute() {
-           path.to_string_lossy().to_string()
-        } else {
-            let red_home = option_env!("RED_HOME").unwrap_or("./");
-            Path::new(red_home).join(theme_path).to_string_lossy().to_string()
-        };
+    fn configure_theme(&mut self) {
+        let theme = Self::load_theme(&self.config).expect("Failed to load theme");
+        self.configure_theme_from(theme);
+    }
 
-        let theme_content = fs::read_to_string(theme_path).expect("Failed to read theme path file");
-        let theme_yaml = serde_yaml::from_str(&theme_content).expect("Failed to parse theme yaml file ");
-        self.theme = utils::yaml_to_map(theme_yaml);
+    fn configure_theme_from(&mut self, theme: HashMap<String, String>) {
+        self.theme = theme;
 
         self.lncolor = self.theme.get("lncolor").map(|c| utils::hex_to_color(c)).unwrap_or(Color::AnsiValue(247));
         self.scolor = self.theme.get("scolor").map(|c| utils::hex_to_color(c)).unwrap_or(Color::AnsiValue(247));
         self.selcolor = self.theme.get("selcolor").map(|c| utils::hex_to_color(c)).unwrap_or(Color::AnsiValue(247));
+        self.occolor = self.theme.get("occolor").map(|c| utils::hex_to_color(c)).unwrap_or(Color::AnsiValue(238));
+        self.hlcolor = self.theme.get("hlcolor").map(|c| utils::hex_to_color(c)).unwrap_or(self.occolor);
         self.ecolor = self.theme.get("ecolor").map(|c| utils::hex_to_color(c)).unwrap_or(Color::AnsiValue(247));
+        self.addcolor = self.theme.get("addcolor").map(|c| utils::hex_to_color(c)).unwrap_or(Color::AnsiValue(71));
+        self.modcolor = self.theme.get("modcolor").map(|c| utils::hex_to_color(c)).unwrap_or(Color::AnsiValue(179));
+        self.delcolor = self.theme.get("delcolor").map(|c| utils::hex_to_color(c)).unwrap_or(Color::AnsiValue(167));
 
         let dircolor = self.theme.get("dircolor").map(|c| utils::hex_to_color(c)).unwrap_or(Color::Reset);
         self.tree_view.set_dir_color(dircolor);
@@ -250,16 +666,43 @@ impl Editor {
 
     pub async fn start(&mut self) {
         self.init();
+        if self.r >= self.y + self.height { self.focus_to_center(); }
 
         self.draw().await;
 
         let (diagnostic_send, mut diagnostic_recv) = tokio::sync::mpsc::channel::<DiagnosticParams>(1);
         self.diagnostics_sender = Some(diagnostic_send.clone());
 
+        let (semantic_send, mut semantic_recv) = tokio::sync::mpsc::channel::<(String, Vec<SemanticToken>)>(1);
+        let (lsp_status_send, mut lsp_status_recv) = tokio::sync::mpsc::unbounded_channel::<(String, String)>();
+        self.lsp_status_sender = Some(lsp_status_send);
+        self.semantic_sender = Some(semantic_send);
+        let (parse_send, mut parse_recv) = tokio::sync::mpsc::unbounded_channel::<(String, usize, tree_sitter::Tree)>();
+        self.parse_sender = Some(parse_send);
+
+        self.check_swap().await;
+        self.check_lock().await;
         self.init_new_lsp();
+        self.schedule_semantic_tokens();
+
+        // after the lsp channels, opened files start their servers
+        if !self.more_files.is_empty() {
+            self.open_more_files().await;
+            self.draw().await;
+        }
+        if self.code.file_name.is_empty() {
+            self.restore_session().await;
+            self.draw().await;
+        }
 
         let mut reader = EventStream::new();
 
+        let swap_interval = self.config.swap_interval.unwrap_or(5);
+        let mut swap_timer = tokio::time::interval(time::Duration::from_secs(swap_interval.max(1)));
+
+        self.config_mtimes = self.read_config_mtimes();
+        let mut config_timer = tokio::time::interval(time::Duration::from_secs(1));
+
         loop {
             // let delay = Delay::new(Duration::from_millis(1_00)).fuse();
             let event = reader.next().fuse();
@@ -269,8 +712,55 @@ impl Editor {
                     let filename = upd.uri.clone();
                     self.diagnostics.lock().await.insert(filename, upd);
                     self.upd = true;
+                    self.draw_throttled().await;
+                }
+                Some((lang, status)) = lsp_status_recv.recv() => {
+                    if status.is_empty() { self.lsp_status.remove(&lang); }
+                    else { self.lsp_status.insert(lang, status); }
+                    self.upd = true; // a shorter status leaves the old one behind
+                    self.draw_throttled().await;
+                }
+                Some((path, version, tree)) = parse_recv.recv() => {
+                    self.set_parsed_tree(&path, version, tree);
+                    self.draw_throttled().await;
+                }
+                Some((path, tokens)) = semantic_recv.recv() => {
+                    self.semantic_tokens.insert(path, tokens);
+                    self.upd = true;
+                    self.draw_throttled().await;
+                }
+                _ = tokio::time::sleep_until(self.idle_due.unwrap_or_else(tokio::time::Instant::now)),
+                    if self.idle_due.is_some() => {
+                    self.on_idle().await;
+                }
+                _ = tokio::time::sleep_until(self.frame_due.unwrap_or_else(tokio::time::Instant::now)),
+                    if self.frame_due.is_some() => {
+                    self.frame_due = None;
+                    self.last_frame = Some(tokio::time::Instant::now());
                     self.draw().await;
                 }
+                _ = tokio::time::sleep_until(self.lsp_changes_due.unwrap_or_else(tokio::time::Instant::now)),
+                    if self.lsp_changes_due.is_some() => {
+                    self.flush_lsp_changes().await;
+                }
+                _ = swap_timer.tick(), if swap_interval > 0 => {
+                    self.write_swaps();
+                }
+                _ = config_timer.tick() => {
+                    self.check_disk_changes().await;
+                    if self.upd { self.draw_throttled().await; }
+                    let mtimes = self.read_config_mtimes();
+                    if mtimes != self.config_mtimes {
+                        self.config_mtimes = mtimes;
+                        self.reload_config();
+                        self.draw_throttled().await;
+                    }
+                }
+                _ = tokio::time::sleep_until(self.undo_due.unwrap_or_else(tokio::time::Instant::now)),
+                    if self.undo_due.is_some() => {
+                    self.undo_due = None;
+                    self.code.undo_checkpoint();
+                }
                 // _ = delay => {
                     // println!(".\r");
                     // let upd_process = self.upd_process.clone();
@@ -292,21 +782,35 @@ impl Editor {
                             match event {
                                 Event::Resize(w, h) => {
                                     self.resize(w as usize, h as usize);
-                                    self.draw().await;
+                                    self.draw_throttled().await;
                                 }
                                 Event::Mouse(e) => {
+                                    if e.kind != MouseEventKind::Moved { self.clear_message(); self.schedule_idle(); }
+                                    if let MouseEventKind::Down(_) = e.kind { self.clear_cursors(); }
                                     self.handle_mouse(e).await;
-                                    self.draw().await;
+                                    self.check_signature_help(false);
+                                    self.check_snippet_stops();
+                                    self.draw_throttled().await;
                                 }
                                 Event::Key(e) => {
-                                    if self.is_quit(e) { break }
+                                    if self.is_quit(e) {
+                                        self.save_session();
+                                        self.save_cursor_position();
+                                        self.remove_swaps();
+                                        break
+                                    }
+                                    self.clear_message();
+                                    self.hovered_diagnostic = None;
+                                    self.schedule_idle();
 
                                     #[cfg(target_os = "windows")] { // skip press event on windows
                                         if e.kind == KeyEventKind::Press { continue; }
                                     };
                                     self.handle_keyboard(e).await;
+                                    self.check_signature_help(e.code == KeyCode::Esc);
+                                    self.check_snippet_stops();
 
-                                    self.draw().await;
+                                    self.draw_throttled().await;
                                     if self.upd_next {
                                         self.upd = true;
                                         self.upd_next = false;
@@ -323,16 +827,147 @@ impl Editor {
                 }
             };
         }
+
+        self.unlock_files();
+        self.terminal = None; // the terminal is back while servers exit
+        self.shutdown_lsp().await;
+    }
+
+    /// Lets all language servers exit cleanly, they are shut down at the same time.
+    async fn shutdown_lsp(&mut self) {
+        let shutdowns = self.lang2lsp.values().map(|lsp| {
+            let lsp = lsp.clone();
+            async move {
+                // a server still busy with a request is killed on drop instead
+                if let Ok(mut lsp) = tokio::time::timeout(time::Duration::from_millis(500), lsp.lock()).await {
+                    lsp.shutdown().await;
+                }
+            }
+        });
+        futures::future::join_all(shutdowns).await;
+    }
+
+    /// Sends the whole buffer to lsp.
+    async fn sync_lsp(&mut self) {
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            let text = self.code.text.to_string();
+            lsp.lock().await.did_change_full(&self.code.abs_path, &text).await;
+        }
+    }
+
+    /// Writes unsaved changes of all buffers to swap files.
+    fn write_swaps(&mut self) {
+        let codes = std::iter::once(&mut self.code).chain(self.codes.values_mut());
+        for code in codes {
+            if let Err(e) = code.write_swap() {
+                debug!("cant write swap for {}: {}", code.abs_path, e);
+            }
+        }
+    }
+
+    fn remove_swaps(&mut self) {
+        let codes = std::iter::once(&mut self.code).chain(self.codes.values_mut());
+        for code in codes { code.remove_swap(); }
+    }
+
+    fn unlock_files(&mut self) {
+        let codes = std::iter::once(&mut self.code).chain(self.codes.values_mut());
+        for code in codes { code.unlock(); }
+    }
+
+    /// Locks the current file, if another red edits it offers to open it read-only.
+    async fn check_lock(&mut self) {
+        if let Some(pid) = self.code.lock_owner() {
+            let question = format!("{} is open in another red (pid {}), open read-only? (y/n): ", self.code.file_name, pid);
+            match self.prompt(&question, "").await.as_deref().map(str::trim) {
+                Some("y") | Some("Y") => {
+                    self.code.read_only = true;
+                    self.upd = true;
+                    return;
+                },
+                _ => {},
+            }
+        }
+        if let Err(e) = self.code.lock() {
+            debug!("cant lock {}: {}", self.code.abs_path, e);
+        }
+    }
+
+    /// Offers to recover the current buffer from a newer swap file.
+    async fn check_swap(&mut self) {
+        if !self.code.has_newer_swap() { return; }
+
+        let answer = self.prompt("swap file found, recover unsaved changes? (y/n): ", "").await;
+        match answer.as_deref().map(str::trim) {
+            Some("y") | Some("Y") => {
+                if let Err(e) = self.code.recover_swap() {
+                    self.set_message(format!("can not recover swap: {}", e));
+                }
+            },
+            _ => self.code.remove_swap(),
+        }
+        self.upd = true;
+    }
+
+    /// Reloads the file changed on disk, asks first if the buffer has unsaved changes.
+    async fn check_disk_changes(&mut self) {
+        if !self.code.modified_on_disk() { return; }
+        if !self.code.changed {
+            self.reload_file().await;
+            return;
+        }
+
+        let question = format!("{} changed on disk, (r)eload / (k)eep mine / (d)iff: ", self.code.file_name);
+        loop {
+            let answer = self.prompt(&question, "").await;
+            match answer.as_deref().map(str::trim) {
+                Some("r") | Some("R") => { self.reload_file().await; break; },
+                Some("d") | Some("D") => self.diff_disk().await,
+                _ => { self.code.keep_disk_change(); break; },
+            }
+        }
+        self.upd = true;
+    }
+
+    async fn reload_file(&mut self) {
+        if let Err(e) = self.code.reload_file() {
+            self.code.keep_disk_change(); // not asked again till the next change
+            self.set_message(format!("can not reload {}: {}", self.code.file_name, e));
+            return;
+        }
+        self.sync_lsp().await;
+        self.r = self.r.min(self.code.len_lines() - 1);
+        self.fit_cursor();
+        self.selection.clean();
+        self.clean_diagnostics();
+        self.set_message(format!("{} reloaded, it changed on disk", self.code.file_name));
+        self.upd = true;
+    }
+
+    /// Shows the unsaved changes against the file on disk.
+    async fn diff_disk(&mut self) {
+        let buffer = std::env::temp_dir().join(format!("red-{}-{}", std::process::id(), self.code.file_name));
+        let lines = self.code.write_file(&buffer).ok()
+            .and_then(|_| git::diff_files(Path::new(&self.code.abs_path), &buffer));
+        let _ = fs::remove_file(&buffer);
+
+        match lines {
+            Some(lines) if !lines.is_empty() => self.diff_view(&lines, "diff disk").await,
+            Some(_) => self.set_message("no changes against disk".to_string()),
+            None => self.set_message("can not diff, git is needed".to_string()),
+        }
+        self.upd = true;
+        self.tree_view.upd = true;
     }
 
     fn is_quit(&self, e: KeyEvent) -> bool {
-        e.modifiers == KeyModifiers::CONTROL && e.code == KeyCode::Char('q')
+        self.keymap.get(&(e.modifiers, e.code)) == Some(&Action::Quit)
     }
 
     fn resize(&mut self, w: usize, h: usize) {
-        if w != self.width {
-            self.width = w;
-        }
+        self.width = w;
+        self.text_area_width = w;
+        self.zen_layout();
         if h != self.height {
             self.height = h;
         }
@@ -349,101 +984,80 @@ impl Editor {
             return;
         }
 
+        let action = self.keymap.get(&(event.modifiers, event.code)).copied();
+
+        if self.selection.block && self.selection.non_empty_and_active() && action.is_none() {
+            match (event.modifiers, event.code) {
+                (KeyModifiers::NONE | KeyModifiers::SHIFT, KeyCode::Char(c)) => {
+                    self.block_to_cursors().await;
+                    self.multi_cursor_edit(&c.to_string(), 0).await;
+                    return;
+                },
+                (KeyModifiers::NONE, KeyCode::Backspace) => {
+                    let (_, _, x, xto) = self.selection.block_range();
+                    self.block_to_cursors().await;
+                    if x == xto { self.multi_cursor_edit("", 1).await; }
+                    return;
+                },
+                _ => {},
+            }
+        }
+
+        if !self.cursors.is_empty() {
+            match (event.modifiers, event.code) {
+                _ if action == Some(Action::NextOccurrence) => {},
+                _ if action == Some(Action::AllOccurrences) => {},
+                (KeyModifiers::NONE | KeyModifiers::SHIFT, KeyCode::Char(c)) => {
+                    self.multi_cursor_edit(&c.to_string(), 0).await;
+                    return;
+                },
+                (KeyModifiers::NONE, KeyCode::Backspace) => {
+                    self.multi_cursor_edit("", 1).await;
+                    return;
+                },
+                _ => self.clear_cursors(), // Esc or any other key leaves one cursor
+            }
+        }
+
+        if let Some(action) = action {
+            self.handle_action(action).await;
+            self.upd = true;
+            // unmodified keys drop the selection below, like typing
+            if event.modifiers != KeyModifiers::NONE { return; }
+        }
+
         if event.modifiers.contains(KeyModifiers::CONTROL | KeyModifiers::SHIFT) {
-            if event.code == KeyCode::Up {
-                self.move_line_up().await;
+            return;
+        }
+
+        if event.modifiers == KeyModifiers::ALT | KeyModifiers::SHIFT {
+            if !self.selection.active && !self.selection.keep_once {
+                self.selection.set_start(self.r, self.c);
             }
-            if event.code == KeyCode::Down {
-                self.move_line_down().await;
+
+            match event.code {
+                KeyCode::Up => self.handle_up(),
+                KeyCode::Down => self.handle_down(),
+                KeyCode::Left => self.handle_left(),
+                KeyCode::Right => self.handle_right(),
+                _ => return,
             }
-            
+
+            self.selection.set_end(self.r, self.c);
+            self.selection.active = true;
+            self.selection.block = true;
+            self.upd = true;
             return;
         }
 
         match event.modifiers {
             KeyModifiers::ALT => {
-                let code = event.code;
-                match code {
-                    KeyCode::Up => {
-                        self.select_more();
-                    }
-                    KeyCode::Down => {
-                        self.select_less();
-                    }
-                    // option + arrow left
-                    KeyCode::Left =>  {
-                        // scroll horizontal
-                        // if self.x > 0 { self.x -= 1 }
-                        let line = self.code.line_at(self.r);
-                        if line.is_none() { return; }
-                        let line = line.unwrap();
-                        let next = utils::find_prev_word(line, self.c-1);
-                        self.c = next;
-                    },
-                    // option + arrow right
-                    KeyCode::Right => {
-                        // scroll horizontal
-                        // self.x += 1
-
-                        let line = self.code.line_at(self.r);
-                        if line.is_none() { return; }
-                        let line = line.unwrap();
-                        let next = utils::find_next_word(line, self.c+1);
-                        self.c = next;
-                    },
-                    KeyCode::Backspace => self.handle_cut_line().await,
-
-                    _ => debug!("event.code {:?}", event.code),
-                }
+                debug!("event.code {:?}", event.code);
                 self.upd = true;
                 return;
             }
 
-            KeyModifiers::CONTROL => {
-                match event.code {
-                    KeyCode::Char('s') => self.save(),
-                    KeyCode::Char('c') => self.copy_to_clipboard(),
-                    KeyCode::Char('v') => self.paste_from_clipboard().await,
-                    KeyCode::Char('d') => self.handle_duplicate().await,
-                    KeyCode::Char('f') => self.local_search().await,
-                    KeyCode::Char('r') => self.references().await,
-                    KeyCode::Char('g') => self.definition().await,
-                    KeyCode::Char('z') => self.undo().await,
-                    KeyCode::Char('o') => self.undo_cursor().await,
-                    KeyCode::Char('p') => self.redo_cursor().await,
-                    KeyCode::Char('g') => {
-                        self.global_search().await;
-                        self.overlay_lines.clear();
-                    },
-                    KeyCode::Char('e') => self.handle_errors().await,
-                    KeyCode::Char('h') => self.hover().await,
-                    KeyCode::Char('t') => {
-                        if self.lp_width == 0 { self.is_lp_focused = true; self.left_panel_toggle(); }
-                        else {
-                            if !self.is_lp_focused {
-                                self.is_lp_focused = true;
-                            } else {
-                                self.is_lp_focused = false;
-                                self.left_panel_toggle();
-                            }
-                        }
-
-
-                        self.tree_view.upd = true;
-                        self.upd = true;
-                    }
-                    KeyCode::Char('x') => {
-                        self.copy_to_clipboard();
-                        self.handle_cut().await;
-                    }
-                    KeyCode::Char(' ') => {
-                        self.lsp_completion().await;
-                    }
-                    _ => {}
-                }
-
-                return;
-            }
+            KeyModifiers::CONTROL => return,
 
             KeyModifiers::SHIFT => match event.code {
                 KeyCode::Char(_) => {}
@@ -457,11 +1071,14 @@ impl Editor {
                         KeyCode::Down => self.handle_down(),
                         KeyCode::Left => self.handle_left(),
                         KeyCode::Right => self.handle_right(),
+                        KeyCode::Home => self.handle_home(),
+                        KeyCode::End => self.handle_end(),
                         _ => {}
                     }
 
                     self.selection.set_end(self.r, self.c);
                     self.selection.active = true;
+                    self.selection.block = false;
                     self.upd = true;
                     return;
                 }
@@ -472,15 +1089,25 @@ impl Editor {
 
 
         match event.code {
+            _ if action.is_some() => {}
             KeyCode::Up => self.handle_up(),
             KeyCode::Down => self.handle_down(),
             KeyCode::Left => self.handle_left(),
             KeyCode::Right => self.handle_right(),
+            KeyCode::Home => self.handle_home(),
+            KeyCode::End => self.handle_end(),
             KeyCode::Enter => self.handle_enter().await,
             KeyCode::Backspace => self.handle_delete().await,
-            KeyCode::Char('÷') => self.comment_line().await,
             KeyCode::Char(c) => self.insert_char(c).await,
-            KeyCode::Tab => self.insert_tab().await,
+            KeyCode::Tab => {
+                // a stop keeps its default text selected
+                if self.next_snippet_stop() || self.expand_snippet().await { return; }
+                self.insert_tab().await;
+            },
+            KeyCode::Esc => {
+                self.snippet_stops.clear();
+                if self.restore_occurrence_origin() { return; }
+            },
             _ => {
                 debug!("event.code {:?}", event.code);
             }
@@ -494,6 +1121,94 @@ impl Editor {
         }
     }
 
+    async fn handle_action(&mut self, action: Action) {
+        match action {
+            Action::Quit => {}, // handled by the event loop
+            Action::Save => self.save().await,
+            Action::Copy => self.copy_to_clipboard(),
+            Action::Paste => self.paste_from_clipboard().await,
+            Action::Cut => {
+                self.copy_to_clipboard();
+                self.handle_cut().await;
+            }
+            Action::NextOccurrence => self.add_next_occurrence().await,
+            Action::AllOccurrences => self.add_all_occurrences(),
+            Action::Find => self.local_search().await,
+            Action::GlobalSearch => {
+                self.global_search().await;
+                self.overlay_lines.clear();
+            }
+            Action::References => self.references().await,
+            Action::Definition => self.definition().await,
+            Action::Undo => self.undo().await,
+            Action::CursorBack => self.undo_cursor().await,
+            Action::CursorForward => self.redo_cursor().await,
+            Action::Errors => self.handle_errors().await,
+            Action::Hover => self.hover().await,
+            Action::Tree => {
+                if self.zen.is_some() { self.toggle_zen(); }
+                if self.lp_width == 0 { self.is_lp_focused = true; self.left_panel_toggle(); }
+                else if !self.is_lp_focused {
+                    self.is_lp_focused = true;
+                } else {
+                    self.is_lp_focused = false;
+                    self.left_panel_toggle();
+                }
+                self.tree_view.upd = true;
+            }
+            Action::Completion => self.lsp_completion().await,
+            Action::GotoLine => self.goto_line().await,
+            Action::Rename => self.rename_symbol().await,
+            Action::MoveLineUp => self.move_line_up().await,
+            Action::MoveLineDown => self.move_line_down().await,
+            Action::SelectMore => self.select_more(),
+            Action::SelectLess => self.select_less(),
+            Action::SelectFunction => self.select_function(),
+            Action::SwapSelectionEnds => self.swap_selection_ends(),
+            Action::SelectNextOccurrence => self.select_next_occurrence(),
+            Action::WordLeft => {
+                let line = match self.code.line_at(self.r) { Some(line) => line, None => return };
+                self.c = utils::find_prev_word(line, self.c-1, self.code.word_chars());
+            }
+            Action::WordRight => {
+                let line = match self.code.line_at(self.r) { Some(line) => line, None => return };
+                self.c = utils::find_next_word(line, self.c+1, self.code.word_chars());
+            }
+            Action::DeleteLine => self.handle_cut_line().await,
+            Action::Comment => self.comment_line().await,
+            Action::Overwrite => self.overwrite = !self.overwrite,
+            Action::TextStats => self.text_stats(),
+            Action::LineNumbers => self.toggle_line_numbers(),
+            Action::Zen => self.toggle_zen(),
+            Action::Blame => self.toggle_blame(),
+            Action::Diff => self.diff_head().await,
+            Action::RevertHunk => self.revert_hunk().await,
+            Action::StageHunk => self.stage_hunk(),
+            Action::RecentLines => self.recent_lines().await,
+            Action::LineEndingWarnings => self.toggle_line_ending_warnings(),
+            Action::RenderWhitespace => self.toggle_render_whitespace(),
+            Action::ClosingContext => self.toggle_closing_context(),
+            Action::Minimap => self.toggle_minimap(),
+            Action::SwitchBuffer => self.switch_buffer().await,
+            Action::CloseBuffer => self.close_buffer().await,
+            Action::AlternateBuffer => self.alternate_buffer().await,
+            Action::NormalizeLineEndings => self.normalize_line_endings().await,
+            Action::OpenConfig => self.open_config(false).await,
+            Action::OpenTheme => self.open_config(true).await,
+            Action::Wrap => self.toggle_wrap(),
+            Action::OpenByWord => self.open_file_by_word().await,
+            Action::QuickOpen => self.quick_open().await,
+            Action::OpenFileUnderCursor => self.open_file_under_cursor().await,
+            Action::Format => self.format_selection().await,
+            Action::ReopenWithEncoding => self.reopen_with_encoding().await,
+            Action::Duplicate => self.handle_duplicate().await,
+            Action::DuplicateAndComment => self.duplicate_and_comment().await,
+            Action::Align => self.align_selection().await,
+            Action::CodeActions => self.code_actions().await,
+            Action::Outline => self.outline().await,
+        }
+    }
+
 
     async fn handle_left_panel(&mut self, event: KeyEvent) {
         match event.modifiers {
@@ -509,6 +1224,15 @@ impl Editor {
             }
             KeyModifiers::NONE => {
 
+            },
+            KeyModifiers::SHIFT => {
+                match event.code {
+                    KeyCode::Right => self.tree_view.expand_all(),
+                    KeyCode::Left => self.tree_view.collapse_all(),
+                    _ => {}
+                }
+                self.upd = true;
+                return;
             },
             _ => {
                 return;
@@ -546,7 +1270,7 @@ impl Editor {
                             self.is_lp_focused = false;
                         }
                         else {
-                            node.toggle();
+                            self.tree_view.toggle_selected();
                         }
 
                         self.upd = true;
@@ -561,6 +1285,9 @@ impl Editor {
     }
     
     async fn open_file(&mut self, path: &String) {
+        self.save_cursor_position();
+        let previous = self.code.abs_path.clone();
+
         if !self.codes.contains_key(path) { // move self.code code to codes buffer
 
             self.code.set_cursor_position(
@@ -571,6 +1298,9 @@ impl Editor {
 
             self.codes.insert(current_code.abs_path.clone(), current_code);
             self.load_file(path);
+            self.check_swap().await;
+            self.check_lock().await;
+            self.schedule_semantic_tokens();
 
             let lang = self.code.lang.clone();
             let lsp = self.lang2lsp.get(&lang);
@@ -598,6 +1328,20 @@ impl Editor {
             self.codes.insert(oldcode.abs_path.clone(), oldcode);
             self.r = r; self.c = c; self.y = y; self.x = x;
         }
+
+        if self.config.reveal_active_file.unwrap_or(false) {
+            self.tree_view.reveal(path); // keeps focus in the editor
+        }
+        self.snippet_stops.clear();
+        self.occurrence_origin = None;
+        self.spawn_parse();
+        self.schedule_blame();
+
+        for path in [previous, self.code.abs_path.clone()] {
+            if path.is_empty() { continue; }
+            self.recent_buffers.retain(|p| *p != path);
+            self.recent_buffers.push_front(path);
+        }
     }
 
     async fn handle_mouse(&mut self, e: MouseEvent) {
@@ -605,6 +1349,14 @@ impl Editor {
             MouseEvent { row, column, kind, modifiers } => {
                 self.is_lp_focused = (column as usize) < self.lp_width;
 
+                if let (Some(dragged), MouseEventKind::Up(_)) = (self.text_drag, kind) {
+                    self.text_drag = None;
+                    self.handle_mouse_click(row as usize, column as usize);
+                    let copy = modifiers.contains(KeyModifiers::ALT);
+                    self.drop_text(dragged, (self.r, self.c), copy).await;
+                    return;
+                }
+
                 match (modifiers, kind) {
                     (KeyModifiers::CONTROL, MouseEventKind::Up(_)) => {
                         self.handle_mouse_click(row as usize, column as usize);
@@ -635,6 +1387,16 @@ impl Editor {
                                 // button clicked
                                 return; 
                             }
+                            if rrow == self.height-1 && self.status_position_range().contains(&ccol) {
+                                return; // cursor position clicked
+                            }
+                            if self.is_minimap_click(rrow, ccol) {
+                                self.minimap_scroll(rrow);
+                                return;
+                            }
+                            if rrow == self.height-1 && self.status_indicators_range().contains(&ccol) {
+                                return; // whitespace indicators clicked
+                            }
 
                             if self.lp_width + self.ln_width < ccol &&
                                 ccol < self.lp_width + self.ln_width + self.lns_width - 1 {
@@ -673,6 +1435,7 @@ impl Editor {
                                     },
                                     None => {},
                                 }
+                                self.tree_view.start_drag(row as usize);
                                 return;
                             }
 
@@ -680,6 +1443,15 @@ impl Editor {
 
                             self.handle_mouse_click(row as usize, column as usize);
 
+                            if !self.selection.empty() && !self.selection.block {
+                                let (from, to) = (self.selection.from(), self.selection.to());
+                                if from <= (self.r, self.c) && (self.r, self.c) < to { // pressed on the selection
+                                    self.text_drag = Some((from, to));
+                                    self.selection.keep_once = true;
+                                    return;
+                                }
+                            }
+
                             if !self.selection.empty() {
                                 self.selection.clean();
                                 self.selection.set_start(self.r, self.c);
@@ -696,8 +1468,8 @@ impl Editor {
                                 if line.is_none() { return; }
                                 let line = line.unwrap();
 
-                                let prev = utils::find_prev_word(line, self.c);
-                                let next = utils::find_next_word(line, self.c);
+                                let prev = utils::find_prev_word(line, self.c, self.code.word_chars());
+                                let next = utils::find_next_word(line, self.c, self.code.word_chars());
 
                                 if prev < self.c && self.c < next { // not first and last symbol
                                     self.selection.set_start(self.r, prev);
@@ -729,6 +1501,15 @@ impl Editor {
                         let rrow = row as usize;
                         let ccol = column as usize;
 
+                        if self.tree_view.is_dragging() {
+                            if let Some((from, to)) = self.tree_view.end_drag() {
+                                self.move_tree_node(&from, &to);
+                                return;
+                            }
+                        }
+
+                        if self.is_minimap_click(rrow, ccol) { return; } // scrolled on press
+
                         if rrow == self.height-1 && ccol == self.width - 9 {
                             // left panel button clicked
                             self.left_panel_toggle();
@@ -746,12 +1527,22 @@ impl Editor {
                             self.process.run_last_tmux();
                             return;
                         }
+                        if rrow == self.height-1 && self.status_position_range().contains(&ccol) {
+                            // cursor position clicked
+                            self.goto_line().await;
+                            return;
+                        }
+                        if rrow == self.height-1 && self.status_indicators_range().contains(&ccol) {
+                            // whitespace indicators clicked
+                            self.goto_whitespace_issue();
+                            return;
+                        }
 
                         let is_runnable_button_clicked = self.lp_width + self.ln_width < ccol &&
                             ccol < self.lp_width + self.ln_width + self.lns_width - 1;
 
                         if is_runnable_button_clicked {
-                            match self.code.get_runnable(row as usize + self.y) {
+                            match self.code.get_runnable((row as usize).saturating_sub(self.text_top()) + self.y) {
                                 Some(runnable) => self.process.run_tmux(&runnable.cmd),
                                 None => {},
                             }
@@ -791,6 +1582,20 @@ impl Editor {
                             return;
                         }
 
+                        if self.tree_view.is_dragging() {
+                            if (column as usize) < self.lp_width {
+                                self.tree_view.drag_over(row as usize);
+                            }
+                            return;
+                        }
+
+                        if self.text_drag.is_some() { // the cursor shows where the text goes
+                            self.handle_mouse_click(row as usize, column as usize);
+                            self.selection.keep_once = true;
+                            self.upd = true;
+                            return;
+                        }
+
                         self.handle_mouse_click(row as usize, column as usize);
 
                         self.selection.set_end(self.r, self.c);
@@ -798,21 +1603,116 @@ impl Editor {
                         self.selection.keep_once = true;
                         self.upd = true;
                     }
+                    MouseEventKind::Moved => {
+                        let hovered = self.error_row_at(row as usize, column as usize).await;
+                        if hovered != self.hovered_diagnostic {
+                            self.hovered_diagnostic = hovered;
+                            self.upd = true;
+                        }
+                    }
                     _ => {}
                 }
             }
         }
     }
 
+    /// Moves the dragged text to the drop position as one undo group, `copy` keeps it at the source too.
+    /// Dropped on itself it is a click, the selection is cleared.
+    async fn drop_text(&mut self, (from, to): ((usize, usize), (usize, usize)), drop: (usize, usize), copy: bool) {
+        self.selection.clean();
+        self.upd = true;
+        if from <= drop && drop <= to { return; }
+
+        let text = self.code.get_text(from.0, from.1, to.0, to.1);
+        let lsp = self.lang2lsp.get(&self.code.lang).cloned();
+        let path = self.code.abs_path.clone();
+
+        self.code.undo_group_start();
+        let mut at = drop;
+        if !copy {
+            self.code.remove_text(from.0, from.1, to.0, to.1);
+            if let Some(lsp) = &lsp {
+                lsp.lock().await.did_change(from.0, from.1, to.0, to.1, &path, "").await;
+            }
+            if drop > to { // the text after the source moved to its start
+                at = match drop.0 == to.0 {
+                    true => (from.0, from.1 + drop.1 - to.1),
+                    false => (drop.0 - (to.0 - from.0), drop.1),
+                };
+            }
+        }
+        self.code.insert_text(&text, at.0, at.1);
+        if let Some(lsp) = &lsp {
+            lsp.lock().await.did_change(at.0, at.1, at.0, at.1, &path, &text).await;
+        }
+        self.code.undo_group_end();
+
+        let end = text.chars().fold(at, |(r, c), ch| if ch == '\n' { (r + 1, 0) } else { (r, c + 1) });
+        self.selection.set_start(at.0, at.1);
+        self.selection.set_end(end.0, end.1);
+        self.selection.keep_once = true;
+        (self.r, self.c) = end;
+        self.clean_diagnostics();
+    }
+
+    /// Moves a file or directory dropped in the tree and updates open buffers.
+    fn move_tree_node(&mut self, from: &str, to_dir: &str) {
+        let new_path = match self.tree_view.move_node(from, to_dir) {
+            Ok(new_path) => new_path,
+            Err(e) => { debug!("cant move {} to {}: {}", from, to_dir, e); return; }
+        };
+
+        let renamed = |path: &str| -> Option<String> {
+            let rest = path.strip_prefix(from)?;
+            if rest.is_empty() || rest.starts_with(std::path::MAIN_SEPARATOR) {
+                Some(format!("{}{}", new_path, rest))
+            } else { None }
+        };
+
+        if let Some(path) = renamed(&self.code.abs_path) {
+            self.code.file_name = utils::get_file_name(&path);
+            self.code.abs_path = path.clone();
+            self.tree_view.set_active(path);
+        }
+
+        let codes: Vec<(String, Code)> = self.codes.drain().collect();
+        for (path, mut code) in codes {
+            let path = match renamed(&path) {
+                Some(new_path) => {
+                    code.file_name = utils::get_file_name(&new_path);
+                    code.abs_path = new_path.clone();
+                    new_path
+                }
+                None => path,
+            };
+            self.codes.insert(path, code);
+        }
+
+        self.upd = true;
+    }
+
     fn handle_mouse_click(&mut self, row_click: usize, column_click: usize) {
+        let row_click = match row_click.checked_sub(self.text_top()) {
+            Some(row) => row, None => return, // the breadcrumb row
+        };
+        let text_start = self.lp_width + self.ln_width + self.lns_width;
+        if self.wrap {
+            let column = column_click.saturating_sub(text_start);
+            let (r, c) = self.visual_to_position(row_click, column)
+                .unwrap_or((self.code.len_lines() - 1, usize::MAX));
+            self.r = r;
+            self.c = c.min(self.code.line_len(r));
+            return;
+        }
+
         self.r = row_click + self.y;
 
         if self.r > self.code.len_lines() - 1 {  // fit to content
             self.r = self.code.len_lines() - 1;
         }
 
-        if column_click < self.lp_width + self.ln_width + self.lns_width + self.x {
-            self.c = 0; // outside of view
+        if column_click < self.lp_width + self.ln_width + self.lns_width {
+            self.c = self.x; // the gutter
         } else {
             self.c = self.find_cursor_x_position(column_click - (self.lp_width + self.ln_width + self.lns_width));
             // self.c -= self.lp_width + self.ln_width + self.lns_width
@@ -824,18 +1724,154 @@ impl Editor {
         }
     }
 
+    /// Status with the buttons at the right end, cut from the left to fit next to the text.
     fn status_line(&self) -> String {
-        if self.code.file_name.is_empty() {
+        let status = if self.code.file_name.is_empty() {
             format!("  {} {} {} {} {}",
                 '☰','☌', '', '▶', '⛭'
             )
         } else {
-            let changed = if self.code.changed { "*" } else { " " };
-            format!("  {}:{} {} {}{} {} {} {} {} {}",
-                self.r + 1, self.c + 1, self.code.lang, self.code.file_name, changed,
-                '☰','☌', '', '▶', '⛭'
+            format!("  {} {} {} {} {} {}",
+                self.status_info(), '☰','☌', '', '▶', '⛭'
             )
+        };
+
+        let width = self.width.saturating_sub(self.lp_width + self.ln_width + self.lns_width);
+        let mut used = 0;
+        let kept: Vec<char> = status.chars().rev()
+            .take_while(|ch| { used += UnicodeWidthChar::width(*ch).unwrap_or(0); used <= width })
+            .collect();
+        kept.into_iter().rev().collect()
+    }
+
+    /// Display columns of the text.
+    fn columns(text: &str) -> usize {
+        text.chars().map(|ch| UnicodeWidthChar::width(ch).unwrap_or(0)).sum()
+    }
+
+    /// File part of the status line, rendered from the `status_line` config format.
+    fn status_info(&self) -> String {
+        const DEFAULT: &str = "{lsp}{position} {lang} {file}{modified}{indicators}{modes}";
+
+        let uri = format!("file://{}", self.code.abs_path);
+        let (errors, warnings) = match self.diagnostics.try_lock() {
+            Ok(diagnostics) => diagnostics.get(&uri).map_or((0, 0), |d| (
+                d.diagnostics.iter().filter(|d| d.severity == 1).count(),
+                d.diagnostics.iter().filter(|d| d.severity == 2).count(),
+            )),
+            Err(_) => (0, 0),
+        };
+        let diagnostics = match (errors, warnings) {
+            (0, 0) => String::new(),
+            (e, w) => format!("✗{} ⚠{}", e, w),
+        };
+
+        let vars: HashMap<String, String> = [
+            ("position", self.status_position()),
+            ("row", (self.r + 1).to_string()),
+            ("col", (self.c + 1).to_string()),
+            ("offset", self.code.byte_offset(self.r, self.c).to_string()),
+            ("lines", self.code.len_lines().to_string()),
+            ("lang", self.code.lang.clone()),
+            ("file", self.code.file_name.clone()),
+            ("path", self.code.abs_path.clone()),
+            ("modified", if self.code.changed { "*" } else { " " }.to_string()),
+            ("indicators", self.status_indicators()),
+            ("modes", self.status_modes()),
+            ("diagnostics", diagnostics),
+            ("lsp", self.status_lsp()),
+        ].into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+
+        let format = self.config.status_line.as_deref().unwrap_or(DEFAULT);
+        strfmt(format, &vars).or_else(|_| strfmt(DEFAULT, &vars)).unwrap_or_default()
+    }
+
+    /// Server state or progress for the buffer language followed by a gap, empty when idle.
+    fn status_lsp(&self) -> String {
+        const MAX: usize = 40;
+        match self.lsp_status.get(&self.code.lang) {
+            Some(status) if status.chars().count() > MAX => format!("{}…  ", status.chars().take(MAX - 1).collect::<String>()),
+            Some(status) => format!("{}  ", status),
+            None => String::new(),
+        }
+    }
+
+    fn status_position(&self) -> String {
+        let offset = self.code.byte_offset(self.r, self.c);
+        format!("{}:{} @{}", self.r + 1, self.c + 1, offset)
+    }
+
+    /// Active editing modes, e.g. ` [OVR]`, empty in the default mode.
+    fn status_modes(&self) -> String {
+        let mut modes = String::new();
+        if self.overwrite { modes.push_str(" [OVR]"); }
+        if self.code.eol == EolStyle::Crlf { modes.push_str(" crlf"); }
+        if self.code.read_only { modes.push_str(" [RO]"); }
+        if !self.cursors.is_empty() { modes.push_str(&format!(" {} cursors", self.cursors.len() + 1)); }
+        modes
+    }
+
+    /// Trailing whitespace and missing final newline marks, empty if the file is clean.
+    fn status_indicators(&self) -> String {
+        let mut indicators = String::new();
+        if self.code.first_trailing_whitespace().is_some() { indicators.push_str(" ␣"); }
+        if !self.code.has_final_newline() { indicators.push_str(" ¬eol"); }
+        if self.line_ending_warnings {
+            if self.code.has_bom() { indicators.push_str(" bom"); }
+            if !self.code.mixed_line_endings().is_empty() { indicators.push_str(" mixed eol"); }
+        }
+        indicators
+    }
+
+    /// Screen columns of the whitespace indicators in the status line.
+    fn status_indicators_range(&self) -> std::ops::Range<usize> {
+        self.status_part_range(&self.status_indicators())
+    }
+
+    /// Screen columns of the part in the status line, empty if the format has no such part.
+    fn status_part_range(&self, part: &str) -> std::ops::Range<usize> {
+        if self.code.file_name.is_empty() || part.is_empty() { return 0..0; }
+        let status = self.status_line();
+        let x = self.width.saturating_sub(Self::columns(&status));
+        match status.find(part) {
+            Some(byte) => {
+                let start = x + Self::columns(&status[..byte]);
+                start..start + Self::columns(part)
+            }
+            None => 0..0,
+        }
+    }
+
+    /// Jumps to the first line with trailing whitespace or to the end without newline.
+    fn goto_whitespace_issue(&mut self) {
+        match self.code.first_trailing_whitespace() {
+            Some(r) => {
+                self.r = r;
+                self.c = self.code.line_len(r);
+            }
+            None if !self.code.has_final_newline() => {
+                self.r = self.code.len_lines() - 1;
+                self.c = self.code.line_len(self.r);
+            }
+            None => return,
         }
+        self.selection.clean();
+        self.focus_to_center();
+        self.upd = true;
+    }
+
+    /// Screen columns of the cursor position in the status line.
+    fn status_position_range(&self) -> std::ops::Range<usize> {
+        self.status_part_range(&self.status_position())
+    }
+
+    fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+        self.upd = true;
+    }
+
+    fn clear_message(&mut self) {
+        if self.message.take().is_some() { self.upd = true; }
     }
 
     fn clear_all(&mut self) {
@@ -856,9 +1892,34 @@ impl Editor {
 
        Filtering by row improves performance a bit, -2%
     */
+    /// Draws now, or with `max_fps` at the next frame when the last one was drawn too recently,
+    /// the redraws requested till then are drawn once.
+    async fn draw_throttled(&mut self) {
+        let interval = match self.config.max_fps {
+            Some(fps) if fps > 0 => time::Duration::from_secs_f64(1.0 / fps as f64),
+            _ => { self.draw().await; return; },
+        };
+        let now = tokio::time::Instant::now();
+        match self.last_frame {
+            Some(last) if now < last + interval => { self.frame_due.get_or_insert(last + interval); },
+            _ => {
+                self.frame_due = None;
+                self.last_frame = Some(now);
+                self.draw().await;
+            }
+        }
+    }
+
+    /// Draws a frame as one synchronized update, so the terminal shows it at once without flicker.
     async fn draw(&mut self) {
-        let start = time::Instant::now();
+        queue!(stdout(), terminal::BeginSynchronizedUpdate).expect("Can not begin update");
 
+        self.draw_frame().await;
+
+        execute!(stdout(), terminal::EndSynchronizedUpdate).expect("Can not end update");
+    }
+
+    async fn draw_frame(&mut self) {
         if self.code.file_name.is_empty() {
             queue!(stdout(), cursor::Hide);
             if self.tree_view.is_search(){ queue!(stdout(), cursor::Show); }
@@ -884,52 +1945,104 @@ impl Editor {
 
         let status = self.status_line();
 
-        if self.width < self.lp_width + self.ln_width + self.lns_width + status.chars().count() { return; } // too small
+        if self.width < self.lp_width + self.ln_width + self.lns_width + Self::columns(&status) { return; } // too small
 
         let colors = self.code.colors(self.y, self.y + self.height, &self.theme);
+        let colors: Vec<_> = self.semantic_colors(self.y, self.y + self.height).into_iter()
+            .chain(colors).collect(); // semantic colors win
+        let (occurrences, occurrence_color) = match self.cursor_word.clone() {
+            Some(word) => (self.word_occurrences(&word, None), self.hlcolor),
+            None => (self.selection_occurrences(), self.occolor),
+        };
 
-        let lines = self.code
-            .slice(self.y, self.y + self.height)
-            .lines()
-            .take(self.height);
-
-        let lines_count = lines.len();
-
-        let mut bytes_counter = self.code.line_to_byte(self.y);
+        let rows = self.visual_rows();
+        let lines_count = rows.len();
 
         let uri = format!("file://{}", self.code.abs_path.clone());
 
-        let diagnostics = self.diagnostics.clone();
-        let maybe_diagnostics = diagnostics.try_lock().unwrap();
-        let maybe_diagnostics = maybe_diagnostics.get(&uri);
-
         let start_row = self.y.clone();
         let end_row = start_row + self.height;
 
-        let line2error = match maybe_diagnostics {
-            Some(d) =>  {
-                d.diagnostics.iter()
-                .filter(|d| d.severity == 1) // errors only
-                .filter(|d| start_row <= d.range.start.line as usize  && d.range.start.line as usize <= end_row)
-                .map(|d| (d.range.start.line as usize, &d.message))
-                .collect::<HashMap<usize, &String>>()
-            },
-            None => HashMap::new(),
+        // owned, so the lock is released before the popup, minimap and status line read diagnostics
+        let (line2error, tagged) = {
+            let diagnostics = self.diagnostics.try_lock().unwrap();
+            let maybe_diagnostics = diagnostics.get(&uri);
+
+            let line2error = match maybe_diagnostics {
+                Some(d) =>  {
+                    d.diagnostics.iter()
+                    .filter(|d| d.severity == 1) // errors only
+                    .filter(|d| start_row <= d.range.start.line as usize  && d.range.start.line as usize <= end_row)
+                    .map(|d| (d.range.start.line as usize, d.message.clone()))
+                    .collect::<HashMap<usize, String>>()
+                },
+                None => HashMap::new(),
+            };
+
+            // ranges of unnecessary (dimmed) and deprecated (crossed out) code
+            let tagged: Vec<((usize, usize), (usize, usize), Attribute)> = match maybe_diagnostics {
+                Some(d) => d.diagnostics.iter()
+                    .filter(|d| d.range.end.line as usize >= start_row && d.range.start.line as usize <= end_row)
+                    .filter_map(|d| {
+                        let tags = d.tags.as_ref()?;
+                        let attr = if tags.contains(&DIAGNOSTIC_TAG_UNNECESSARY) { Attribute::Dim }
+                            else if tags.contains(&DIAGNOSTIC_TAG_DEPRECATED) { Attribute::CrossedOut }
+                            else { return None };
+                        let start = (d.range.start.line as usize, d.range.start.character as usize);
+                        let end = (d.range.end.line as usize, d.range.end.character as usize);
+                        Some((start, end, attr))
+                    })
+                    .collect(),
+                None => vec![],
+            };
+            (line2error, tagged)
         };
 
+        let line_changes = self.code.line_changes();
+        let render_whitespace = self.render_whitespace;
+        let text_width = self.text_width();
+        let scroll_x = if self.wrap { 0 } else { self.visual_col(self.r, self.x) };
+        let mixed_line_endings: HashSet<usize> = match self.line_ending_warnings {
+            true => self.code.mixed_line_endings().iter().copied().collect(),
+            false => HashSet::new(),
+        };
+        let closing_contexts = match (self.closing_context, rows.first(), rows.last()) {
+            (true, Some(first), Some(last)) => {
+                let min_lines = self.config.closing_context_lines.unwrap_or(20);
+                let comment = self.code.get_lang_comment().unwrap_or_default();
+                self.code.closing_contexts(first.0, last.0, min_lines).into_iter()
+                    .map(|(row, signature)| (row, format!("{} end {}", comment, signature).trim_start().to_string()))
+                    .collect()
+            },
+            _ => HashMap::new(),
+        };
 
-        for (row, line) in lines.enumerate() {
-            let rrow = row + self.y;
+        let top = self.text_top();
+        for (row, &(rrow, from, to)) in rows.iter().enumerate() {
+            let screen_row = row + top;
+            let line = self.code.text.line(rrow);
+            let mut bytes_counter = self.code.line_to_byte(rrow);
+            let line_occurrences = occurrences.get(&rrow);
+            let is_first_row = from == 0; // wrapped rows have no number and run button
 
-            queue!(stdout, cursor::MoveTo(self.lp_width as u16, row as u16)).unwrap();
+            queue!(stdout, cursor::MoveTo(self.lp_width as u16, screen_row as u16)).unwrap();
 
-            let line_number = format!("{:width$}", rrow + 1, width = self.ln_width);
-            let lncolor = if line2error.contains_key(&rrow) { self.ecolor } else { self.lncolor };
-            queue!(stdout, BColor(Color::Reset), FColor(lncolor), Print(line_number));
+            let number = match self.line_numbers {
+                LineNumbers::Relative if rrow != self.r => rrow.abs_diff(self.r),
+                _ => rrow + 1,
+            };
+            if self.ln_width > 0 && is_first_row {
+                let line_number = format!("{:width$}", number, width = self.ln_width);
+                let flagged = line2error.contains_key(&rrow) || mixed_line_endings.contains(&rrow);
+                let lncolor = if flagged { self.ecolor } else { self.lncolor };
+                queue!(stdout, BColor(Color::Reset), FColor(lncolor), Print(line_number));
+            } else if self.ln_width > 0 {
+                queue!(stdout, BColor(Color::Reset), Print(" ".repeat(self.ln_width)));
+            }
 
             let (run_or_empty, run_or_empty_color) = match self.code.is_runnable(rrow) {
-                false => (" ".repeat(self.lns_width), Color::Reset),
-                true => (format!("  {}  ", '▶'), Color::AnsiValue((87))), // todo: make it dynamic
+                true if is_first_row => (format!("{}  {}  ", " ".repeat(self.lns_width - 5), '▶'), Color::AnsiValue((87))), // todo: make it dynamic
+                _ => (" ".repeat(self.lns_width), Color::Reset),
             };
 
             // let (run_or_empty, run_or_empty_color) = (" ".repeat(self.lns_width), Color::Reset)
@@ -937,6 +2050,23 @@ impl Editor {
             queue!(stdout, BColor(Color::Reset), FColor(run_or_empty_color), Print(run_or_empty));
             queue!(stdout, BColor(Color::Reset), FColor(Color::Reset));
 
+            // change bar left of the line number, where the number has no padding left it is skipped
+            let change = line_changes.get(&rrow).filter(|_| is_first_row)
+                .filter(|_| self.ln_width == 0 || number.to_string().len() < self.ln_width);
+            if let Some(change) = change {
+                let color = match change {
+                    ChangeKind::Added => self.addcolor,
+                    ChangeKind::Modified => self.modcolor,
+                    ChangeKind::Deleted => self.delcolor,
+                };
+                queue!(stdout, cursor::MoveTo(self.lp_width as u16, screen_row as u16), FColor(color), Print('▎'),
+                    cursor::MoveTo((self.lp_width + self.ln_width + self.lns_width) as u16, screen_row as u16),
+                    FColor(Color::Reset)).unwrap();
+            }
+            if !is_first_row {
+                queue!(stdout, Print(" ".repeat(self.wrap_indent(rrow)))).unwrap();
+            }
+
 
             let filtered_colors: Vec<_> = colors.iter()
                 .filter(|(_, _, start, end, _)| *start <= rrow && rrow <= *end)
@@ -946,13 +2076,27 @@ impl Editor {
             let chars_len = line.chars().len();
             let mut last_fg_color = Color::Reset;
             let mut last_bg_color = Color::Reset;
-            let mut tabs_offset = 0;
-
-            let is_overlayed = self.overlay_lines.contains(&row);
+            let mut last_attr = None;
+            let mut used = 0; // screen columns from the row start, tabs reach the next tab stop
+
+            let is_overlayed = self.overlay_lines.contains(&screen_row);
+            let trailing_from = match render_whitespace {
+                true => line.len_chars() - line.chars_at(line.len_chars()).reversed()
+                    .take_while(|c| c.is_whitespace()).count(),
+                false => usize::MAX,
+            };
 
             for (col, ch) in chars.enumerate() {
-                let outside_left = self.x > col;
-                let outside_right = col >= self.width - self.lp_width - self.ln_width - self.lns_width  + self.x - 1 - tabs_offset;
+                if col < from {
+                    bytes_counter += ch.len_utf8();
+                    continue;
+                }
+                let (vcol, w) = (used, self.char_width(ch, used));
+                used += w;
+
+                let skip = scroll_x.saturating_sub(vcol); // columns scrolled out on the left
+                let outside_left = skip >= w;
+                let outside_right = col >= to || !self.wrap && vcol + w > scroll_x + text_width;
 
                 if outside_right || ch == '\n' || outside_left || is_overlayed {
                     bytes_counter += ch.len_utf8();
@@ -969,16 +2113,41 @@ impl Editor {
                     None => Color::Reset,
                 };
 
+                let is_occurrence = line_occurrences
+                    .map_or(false, |o| o.iter().any(|(from, to)| *from <= col && col < *to));
+
                 let bg_color = match self.selection.is_selected(row + self.y, col) {
                     true => self.selcolor,
+                    false if is_occurrence => occurrence_color,
                     false => Color::Reset,
                 };
 
-                let chr = if ch == '\t' {
-                    let tab_width = self.code.indent_width().unwrap_or(2);
-                    if self.x == 0 { tabs_offset += tab_width; " ".repeat(tab_width) }
-                    else { " ".to_string() }
-                } else { ch.to_string() };
+                let (chr, is_whitespace_mark) = match ch {
+                    '\t' if render_whitespace => (format!("→{}", " ".repeat(w - skip - 1)), true),
+                    ' ' if render_whitespace && col >= trailing_from => ("·".to_string(), true),
+                    '\t' => (" ".repeat(w - skip), false),
+                    _ if skip > 0 => (" ".repeat(w - skip), false), // wide char cut by the scroll
+                    _ => (ch.to_string(), false),
+                };
+                let fg_color = if is_whitespace_mark { self.lncolor } else { fg_color };
+
+                let is_cursor = self.cursors.contains(&(rrow, col));
+                let bg_color = if self.is_cursor_selected(rrow, col) { self.selcolor } else { bg_color };
+
+                let attr = if is_cursor { Some(Attribute::Reverse) } else {
+                    tagged.iter()
+                        .find(|(start, end, _)| *start <= (rrow, col) && (rrow, col) < *end)
+                        .map(|(_, _, attr)| *attr)
+                };
+
+                if attr != last_attr {
+                    queue!(stdout, SetAttribute(Attribute::Reset)).unwrap();
+                    if let Some(attr) = attr { queue!(stdout, SetAttribute(attr)).unwrap(); }
+                    queue!(stdout, BColor(bg_color), FColor(fg_color)).unwrap();
+                    last_fg_color = fg_color;
+                    last_bg_color = bg_color;
+                    last_attr = attr;
+                }
 
                 if last_fg_color == fg_color && last_bg_color == bg_color {
                     queue!(stdout, Print(chr)).unwrap();
@@ -991,11 +2160,30 @@ impl Editor {
                 bytes_counter += ch.len_utf8();
             }
 
+            if last_attr.is_some() {
+                queue!(stdout, SetAttribute(Attribute::Reset)).unwrap();
+            }
+
+            let line_len = chars_len - if line.chars().last() == Some('\n') { 1 } else { 0 };
+            let is_last_row = to >= line_len;
+            if !is_overlayed && is_last_row && line_len >= self.x && self.cursors.contains(&(rrow, line_len)) {
+                // secondary cursor after the line end
+                queue!(stdout, BColor(Color::Reset), SetAttribute(Attribute::Reverse), Print(' '),
+                    SetAttribute(Attribute::Reset)).unwrap();
+            }
 
-            if line2error.contains_key(&rrow) {
+            if line2error.contains_key(&rrow) && is_first_row && is_last_row {
                 let error_message = &line2error.get(&rrow).unwrap();
-                self.draw_error(error_message, rrow, row)
-            };
+                self.draw_error(error_message, rrow, screen_row)
+            } else if rrow == self.r && is_last_row && self.blame.as_ref().map_or(false, |b| b.len() > rrow) {
+                let blame = &self.blame.as_ref().unwrap()[rrow];
+                self.draw_dimmed_note(blame, rrow, screen_row);
+            } else if is_last_row && mixed_line_endings.contains(&rrow) {
+                let ending = match self.code.eol { EolStyle::Crlf => "lf", EolStyle::Lf => "crlf" };
+                self.draw_line_note(&format!("{} line ending", ending), rrow, screen_row);
+            } else if let Some(context) = closing_contexts.get(&rrow).filter(|_| is_last_row) {
+                self.draw_dimmed_note(context, rrow, screen_row);
+            }
 
             // if row < self.height -1{
                 queue!(stdout, BColor(Color::Reset), terminal::Clear(ClearType::UntilNewLine)).unwrap();
@@ -1018,20 +2206,29 @@ impl Editor {
         }
 
 
-        if lines_count < self.height && status.chars().count() < self.width {
+        if lines_count + top < self.height && Self::columns(&status) < self.width {
             // queue!(stdout, terminal::Clear(ClearType::FromCursorDown)).unwrap(); // flickering???
             // fill empty space
-            for row in lines_count..self.height {
+            for row in lines_count + top..self.height {
                 queue!(stdout, cursor::MoveTo(self.lp_width as u16, row as u16));
                 queue!(stdout, BColor(Color::Reset), terminal::Clear(ClearType::UntilNewLine)).unwrap();
             }
 
             queue!(stdout, cursor::MoveTo(self.lp_width as u16, self.height as u16));
-            for c in self.lp_width..self.width-status.chars().count()-1 {
+            for c in self.lp_width..self.width.saturating_sub(Self::columns(&status) + 1) {
                 queue!(stdout, Print(' ')).unwrap();
             }
         }
 
+        self.draw_minimap();
+
+        if self.text_top() > 0 {
+            self.draw_breadcrumb();
+        }
+
+        self.draw_diagnostic_popup();
+        self.draw_signature_help();
+
         self.draw_status();
         self.draw_cursor();
 
@@ -1056,15 +2253,180 @@ impl Editor {
         self.upd = false;
     }
 
+    /// Pins enclosing scopes of the first visible line in the row above the text.
+    fn draw_breadcrumb(&mut self) {
+        let scopes = self.code.scopes_at(self.y);
+        let breadcrumb = scopes.into_iter()
+            .map(|(_, signature)| signature)
+            .collect::<Vec<_>>()
+            .join(" › ");
+
+        let width = self.text_area_width.saturating_sub(self.lp_width + self.minimap_width());
+        let breadcrumb: String = breadcrumb.chars().take(width).collect();
+
+        queue!(stdout(),
+            cursor::MoveTo(self.lp_width as u16, 0),
+            BColor(Color::Reset), FColor(self.lncolor),
+            Print(format!("{:<width$}", breadcrumb, width = width)),
+        ).expect("Can not draw breadcrumb");
+    }
+
+    /// Finds the selected word in visible lines, returns row to char ranges.
+    fn selection_occurrences(&mut self) -> HashMap<usize, Vec<(usize, usize)>> {
+        if !self.selection.non_empty() { return HashMap::new(); }
+
+        let (y, x) = self.selection.from();
+        let (yto, xto) = self.selection.to();
+        if y != yto { return HashMap::new(); }
+
+        let word = self.code.from_to(y, x, yto, xto).to_string();
+        self.word_occurrences(&word, Some((y, x)))
+    }
+
+    /// Whole word matches of `word` in visible lines but the one at `skip`, row to char ranges.
+    fn word_occurrences(&self, word: &str, skip: Option<(usize, usize)>) -> HashMap<usize, Vec<(usize, usize)>> {
+        let mut occurrences = HashMap::new();
+        let word_chars = self.code.word_chars();
+        let is_word_char = |c: char| c.is_alphanumeric() || c == '_' || word_chars.contains(c);
+        if word.is_empty() || !word.chars().all(is_word_char) { return occurrences; }
+
+        let end = (self.y + self.height).min(self.code.len_lines());
+        for row in self.y..end {
+            let line = self.code.text.line(row).to_string();
+            let ranges: Vec<(usize, usize)> = line.match_indices(&word)
+                .filter(|(i, _)| {
+                    let before = line[..*i].chars().last();
+                    let after = line[*i + word.len()..].chars().next();
+                    !before.map_or(false, is_word_char) && !after.map_or(false, is_word_char)
+                })
+                .map(|(i, _)| {
+                    let from = line[..i].chars().count();
+                    (from, from + word.chars().count())
+                })
+                .filter(|(from, _)| skip != Some((row, *from)))
+                .collect();
+            if !ranges.is_empty() { occurrences.insert(row, ranges); }
+        }
+        occurrences
+    }
+
+    /// File row under the mouse if it has errors, the ones marked in the line numbers.
+    async fn error_row_at(&self, row: usize, column: usize) -> Option<usize> {
+        if column < self.lp_width + self.ln_width || row >= self.height - 1 { return None; }
+
+        let rrow = row.checked_sub(self.text_top())? + self.y;
+        let uri = format!("file://{}", self.code.abs_path);
+        let diagnostics = self.diagnostics.lock().await;
+        let has_error = diagnostics.get(&uri).map_or(false, |d| d.diagnostics.iter()
+            .any(|d| d.severity == 1 && d.range.start.line as usize == rrow));
+
+        if has_error { Some(rrow) } else { None }
+    }
+
+    /// Full messages of the hovered row diagnostics, below the row or above it at the bottom.
+    fn draw_diagnostic_popup(&mut self) {
+        let rrow = match self.hovered_diagnostic {
+            Some(rrow) if self.y <= rrow && rrow < self.y + self.height - 1 => rrow,
+            _ => return,
+        };
+
+        let x = self.lp_width + self.ln_width + self.lns_width;
+        if self.text_area_width <= x + 4 { return; }
+        let width = self.text_area_width - x - 2;
+
+        let lines = self.diagnostic_popup_lines(rrow, width);
+        if lines.is_empty() { return; }
+
+        let height = lines.len().min(10);
+        let row = rrow - self.y;
+        let from = if row + 1 + height < self.height { row + 1 } else { row.saturating_sub(height) };
+
+        for (i, line) in lines.iter().take(height).enumerate() {
+            queue!(stdout(),
+                cursor::MoveTo(x as u16, (from + i) as u16),
+                BColor(Color::Reset), FColor(self.ecolor),
+                Print(format!(" {:width$} ", line, width = width))
+            ).expect("Can not draw diagnostic");
+        }
+    }
+
+    /// Messages of the row diagnostics with their related information, wrapped to width.
+    fn diagnostic_popup_lines(&self, rrow: usize, width: usize) -> Vec<String> {
+        let uri = format!("file://{}", self.code.abs_path);
+        let diagnostics = match self.diagnostics.try_lock() {
+            Ok(diagnostics) => diagnostics,
+            Err(_) => return vec![],
+        };
+        let diagnostics = match diagnostics.get(&uri) {
+            Some(d) => d.diagnostics.clone(),
+            None => return vec![],
+        };
+
+        let mut lines = vec![];
+        for d in diagnostics.iter().filter(|d| d.range.start.line as usize == rrow) {
+            let severity = match d.severity { 1 => "error", 2 => "warning", 3 => "info", _ => "hint" };
+            let text = format!("{} {}: {}", d.source, severity, d.message);
+            lines.extend(text.lines().flat_map(|l| Self::wrap_line(l, width)));
+
+            for info in d.relatedInformation.iter().flatten() {
+                let path = info.location.uri.strip_prefix("file://").unwrap_or(&info.location.uri);
+                let file = utils::get_file_name(path);
+                let text = format!("  {}:{}: {}", file, info.location.range.start.line + 1.0, info.message);
+                lines.extend(Self::wrap_line(&text, width));
+            }
+        }
+        lines
+    }
+
+    /// One line with the signature of the called function above the cursor,
+    /// the active parameter underlined.
+    fn draw_signature_help(&mut self) {
+        let (open, label, active) = match &self.signature {
+            Some(signature) => signature.clone(),
+            None => return,
+        };
+        let (row, _) = match self.visual_cursor() {
+            Some(cursor) => cursor,
+            None => return,
+        };
+        let row = if row > 0 { row - 1 } else { row + 1 };
+        if row >= self.height { return; }
+
+        let text_x = self.lp_width + self.ln_width + self.lns_width;
+        if self.text_area_width <= text_x + 4 { return; }
+        let width = self.text_area_width - text_x - 2;
+        let x = text_x + open.saturating_sub(self.x).min(width.saturating_sub(label.chars().count()));
+        let (from, to) = active.unwrap_or((0, 0));
+
+        queue!(stdout(),
+            cursor::MoveTo(x as u16, row as u16),
+            BColor(Color::Reset), FColor(self.lncolor), Print(" "),
+        ).expect("Can not draw signature");
+        for (i, ch) in label.chars().take(width).enumerate() {
+            let active = from <= i && i < to;
+            if active { queue!(stdout(), SetAttribute(Attribute::Underlined)).expect("Can not draw signature"); }
+            queue!(stdout(), Print(ch)).expect("Can not draw signature");
+            if active { queue!(stdout(), SetAttribute(Attribute::NoUnderline)).expect("Can not draw signature"); }
+        }
+        queue!(stdout(), Print(" "), FColor(Color::Reset)).expect("Can not draw signature");
+    }
+
+    /// Splits a line into chunks of at most width chars.
+    fn wrap_line(line: &str, width: usize) -> Vec<String> {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() { return vec![String::new()]; }
+        chars.chunks(width).map(|c| c.iter().collect()).collect()
+    }
+
     fn draw_error(&self, error_message: &String, rrow:usize, row:usize) {
         let space = 3;
-        let max_x = self.lp_width + self.ln_width + self.lns_width + self.code.line_len(rrow) + space;
+        let max_x = self.lp_width + self.ln_width + self.lns_width + self.visual_col(rrow, self.code.line_len(rrow)) + space;
 
-        if max_x > self.width { return; }
+        if max_x > self.text_area_width { return; }
 
         queue!(stdout(), Print(" ".repeat(space)));
 
-        let limit = self.width - max_x;
+        let limit = self.text_area_width - max_x;
 
         let m: String = error_message.chars()
             .map(|ch| if ch == '\n' { ' ' } else { ch })
@@ -1077,32 +2439,56 @@ impl Editor {
         ).unwrap();
     }
 
+    fn draw_line_note(&self, note: &str, rrow: usize, row: usize) {
+        let space = 4;
+        let max_x = self.lp_width + self.ln_width + self.lns_width + self.visual_col(rrow, self.code.line_len(rrow)) + space;
+        if max_x >= self.text_area_width { return; }
+
+        let note: String = note.chars().take(self.text_area_width - max_x).collect();
+        queue!(stdout(),
+            cursor::MoveTo(max_x as u16, row as u16),
+            BColor(Color::Reset), FColor(self.ecolor), Print(note),
+        ).unwrap();
+    }
+
+    /// Blame or closing context after the line end.
+    fn draw_dimmed_note(&self, note: &str, rrow: usize, row: usize) {
+        let space = 4;
+        let max_x = self.lp_width + self.ln_width + self.lns_width + self.visual_col(rrow, self.code.line_len(rrow)) + space;
+        if max_x >= self.text_area_width { return; }
+
+        let b: String = note.chars().take(self.text_area_width - max_x).collect();
+
+        queue!(stdout(),
+            cursor::MoveTo(max_x as u16, row as u16),
+            BColor(Color::Reset), FColor(self.lncolor),
+            SetAttribute(Attribute::Dim), Print(b), SetAttribute(Attribute::Reset),
+        ).unwrap();
+    }
+
     fn draw_cursor(&mut self) {
         if !self.cursor_is_focused() { return; }
         if self.code.file_name.is_empty() { return; }
 
         let out_left = self.c < self.x;
-        let out_right = self.lp_width + self.ln_width + self.lns_width + self.c - self.x >= self.width;
-        if out_left || out_right {
+        if out_left || self.cursor_is_invisible_at_right() {
             queue!(stdout(), cursor::Hide).expect("Can not hide cursor");
             return;
         }
 
-        let cursor_x_pos = if self.x != 0 { // if horizontal scroll, ignore indentation
-            self.c + self.lp_width + self.ln_width + self.lns_width - self.x
-        } else {
-            let tabs_count = self.code.count_tabs(self.r, self.c).unwrap_or(0);
-            let ident_width = self.code.indent_width().unwrap_or(2);
-            let tabs_correction = tabs_count * (ident_width-1);
-            self.c + self.lp_width + self.ln_width + self.lns_width - self.x + tabs_correction
+        let (cursor_x_pos, cursor_y_pos) = match self.visual_cursor() {
+            Some((row, col)) if self.wrap => (self.lp_width + self.ln_width + self.lns_width + col, row),
+            _ => (self.cursor_x_pos(), self.r - self.y),
         };
-
-        let cursor_y_pos = self.r - self.y;
+        let cursor_y_pos = cursor_y_pos + self.text_top();
+        let cursor_style = if self.overwrite { cursor::SetCursorStyle::SteadyBlock }
+            else { cursor::SetCursorStyle::DefaultUserShape };
 
         queue!(
             stdout(),
             cursor::MoveTo(cursor_x_pos as u16, cursor_y_pos as u16),
             FColor(Color::Reset),
+            cursor_style,
             cursor::Show
         )
         .expect("Can not show cursor");
@@ -1110,39 +2496,231 @@ impl Editor {
         stdout().flush().expect("flush");
     }
 
-    fn draw_status(&mut self) {
-        let status = self.status_line();
-        let x = self.width - status.chars().count();
-        let y = self.height - 1;
+    fn cursor_x_pos(&self) -> usize {
+        let scrolled = self.visual_col(self.r, self.c).saturating_sub(self.visual_col(self.r, self.x));
+        self.lp_width + self.ln_width + self.lns_width + scrolled
+    }
 
-        queue!(
-            stdout(),
-            cursor::Hide,
-            cursor::MoveTo(x as u16, y as u16),
-            FColor(self.scolor),
-            Print(status)
-        )
-        .expect("Can not print status");
+    fn toggle_wrap(&mut self) {
+        self.wrap = !self.wrap;
+        self.x = 0;
+        self.upd = true;
+    }
 
-        stdout().flush().expect("flush");
+    /// Columns available for the text.
+    fn text_width(&self) -> usize {
+        self.text_area_width.saturating_sub(self.lp_width + self.ln_width + self.lns_width + self.minimap_width() + 1).max(1)
     }
 
-    fn draw_logo(&mut self) {
-        let logo = r#"🅡 🅔 🅓"#;
-        // let logo = "RED";
+    /// Columns of the minimap at the right, 0 when it is off.
+    fn minimap_width(&self) -> usize {
+        if self.minimap { MINIMAP_WIDTH } else { 0 }
+    }
 
-        let lines:Vec<&str> = logo.split("\n").collect();
-        let logo_width = lines.get(0).unwrap().len();
+    /// Lines per minimap row, so the whole file fits the rows above the status line.
+    fn minimap_lines_per_row(&self) -> usize {
+        let rows = self.height.saturating_sub(1).max(1);
+        ((self.code.len_lines() + rows - 1) / rows).max(1)
+    }
 
-        let fromy = self.height / 2 - lines.len() / 2;
-        let fromx = self.lp_width + (self.width - self.lp_width)/ 2;
+    fn is_minimap_click(&self, row: usize, column: usize) -> bool {
+        self.minimap && row + 1 < self.height && column + self.minimap_width() >= self.text_area_width
+    }
 
-        let mut stdout = stdout();
+    /// Overview of the file: a shade by the line lengths of each row, errors and changes colored,
+    /// the visible lines highlighted.
+    fn draw_minimap(&self) {
+        if !self.minimap || self.text_area_width < MINIMAP_WIDTH { return; }
 
-        for r in 0..self.height{
-            queue!(stdout,
-                cursor::MoveTo(self.lp_width as u16, r as u16),
-                terminal::Clear(ClearType::UntilNewLine)
+        let errors = self.error_lines();
+        let line_changes = self.code.line_changes();
+
+        let (lines, per_row) = (self.code.len_lines(), self.minimap_lines_per_row());
+        let text_width = self.text_width();
+        let x = self.text_area_width - MINIMAP_WIDTH;
+
+        for row in 0..self.height.saturating_sub(1) {
+            let (from, to) = ((row * per_row).min(lines), ((row + 1) * per_row).min(lines));
+            let visible = from < to && from < self.y + self.height && self.y < to;
+
+            let used: usize = (from..to).map(|r| self.code.line_len(r).min(text_width)).sum();
+            let density = if from < to { used * 4 / (text_width * (to - from)) } else { 0 };
+            let shade = [' ', '░', '▒', '▓', '█'][density.max((used > 0) as usize).min(4)];
+
+            let color = if (from..to).any(|r| errors.contains(&r)) { self.ecolor }
+                else if (from..to).any(|r| line_changes.contains_key(&r)) { self.modcolor }
+                else { self.lncolor };
+            let bg = if visible { self.selcolor } else { Color::Reset };
+
+            queue!(stdout(), cursor::MoveTo(x as u16, row as u16), BColor(bg), FColor(color),
+                Print(shade.to_string().repeat(MINIMAP_WIDTH)), BColor(Color::Reset)).unwrap();
+        }
+    }
+
+    /// Rows with an error of the current file.
+    fn error_lines(&self) -> HashSet<usize> {
+        let uri = format!("file://{}", self.code.abs_path);
+        match self.diagnostics.try_lock() {
+            Ok(diagnostics) => diagnostics.get(&uri).map_or(HashSet::new(), |d| d.diagnostics.iter()
+                .filter(|d| d.severity == 1).map(|d| d.range.start.line as usize).collect()),
+            Err(_) => HashSet::new(),
+        }
+    }
+
+    /// Scrolls so the lines of the clicked minimap row are in the middle of the screen.
+    fn minimap_scroll(&mut self, row: usize) {
+        let line = row * self.minimap_lines_per_row();
+        let max_y = self.code.len_lines().saturating_sub(self.height);
+        self.y = line.saturating_sub(self.height / 2).min(max_y);
+        self.upd = true;
+    }
+
+    /// Columns a tab takes at most, `tab_width` config or the language indent width.
+    fn tab_width(&self) -> usize {
+        self.config.tab_width.or(self.code.indent_width()).filter(|w| *w > 0).unwrap_or(4)
+    }
+
+    /// Screen columns of the char at the screen column `at`, a tab reaches the next tab stop.
+    fn char_width(&self, ch: char, at: usize) -> usize {
+        if ch == '\t' { self.tab_width() - at % self.tab_width() }
+        else { UnicodeWidthChar::width(ch).unwrap_or(1) }
+    }
+
+    /// Screen columns of the first `col` chars of the line.
+    fn visual_col(&self, rrow: usize, col: usize) -> usize {
+        match self.code.text.get_line(rrow) {
+            Some(line) => line.chars().take(col).fold(0, |used, ch| used + self.char_width(ch, used)),
+            None => 0,
+        }
+    }
+
+    /// Leading columns of the wrapped rows of a line after the first one,
+    /// at most half of the text width.
+    fn wrap_indent(&self, rrow: usize) -> usize {
+        let indent = match self.config.wrap_keep_indent.unwrap_or(true) {
+            true => self.code.text.line(rrow).chars().take_while(|c| *c == ' ' || *c == '\t')
+                .fold(0, |used, ch| used + self.char_width(ch, used)),
+            false => 0,
+        };
+        (indent + self.config.wrap_indent.unwrap_or(2)).min(self.text_width() / 2)
+    }
+
+    /// Char ranges of the screen rows of a line, the whole line if wrap is off.
+    fn line_segments(&self, rrow: usize) -> Vec<(usize, usize)> {
+        let len = self.code.line_len(rrow);
+        if !self.wrap { return vec![(0, usize::MAX)]; }
+
+        let mut width = self.text_width();
+        let continuation_width = width - self.wrap_indent(rrow);
+        let mut segments = vec![];
+        let (mut from, mut used) = (0, 0);
+
+        for (col, ch) in self.code.text.line(rrow).chars().take(len).enumerate() {
+            let w = self.char_width(ch, used);
+            if used + w > width && col > from {
+                segments.push((from, col));
+                from = col;
+                used = 0;
+                width = continuation_width;
+            }
+            used += w;
+        }
+        segments.push((from, len));
+        segments
+    }
+
+    /// (line, from col, to col) of the screen rows starting from the top line.
+    fn visual_rows(&self) -> Vec<(usize, usize, usize)> {
+        let mut rows = vec![];
+        for rrow in self.y..self.code.len_lines() {
+            for (from, to) in self.line_segments(rrow) {
+                if rows.len() == self.text_rows() { return rows; }
+                rows.push((rrow, from, to));
+            }
+        }
+        rows
+    }
+
+    /// Screen row and text column of the cursor, None if it is out of the screen.
+    fn visual_cursor(&self) -> Option<(usize, usize)> {
+        if self.r < self.y { return None; }
+        let rows = self.visual_rows();
+
+        // the last row of the line holds the cursor at the line end
+        let row = rows.iter().position(|&(rrow, from, to)| {
+            rrow == self.r && from <= self.c && (self.c < to || self.c == to && to >= self.code.line_len(rrow))
+        })?;
+        let (_, from, _) = rows[row];
+
+        let indent = if from > 0 { self.wrap_indent(self.r) } else { 0 };
+        let col = self.code.text.line(self.r).chars().skip(from).take(self.c - from)
+            .fold(0, |used, ch| used + self.char_width(ch, used));
+        Some((row, indent + col))
+    }
+
+    /// Cursor position for a click on the screen row and the text column.
+    fn visual_to_position(&self, row: usize, column: usize) -> Option<(usize, usize)> {
+        let rows = self.visual_rows();
+        let &(rrow, from, to) = rows.get(row)?;
+        let line_len = self.code.line_len(rrow);
+        let column = if from > 0 { column.saturating_sub(self.wrap_indent(rrow)) } else { column };
+
+        let mut used = 0;
+        let mut c = from;
+        for ch in self.code.text.line(rrow).chars().skip(from).take(to.min(line_len) - from) {
+            let w = self.char_width(ch, used);
+            if used + w > column { break; }
+            used += w;
+            c += 1;
+        }
+        // a click past a wrapped row end stays on that row
+        if c == to && to < line_len { c = to - 1; }
+        Some((rrow, c))
+    }
+
+    fn draw_status(&mut self) {
+        let status = self.status_line();
+        let x = self.width.saturating_sub(Self::columns(&status));
+        let y = self.height.saturating_sub(1);
+
+        queue!(
+            stdout(),
+            cursor::Hide,
+            cursor::MoveTo(x as u16, y as u16),
+            FColor(self.scolor),
+            Print(status)
+        )
+        .expect("Can not print status");
+
+        if let Some(message) = &self.message {
+            let len = message.chars().count() + 1;
+            if self.lp_width + len < x {
+                queue!(stdout(),
+                    cursor::MoveTo((x - len) as u16, y as u16),
+                    BColor(Color::Reset), FColor(self.scolor), Print(message)
+                ).expect("Can not print message");
+            }
+        }
+
+        stdout().flush().expect("flush");
+    }
+
+    fn draw_logo(&mut self) {
+        let logo = r#"🅡 🅔 🅓"#;
+        // let logo = "RED";
+
+        let lines:Vec<&str> = logo.split("\n").collect();
+        let logo_width = lines.get(0).unwrap().len();
+
+        let fromy = self.height / 2 - lines.len() / 2;
+        let fromx = self.lp_width + (self.width - self.lp_width)/ 2;
+
+        let mut stdout = stdout();
+
+        for r in 0..self.height{
+            queue!(stdout,
+                cursor::MoveTo(self.lp_width as u16, r as u16),
+                terminal::Clear(ClearType::UntilNewLine)
             );
         }
 
@@ -1154,31 +2732,35 @@ impl Editor {
         }
     }
 
+    /// Char column of the cursor line under the text screen column `mx`.
     fn find_cursor_x_position(&self, mx: usize) -> usize {
-        let mut count = 0;
-        let mut real_count = 0; // searching x position
-
-        let line = self.code.get_line_at(self.r);
-        if line.is_none() { return 0; }
-        let line = line.unwrap();
-
-        for ch in line.chars() {
-            if count >= mx + self.x { break; }
-            if ch == '\t' && self.x == 0 {
-                count += self.code.indent_width().unwrap_or(2);
-                real_count += 1;
-            } else {
-                count += 1;
-                real_count += 1;
-            }
-        }
+        let line = match self.code.get_line_at(self.r) {
+            Some(line) => line, None => return 0,
+        };
+        let target = self.visual_col(self.r, self.x) + mx;
 
-        real_count
+        let mut used = 0;
+        for (col, ch) in line.chars().enumerate() {
+            let w = self.char_width(ch, used);
+            if used + w > target { return col; }
+            used += w;
+        }
+        line.chars().count()
     }
 
 
+    /// Rows above the text, the breadcrumb row with `sticky_scroll`.
+    fn text_top(&self) -> usize {
+        self.config.sticky_scroll.unwrap_or(false) as usize
+    }
+    /// Screen rows showing text.
+    fn text_rows(&self) -> usize {
+        self.height.saturating_sub(self.text_top())
+    }
+
     fn cursor_is_focused(&mut self) -> bool {
-        (self.r >= self.y) && (self.r - self.y) < self.height
+        if self.wrap { return self.visual_cursor().is_some(); }
+        (self.r >= self.y) && (self.r - self.y) < self.text_rows()
     }
     fn cursor_is_invisible_at_bottom(&mut self) -> bool {
         self.r >= self.y && !self.cursor_is_focused()
@@ -1190,17 +2772,46 @@ impl Editor {
         self.c < self.x
     }
     fn cursor_is_invisible_at_right(&mut self) -> bool {
-        self.lp_width + self.ln_width + self.lns_width + self.c - self.x >= self.width
+        !self.wrap && self.cursor_x_pos() + self.minimap_width() >= self.text_area_width
     }
 
     fn focus_to_down(&mut self) {
-        self.y = self.r - self.height + 1
+        if !self.wrap {
+            self.y = (self.r + 1).saturating_sub(self.text_rows());
+            return;
+        }
+        // the highest top line that still shows the cursor row
+        let cursor_row = self.line_segments(self.r).iter()
+            .position(|(from, to)| *from <= self.c && self.c <= *to).unwrap_or(0);
+        let mut rows = cursor_row + 1;
+        self.y = self.r;
+        while self.y > 0 {
+            let above = self.line_segments(self.y - 1).len();
+            if rows + above > self.text_rows() { break; }
+            rows += above;
+            self.y -= 1;
+        }
     }
     fn focus_to_up(&mut self) {
         self.y = self.r
     }
     fn focus_to_right(&mut self) {
-        self.x = self.c - self.width + 1 + self.ln_width + self.lns_width + self.lp_width;
+        let text_width = self.text_width();
+        let line = match self.code.text.get_line(self.r) {
+            Some(line) => line, None => return,
+        };
+        // visual column before every char up to the cursor, one pass for long lines
+        let mut cols = Vec::with_capacity(self.c + 1);
+        let mut used = 0;
+        for ch in line.chars().take(self.c) {
+            cols.push(used);
+            used += self.char_width(ch, used);
+        }
+        cols.push(used);
+
+        while self.x < self.c && cols.get(self.x).map_or(false, |col| used - col > text_width) {
+            self.x += 1;
+        }
     }
     fn focus_to_left(&mut self) {
         self.x = self.c;
@@ -1251,6 +2862,27 @@ impl Editor {
         self.handle_movement();
     }
 
+    /// Smart home: start of the wrapped row, then first non-whitespace char, then column 0.
+    fn handle_home(&mut self) {
+        let indent = self.code.find_first_non_whitespace(self.r, usize::MAX).unwrap_or(0);
+        let row_start = self.line_segments(self.r).iter()
+            .rev().find(|(from, _)| *from <= self.c).map_or(0, |(from, _)| *from);
+
+        self.c = if row_start > indent && self.c > row_start { row_start }
+            else if self.c != indent { indent }
+            else { 0 };
+
+        if self.cursor_is_invisible_at_left() { self.focus_to_left(); self.upd = true; }
+        if self.cursor_is_invisible_at_right() { self.focus_to_right(); self.upd = true; }
+        self.handle_movement();
+    }
+
+    fn handle_end(&mut self) {
+        self.c = self.code.line_len(self.r);
+        if self.cursor_is_invisible_at_right() { self.focus_to_right(); self.upd = true; }
+        self.handle_movement();
+    }
+
     fn handle_right(&mut self) {
         if self.c < self.code.line_len(self.r) {
             self.c += 1;
@@ -1271,7 +2903,35 @@ impl Editor {
     }
 
     async fn handle_enter(&mut self) {
-        let ic = self.code.indentation_level(self.r);
+        let comment = match self.config.comment_continuation.unwrap_or(false) {
+            true => self.code.comment_prefix(self.r)
+                .filter(|(start, prefix)| self.c >= start + prefix.trim_end().chars().count()),
+            false => None,
+        };
+
+        // split is for `{|}`, the closing char goes to the next line
+        let (ic, split) = match comment {
+            Some(_) => (self.code.indentation_level(self.r), false),
+            None => self.code.enter_indent(self.r, self.c),
+        };
+
+        if let Some((start, prefix)) = &comment {
+            let line_len = self.code.line_len(self.r);
+            let is_empty = self.code.text.line(self.r).to_string().trim() == prefix.trim_end();
+
+            if is_empty { // empty comment line ends the comment
+                self.code.remove_text(self.r, *start, self.r, line_len);
+                if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                    lsp.lock().await.did_change(
+                        self.r, *start, self.r, line_len, &self.code.abs_path, ""
+                    ).await;
+                }
+                self.clean_diagnostics();
+                self.c = *start;
+                self.upd = true;
+                return;
+            }
+        }
 
         self.insert_char('\n').await;
 
@@ -1294,9 +2954,29 @@ impl Editor {
                 self.clean_diagnostics();
 
                 self.c = indentation.chars().count();
+
+                if split {
+                    let closing = format!("\n{}", indent_string.repeat(ic - 1));
+                    self.code.insert_text(&closing, self.r, self.c);
+                    if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                        lsp.lock().await.did_change(
+                            self.r, self.c, self.r, self.c, &self.code.abs_path, &closing
+                        ).await;
+                    }
+                }
             },
             None => {},
         }
+
+        if let Some((_, prefix)) = comment {
+            self.code.insert_text(&prefix, self.r, self.c);
+            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                lsp.lock().await.did_change(
+                    self.r, self.c, self.r, self.c, &self.code.abs_path, &prefix
+                ).await;
+            }
+            self.c += prefix.chars().count();
+        }
         self.handle_movement();
     }
 
@@ -1342,7 +3022,19 @@ impl Editor {
             if remove_all_indents == false { return }
         }
 
-        if self.c > 0 {
+        if self.c > 0 && self.config.auto_pairs.unwrap_or(false) && self.code.is_empty_pair(self.r, self.c) {
+            // remove both chars of an empty pair
+            self.code.remove_text(self.r, self.c - 1, self.r, self.c + 1);
+
+            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                lsp.lock().await.did_change(self.r, self.c-1, self.r, self.c+1, &self.code.abs_path, "").await;
+            }
+
+            self.c -= 1;
+            self.upd = true;
+            self.clean_diagnostics();
+
+        } else if self.c > 0 {
             // remove single char
             self.code.remove_char(self.r, self.c);
 
@@ -1377,9 +3069,14 @@ impl Editor {
     fn copy_to_clipboard(&mut self) {
         if self.selection.empty() { return; }
 
-        let (y, x) = self.selection.from();
-        let (yto, xto) = self.selection.to();
-        let text = self.code.get_text(y, x, yto, xto);
+        let text = match self.selection.block {
+            true => self.block_text(),
+            false => {
+                let (y, x) = self.selection.from();
+                let (yto, xto) = self.selection.to();
+                self.code.get_text(y, x, yto, xto)
+            }
+        };
 
         let mut ctx = ClipboardContext::new().unwrap();
         ctx.set_contents(text).unwrap();
@@ -1388,6 +3085,10 @@ impl Editor {
     }
 
     async fn paste_from_clipboard(&mut self) {
+        if self.selection.block && self.selection.non_empty_and_active() {
+            self.paste_into_block().await;
+            return;
+        }
         if self.selection.non_empty_and_active() {
             self.handle_cut().await;
         }
@@ -1396,9 +3097,16 @@ impl Editor {
         // let text = clipboard.get_text().unwrap_or_default();
 
 
-        let mut ctx = ClipboardContext::new().unwrap();
-        let text = ctx.get_contents().unwrap();
-        self.code.insert_text(&text, self.r, self.c);
+        let mut text = Self::clipboard_text();
+        if self.config.smart_paste.unwrap_or(false) {
+            text = self.code.reindent_paste(&text, self.r, self.c);
+        }
+        if text.len() > LARGE_PASTE {
+            self.code.insert_text_deferred(&text, self.r, self.c);
+            self.spawn_parse();
+        } else {
+            self.code.insert_text(&text, self.r, self.c);
+        }
 
         let path = &self.code.abs_path;
         let lang = &self.code.lang;
@@ -1422,6 +3130,12 @@ impl Editor {
         self.upd = true;
     }
 
+    /// Clipboard text with `\r\n` turned into `\n`, the line ending is added on save.
+    fn clipboard_text() -> String {
+        let mut ctx = ClipboardContext::new().unwrap();
+        ctx.get_contents().unwrap().replace("\r\n", "\n")
+    }
+
     fn selected_text(&mut self) -> String {
         let (y, x) = self.selection.from();
         let (yto, xto) = self.selection.to();
@@ -1429,6 +3143,225 @@ impl Editor {
         return text;
     }
 
+    /// Adds a cursor at the next occurrence of the selected word, needs a single line selection.
+    async fn add_next_occurrence(&mut self) {
+        let single_line = self.selection.non_empty_and_active() &&
+            self.selection.from().0 == self.selection.to().0;
+
+        if self.cursors.is_empty() && !single_line { return; }
+
+        if self.cursors.is_empty() {
+            let (y, x) = self.selection.from();
+            let (_, xto) = self.selection.to();
+            self.cursors_selection_len = xto - x;
+            self.r = y; self.c = xto;
+        }
+        if self.cursors_selection_len == 0 { return; }
+
+        let len = self.cursors_selection_len;
+        let pattern = self.code.get_text(self.r, self.c - len, self.r, self.c);
+
+        // next occurrence after the last added cursor, wrapping around the file
+        let (lr, lc) = *self.cursors.last().unwrap_or(&(self.r, self.c));
+        let ends: Vec<(usize, usize)> = self.code.search(&pattern, &SearchOptions::default()).unwrap_or_default().iter()
+            .map(|(line, position, _)| (*line, position + len))
+            .filter(|end| *end != (self.r, self.c) && !self.cursors.contains(end))
+            .collect();
+
+        let next = ends.iter().find(|end| **end > (lr, lc)).or(ends.first());
+        if let Some(next) = next {
+            self.cursors.push(*next);
+            self.selection.keep_once = true;
+        }
+        self.upd = true;
+    }
+
+    /// Adds a cursor at every occurrence of the selected text or of the word under the cursor,
+    /// the first `MAX_OCCURRENCE_CURSORS` of them.
+    fn add_all_occurrences(&mut self) {
+        let single_line = self.selection.non_empty_and_active() &&
+            self.selection.from().0 == self.selection.to().0;
+
+        let (y, x, xto) = if single_line {
+            (self.selection.from().0, self.selection.from().1, self.selection.to().1)
+        } else {
+            let line = self.code.text.line(self.r).to_string();
+            match utils::word_boundaries(&line, self.c, self.code.word_chars()) {
+                Some((x, xto)) if x < xto => (self.r, x, xto),
+                _ => return,
+            }
+        };
+
+        let len = xto - x;
+        let pattern = self.code.get_text(y, x, y, xto);
+        let ends: Vec<(usize, usize)> = self.code.search(&pattern, &SearchOptions::default()).unwrap_or_default().iter()
+            .map(|(line, position, _)| (*line, position + len))
+            .filter(|end| *end != (y, xto))
+            .collect();
+
+        if ends.len() > MAX_OCCURRENCE_CURSORS {
+            self.set_message(format!("cursors at the first {} of {} occurrences", MAX_OCCURRENCE_CURSORS, ends.len() + 1));
+        }
+
+        self.cursors = ends.into_iter().take(MAX_OCCURRENCE_CURSORS).collect();
+        self.cursors_selection_len = len;
+        self.r = y; self.c = xto;
+        self.selection.set_start(y, x);
+        self.selection.set_end(y, xto);
+        self.selection.active = false;
+        self.selection.keep_once = true;
+        self.upd = true;
+    }
+
+    /// Selected columns of every block row joined with newlines.
+    fn block_text(&mut self) -> String {
+        let (y, yto, x, xto) = self.selection.block_range();
+        (y..=yto.min(self.code.len_lines() - 1))
+            .map(|r| {
+                let len = self.code.line_len(r);
+                self.code.get_text(r, x.min(len), r, xto.min(len))
+            })
+            .collect::<Vec<String>>().join("\n")
+    }
+
+    /// Removes the block columns from every row as one undo group,
+    /// the cursor goes to the top left corner. Returns the block rows.
+    async fn remove_block(&mut self) -> Vec<usize> {
+        let (y, yto, x, xto) = self.selection.block_range();
+        let rows: Vec<usize> = (y..=yto.min(self.code.len_lines() - 1)).collect();
+
+        self.code.undo_group_start();
+        for &r in rows.iter() {
+            let len = self.code.line_len(r);
+            let (c, c1) = (x.min(len), xto.min(len));
+            if c == c1 { continue; }
+            self.code.remove_text(r, c, r, c1);
+
+            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                lsp.lock().await.did_change(r, c, r, c1, &self.code.abs_path, "").await;
+            }
+        }
+        self.code.undo_group_end();
+
+        self.r = y;
+        self.c = x.min(self.code.line_len(y));
+        self.clean_diagnostics();
+        self.upd = true;
+        rows
+    }
+
+    /// Clears the block and puts a cursor at its left column on every row long enough,
+    /// so typing goes to all rows.
+    async fn block_to_cursors(&mut self) {
+        let (_, _, x, _) = self.selection.block_range();
+        let rows = self.remove_block().await;
+        self.selection.clean();
+        self.selection.keep_once = false;
+
+        self.cursors = rows.into_iter()
+            .filter(|&r| r != self.r && self.code.line_len(r) >= x)
+            .map(|r| (r, x))
+            .collect();
+        self.cursors_selection_len = 0;
+    }
+
+    /// Pastes a line per block row if the clipboard has as many lines as the block,
+    /// otherwise pastes the text at the top left corner.
+    async fn paste_into_block(&mut self) {
+        let text = Self::clipboard_text();
+        let (_, _, x, _) = self.selection.block_range();
+        let rows = self.remove_block().await;
+        self.selection.clean();
+        self.selection.keep_once = false;
+
+        let lines: Vec<&str> = text.lines().collect();
+        if lines.len() != rows.len() {
+            self.code.insert_text(&text, self.r, self.c);
+            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                lsp.lock().await.did_change(self.r, self.c, self.r, self.c, &self.code.abs_path, &text).await;
+            }
+            for ch in text.chars() {
+                match ch {
+                    '\n' => { self.r += 1; self.c = 0; }
+                    _ => self.c += 1,
+                }
+            }
+            return;
+        }
+
+        self.code.undo_group_start();
+        for (&r, line) in rows.iter().zip(lines.iter()) {
+            // short rows are padded to the block column
+            let len = self.code.line_len(r);
+            let text = format!("{}{}", " ".repeat(x.saturating_sub(len)), line);
+            let c = x.min(len);
+            self.code.insert_text(&text, r, c);
+
+            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                lsp.lock().await.did_change(r, c, r, c, &self.code.abs_path, &text).await;
+            }
+        }
+        self.code.undo_group_end();
+
+        self.c = x + lines.first().map_or(0, |l| l.chars().count());
+        self.clean_diagnostics();
+        self.upd = true;
+    }
+
+    fn clear_cursors(&mut self) {
+        if self.cursors.is_empty() { return; }
+        self.cursors.clear();
+        self.cursors_selection_len = 0;
+        self.upd = true;
+    }
+
+    fn is_cursor_selected(&self, row: usize, col: usize) -> bool {
+        let len = self.cursors_selection_len;
+        len > 0 && self.cursors.iter().any(|&(r, c)| r == row && c - len <= col && col < c)
+    }
+
+    /// Replaces `back` chars before every cursor (or its selected occurrence) with text,
+    /// last cursor first, as one undo group.
+    async fn multi_cursor_edit(&mut self, text: &str, back: usize) {
+        let mut cursors = self.cursors.clone();
+        cursors.push((self.r, self.c));
+        cursors.sort();
+        cursors.dedup();
+
+        let back = if self.cursors_selection_len > 0 { self.cursors_selection_len } else { back };
+        let main = (self.r, self.c);
+
+        self.code.undo_group_start();
+        for &(r, c) in cursors.iter().rev() {
+            let from = c.saturating_sub(back);
+            if from == c && text.is_empty() { continue; }
+            self.code.replace_text(r, from, r, c, text);
+
+            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                lsp.lock().await.did_change(r, from, r, c, &self.code.abs_path, text).await;
+            }
+        }
+        self.code.undo_group_end();
+
+        // earlier edits on the same line shift the cursors after them
+        let inserted = text.chars().count();
+        let (mut line, mut shift) = (usize::MAX, 0isize);
+        let mut moved = vec![];
+        for &(r, c) in cursors.iter() {
+            if r != line { line = r; shift = 0; }
+            let from = c.saturating_sub(back);
+            let new_c = (from as isize + shift) as usize + inserted;
+            shift += inserted as isize - (c - from) as isize;
+            if (r, c) == main { self.c = new_c; } else { moved.push((r, new_c)); }
+        }
+
+        self.cursors = moved;
+        self.cursors_selection_len = 0;
+        self.selection.clean();
+        self.clean_diagnostics();
+        self.upd = true;
+    }
+
     async fn handle_duplicate(&mut self) {
         if self.selection.non_empty_and_active() {
             let text = self.selected_text();
@@ -1475,6 +3408,12 @@ impl Editor {
 
     async fn handle_cut(&mut self) {
         if self.selection.empty() { return; }
+        if self.selection.block {
+            self.remove_block().await;
+            self.selection.clean();
+            self.selection.keep_once = false;
+            return;
+        }
 
         let (y, x) = self.selection.from();
         let (yto, xto) = self.selection.to();
@@ -1516,6 +3455,9 @@ impl Editor {
     }
 
     fn handle_movement(&mut self) {
+        if self.line_numbers == LineNumbers::Relative || self.blame.is_some() {
+            self.upd = true; // numbers and blame depend on cursor row
+        }
         if self.cursor_is_focused() {
             // optimization
             self.draw_status(); // no need full update
@@ -1550,36 +3492,201 @@ impl Editor {
     }
 
     async fn insert_char(&mut self, c: char) {
-        if self.selection.non_empty_and_active() { self.handle_cut().await;}
+        let selected = self.selection.non_empty_and_active();
+        if selected { self.handle_cut().await;}
+
+        // overwrite replaces the char under the cursor, at the line end it inserts
+        let replace = self.overwrite && !selected && self.c < self.code.line_len(self.r);
+
+        let auto_pairs = self.config.auto_pairs.unwrap_or(false) && !replace;
+        let next = self.code.get_line_at(self.r).and_then(|line| line.chars().nth(self.c));
+        if auto_pairs && ")]}\"'".contains(c) && next == Some(c) {
+            self.c += 1; // type over the closing char
+            if c == ')' { self.check_signature_help(true); }
+            self.handle_movement();
+            return;
+        }
+        let pair = if auto_pairs { self.code.closing_pair(self.r, self.c, c) } else { None };
 
-        self.code.insert_char(c, self.r, self.c);
+        if replace {
+            self.code.undo_group_start();
+            self.code.remove_text(self.r, self.c, self.r, self.c + 1);
+            self.code.insert_char(c, self.r, self.c);
+            self.code.undo_group_end();
+        } else {
+            self.code.insert_char(c, self.r, self.c);
+        }
 
         let path = &self.code.abs_path;
         let lang = &self.code.lang;
+        let c1 = if replace { self.c + 1 } else { self.c };
 
         if let Some(lsp) = self.lang2lsp.get(lang) {
-            lsp.lock().await.did_change(self.r, self.c, self.r, self.c, path, &c.to_string()).await;
+            lsp.lock().await.did_change(self.r, self.c, self.r, c1, path, &c.to_string()).await;
+        }
+
+        if matches!(c, '}' | ')' | ']') && !replace {
+            self.dedent_closing().await;
+        }
+
+        if let Some(pair) = pair {
+            self.code.insert_char(pair, self.r, self.c + 1);
+            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                let (r, c1) = (self.r, self.c + 1);
+                lsp.lock().await.did_change(r, c1, r, c1, &self.code.abs_path, &pair.to_string()).await;
+            }
         }
 
         self.c += 1;
         self.upd = true;
         self.clean_diagnostics();
-    }
-
-    async fn insert_tab(&mut self) {
-        let (r,c) = (self.r, self.c);
-        let inserted = self.code.insert_tab(r,c);
 
-        self.c += inserted.chars().count();
+        match c {
+            '(' | ',' => self.signature_help().await,
+            ')' => self.check_signature_help(true),
+            _ => {}
+        }
 
-        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
-            lsp.lock().await.did_change(r,c, r,c, &self.code.abs_path, &inserted).await;
+        if c == '>' && auto_pairs {
+            if let Some(tag) = self.code.closing_tag_at(self.r, self.c) {
+                self.code.insert_text(&tag, self.r, self.c);
+                if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                    lsp.lock().await.did_change(self.r, self.c, self.r, self.c, &self.code.abs_path, &tag).await;
+                }
+            }
         }
-        self.upd = true;
-        self.clean_diagnostics();
     }
 
-    async fn comment_line(&mut self) {
+    /// Moves the closing char just typed at the cursor to the indentation of its opening line.
+    async fn dedent_closing(&mut self) {
+        let level = match self.code.closing_indent(self.r, self.c) {
+            Some(level) => level,
+            None => return,
+        };
+        let indentation = match self.code.indent_string() {
+            Some(indent_string) => indent_string.repeat(level),
+            None => return,
+        };
+        if indentation.chars().count() == self.c { return; }
+
+        self.code.replace_text(self.r, 0, self.r, self.c, &indentation);
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            lsp.lock().await.did_change(self.r, 0, self.r, self.c, &self.code.abs_path, &indentation).await;
+        }
+        self.c = indentation.chars().count();
+    }
+
+    async fn insert_tab(&mut self) {
+        let (r,c) = (self.r, self.c);
+        let inserted = self.code.insert_tab(r,c);
+
+        self.c += inserted.chars().count();
+
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            lsp.lock().await.did_change(r,c, r,c, &self.code.abs_path, &inserted).await;
+        }
+        self.upd = true;
+        self.clean_diagnostics();
+    }
+
+    /// Replaces the snippet trigger before the cursor with its template from config.
+    async fn expand_snippet(&mut self) -> bool {
+        let line = match self.code.line_at(self.r) {
+            Some(line) => line, None => return false,
+        };
+        let start = utils::find_prev_word(line, self.c, self.code.word_chars());
+        let trigger: String = line.chars().skip(start).take(self.c - start).collect();
+        let template = match self.config.snippets.as_ref()
+            .and_then(|s| s.get(&self.code.lang))
+            .and_then(|s| s.get(&trigger)) {
+            Some(template) => template.clone(),
+            None => return false,
+        };
+
+        // new lines keep the indentation of the line, tabs become its indent unit
+        let indent: String = line.chars().take_while(|c| c.is_whitespace()).collect();
+        let unit = self.code.indent_string().unwrap_or("\t".to_string());
+        let (text, stops) = utils::parse_snippet(&template);
+        let mut expanded = String::new();
+        let mut offsets = vec![]; // chars of the template text to chars of the expanded one
+        for ch in text.chars() {
+            offsets.push(expanded.chars().count());
+            match ch {
+                '\n' => { expanded.push('\n'); expanded.push_str(&indent); },
+                '\t' => expanded.push_str(&unit),
+                _ => expanded.push(ch),
+            }
+        }
+        offsets.push(expanded.chars().count());
+
+        let (r, c) = (self.r, self.c);
+        self.code.undo_group_start();
+        self.code.remove_text(r, start, r, c);
+        self.code.insert_text(&expanded, r, start);
+        self.code.undo_group_end();
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            lsp.lock().await.did_change(r, start, r, c, &self.code.abs_path, &expanded).await;
+        }
+
+        let at = self.code.text.line_to_char(r) + start;
+        self.snippet_stops = stops.iter().rev()
+            .map(|(offset, len)| (at + offsets[*offset], offsets[offset + len] - offsets[*offset]))
+            .collect();
+        self.snippet_current = (at, at, self.code.text.len_chars());
+        self.next_snippet_stop();
+        self.clean_diagnostics();
+        true
+    }
+
+    /// Drops the snippet stops once the cursor leaves the current one, e.g. after moving or editing elsewhere.
+    fn check_snippet_stops(&mut self) {
+        if self.snippet_stops.is_empty() { return; }
+        let (start, end, len_chars) = self.snippet_current;
+        let end = (end as isize + self.code.text.len_chars() as isize - len_chars as isize).max(0) as usize;
+        let at = self.code.text.try_line_to_char(self.r).map_or(usize::MAX, |i| i + self.c);
+        if at < start || at > end { self.snippet_stops.clear(); }
+    }
+
+    /// Moves to the next snippet stop and selects its default text, false when none are left.
+    fn next_snippet_stop(&mut self) -> bool {
+        let (at, len) = match self.snippet_stops.pop() {
+            Some(stop) => stop, None => return false,
+        };
+
+        // text typed at the previous stop moves the stops after it
+        let len_chars = self.code.text.len_chars();
+        let (_, current_end, current_len_chars) = self.snippet_current;
+        let delta = len_chars as isize - current_len_chars as isize;
+        let shift = |i: usize| match i >= current_end {
+            true => (i as isize + delta).max(0) as usize,
+            false => i,
+        };
+        for stop in self.snippet_stops.iter_mut() { stop.0 = shift(stop.0); }
+        let at = shift(at).min(len_chars);
+        let end = (at + len).min(len_chars);
+        self.snippet_current = (at, end, len_chars);
+
+        let row = self.code.text.char_to_line(at);
+        (self.r, self.c) = (row, at - self.code.text.line_to_char(row));
+        if end > at {
+            let row_end = self.code.text.char_to_line(end);
+            self.selection.set_start(self.r, self.c);
+            self.selection.set_end(row_end, end - self.code.text.line_to_char(row_end));
+            self.selection.active = true;
+            self.selection.keep_once = true;
+            (self.r, self.c) = (row_end, end - self.code.text.line_to_char(row_end));
+        }
+        self.handle_movement();
+        self.upd = true;
+        true
+    }
+
+    async fn comment_line(&mut self) {
+        if self.selection.non_empty_and_active() {
+            self.comment_selection().await;
+            return;
+        }
+
         let comment = self.code.get_lang_comment();
         if comment.is_none() { return; }
         let comment = comment.unwrap();
@@ -1623,58 +3730,320 @@ impl Editor {
         self.handle_down();
     }
 
-    fn save(&mut self) {
-        self.code.save_file().expect("Can not save file");
+    /// Toggles the comment on every selected line, the selection keeps the same lines.
+    async fn comment_selection(&mut self) {
+        let (from, _) = self.selection.from();
+        let (to, xto) = self.selection.to();
+        let to = if xto == 0 && to > from { to - 1 } else { to };
+
+        let replaced = self.code.toggle_comment_lines(from, to);
+        if replaced.is_empty() { return; }
+
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            let mut lsp = lsp.lock().await;
+            for (r, c, c1, text) in &replaced {
+                lsp.did_change(*r, *c, *r, *c1, &self.code.abs_path, text).await;
+            }
+        }
+
+        // columns after the edit move with the text
+        let shift = |row: usize, col: usize| match replaced.iter().find(|(r, ..)| *r == row) {
+            Some((_, c, c1, text)) if col > *c => (col - (col - c).min(c1 - c)) + text.chars().count(),
+            _ => col,
+        };
+        let (sy, sx) = (self.selection.start.y as usize, self.selection.start.x as usize);
+        let (ey, ex) = (self.selection.end.y as usize, self.selection.end.x as usize);
+        self.selection.set_start(sy, shift(sy, sx));
+        self.selection.set_end(ey, shift(ey, ex));
+        self.c = shift(self.r, self.c);
+
+        self.fit_cursor();
+        self.clean_diagnostics();
+        self.upd = true;
+    }
+
+    /// Aligns selected lines on a delimiter asked in the prompt.
+    async fn align_selection(&mut self) {
+        if !self.selection.non_empty_and_active() { return; }
+
+        let (from, _) = self.selection.from();
+        let (to, xto) = self.selection.to();
+        let to = if xto == 0 && to > from { to - 1 } else { to };
+
+        let delimiter = match self.prompt("align on: ", "=").await {
+            Some(delimiter) if !delimiter.is_empty() => delimiter,
+            _ => return,
+        };
+
+        let replaced = self.code.align_on(from, to, &delimiter);
+
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            let mut lsp = lsp.lock().await;
+            for (r, c, c1, spaces) in replaced {
+                lsp.did_change(r, c, r, c1, &self.code.abs_path, &spaces).await;
+            }
+        }
+
+        self.fit_cursor();
+        self.clean_diagnostics();
+        self.upd = true;
+    }
+
+    /// Puts a commented out copy of the selected lines above them, the live lines keep the cursor.
+    async fn duplicate_and_comment(&mut self) {
+        let comment = match self.code.get_lang_comment() {
+            Some(comment) => comment,
+            None => return,
+        };
+
+        let has_selection = self.selection.non_empty_and_active();
+        let (y, yto) = if has_selection {
+            let ((y, _), (yto, xto)) = (self.selection.from(), self.selection.to());
+            // selection ending at the line start does not include that line
+            (y, if xto == 0 && yto > y { yto - 1 } else { yto })
+        } else {
+            (self.r, self.r)
+        };
+
+        let mut text = String::new();
+        for r in y..=yto {
+            let line = self.code.text.line(r).to_string();
+            let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+            match self.code.find_first_non_whitespace(r, usize::MAX) {
+                Some(i) if !line.trim().is_empty() => {
+                    let indent: String = line.chars().take(i).collect();
+                    let rest: String = line.chars().skip(i).collect();
+                    text.push_str(&format!("{}{}{}", indent, comment, rest));
+                }
+                _ => text.push_str(line),
+            }
+            text.push('\n');
+        }
+
+        self.code.insert_text(&text, y, 0);
+
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            lsp.lock().await.did_change(y, 0, y, 0, &self.code.abs_path, &text).await;
+        }
+
+        let lines = yto - y + 1;
+        self.r += lines;
+        if has_selection {
+            let ((sy, sx), (ey, ex)) = (self.selection.from(), self.selection.to());
+            self.selection.set_start(sy + lines, sx);
+            self.selection.set_end(ey + lines, ex);
+        }
+
+        self.upd = true;
+        self.clean_diagnostics();
+    }
+
+    async fn save(&mut self) {
+        if self.code.is_scratch() {
+            self.save_as().await;
+            return;
+        }
+        if self.code.read_only {
+            self.set_message("read-only, the file is open in another red".to_string());
+            return;
+        }
+
+        if self.code.modified_on_disk() {
+            let question = format!("{} changed on disk, overwrite it? (y/n): ", self.code.file_name);
+            match self.prompt(&question, "").await.as_deref().map(str::trim) {
+                Some("y") | Some("Y") => {},
+                _ => { self.set_message("not saved".to_string()); return; },
+            }
+        }
+
+        if self.config.trim_eof_blank_lines.unwrap_or(false) {
+            if let Some((r, c, r1, c1)) = self.code.trim_eof_blank_lines() {
+                if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                    lsp.lock().await.did_change(r, c, r1, c1, &self.code.abs_path, "").await;
+                }
+                self.r = self.r.min(self.code.len_lines() - 1);
+                self.fit_cursor();
+                self.clean_diagnostics();
+            }
+        }
+
+        let trim = self.config.trim_trailing_whitespace.unwrap_or(false);
+        let final_newline = self.config.ensure_final_newline.unwrap_or(false);
+        if trim || final_newline {
+            self.code.undo_group_start();
+            let trimmed = if trim { self.code.trim_trailing_whitespace() } else { vec![] };
+            let added = if final_newline { self.code.ensure_final_newline() } else { None };
+            self.code.undo_group_end();
+
+            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                let mut lsp = lsp.lock().await;
+                for &(r, c, c1) in &trimmed {
+                    lsp.did_change(r, c, r, c1, &self.code.abs_path, "").await;
+                }
+                if let Some((r, c)) = added {
+                    lsp.did_change(r, c, r, c, &self.code.abs_path, "\n").await;
+                }
+            }
+
+            if !trimmed.is_empty() || added.is_some() {
+                self.fit_cursor();
+                self.clean_diagnostics();
+            }
+        }
+
+        if !self.create_dir().await { return; }
+        let mixed = !self.code.mixed_line_endings().is_empty();
+        if let Err(e) = self.code.save_file() {
+            self.set_message(format!("can not save {}: {}", self.code.file_name, e));
+            return;
+        }
+        if mixed {
+            self.set_message(format!("mixed line endings saved as {}", self.code.eol.name()));
+        }
+        self.save_cursor_position();
+        self.schedule_blame();
         self.upd = true;
     }
 
+    fn read_config_mtimes(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let mtime = |path: PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+        (mtime(config::path()), mtime(self.config.theme_path()))
+    }
+
+    /// Applies config.toml and the theme again, keeps the old config if they do not load.
+    /// Intervals of the start loop keep their values until restart.
+    fn reload_config(&mut self) {
+        let loaded = config::load().and_then(|config| Ok((Self::load_theme(&config)?, config)));
+        let (theme, config) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                self.set_message(format!("config not reloaded: {}", e));
+                return;
+            }
+        };
+
+        self.keymap = keys::keymap(config.keys.as_ref()).expect("keys are checked on config load");
+        self.tree_view.set_ignore(utils::Ignore::from_config(&config));
+        if self.zen.is_none() { // zen restores its own line numbers
+            self.line_numbers = LineNumbers::from_config(config.line_numbers.as_ref());
+            self.ln_width = self.line_numbers.width();
+        }
+        self.wrap = config.wrap.unwrap_or(false);
+        if self.lp_width > 0 && self.zen.is_none() {
+            self.lp_width = config.left_panel_width.unwrap_or(25);
+            self.tree_view.set_width(self.lp_width);
+        }
+        self.code.set_lang(self.code.lang.clone(), &config);
+        for code in self.codes.values_mut() {
+            code.set_lang(code.lang.clone(), &config);
+        }
+        self.config = config;
+        self.config_mtimes = self.read_config_mtimes(); // the theme may have changed
+        self.configure_theme_from(theme);
+        if self.zen.is_some() { self.zen_layout(); }
+        self.set_message("config reloaded".to_string());
+    }
+
+    /// Opens config.toml, or the theme file with `theme`, for editing.
+    async fn open_config(&mut self, theme: bool) {
+        let path = match theme {
+            true => self.config.theme_path(),
+            false => config::path(),
+        };
+        let path = utils::abs_file(&path.to_string_lossy());
+        if path == self.code.abs_path { return; }
+
+        self.cursor_history.push(CursorPosition{
+            filename: self.code.abs_path.clone(),
+            row: self.r, col: self.c, y: self.y, x: self.x,
+        });
+        self.cursor_history_undo.clear();
+        self.open_file(&path).await;
+        self.upd = true;
+    }
+
+    /// Creates the missing dirs of the file before saving, asks first unless `create_dirs` is auto.
+    /// False when the dir does not exist afterwards.
+    async fn create_dir(&mut self) -> bool {
+        let dir = match self.code.missing_dir() { Some(dir) => dir, None => return true };
+        let mode = self.config.create_dirs.as_deref().unwrap_or("ask");
+        if mode == "off" {
+            self.set_message(format!("can not save, no directory {}", dir.display()));
+            return false;
+        }
+        if mode != "auto" {
+            let question = format!("create directory {}? (y/n): ", dir.display());
+            match self.prompt(&question, "").await.as_deref().map(str::trim) {
+                Some("y") | Some("Y") => {},
+                _ => return false,
+            }
+        }
+        match self.code.create_dir() {
+            Ok(_) => true,
+            Err(e) => {
+                self.set_message(format!("can not create {}: {}", dir.display(), e));
+                false
+            }
+        }
+    }
+
+    /// Asks for a file path and saves the buffer there.
+    async fn save_as(&mut self) {
+        let path = match self.prompt("save as: ", "").await {
+            Some(path) if !path.trim().is_empty() => path.trim().to_string(),
+            _ => return,
+        };
+
+        let old_path = self.code.abs_path.clone();
+        let old_name = self.code.file_name.clone();
+        let old_changed = self.code.changed;
+        self.code.unlock();
+        self.code.set_path(&path);
+
+        let saved = match self.create_dir().await {
+            true => self.code.save_file(),
+            false => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no such directory")),
+        };
+        match saved {
+            Ok(_) => {
+                self.codes.remove(&old_path);
+                self.recent_buffers.retain(|p| *p != old_path);
+                self.recent_buffers.push_front(self.code.abs_path.clone());
+                self.tree_view.set_active(self.code.abs_path.clone());
+                self.set_message(format!("saved {}", self.code.abs_path));
+            },
+            Err(e) => {
+                self.code.abs_path = old_path;
+                self.code.file_name = old_name;
+                self.code.changed = old_changed;
+                self.set_message(format!("can not save {}: {}", path, e));
+                if self.code.read_only { return; } // the lock stays with the other red
+            },
+        }
+        let _ = self.code.lock();
+        self.upd = true;
+    }
+
+    fn undo_checkpoint_interval(&self) -> u64 {
+        self.config.undo_checkpoint_interval.unwrap_or(1000)
+    }
+
     async fn undo(&mut self) {
+        if self.undo_checkpoint_interval() > 0 {
+            self.code.undo_checkpoint(); // pending changes are undone as one group
+        }
         let maybe_change = self.code.undo();
         match maybe_change {
             Some(changes) => {
-
                 for change in changes.changes {
-
                     self.r = change.row;
                     self.c = change.column;
-                    let text = &change.text;
-
-                    match change.operation {
-                        crate::code::Operation::Insert => {
-                            let r = change.row;
-                            let c = change.column;
-                            let mut r_end = r;
-                            let mut c_end = c;
-
-                            for ch in text.chars() { match ch {
-                                '\n' => { r_end += 1; c_end = 0;}
-                                _ => c_end += 1,
-                            }}
-
-                            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
-                                lsp.lock().await.did_change(
-                                    r, c, r_end, c_end, &self.code.abs_path, ""
-                                ).await;
-                            }
-                        },
-                        crate::code::Operation::Remove => {
-                            let mut r = change.row;
-                            let mut c = change.column;
-
-                            for ch in text.chars() { match ch {
-                                '\n' => { r -= 1; c = 0;}
-                                _ => c -= 1,
-                            }}
-                            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
-                                lsp.lock().await.did_change(
-                                    r, c,  r, c, &self.code.abs_path, &change.text
-                                ).await;
-                            }
-                        }
-                        crate::code::Operation::Start => {},
-                        crate::code::Operation::End => {},
+                }
+                if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                    let mut lsp = lsp.lock().await;
+                    for (r, c, r1, c1, text) in changes.edits {
+                        lsp.did_change(r, c, r1, c1, &self.code.abs_path, &text).await;
                     }
-
                 }
             },
             None => {},
@@ -1695,38 +4064,36 @@ impl Editor {
             changed = true;
         }
         if self.search.pattern.len_chars() > 0 {
-            let search_results = self.code.search(&self.search.pattern.to_string());
-            let search_results: Vec<SearchResult> = search_results.iter()
-                .map(|(line, position)| SearchResult{ line:*line, position:*position })
-                .collect();
-            self.search.results = search_results;
+            self.refresh_search_results();
             self.search.index = 0;
             changed = true;
         }
 
         let mut x = self.search.pattern.len_chars();
+        let mut rx = self.search.replacement.len_chars();
 
         while !end {
 
             self.draw_search_line(x, self.height-1);
+            self.draw_replace_line(rx);
 
             if changed && self.search.pattern.len_chars() > 0 &&
                 !self.search.results.is_empty() {
 
                 let search_result = &self.search.results[self.search.index];
-                let sy = search_result.line;
-                let sx = search_result.position;
+                let (sy, sx, len) = (search_result.line, search_result.position, search_result.len);
                 self.r = sy;
-                self.c = sx + self.search.pattern.chars().count();
+                self.c = sx + len;
                 self.handle_movement();
                 if self.r - self.y == self.height-1 { self.y += 1; }  // if last line, scroll down
                 self.selection.active = true;
                 self.selection.set_start(sy, sx);
-                self.selection.set_end(sy, sx + self.search.pattern.chars().count());
+                self.selection.set_end(sy, sx + len);
 
                 self.upd = true;
                 self.draw().await;
                 self.draw_search_line(x, self.height-1);
+                self.draw_replace_line(rx);
 
                 changed = false;
             }
@@ -1748,11 +4115,60 @@ impl Editor {
                                             self.overlay_lines.clear();
                                             return;
                                         }
+                                        (KeyModifiers::CONTROL, KeyCode::Char('h')) => {
+                                            self.search.replacing = !self.search.replacing;
+                                            self.search.replacement_focused = self.search.replacing;
+                                            self.upd = true;
+                                            self.draw().await;
+                                            continue;
+                                        }
+                                        (KeyModifiers::CONTROL, KeyCode::Char('a')) if self.search.replacing => {
+                                            self.replace_all().await;
+                                            changed = true;
+                                            continue;
+                                        }
+                                        (KeyModifiers::ALT, KeyCode::Char(mode @ ('r' | 'c' | 'w'))) => {
+                                            self.clean_search_line();
+                                            self.toggle_search_option(mode);
+                                            if self.search.pattern.len_chars() > 0 {
+                                                self.refresh_search_results();
+                                                self.search.index = 0;
+                                            }
+                                            self.upd = true;
+                                            changed = true;
+                                            continue;
+                                        }
                                         _ => {}
                                     }
 
+                                    if self.search.replacement_focused {
+                                        match event.code {
+                                            KeyCode::Left if rx > 0 => rx -= 1,
+                                            KeyCode::Right if rx < self.search.replacement.len_chars() => rx += 1,
+                                            KeyCode::Backspace if rx > 0 => {
+                                                self.clean_replace_line();
+                                                rx -= 1;
+                                                self.search.replacement.remove(rx..rx+1);
+                                            },
+                                            KeyCode::Char(c) => {
+                                                self.search.replacement.insert_char(rx, c);
+                                                rx += 1;
+                                            },
+                                            _ => {}
+                                        }
+                                    }
+
                                     match event.code {
-                                        KeyCode::Up => {
+                                        KeyCode::Tab if self.search.replacing => {
+                                            self.search.replacement_focused = !self.search.replacement_focused;
+                                        },
+                                        KeyCode::Enter if self.search.replacing => {
+                                            self.replace_current().await;
+                                            changed = true;
+                                        },
+                                        KeyCode::Left | KeyCode::Right | KeyCode::Backspace | KeyCode::Char(_)
+                                            if self.search.replacement_focused => {},
+                                        KeyCode::Up if !self.search.results.is_empty() => {
                                             if self.search.index > 0 {
                                                 self.search.index -= 1;
                                             } else {
@@ -1760,7 +4176,7 @@ impl Editor {
                                             }
                                             changed = true;
                                         },
-                                        KeyCode::Down => {
+                                        KeyCode::Down if !self.search.results.is_empty() => {
                                             self.search.index += 1;
                                             if self.search.index >= self.search.results.len() {
                                                 self.search.index = 0;
@@ -1777,13 +4193,7 @@ impl Editor {
                                             self.search.pattern.remove(x..x+1);
 
                                             if self.search.pattern.len_chars() > 0 {
-
-                                                let search_results = self.code.search(&self.search.pattern.to_string());
-                                                let search_results: Vec<SearchResult> = search_results.iter()
-                                                    .map(|(line, position)| SearchResult{ line:*line, position:*position })
-                                                    .collect();
-
-                                                self.search.results = search_results;
+                                                self.refresh_search_results();
                                                 self.search.index = 0;
                                                 changed = true;
                                             }
@@ -1792,12 +4202,7 @@ impl Editor {
                                             self.clean_search_line();
                                             self.search.pattern.insert_char(x, c);
                                             x += 1;
-                                            let search_results = self.code.search(&self.search.pattern.to_string());
-                                            let search_results: Vec<SearchResult> = search_results.iter()
-                                                .map(|(line, position)| SearchResult{ line:*line, position:*position })
-                                                .collect();
-
-                                            self.search.results = search_results;
+                                            self.refresh_search_results();
                                             self.search.index = 0;
                                             changed = true;
                                             // debug!("search_results {:?}", search_results);
@@ -1821,17 +4226,142 @@ impl Editor {
                 }
             };
         }
+        self.search.replacing = false;
+        self.search.replacement_focused = false;
         self.upd = true;
         self.search.active = false;
     }
-    pub fn draw_search_line(&mut self, x:usize, y:usize) {
-        let prefix = "search: ";
-        let line = if !self.search.results.is_empty() && self.search.pattern.len_chars() > 0 {
-            let postfix = format!("{}/{}", self.search.index+1, self.search.results.len());
-            format!("{}{} {}", prefix, &self.search.pattern, postfix)
-        } else {
-            format!("{}{} ", prefix, &self.search.pattern)
+
+    /// Char range (row, col, row1, col1) of the search match.
+    fn search_match_range(&self, i: usize) -> Option<(usize, usize, usize, usize)> {
+        let result = self.search.results.get(i)?;
+        let from = self.code.text.line_to_char(result.line) + result.position;
+        let to = from + result.len;
+        if to > self.code.text.len_chars() { return None; }
+        let r1 = self.code.text.char_to_line(to);
+        Some((result.line, result.position, r1, to - self.code.text.line_to_char(r1)))
+    }
+
+    /// Replaces one match, not grouped for undo, positions of later matches become stale.
+    async fn replace_match(&mut self, i: usize) -> Option<(usize, usize)> {
+        let (r, c, r1, c1) = self.search_match_range(i)?;
+        let replacement = self.search.replacement.to_string();
+
+        self.code.replace_text(r, c, r1, c1, &replacement);
+
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            lsp.lock().await.did_change(r, c, r1, c1, &self.code.abs_path, &replacement).await;
+        }
+        Some((r, c))
+    }
+
+    /// Searches the buffer for the pattern again, an invalid regex leaves no results.
+    fn refresh_search_results(&mut self) {
+        let found = match self.search.pattern.len_chars() {
+            0 => Ok(vec![]),
+            _ => self.code.search(&self.search.pattern.to_string(), &self.search.options),
         };
+        self.search.error = found.as_ref().err().cloned();
+        self.search.results = found.unwrap_or_default().into_iter()
+            .map(|(line, position, len)| SearchResult{ line, position, len })
+            .collect();
+    }
+
+    /// Toggles a search mode by its key in the search prompt: r regex, c case-insensitive, w whole word.
+    fn toggle_search_option(&mut self, mode: char) {
+        let options = &mut self.search.options;
+        match mode {
+            'r' => options.regex = !options.regex,
+            'c' => options.case_insensitive = !options.case_insensitive,
+            'w' => options.whole_word = !options.whole_word,
+            _ => {},
+        }
+    }
+
+    /// Replaces the highlighted match, undone with a single undo, and moves to the next one.
+    async fn replace_current(&mut self) {
+        if self.search.pattern.len_chars() == 0 { return; }
+
+        self.code.undo_group_start();
+        let replaced = self.replace_match(self.search.index).await;
+        self.code.undo_group_end();
+
+        let (r, c) = match replaced {
+            Some(position) => position,
+            None => return,
+        };
+
+        self.refresh_search_results();
+        // next match after the inserted text
+        let c = c + self.search.replacement.len_chars();
+        self.search.index = self.search.results.iter()
+            .position(|m| (m.line, m.position) >= (r, c))
+            .unwrap_or(0);
+
+        self.selection.clean();
+        self.clean_diagnostics();
+        self.upd = true;
+    }
+
+    /// Replaces every match back to front as one undo group.
+    async fn replace_all(&mut self) {
+        if self.search.pattern.len_chars() == 0 || self.search.results.is_empty() { return; }
+
+        let count = self.search.results.len();
+
+        self.code.undo_group_start();
+        for i in (0..count).rev() {
+            self.replace_match(i).await;
+        }
+        self.code.undo_group_end();
+
+        self.refresh_search_results();
+        self.search.index = 0;
+        self.r = self.r.min(self.code.len_lines() - 1);
+        self.fit_cursor();
+        self.selection.clean();
+        self.clean_diagnostics();
+        self.set_message(format!("replaced {} matches", count));
+        self.upd = true;
+    }
+
+    fn draw_replace_line(&mut self, x: usize) {
+        if !self.search.replacing { return; }
+        let prefix = "replace: ";
+        let line = format!("{}{} ", prefix, &self.search.replacement);
+
+        queue!(stdout(),
+            cursor::MoveTo((self.lp_width + 1) as u16, (self.height-2) as u16),
+            BColor(Color::Reset), FColor(Color::Reset), Print(line),
+        ).unwrap();
+        if self.search.replacement_focused {
+            queue!(stdout(), cursor::MoveTo((self.lp_width + 1 + prefix.len() + x) as u16, (self.height-2) as u16)).unwrap();
+        }
+        stdout().flush().unwrap();
+    }
+
+    fn clean_replace_line(&mut self) {
+        let line = format!("replace: {} ", &self.search.replacement);
+        queue!(stdout(),
+            cursor::MoveTo((self.lp_width + 1) as u16, (self.height-2) as u16),
+            BColor(Color::Reset), Print(" ".repeat(line.chars().count())),
+        ).unwrap();
+    }
+    /// Prompt and text of the search line, with the modes and the match count or the regex error.
+    fn search_line(&self) -> (String, String) {
+        let prefix = format!("search{}: ", self.search.options.indicator());
+        let postfix = match &self.search.error {
+            Some(error) => format!("invalid regex, {}", error),
+            None if !self.search.results.is_empty() && self.search.pattern.len_chars() > 0 =>
+                format!("{}/{}", self.search.index+1, self.search.results.len()),
+            None => String::new(),
+        };
+        let line = format!("{}{} {}", prefix, &self.search.pattern, postfix);
+        (prefix, line)
+    }
+
+    pub fn draw_search_line(&mut self, x:usize, y:usize) {
+        let (prefix, line) = self.search_line();
 
         queue!(stdout(),
             cursor::MoveTo((self.lp_width + 1) as u16, (self.height-1) as u16),
@@ -1844,13 +4374,7 @@ impl Editor {
         stdout().flush();
     }
     pub fn clean_search_line(&mut self) {
-        let prefix = "search: ";
-        let line = if !self.search.results.is_empty() && self.search.pattern.len_chars() > 0 {
-            let postfix = format!("{}/{}", self.search.index+1, self.search.results.len());
-            format!("{}{} {}", prefix, &self.search.pattern, postfix)
-        } else {
-            format!("{}{}", prefix, &self.search.pattern)
-        };
+        let (_, line) = self.search_line();
 
         queue!(stdout(),
             cursor::MoveTo((self.lp_width + 1) as u16, (self.height-1) as u16),
@@ -1860,7 +4384,208 @@ impl Editor {
         stdout().flush();
     }
 
+    /// Cycles line numbers: absolute, relative, hidden.
+    fn toggle_line_numbers(&mut self) {
+        self.line_numbers = self.line_numbers.next();
+        self.ln_width = self.line_numbers.width();
+        self.upd = true;
+    }
+
+    fn toggle_minimap(&mut self) {
+        self.minimap = !self.minimap;
+        self.upd = true;
+    }
+
+    fn toggle_closing_context(&mut self) {
+        self.closing_context = !self.closing_context;
+        self.upd = true;
+    }
+
+    fn toggle_render_whitespace(&mut self) {
+        self.render_whitespace = !self.render_whitespace;
+        self.upd = true;
+    }
+
+    fn toggle_line_ending_warnings(&mut self) {
+        self.line_ending_warnings = !self.line_ending_warnings;
+        self.upd = true;
+    }
+
+    /// Fixes what the line ending warnings show: the BOM and the less common line endings.
+    async fn normalize_line_endings(&mut self) {
+        let bom = self.code.has_bom();
+        if !self.code.normalize_line_endings() {
+            self.set_message("line endings are consistent".to_string());
+            return;
+        }
+        if bom {
+            if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+                lsp.lock().await.did_change(0, 0, 0, 1, &self.code.abs_path, "").await;
+            }
+        }
+        self.fit_cursor();
+        self.clean_diagnostics();
+        self.upd = true;
+    }
+
+    /// Shows git blame of the cursor line, nothing outside a git repo.
+    fn toggle_blame(&mut self) {
+        self.blame = match self.blame {
+            Some(_) => None,
+            None => Some(self.load_blame()),
+        };
+        self.upd = true;
+    }
+
+    fn load_blame(&self) -> Vec<String> {
+        if self.code.abs_path.is_empty() { return vec![]; }
+        git::blame(&self.code.abs_path).unwrap_or_default()
+    }
+
+    /// Hides the tree and line numbers and centers the text column, toggles back to the previous layout.
+    fn toggle_zen(&mut self) {
+        match self.zen.take() {
+            Some((lp_width, line_numbers)) => {
+                self.lp_width = lp_width;
+                self.line_numbers = line_numbers;
+                self.ln_width = line_numbers.width();
+                self.lns_width = 5;
+                self.text_area_width = self.width;
+            }
+            None => {
+                self.zen = Some((self.lp_width, self.line_numbers));
+                self.lp_width = 0;
+                self.is_lp_focused = false;
+                self.line_numbers = LineNumbers::Hidden;
+                self.ln_width = 0;
+                self.zen_layout();
+            }
+        }
+        self.tree_view.set_width(self.lp_width);
+        self.tree_view.upd = true;
+        self.x = 0;
+        if self.cursor_is_invisible_at_right() { self.focus_to_right(); }
+        self.upd = true;
+    }
+
+    /// Pads both sides of the text column up to `zen_width`, the run button stays in the left padding.
+    fn zen_layout(&mut self) {
+        if self.zen.is_none() { return; }
+        let width = self.width;
+        let text = self.config.zen_width.unwrap_or(100).min(width.saturating_sub(10));
+        self.lns_width = ((width - text) / 2).max(5);
+        self.text_area_width = (self.lns_width + text).min(width);
+    }
+
+    /// Shows lines, words, chars and bytes of the selection or the whole buffer.
+    fn text_stats(&mut self) {
+        if self.code.file_name.is_empty() { return; }
+
+        let (from, to, what) = if self.selection.non_empty() {
+            let (y, x) = self.selection.from();
+            let (yto, xto) = self.selection.to();
+            (self.code.text.line_to_char(y) + x, self.code.text.line_to_char(yto) + xto, "selection")
+        } else {
+            (0, self.code.text.len_chars(), "file")
+        };
+
+        let (lines, words, chars, bytes) = self.code.text_stats(from, to);
+        self.set_message(format!(
+            "{}: {} lines, {} words, {} chars, {} bytes", what, lines, words, chars, bytes
+        ));
+    }
+
+    /// Reads a line of input on the status row, returns None if cancelled.
+    async fn prompt(&mut self, prefix: &str, initial: &str) -> Option<String> {
+        let mut input = ropey::Rope::from_str(initial);
+        let mut x = input.len_chars();
+        let y = self.height - 1;
+        let width = self.width - self.lp_width - 1;
+
+        loop {
+            let line = format!("{}{}", prefix, input);
+            queue!(stdout(),
+                cursor::MoveTo((self.lp_width + 1) as u16, y as u16),
+                BColor(Color::Reset), FColor(Color::Reset),
+                Print(format!("{:<width$}", line, width = width)),
+                cursor::MoveTo((self.lp_width + 1 + prefix.chars().count() + x) as u16, y as u16),
+                cursor::Show,
+            );
+            stdout().flush();
+
+            let mut reader = EventStream::new();
+            let mut event = reader.next().fuse();
+
+            select! {
+                maybe_event = event => {
+                    match maybe_event {
+                        Some(Ok(Event::Key(event))) => match event.code {
+                            KeyCode::Enter => break,
+                            KeyCode::Esc => { self.upd = true; return None; },
+                            KeyCode::Left if x > 0 => x -= 1,
+                            KeyCode::Right if x < input.len_chars() => x += 1,
+                            KeyCode::Backspace if x > 0 => {
+                                x -= 1;
+                                input.remove(x..x+1);
+                            },
+                            KeyCode::Char(c) => {
+                                input.insert_char(x, c);
+                                x += 1;
+                            },
+                            _ => {}
+                        },
+                        Some(Ok(_)) => {},
+                        Some(Err(e)) => {
+                            debug!("Error: {:?}\r", e);
+                            break;
+                        },
+                        None => break,
+                    }
+                }
+            };
+        }
+
+        self.upd = true;
+        Some(input.to_string())
+    }
+
+    /// Jumps to `line` or `line:col` entered in the prompt.
+    async fn goto_line(&mut self) {
+        if self.code.file_name.is_empty() { return; }
+
+        let position = format!("{}:{}", self.r + 1, self.c + 1);
+        let input = match self.prompt("goto: ", &position).await {
+            Some(input) => input,
+            None => return,
+        };
+
+        let mut parts = input.trim().splitn(2, ':');
+        let line = parts.next().and_then(|l| l.trim().parse::<usize>().ok());
+        let col = parts.next().and_then(|c| c.trim().parse::<usize>().ok());
+
+        let line = match line {
+            Some(line) => line,
+            None => return,
+        };
+
+        self.cursor_history.push(CursorPosition{
+            filename: self.code.abs_path.clone(),
+            row: self.r, col: self.c, y: self.y, x: self.x,
+        });
+        self.cursor_history_undo.clear();
+
+        self.r = line.saturating_sub(1).min(self.code.len_lines() - 1);
+        self.c = col.unwrap_or(1).saturating_sub(1);
+        self.fit_cursor();
+        self.selection.clean();
+
+        self.focus_to_center();
+        self.handle_movement();
+        self.upd = true;
+    }
+
     pub fn init_new_lsp(&mut self) {
+        if self.code.is_scratch() { return; }
         let lang = self.code.lang.clone();
         if self.lang2lsp.contains_key(&lang) { return; }
 
@@ -1881,17 +4606,27 @@ impl Editor {
         if lsp_cmd.is_none() { return; }
 
         let lsp_cmd = lsp_cmd.unwrap();
+        let status_send = self.lsp_status_sender.clone();
+        let server = lsp::server_name(&lsp_cmd);
+        let status = {
+            let (lang, status_send) = (lang.clone(), status_send.clone());
+            move |status: String| {
+                if let Some(status_send) = status_send.as_ref() { let _ = status_send.send((lang.clone(), status)); }
+            }
+        };
+        status(format!("{}: starting", server));
 
-        tokio::task::spawn(async move {
+        utils::spawn("lsp start", async move {
             // lsp start, initialization
             let mut lsp = lsp.lock().await;
 
-            let result = lsp.start(&lang, &lsp_cmd, Some(diagnostic_send));
+            let result = lsp.start(&lang, &lsp_cmd, Some(diagnostic_send), status_send);
 
             match result {
                 Ok(_) => {},
                 Err(e) => {
                     debug!("error starting lsp process {}", e.to_string());
+                    status(format!("{}: failed to start", server));
                     return;
                 },
             }
@@ -1906,12 +4641,143 @@ impl Editor {
     pub fn clean_diagnostics(&mut self) {
         let uri = format!("file://{}", self.code.abs_path);
         self.diagnostics.clone().try_lock().unwrap().remove(&uri);
+        self.schedule_semantic_tokens(); // buffer changed
+    }
+
+    fn schedule_semantic_tokens(&mut self) {
+        self.semantic_pending = true;
+        self.schedule_idle();
+    }
+
+    /// Restarts the idle debounces, the highlight of the cursor word and lsp changes wait for them.
+    fn schedule_idle(&mut self) {
+        let delay = time::Duration::from_millis(self.config.idle_delay.unwrap_or(300));
+        self.idle_due = Some(tokio::time::Instant::now() + delay);
+        self.lsp_changes_due = Some(tokio::time::Instant::now() + LSP_CHANGES_DEBOUNCE);
+        let undo_interval = self.undo_checkpoint_interval();
+        if undo_interval > 0 {
+            self.undo_due = Some(tokio::time::Instant::now() + time::Duration::from_millis(undo_interval));
+        }
+        if self.cursor_word.take().is_some() { self.upd = true; }
+    }
+
+    /// Sends the edits the language servers collected as one didChange per file.
+    async fn flush_lsp_changes(&mut self) {
+        self.lsp_changes_due = None;
+        for lsp in self.lang2lsp.values() {
+            let mut lsp = lsp.lock().await;
+            if lsp.has_changes() { lsp.flush_changes(); }
+        }
+    }
+
+    /// Reloads blame of a newly active or saved file when idle, the old one is hidden till then.
+    fn schedule_blame(&mut self) {
+        if self.blame.is_none() { return; }
+        self.blame = Some(vec![]);
+        self.blame_pending = true;
+        self.schedule_idle();
+    }
+
+    /// Work put off until typing and moving pause: semantic tokens, blame, runnables and the cursor word.
+    async fn on_idle(&mut self) {
+        self.idle_due = None;
+        if self.code.refresh_runnables() { self.upd = true; }
+        if std::mem::take(&mut self.semantic_pending) {
+            self.request_semantic_tokens();
+        }
+        if std::mem::take(&mut self.blame_pending) && self.blame.is_some() {
+            self.blame = Some(self.load_blame());
+            self.upd = true;
+        }
+        let word = self.word_under_cursor();
+        if word != self.cursor_word {
+            self.cursor_word = word;
+            self.upd = true;
+        }
+        if self.upd { self.draw_throttled().await; }
+    }
+
+    /// Word the cursor is in or at the end of, when there is no selection or cursors.
+    fn word_under_cursor(&mut self) -> Option<String> {
+        if !self.config.highlight_word.unwrap_or(true) || self.code.file_name.is_empty() { return None; }
+        if self.selection.non_empty() || !self.cursors.is_empty() { return None; }
+        let line = self.code.text.get_line(self.r)?.to_string();
+        let (x, xto) = utils::word_boundaries(&line, self.c, self.code.word_chars())?;
+        let word: String = line.chars().skip(x).take(xto - x).collect();
+        match word.is_empty() {
+            false => Some(word),
+            true => None,
+        }
+    }
+
+    /// Requests semantic tokens in background, the result comes to the start loop.
+    fn request_semantic_tokens(&mut self) {
+        let lsp = match self.lang2lsp.get(&self.code.lang) {
+            Some(lsp) => lsp.clone(),
+            None => return,
+        };
+        let sender = match self.semantic_sender.as_ref() {
+            Some(sender) => sender.clone(),
+            None => return,
+        };
+        let path = self.code.abs_path.clone();
+
+        utils::spawn("semantic tokens", async move {
+            let tokens = lsp.lock().await.semantic_tokens_full(&path).await;
+            if let Some(tokens) = tokens {
+                sender.send((path, tokens)).await;
+            }
+        });
     }
 
-    pub async fn set_lsp_status(&mut self, status: &str) {
-        let lsp_status = self.lsp_status.clone();
-        let mut lsp_status = lsp_status.lock().await;
-        *lsp_status = status.to_string();
+    /// Parses the current buffer off the editor task if its parse was deferred.
+    fn spawn_parse(&mut self) {
+        let (version, parse) = match self.code.background_parse() {
+            Some(job) => job, None => return,
+        };
+        let sender = match self.parse_sender.as_ref() {
+            Some(sender) => sender.clone(),
+            None => return,
+        };
+        let path = self.code.abs_path.clone();
+
+        utils::spawn("background parse", async move {
+            if let Ok(Some(tree)) = tokio::task::spawn_blocking(parse).await {
+                let _ = sender.send((path, version, tree));
+            }
+        });
+    }
+
+    /// Takes a background parse, parses again if the buffer was edited meanwhile.
+    fn set_parsed_tree(&mut self, path: &str, version: usize, tree: tree_sitter::Tree) {
+        if path == self.code.abs_path {
+            if !self.code.set_tree(tree, version) { self.spawn_parse(); }
+            self.upd = true;
+        } else if let Some(code) = self.codes.get_mut(path) {
+            code.set_tree(tree, version); // parsed again when opened
+        }
+    }
+
+    /// Colors of semantic tokens for visible rows, in the same format as tree-sitter colors.
+    fn semantic_colors(&self, from: usize, to: usize) -> Vec<(usize, usize, usize, usize, Color)> {
+        let tokens = match self.semantic_tokens.get(&self.code.abs_path) {
+            Some(tokens) => tokens,
+            None => return vec![],
+        };
+
+        tokens.iter()
+            .filter(|t| from <= t.line && t.line < to && t.line < self.code.len_lines())
+            .filter_map(|t| {
+                let color = t.modifiers.iter()
+                    .find_map(|m| self.theme.get(&format!("semantic.{}.{}", t.token_type, m)))
+                    .or_else(|| self.theme.get(&format!("semantic.{}", t.token_type)))?;
+                // utf-16 columns, past the line end when stale after edits
+                let start = self.code.utf16_to_char(t.line, t.start)?;
+                let end = self.code.utf16_to_char(t.line, t.start + t.length)?;
+                let (start, end) = (self.code.byte_offset(t.line, start), self.code.byte_offset(t.line, end));
+                Some((start, end, t.line, t.line, utils::hex_to_color(color)))
+            })
+            .collect()
     }
 
 
@@ -1936,17 +4802,31 @@ impl Editor {
         score
     }
 
+    /// Drops the kinds turned off in config, sorts by `filterText` (or label) match
+    /// and keeps the best `completion_limit` items.
     fn sort_completion_items(&self, items: &mut Vec<CompletionItem>, prev_word: &str) {
+        let snippets = self.config.completion_snippets.unwrap_or(true);
+        let text = self.config.completion_text.unwrap_or(true);
+        items.retain(|item| match item.kind as u32 {
+            COMPLETION_KIND_SNIPPET => snippets,
+            COMPLETION_KIND_TEXT => text,
+            _ => true,
+        });
+
+        let filter = |item: &CompletionItem| item.filterText.clone().unwrap_or_else(|| item.label.clone());
         items.sort_by(|a, b| {
-            let sa = Self::score_matches(&a.label, prev_word);
-            let sb = Self::score_matches(&b.label, prev_word);
+            let (fa, fb) = (filter(a), filter(b));
+            let sa = Self::score_matches(&fa, prev_word);
+            let sb = Self::score_matches(&fb, prev_word);
             let r = sb.cmp(&sa);
             if r == Ordering::Equal {
-                a.label.len().cmp(&b.label.len())
+                fa.len().cmp(&fb.len())
             } else {
                 r
             }
         });
+
+        items.truncate(self.config.completion_limit.unwrap_or(100));
     }
     pub async fn lsp_completion(&mut self) {
         let mut end = false;
@@ -1968,8 +4848,6 @@ impl Editor {
 
             if completion_result.items.is_empty() { return; }
 
-            self.set_lsp_status("lsp completion").await;
-
             let (mut selected, mut selected_offset) = (0, 0);
             let (height, mut width) = (5, 30);
 
@@ -1977,11 +4855,12 @@ impl Editor {
                 Some(line) => line, None => return,
             };
 
-            let prev = utils::find_prev_word(line, self.c);
+            let prev = utils::find_prev_word(line, self.c, self.code.word_chars());
             let prev_word = line.chars().skip(prev).take(self.c - prev).collect::<String>();
 
             // Sort completion items
             self.sort_completion_items(&mut completion_result.items, &prev_word);
+            if completion_result.items.is_empty() { return; }
 
             let mut options = &completion_result.items;
 
@@ -2083,7 +4962,7 @@ impl Editor {
             queue!(stdout(),
                 cursor::MoveTo(
                     (self.c + self.lp_width + self.ln_width + self.lns_width - 2) as u16,
-                    (self.r - self.y + row + 1) as u16
+                    (self.r - self.y + self.text_top() + row + 1) as u16
                 ),
                 BColor(bgcolor), FColor(self.lncolor),
                 Print(label),
@@ -2099,29 +4978,48 @@ impl Editor {
     pub async fn lsp_completion_apply(&mut self, item: &lsp::lsp_messages::CompletionItem) {
         if item.textEdit.is_none() && item.label.is_empty() { return; }
 
-        let line = match self.code.line_at(self.r) {
-            Some(line) => line, None => return,
-        };
-
-        let prev = utils::find_prev_word(line, self.c);
-        let next = utils::find_next_word(line, self.c);
-
-        let insert_text = match item.textEdit.as_ref() {
-            Some(t) => &t.newText, None => &item.label,
+        // the server range if it gives one, otherwise the word around the cursor
+        let (r, c, r1, c1, insert_text) = match item.edit() {
+            Some((range, text)) => {
+                let r1 = (range.end.line as usize).min(self.code.len_lines() - 1);
+                let r = (range.start.line as usize).min(r1);
+                let c = (range.start.character as usize).min(self.code.line_len(r));
+                let c1 = (range.end.character as usize).min(self.code.line_len(r1));
+                (r, c, r1, c1, text.to_string())
+            },
+            None => {
+                let line = match self.code.line_at(self.r) {
+                    Some(line) => line, None => return,
+                };
+                let prev = utils::find_prev_word(line, self.c, self.code.word_chars());
+                let next = utils::find_next_word(line, self.c, self.code.word_chars());
+                let text = item.textEdit.as_ref().map_or(&item.label, |t| &t.newText);
+                (self.r, prev, self.r, next, text.clone())
+            },
         };
+        if r == r1 && c1 < c { return; }
 
-        self.code.remove_text(self.r, prev, self.r, next);
-        self.code.insert_text(insert_text, self.r, prev);
+        self.code.remove_text(r, c, r1, c1);
+        self.code.insert_text(&insert_text, r, c);
 
         let path = &self.code.abs_path;
         let lang = &self.code.lang;
 
         if let Some(lsp) = self.lang2lsp.get(lang) {
-            lsp.lock().await.did_change(self.r, prev, self.r, next,  &path, "").await;
-            lsp.lock().await.did_change(self.r, prev, self.r, prev, &path, insert_text).await;
+            lsp.lock().await.did_change(r, c, r1, c1, &path, "").await;
+            lsp.lock().await.did_change(r, c, r, c, &path, &insert_text).await;
         }
 
-        self.c = prev + insert_text.len();
+        match insert_text.rsplit_once('\n') {
+            Some((before, last)) => {
+                self.r = r + before.matches('\n').count() + 1;
+                self.c = last.chars().count();
+            },
+            None => {
+                self.r = r;
+                self.c = c + insert_text.chars().count();
+            },
+        }
         self.upd = true;
         self.clean_diagnostics();
     }
@@ -2236,33 +5134,567 @@ impl Editor {
                                     self.handle_movement();
                                     self.selection.clean();
 
-                                    self.upd = true; self.tree_view.upd = true;
-                                    self.overlay_lines.clear();
-                                    return;
-                                }
-                                if event == Event::Key(KeyCode::Down.into()) && selected < references.len() - 1 {
-                                    selected += 1;
-                                    self.upd = true;
-                                    self.tree_view.upd = true;
-                                }
-                                if event == Event::Key(KeyCode::Up.into()) && selected > 0 {
-                                    selected -= 1;
-                                    self.upd = true; self.tree_view.upd = true;
-                                }
-                                if event == Event::Key(KeyCode::Enter.into())
-                                || event == Event::Key(KeyCode::Tab.into()) {
-                                    self.apply_reference(reference).await;
-                                    self.overlay_lines.clear();
-                                    return;
-                                }
-                            }
-                            Some(Err(e)) => { debug!("Error: {:?}\r", e); self.overlay_lines.clear(); return; },
-                            None => break,
-                        }
-                    }
-                };
-            }
-        }
+                                    self.upd = true; self.tree_view.upd = true;
+                                    self.overlay_lines.clear();
+                                    return;
+                                }
+                                if event == Event::Key(KeyCode::Down.into()) && selected < references.len() - 1 {
+                                    selected += 1;
+                                    self.upd = true;
+                                    self.tree_view.upd = true;
+                                }
+                                if event == Event::Key(KeyCode::Up.into()) && selected > 0 {
+                                    selected -= 1;
+                                    self.upd = true; self.tree_view.upd = true;
+                                }
+                                if event == Event::Key(KeyCode::Enter.into())
+                                || event == Event::Key(KeyCode::Tab.into()) {
+                                    self.apply_reference(reference).await;
+                                    self.overlay_lines.clear();
+                                    return;
+                                }
+                            }
+                            Some(Err(e)) => { debug!("Error: {:?}\r", e); self.overlay_lines.clear(); return; },
+                            None => break,
+                        }
+                    }
+                };
+            }
+        }
+    }
+
+    /// Lists lsp symbols of the file, children indented, and jumps to the picked one.
+    async fn outline(&mut self) {
+        let symbols = match self.lang2lsp.get(&self.code.lang) {
+            Some(lsp) => lsp.lock().await.document_symbols(&self.code.abs_path).await,
+            None => return,
+        };
+        let symbols = match symbols {
+            Some(symbols) if !symbols.is_empty() => symbols,
+            _ => { self.set_message("no symbols".to_string()); return; }
+        };
+
+        let flat = lsp_messages::flatten_symbols(&symbols);
+        let options: Vec<String> = flat.iter().map(|(depth, symbol)| {
+            let line = symbol.start().map_or(0, |p| p.line as usize + 1);
+            format!("{}{} {} {}", "  ".repeat(*depth), symbol.name, symbol.kind_name(), line)
+        }).collect();
+
+        let start = match self.select_from_list("outline", &options).await {
+            Some(i) => flat[i].1.start().cloned(),
+            None => return,
+        };
+
+        if let Some(start) = start {
+            self.r = (start.line as usize).min(self.code.len_lines().saturating_sub(1));
+            self.c = start.character as usize;
+            self.fit_cursor();
+            self.selection.clean();
+            self.handle_movement();
+
+            self.cursor_history.push(CursorPosition{
+                filename: self.code.abs_path.clone(),
+                row: self.r, col: self.c, y: self.y, x: self.x,
+            });
+            self.cursor_history_undo.clear();
+        }
+    }
+
+    /// Lists recently edited lines of the file and jumps to the picked one.
+    async fn recent_lines(&mut self) {
+        let rows = self.code.recent_lines();
+        if rows.is_empty() {
+            self.set_message("no recent edits".to_string());
+            return;
+        }
+
+        let options: Vec<String> = rows.iter()
+            .map(|r| format!("{} {}", r + 1, self.code.text.line(*r).to_string().trim()))
+            .collect();
+        let row = match self.select_from_list("recent lines", &options).await {
+            Some(i) => rows[i],
+            None => return,
+        };
+
+        self.r = row;
+        self.c = self.code.text.line(row).chars().take_while(|c| *c == ' ' || *c == '\t').count();
+        self.selection.clean();
+        if !self.cursor_is_focused() { self.focus_to_center(); }
+
+        self.cursor_history.push(CursorPosition{
+            filename: self.code.abs_path.clone(),
+            row: self.r, col: self.c, y: self.y, x: self.x,
+        });
+        self.cursor_history_undo.clear();
+        self.upd = true;
+    }
+
+    /// Shows the saved file diff against git HEAD, `n`/`p` go to the next/previous hunk,
+    /// `Enter` jumps to the line.
+    async fn diff_head(&mut self) {
+        if self.code.is_scratch() { return; }
+        let lines = match git::diff(&self.code.abs_path) {
+            Some(lines) if !lines.is_empty() => lines,
+            Some(_) => { self.set_message("no changes against HEAD".to_string()); return; }
+            None => { self.set_message("not in a git repo".to_string()); return; }
+        };
+        self.diff_view(&lines, "diff HEAD").await;
+    }
+
+    /// Shows the diff lines titled in the status line until `Esc` or `Enter`.
+    async fn diff_view(&mut self, lines: &Vec<DiffLine>, title: &str) {
+        let height = self.height - 1;
+        let (mut selected, mut offset) = (0, 0);
+        let mut reader = EventStream::new();
+
+        loop {
+            if selected < offset { offset = selected }
+            if selected >= offset + height { offset = selected - height + 1 }
+            self.diff_draw(lines, title, height, selected, offset);
+
+            let event = match reader.next().await {
+                Some(Ok(Event::Key(event))) => event,
+                Some(Ok(_)) => continue,
+                _ => break,
+            };
+            match event.code {
+                KeyCode::Up if selected > 0 => selected -= 1,
+                KeyCode::Down if selected + 1 < lines.len() => selected += 1,
+                KeyCode::PageUp => selected = selected.saturating_sub(height),
+                KeyCode::PageDown => selected = (selected + height).min(lines.len() - 1),
+                KeyCode::Char('n') => {
+                    let next = lines.iter().skip(selected + 1).position(|l| l.kind == DiffKind::Hunk);
+                    if let Some(i) = next { selected += i + 1; }
+                }
+                KeyCode::Char('p') => {
+                    let prev = lines[..selected].iter().rposition(|l| l.kind == DiffKind::Hunk);
+                    if let Some(i) = prev { selected = i; }
+                }
+                KeyCode::Enter => {
+                    self.r = lines[selected].row.min(self.code.len_lines() - 1);
+                    self.c = 0;
+                    self.selection.clean();
+                    if !self.cursor_is_focused() { self.focus_to_center(); }
+
+                    self.cursor_history.push(CursorPosition{
+                        filename: self.code.abs_path.clone(),
+                        row: self.r, col: self.c, y: self.y, x: self.x,
+                    });
+                    self.cursor_history_undo.clear();
+                    break;
+                }
+                KeyCode::Esc => break,
+                _ => {}
+            }
+        }
+
+        self.upd = true;
+        self.tree_view.upd = true;
+    }
+
+    /// Hunk against HEAD at the cursor row, the buffer must be saved since git diffs the file.
+    fn hunk_at_cursor(&mut self) -> Option<git::Hunk> {
+        if self.code.is_scratch() { return None; }
+        if self.code.changed {
+            self.set_message("save the file first".to_string());
+            return None;
+        }
+        let hunks = match git::hunks(&self.code.abs_path) {
+            Some(hunks) => hunks,
+            None => { self.set_message("not in a git repo".to_string()); return None; }
+        };
+        let hunk = hunks.into_iter().find(|h| h.contains(self.r));
+        if hunk.is_none() { self.set_message("no changes at the cursor".to_string()); }
+        hunk
+    }
+
+    /// Restores the HEAD version of the hunk at the cursor.
+    async fn revert_hunk(&mut self) {
+        let hunk = match self.hunk_at_cursor() { Some(hunk) => hunk, None => return };
+
+        let mut old: String = hunk.old.iter().map(|line| format!("{}\n", line)).collect();
+        let (mut r, mut c) = (hunk.row, 0);
+        let (mut r1, mut c1) = (hunk.row + hunk.count, 0);
+
+        let last = self.code.len_lines() - 1;
+        if r1 > last { // no final newline
+            (r1, c1) = (last, self.code.line_len(last));
+            old.pop();
+        }
+        if r > last {
+            (r, c) = (last, self.code.line_len(last));
+            old.insert(0, '\n');
+        }
+
+        self.code.undo_group_start();
+        self.code.replace_text(r, c, r1, c1, &old);
+        self.code.undo_group_end();
+
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            lsp.lock().await.did_change(r, c, r1, c1, &self.code.abs_path, &old).await;
+        }
+
+        self.r = r.min(self.code.len_lines() - 1);
+        self.c = 0;
+        self.selection.clean();
+        self.clean_diagnostics();
+        self.upd = true;
+    }
+
+    /// Stages the hunk at the cursor with `git apply --cached`.
+    fn stage_hunk(&mut self) {
+        let hunk = match self.hunk_at_cursor() { Some(hunk) => hunk, None => return };
+
+        match git::stage(&self.code.abs_path, &hunk) {
+            Ok(_) => self.set_message("hunk staged".to_string()),
+            Err(e) => self.set_message(format!("can not stage hunk: {}", e)),
+        }
+    }
+
+    fn diff_draw(&mut self, lines: &Vec<DiffLine>, title: &str, height: usize, selected: usize, offset: usize) {
+        let x = self.lp_width;
+        let width = self.width - x;
+
+        for row in 0..height {
+            queue!(stdout(), cursor::MoveTo(x as u16, row as u16), BColor(Color::Reset));
+
+            let line = match lines.get(row + offset) {
+                Some(line) => line,
+                None => {
+                    queue!(stdout(), terminal::Clear(ClearType::UntilNewLine));
+                    continue;
+                }
+            };
+
+            let (prefix, color) = match line.kind {
+                DiffKind::Hunk => ("", self.lncolor),
+                DiffKind::Added => ("+", Color::Green),
+                DiffKind::Removed => ("-", Color::Red),
+                DiffKind::Context => (" ", Color::Reset),
+            };
+            let text: String = format!("{}{}", prefix, line.text.replace('\t', "    "))
+                .chars().take(width).collect();
+
+            if row + offset == selected { queue!(stdout(), BColor(self.selcolor)); }
+            queue!(stdout(),
+                FColor(color), Print(text), BColor(Color::Reset), FColor(Color::Reset),
+                terminal::Clear(ClearType::UntilNewLine),
+            );
+        }
+
+        let added = lines.iter().filter(|l| l.kind == DiffKind::Added).count();
+        let removed = lines.iter().filter(|l| l.kind == DiffKind::Removed).count();
+        let status = format!("{} {}/{} +{} -{}", title, selected + 1, lines.len(), added, removed);
+
+        queue!(stdout(),
+            cursor::MoveTo(x as u16, (self.height-1) as u16),
+            BColor(Color::Reset), FColor(Color::Reset), Print(status),
+            terminal::Clear(ClearType::UntilNewLine),
+        );
+
+        stdout().flush().expect("cant flush");
+    }
+
+    /// Picker over the options, typing filters them by fuzzy match.
+    /// Returns index of the picked option in `options`.
+    async fn select_from_list(&mut self, title: &str, options: &Vec<String>) -> Option<usize> {
+        if options.is_empty() { return None; }
+
+        let max_visible = 10;
+        let height = std::cmp::min(max_visible, options.len());
+        let fromy = self.height - height - 1;
+        let (mut selected, mut selected_offset) = (0, 0);
+        let mut query = String::new();
+        let mut filtered: Vec<usize> = (0..options.len().min(LIST_MATCHES)).collect();
+
+        self.overlay_lines.clear();
+        for i in fromy..=self.height { self.overlay_lines.insert(i); }
+        self.upd = true; self.tree_view.upd = true;
+
+        let mut reader = EventStream::new();
+
+        let result = loop {
+            if selected < selected_offset { selected_offset = selected } // calculate scrolling offsets
+            if selected >= selected_offset + height { selected_offset = selected - height + 1 }
+
+            self.draw().await;
+            let visible: Vec<String> = filtered.iter().map(|&i| options[i].clone()).collect();
+            self.list_draw(height, fromy, title, &visible, &query, selected, selected_offset);
+
+            let mut event = reader.next().fuse();
+
+            select! {
+                maybe_event = event => {
+                    match maybe_event {
+                        Some(Ok(Event::Key(event))) => match event.code {
+                            KeyCode::Esc => break None,
+                            KeyCode::Down if selected + 1 < filtered.len() => selected += 1,
+                            KeyCode::Up if selected > 0 => selected -= 1,
+                            KeyCode::Enter | KeyCode::Tab => match filtered.get(selected) {
+                                Some(&i) => break Some(i),
+                                None => {},
+                            },
+                            KeyCode::Char(c) => {
+                                query.push(c);
+                                filtered = Self::filter_options(options, &query);
+                                (selected, selected_offset) = (0, 0);
+                            }
+                            KeyCode::Backspace => {
+                                query.pop();
+                                filtered = Self::filter_options(options, &query);
+                                (selected, selected_offset) = (0, 0);
+                            }
+                            _ => {}
+                        },
+                        Some(Ok(_)) => {},
+                        Some(Err(e)) => { debug!("Error: {:?}\r", e); break None; },
+                        None => break None,
+                    }
+                }
+            };
+        };
+
+        self.overlay_lines.clear();
+        self.upd = true; self.tree_view.upd = true;
+        result
+    }
+
+    /// Indexes of the best `LIST_MATCHES` options matching the query, best scored first.
+    fn filter_options(options: &Vec<String>, query: &str) -> Vec<usize> {
+        let mut filtered: Vec<(i32, usize)> = (0..options.len())
+            .filter_map(|i| Some((utils::fuzzy_score(&options[i], query)?, i)))
+            .collect();
+        filtered.sort_by(|a, b| b.0.cmp(&a.0).then(options[a.1].len().cmp(&options[b.1].len())));
+        filtered.truncate(LIST_MATCHES);
+        filtered.into_iter().map(|(_, i)| i).collect()
+    }
+
+    fn list_draw(&mut self,
+        height: usize, fromy: usize, title: &str,
+        options: &Vec<String>, query: &str, selected: usize, offset: usize,
+    ) {
+        let x = self.lp_width + self.ln_width + self.lns_width - 2;
+        let limit = self.width - x - 2;
+        let width = options.iter().map(|o| o.chars().count()).max().unwrap_or(0).min(limit);
+
+        for row in 0..height {
+            queue!(stdout(), cursor::MoveTo(x as u16, (row + fromy) as u16));
+
+            let option = match options.get(row + offset) {
+                Some(option) => option,
+                None => { // filtered out, clean the row
+                    queue!(stdout(), BColor(Color::Reset), terminal::Clear(ClearType::UntilNewLine));
+                    continue;
+                }
+            };
+
+            let is_selected = selected == row + offset;
+            let bgcolor = if is_selected { Color::Grey } else { Color::Reset };
+            let matched = utils::fuzzy_match(option, query).unwrap_or_default();
+
+            queue!(stdout(), BColor(bgcolor), FColor(self.lncolor), Print(" "));
+            for (i, ch) in option.chars().take(width).enumerate() {
+                match matched.contains(&i) {
+                    true => queue!(stdout(),
+                        SetAttribute(Attribute::Underlined), Print(ch), SetAttribute(Attribute::NoUnderline)),
+                    false => queue!(stdout(), Print(ch)),
+                };
+            }
+            let padding = width + 1 - option.chars().count().min(width);
+            queue!(stdout(),
+                Print(" ".repeat(padding)), BColor(Color::Reset), FColor(Color::Reset),
+                terminal::Clear(ClearType::UntilNewLine),
+            );
+        }
+
+        let status = match query.is_empty() {
+            true => format!("{} {}/{}", title, selected + 1, options.len()),
+            false => format!("{} {}/{} {}", title, selected + 1, options.len(), query),
+        };
+
+        queue!(stdout(),
+            cursor::MoveTo(x as u16, (self.height-1) as u16),
+            BColor(Color::Reset), FColor(Color::Reset), Print(status),
+            terminal::Clear(ClearType::UntilNewLine),
+        );
+
+        stdout().flush().expect("cant flush");
+    }
+
+    /// Rereads the current file from disk with an encoding picked from the list.
+    async fn reopen_with_encoding(&mut self) {
+        if self.code.is_scratch() { return; }
+
+        let options: Vec<String> = utils::ENCODINGS.iter().map(|e| e.to_string()).collect();
+        let encoding = match self.select_from_list("reopen with encoding", &options).await {
+            Some(i) => options[i].clone(),
+            None => return,
+        };
+
+        let path = self.code.abs_path.clone();
+        let mut code = match Code::from_file_with_encoding(&path, &encoding, &self.config) {
+            Ok(code) => code,
+            Err(e) => {
+                self.set_message(e.to_string());
+                return;
+            }
+        };
+
+        let last = self.code.len_lines() - 1;
+        let last_len = self.code.line_len(last);
+
+        code.take_lock(&mut self.code);
+        self.code = code;
+        self.r = self.r.min(self.code.len_lines() - 1);
+        self.fit_cursor();
+        self.selection.clean();
+        self.set_message(format!("reopened as {}", encoding));
+
+        if let Some(lsp) = self.lang2lsp.get(&self.code.lang) {
+            let text = self.code.text.to_string();
+            lsp.lock().await.did_change(0, 0, last, last_len, &path, &text).await;
+        }
+        self.clean_diagnostics();
+        self.upd = true;
+    }
+
+    /// Opens the file path under the cursor, relative to the file, the project root
+    /// or the config `path` dirs, offers to create it if it does not exist.
+    async fn open_file_under_cursor(&mut self) {
+        let line = self.code.text.line(self.r).to_string();
+        let token = match utils::path_boundaries(&line, self.c) {
+            Some((start, end)) => line.chars().skip(start).take(end - start).collect::<String>(),
+            None => return,
+        };
+        let token = token.trim_end_matches('.');
+
+        let root = PathBuf::from(utils::current_dir());
+        let file_dir = Path::new(&self.code.abs_path).parent()
+            .map(|p| p.to_path_buf()).unwrap_or(root.clone());
+
+        let candidates: Vec<PathBuf> = if let Some(rest) = token.strip_prefix("~/") {
+            std::env::var("HOME").map(|home| vec![Path::new(&home).join(rest)]).unwrap_or_default()
+        } else if Path::new(token).is_absolute() {
+            vec![PathBuf::from(token)]
+        } else {
+            let mut dirs = vec![file_dir, root.clone()];
+            dirs.extend(self.config.path.iter().flatten().map(|d| root.join(d)));
+            dirs.iter().map(|d| d.join(token)).collect()
+        };
+
+        let path = match candidates.iter().find(|p| p.is_file()) {
+            Some(path) => path.clone(),
+            None => {
+                let path = match candidates.first() {
+                    Some(path) if !path.exists() => path.clone(),
+                    _ => return, // a directory
+                };
+                let question = format!("create {}? (y/n): ", path.display());
+                match self.prompt(&question, "").await.as_deref().map(str::trim) {
+                    Some("y") | Some("Y") => {},
+                    _ => return,
+                }
+                let created = path.parent().map_or(Ok(()), |dir| std::fs::create_dir_all(dir))
+                    .and_then(|_| std::fs::write(&path, ""));
+                if let Err(e) = created {
+                    self.set_message(format!("can not create {}: {}", path.display(), e));
+                    return;
+                }
+                let _ = self.tree_view.refresh();
+                path
+            }
+        };
+
+        let path = utils::abs_file(&path.to_string_lossy());
+        if path == self.code.abs_path { return; }
+
+        self.cursor_history.push(CursorPosition{
+            filename: self.code.abs_path.clone(),
+            row: self.r, col: self.c, y: self.y, x: self.x,
+        });
+        self.cursor_history_undo.clear();
+        self.tree_view.set_active(path.clone());
+        self.open_file(&path).await;
+        self.upd = true;
+    }
+
+    /// Picker over all project files, typing ranks them by fuzzy match.
+    async fn quick_open(&mut self) {
+        let dir = utils::current_dir();
+        let ignore = utils::Ignore::from_config(&self.config);
+        let options = match search::search::project_files(Path::new(&dir), &ignore, QUICK_OPEN_FILES) {
+            Ok(files) => files,
+            Err(e) => { debug!("cant read dir {}", e); return; }
+        };
+
+        let index = match self.select_from_list("open", &options).await {
+            Some(index) => index,
+            None => return,
+        };
+
+        let path = Path::new(&dir).join(&options[index]).to_string_lossy().to_string();
+        if path == self.code.abs_path { return; }
+
+        self.cursor_history.push(CursorPosition{
+            filename: self.code.abs_path.clone(),
+            row: self.r, col: self.c, y: self.y, x: self.x,
+        });
+        self.cursor_history_undo.clear();
+        self.open_file(&path).await;
+        self.tree_view.reveal(&path);
+        self.upd = true;
+        self.tree_view.upd = true;
+    }
+
+    /// Finds project files which names contain the word under cursor.
+    async fn open_file_by_word(&mut self) {
+        let line = self.code.text.line(self.r).to_string();
+        let word = match utils::word_boundaries(&line, self.c, self.code.word_chars()) {
+            Some((start, end)) => line.chars().skip(start).take(end - start).collect::<String>(),
+            None => return,
+        };
+        let word = word.to_lowercase();
+
+        let dir = utils::current_dir();
+        let ignore = utils::Ignore::from_config(&self.config);
+        let files = match search::search::read_directory_recursive(Path::new(&dir), &ignore) {
+            Ok(files) => files,
+            Err(e) => { debug!("cant read dir {}", e); return; }
+        };
+
+        let mut matches: Vec<(i32, String)> = files.iter()
+            .filter_map(|path| {
+                let name = path.file_name()?.to_string_lossy().to_lowercase();
+                if !name.contains(&word) { return None; }
+                let relative = path.strip_prefix(&dir).unwrap_or(path).to_string_lossy().to_string();
+                Some((Self::score_matches(&name, &word), relative))
+            })
+            .collect();
+
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.len().cmp(&b.1.len())));
+        let options: Vec<String> = matches.into_iter().map(|(_, path)| path).collect();
+
+        let index = match options.len() {
+            0 => { self.set_message(format!("no files matching {}", word)); return; },
+            1 => 0,
+            _ => match self.select_from_list("files", &options).await {
+                Some(index) => index,
+                None => return,
+            },
+        };
+
+        let path = Path::new(&dir).join(&options[index]).to_string_lossy().to_string();
+        if path == self.code.abs_path { return; }
+
+        self.cursor_history.push(CursorPosition{
+            filename: self.code.abs_path.clone(),
+            row: self.r, col: self.c, y: self.y, x: self.x,
+        });
+        self.cursor_history_undo.clear();
+        self.tree_view.set_active(path.clone());
+        self.open_file(&path).await;
+        self.upd = true;
+        self.tree_view.upd = true;
     }
 
     async fn apply_reference(&mut self, reference: &ReferencesResult) {
@@ -2324,6 +5756,262 @@ impl Editor {
         stdout().flush().expect("cant flush");
     }
 
+    /// Applies lsp text edits bottom-up as one undo group, keeps lsp in sync.
+    async fn apply_text_edits(&mut self, edits: Vec<TextEdit>) {
+        let lsp = self.lang2lsp.get(&self.code.lang).cloned();
+        Self::edit_code(&mut self.code, lsp, edits).await;
+
+        self.r = self.r.min(self.code.len_lines() - 1);
+        self.fit_cursor();
+        self.clean_diagnostics();
+        self.upd = true;
+    }
+
+    /// Orders edits with a range last first, edits at the same position in reverse
+    /// so their texts end up in the given order.
+    fn sort_edits_back_to_front(edits: Vec<TextEdit>) -> Vec<TextEdit> {
+        let range = |e: &TextEdit| e.range.clone().or(e.replace.clone());
+        let mut edits: Vec<(usize, TextEdit)> = edits.into_iter()
+            .filter(|e| range(e).is_some())
+            .enumerate()
+            .collect();
+        edits.sort_by(|(i, a), (j, b)| {
+            let (a, b) = (range(a).unwrap().start, range(b).unwrap().start);
+            (b.line, b.character).partial_cmp(&(a.line, a.character)).unwrap_or(Ordering::Equal)
+                .then(j.cmp(i))
+        });
+        edits.into_iter().map(|(_, edit)| edit).collect()
+    }
+
+    /// Applies edits to any buffer as one undo group, last edit first so
+    /// earlier positions stay valid.
+    async fn edit_code(code: &mut Code, lsp: Option<Arc<Mutex<Lsp>>>, edits: Vec<TextEdit>) {
+        let range = |e: &TextEdit| e.range.clone().or(e.replace.clone());
+        let edits = Self::sort_edits_back_to_front(edits);
+        if edits.is_empty() { return; }
+
+        code.undo_group_start();
+
+        for edit in edits {
+            let range = range(&edit).unwrap();
+            let last = code.len_lines() - 1;
+            let r = (range.start.line as usize).min(last);
+            let c = (range.start.character as usize).min(code.line_len(r));
+            let r1 = (range.end.line as usize).min(last);
+            let c1 = (range.end.character as usize).min(code.line_len(r1));
+
+            code.replace_text(r, c, r1, c1, &edit.newText);
+
+            if let Some(lsp) = &lsp {
+                lsp.lock().await.did_change(r, c, r1, c1, &code.abs_path, &edit.newText).await;
+            }
+        }
+
+        code.undo_group_end();
+    }
+
+    /// Renames the symbol under the cursor in every file the server reports.
+    async fn rename_symbol(&mut self) {
+        let lsp = match self.lang2lsp.get(&self.code.lang) {
+            Some(lsp) => lsp.clone(),
+            None => return,
+        };
+
+        let path = self.code.abs_path.clone();
+        let prepared = lsp.lock().await.prepare_rename(&path, self.r, self.c).await;
+
+        let line = self.code.text.line(self.r).to_string();
+        let word = match prepared {
+            PrepareRename::Invalid => {
+                self.set_message("nothing to rename here".to_string());
+                return;
+            },
+            PrepareRename::Range(_, Some(placeholder)) => placeholder,
+            PrepareRename::Range(range, None) if range.start.line == range.end.line => line.chars()
+                .skip(range.start.character as usize)
+                .take((range.end.character - range.start.character) as usize)
+                .collect::<String>(),
+            _ => match utils::word_boundaries(&line, self.c, self.code.word_chars()) {
+                Some((start, end)) => line.chars().skip(start).take(end - start).collect::<String>(),
+                None => return,
+            },
+        };
+
+        let new_name = match self.prompt("rename: ", &word).await {
+            Some(name) if !name.trim().is_empty() && name.trim() != word => name.trim().to_string(),
+            _ => return,
+        };
+
+        let changes = lsp.lock().await.rename(&path, self.r, self.c, &new_name).await;
+
+        let changes = match changes {
+            Some(changes) if !changes.is_empty() => changes,
+            _ => {
+                self.set_message(format!("can not rename {}", word));
+                return;
+            }
+        };
+
+        let files = changes.len();
+        self.apply_workspace_edit(changes).await;
+        self.set_message(format!("renamed {} to {} in {} files", word, new_name, files));
+    }
+
+    /// Applies edits by file uri, files not opened yet are loaded into buffers.
+    async fn apply_workspace_edit(&mut self, changes: HashMap<String, Vec<TextEdit>>) {
+        for (uri, edits) in changes {
+            let path = match uri.strip_prefix("file://") {
+                Some(path) => path.to_string(),
+                None => continue,
+            };
+
+            if path == self.code.abs_path {
+                self.apply_text_edits(edits).await;
+                continue;
+            }
+
+            if !self.codes.contains_key(&path) {
+                let code = match Code::from_file(&path, &self.config) {
+                    Ok(code) => code,
+                    Err(e) => { debug!("can not open {} {}", path, e); continue; }
+                };
+                if let Some(lsp) = self.lang2lsp.get(&code.lang) {
+                    lsp.lock().await.did_open(&code.lang, &path, &code.text.to_string());
+                }
+                self.codes.insert(path.clone(), code);
+            }
+
+            let code = self.codes.get_mut(&path).unwrap();
+            let lsp = self.lang2lsp.get(&code.lang).cloned();
+            Self::edit_code(code, lsp, edits).await;
+        }
+    }
+
+    /// Lists code actions for diagnostics on the cursor line and applies the picked one.
+    async fn code_actions(&mut self) {
+        let lsp = match self.lang2lsp.get(&self.code.lang) {
+            Some(lsp) => lsp.clone(),
+            None => return,
+        };
+
+        let uri = format!("file://{}", self.code.abs_path);
+        let diagnostics: Vec<Diagnostic> = match self.diagnostics.lock().await.get(&uri) {
+            Some(d) => d.diagnostics.iter()
+                .filter(|d| d.range.start.line as usize <= self.r && self.r <= d.range.end.line as usize)
+                .cloned().collect(),
+            None => vec![],
+        };
+
+        // the range of the diagnostics on the line, or the cursor without them
+        let (r, c, r1, c1) = match (diagnostics.first(), diagnostics.last()) {
+            (Some(first), Some(last)) => (
+                first.range.start.line as usize, first.range.start.character as usize,
+                last.range.end.line as usize, last.range.end.character as usize,
+            ),
+            _ => (self.r, self.c, self.r, self.c),
+        };
+
+        let path = self.code.abs_path.clone();
+        let actions = lsp.lock().await.code_action(&path, r, c, r1, c1, diagnostics).await;
+
+        let actions = match actions {
+            Some(actions) if !actions.is_empty() => actions,
+            _ => {
+                self.set_message("no code actions".to_string());
+                return;
+            }
+        };
+
+        let titles: Vec<String> = actions.iter().map(|a| a.title.clone()).collect();
+        let action = match self.select_from_list("code actions", &titles).await {
+            Some(i) => actions[i].clone(),
+            None => return,
+        };
+
+        let command = action.command();
+        if let Some(edit) = action.edit {
+            self.apply_workspace_edit(edit.text_edits()).await;
+        }
+        if let Some(command) = command {
+            lsp.lock().await.execute_command(command);
+        }
+        self.upd = true;
+    }
+
+    /// Formats selected lines, or the whole file if the server has no range formatting.
+    async fn format_selection(&mut self) {
+        if !self.selection.non_empty() {
+            self.format_document().await;
+            return;
+        }
+
+        let lsp = match self.lang2lsp.get(&self.code.lang) {
+            Some(lsp) => lsp.clone(),
+            None => return,
+        };
+
+        let (y, x) = self.selection.from();
+        let (yto, xto) = self.selection.to();
+        let tab_size = self.code.indent_width().unwrap_or(4);
+        let insert_spaces = self.code.indent_unit().map_or(true, |u| u == " ");
+        let path = self.code.abs_path.clone();
+
+        let edits = {
+            let mut lsp = lsp.lock().await;
+            if lsp.supports("documentRangeFormattingProvider") {
+                lsp.range_formatting(&path, y, x, yto, xto, tab_size, insert_spaces).await
+            } else {
+                lsp.formatting(&path, tab_size, insert_spaces).await
+            }
+        };
+
+        let edits = match edits {
+            Some(edits) if !edits.is_empty() => edits,
+            _ => return,
+        };
+
+        let lines_before = self.code.len_lines();
+        self.apply_text_edits(edits).await;
+
+        // keep the selection over the formatted lines
+        let yto = (yto + self.code.len_lines()).saturating_sub(lines_before).min(self.code.len_lines() - 1);
+        let xto = xto.min(self.code.line_len(yto));
+        self.selection.set_start(y, x.min(self.code.line_len(y)));
+        self.selection.set_end(yto, xto);
+        self.selection.activate();
+    }
+
+    /// Formats the whole file, the cursor stays at the same place of its line text.
+    async fn format_document(&mut self) {
+        let lsp = match self.lang2lsp.get(&self.code.lang) {
+            Some(lsp) => lsp.clone(),
+            None => return,
+        };
+
+        let tab_size = self.code.indent_width().unwrap_or(4);
+        let insert_spaces = self.code.indent_unit().map_or(true, |u| u == " ");
+        let path = self.code.abs_path.clone();
+
+        let edits = lsp.lock().await.formatting(&path, tab_size, insert_spaces).await;
+
+        let edits = match edits {
+            Some(edits) if !edits.is_empty() => edits,
+            Some(_) => { self.set_message("already formatted".to_string()); return; }
+            None => { self.set_message("can not format".to_string()); return; }
+        };
+
+        let indent = self.code.find_first_non_whitespace(self.r, usize::MAX).unwrap_or(0);
+        let offset = self.c.saturating_sub(indent);
+
+        self.apply_text_edits(edits).await;
+
+        let indent = self.code.find_first_non_whitespace(self.r, usize::MAX).unwrap_or(0);
+        self.c = indent + offset;
+        self.fit_cursor();
+        self.selection.clean();
+        self.handle_movement();
+    }
+
     pub async fn hover(&mut self) {
         let path = &self.code.abs_path;
         let lang = &self.code.lang;
@@ -2338,8 +6026,6 @@ impl Editor {
             None => return,
         };
 
-        self.set_lsp_status("lsp completion").await;
-
         let (mut end, mut selected, mut selected_offset) = (false, 0, 0);
         let (height, mut width) = (10, 30);
 
@@ -2371,6 +6057,14 @@ impl Editor {
                             {
                                 break
                             }
+                            if event == Event::Key(KeyEvent::new(KeyCode::Char('g'), KeyModifiers::CONTROL))
+                                || event == Event::Key(KeyCode::Char('g').into())
+                            {
+                                // hover to definition of the symbol
+                                self.upd = true;
+                                self.definition().await;
+                                break
+                            }
                         }
                         Some(Err(e)) => {
                             debug!("Error: {:?}\r", e)
@@ -2383,6 +6077,34 @@ impl Editor {
 
     }
 
+    /// Requests the signature of the call under the cursor for the popup.
+    async fn signature_help(&mut self) {
+        let open = match self.code.call_start(self.r, self.c) {
+            Some(open) => open,
+            None => return,
+        };
+
+        let help = match self.lang2lsp.get(&self.code.lang) {
+            Some(lsp) => lsp.lock().await.signature_help(&self.code.abs_path, self.r, self.c).await,
+            None => return,
+        };
+
+        self.signature = help.and_then(|h| h.active()).map(|(label, active)| (open, label, active));
+        self.upd = true;
+    }
+
+    /// Hides the signature popup on dismiss or when the cursor left the call.
+    fn check_signature_help(&mut self, dismiss: bool) {
+        let open = match &self.signature {
+            Some((open, _, _)) => *open,
+            None => return,
+        };
+        if dismiss || self.code.call_start(self.r, self.c) != Some(open) {
+            self.signature = None;
+            self.upd = true;
+        }
+    }
+
     pub fn hover_draw(&mut self,
         height: usize, width:usize,
         options: &Vec<String>,
@@ -2401,7 +6123,7 @@ impl Editor {
             queue!(stdout(),
                 cursor::MoveTo(
                     (self.c + self.lp_width + self.ln_width + self.lns_width - 2) as u16,
-                    (self.r - self.y + row + 1) as u16
+                    (self.r - self.y + self.text_top() + row + 1) as u16
                 ),
                 BColor(bgcolor), FColor(self.lncolor), Print(label),
             );
@@ -2547,79 +6269,114 @@ impl Editor {
         stdout().flush().expect("cant flush");
     }
 
+    /// Searches all files of the dir while the pattern is typed, the picker shows matches as
+    /// they are found and opens the selected one. A new pattern cancels the running search.
     pub async fn global_search(&mut self) {
-        if self.search.pattern.len_chars() == 0 { return; }
-
         let (r, c, initial_abs_path) = (self.r.clone(), self.c.clone(), self.code.abs_path.clone());
         let max_visible = 3;
         let mut changed = true;
         let (mut selected, mut selected_offset) = (0, 0);
-        let (mut height, mut width) = (max_visible, 30);
+        let width = 30;
         self.upd = true; self.tree_view.upd = true;
 
         self.overlay_lines.clear();
 
-        let path = Path::new("./");
+        let ignore = Arc::new(utils::Ignore::from_config(&self.config));
+        let (found_send, mut found_recv) = tokio::sync::mpsc::unbounded_channel::<(usize, FileSearchResult)>();
         let mut search_results:Vec<(String, search::search::SearchResult)> = Vec::new();
-
-        let start = Instant::now();
-        let search_resilts = search::search::search_in_directory(&path, &self.search.pattern.to_string());
-
-        let elapsed = start.elapsed().as_millis();
-
-        match search_resilts {
-            Ok(srs) => {
-                for sr in srs {
-                    for r in sr.search_results {
-                        search_results.push((sr.file_path.clone(), r));
-                    }
-                }
-            },
-            Err(_) => return,
-        }
-
-        if search_results.is_empty() { return; }
-
-        if search_results.len() < height { height = search_results.len() }
+        let (mut generation, mut cancel) = (0, Arc::new(AtomicBool::new(false)));
+        let mut start = Instant::now();
+        let mut elapsed = 0;
+        let mut deadline = Some(tokio::time::Instant::now()); // the pattern of the local search runs at once
 
         let mut reader = EventStream::new();
 
         loop {
-
+            let height = std::cmp::min(max_visible, search_results.len());
             if selected < selected_offset { selected_offset = selected } // calculate scrolling offsets
-            if selected >= selected_offset + height { selected_offset = selected - height + 1 }
+            if selected >= selected_offset + height { selected_offset = (selected + 1).saturating_sub(height) }
 
             if changed {
-                let search_result = search_results.get(selected).unwrap();
+                if let Some(search_result) = search_results.get(selected) {
+                    let (path, line, column, len) =
+                        (search_result.0.clone(), search_result.1.line, search_result.1.column, search_result.1.len);
+                    if path != self.code.abs_path {
+                        self.open_file(&path).await;
+                    }
 
-                if search_result.0 != self.code.abs_path {
-                    self.open_file(&search_result.0).await;
+                    self.r = line-1;
+                    self.c = column;
+                    self.handle_movement();
+                    self.focus_to_center();
+                    self.selection.set_start(line-1, column);
+                    self.selection.set_end(line-1, column + len);
+                    self.selection.activate();
                 }
 
-                self.r = search_result.1.line-1;
-                self.c = search_result.1.column;
-                self.handle_movement();
-                self.focus_to_center();
-                self.selection.set_start(search_result.1.line-1, search_result.1.column);
-                self.selection.set_end(search_result.1.line-1, search_result.1.column + self.search.pattern.chars().count());
-                self.selection.activate();
-
-                let fromy = self.height - std::cmp::min(max_visible, search_results.len());
+                let fromy = self.height - height;
+                self.overlay_lines.clear();
                 for i in fromy-1..=self.height { self.overlay_lines.insert(i); }
 
+                self.upd = true;
                 self.draw().await;
                 self.draw_search_result(height, width, fromy-1, &search_results, selected, selected_offset, elapsed);
-                self.draw_cursor();
+                queue!(stdout(), cursor::Show).expect("show cursor");
+                stdout().flush().expect("flush");
                 changed = false;
             }
 
+            let debounce = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => futures::future::pending().await,
+                }
+            };
+
             let mut event = reader.next().fuse();
 
             select! {
+                _ = debounce.fuse() => {
+                    deadline = None;
+                    cancel.store(true, atomic::Ordering::Relaxed);
+                    cancel = Arc::new(AtomicBool::new(false));
+                    generation += 1;
+                    search_results.clear();
+                    (selected, selected_offset) = (0, 0);
+                    start = Instant::now();
+                    elapsed = 0;
+                    changed = true;
+
+                    self.search.error = None;
+                    let pattern = self.search.pattern.to_string();
+                    if pattern.is_empty() { continue; }
+                    let matcher = match self.search.options.matcher(&pattern) {
+                        Ok(matcher) => matcher,
+                        Err(e) => { self.search.error = Some(e); continue; }
+                    };
+                    let (found_send, cancel, ignore) = (found_send.clone(), cancel.clone(), ignore.clone());
+                    tokio::task::spawn_blocking(move || {
+                        let _ = search::search::stream_search_in_directory(Path::new("./"), &matcher, &ignore,
+                            &cancel, |fsr| { let _ = found_send.send((generation, fsr)); });
+                    });
+                }
+                found = found_recv.recv().fuse() => {
+                    let mut found = found;
+                    while let Some((search_generation, fsr)) = found {
+                        if search_generation == generation {
+                            for sr in fsr.search_results {
+                                search_results.push((fsr.file_path.clone(), sr));
+                            }
+                            elapsed = start.elapsed().as_millis();
+                            changed = true;
+                        }
+                        found = found_recv.try_recv().ok(); // draw once for the results at hand
+                    }
+                }
                 maybe_event = event => {
                     match maybe_event {
                         Some(Ok(event)) => {
                             if event == Event::Key(KeyCode::Esc.into()) {
+                                cancel.store(true, atomic::Ordering::Relaxed);
                                 if self.code.abs_path != initial_abs_path {
                                     self.open_file(&initial_abs_path).await;
                                 }
@@ -2631,7 +6388,7 @@ impl Editor {
                                 self.tree_view.upd = true;
                                 return;
                             }
-                            if event == Event::Key(KeyCode::Down.into()) && selected < search_results.len() - 1 {
+                            if event == Event::Key(KeyCode::Down.into()) && selected + 1 < search_results.len() {
                                 changed = true;
                                 selected += 1;
                                 self.upd = true;
@@ -2652,8 +6409,32 @@ impl Editor {
                                 changed = true;
                             }
 
+                            if let Event::Key(KeyEvent { code, modifiers, kind: KeyEventKind::Press, .. }) = event {
+                                let typed = !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+                                match code {
+                                    KeyCode::Char(mode @ ('r' | 'c' | 'w')) if modifiers == KeyModifiers::ALT => {
+                                        self.toggle_search_option(mode);
+                                        deadline = Some(tokio::time::Instant::now());
+                                        changed = true;
+                                    }
+                                    KeyCode::Char(c) if typed => {
+                                        self.search.pattern.insert_char(self.search.pattern.len_chars(), c);
+                                    }
+                                    KeyCode::Backspace if self.search.pattern.len_chars() > 0 => {
+                                        let len = self.search.pattern.len_chars();
+                                        self.search.pattern.remove(len-1..len);
+                                    }
+                                    _ => {}
+                                }
+                                if matches!(code, KeyCode::Char(_) | KeyCode::Backspace) {
+                                    deadline = Some(tokio::time::Instant::now() + GLOBAL_SEARCH_DEBOUNCE);
+                                    changed = true;
+                                }
+                            }
+
                             if event == Event::Key(KeyCode::Enter.into())
                             || event == Event::Key(KeyCode::Tab.into()) {
+                                cancel.store(true, atomic::Ordering::Relaxed);
                                 self.upd = true;
                                 self.tree_view.upd = true;
                                 return;
@@ -2673,14 +6454,16 @@ impl Editor {
         selected: usize, offset: usize, elapsed: u128
     ) {
         let limit = self.width - self.lp_width - self.ln_width - self.lns_width - 1;
+        let results = options;
 
         let options: Vec<String> = options.iter().enumerate().map(|(i, (path, sr))| {
             let prefix = format!("{}/{} {}:{} ", i+1, options.len(), sr.line,  sr.column);
-            let path = path.chars().take(limit-prefix.len()).collect::<String>();
+            let path = path.chars().take(limit.saturating_sub(prefix.len())).collect::<String>();
             format!("{} {}", prefix, path)
         }).collect();
 
-        let width = options.iter().map(|o| o.len()).max().unwrap_or(width);
+        let width = options.iter().map(|o| o.chars().count()).max().unwrap_or(width).min(limit);
+        let preview_width = limit.saturating_sub(width + 3);
 
         for row in 0..options.len() {
             if row >= options.len() || row >= height { break; }
@@ -2690,22 +6473,29 @@ impl Editor {
             let bgcolor = if is_selected { Color::Grey } else { Color::Reset };
 
             let label = format!(" {:width$} ", option, width = width);
+            let (before, matched, after) = results[row + offset].1.preview_parts(preview_width);
 
             queue!(stdout(),
                 cursor::MoveTo((self.lp_width + self.ln_width + self.lns_width - 1) as u16, (row + fromy) as u16),
                 BColor(bgcolor), FColor(self.lncolor), Print(label),
+                FColor(Color::Reset), Print(" "), Print(before),
+                BColor(self.occolor), Print(matched), BColor(bgcolor), Print(after), Print(" "),
                 terminal::Clear(ClearType::UntilNewLine), BColor(Color::Reset), FColor(Color::Reset),
             );
         }
 
-        let status = format!(
-            "global search on '{}', elapsed {} ms",
-            &self.search.pattern,  elapsed
-        );
+        let prefix = format!("global search{}: {}", self.search.options.indicator(), &self.search.pattern);
+        let status = match &self.search.error {
+            Some(error) => format!(" invalid regex, {}", error),
+            None if options.is_empty() => String::new(),
+            None => format!(" {} found, elapsed {} ms", options.len(), elapsed),
+        };
 
         queue!(stdout(),
             cursor::MoveTo((self.lp_width + 1) as u16, (self.height-1) as u16),
-            BColor(Color::Reset), FColor(Color::Reset), Print(status),
+            BColor(Color::Reset), FColor(Color::Reset), Print(&prefix), FColor(self.lncolor), Print(status),
+            FColor(Color::Reset), terminal::Clear(ClearType::UntilNewLine),
+            cursor::MoveTo((self.lp_width + 1 + prefix.chars().count()) as u16, (self.height-1) as u16),
         );
 
 
@@ -2866,6 +6656,100 @@ impl Editor {
         }
     }
 
+    /// Moves the cursor to the other end of the selection, extending then continues from there.
+    fn swap_selection_ends(&mut self) {
+        if !self.selection.non_empty() { return; }
+
+        self.selection.swap_ends();
+        self.r = (self.selection.end.y as usize).min(self.code.len_lines().saturating_sub(1));
+        self.c = (self.selection.end.x as usize).min(self.code.line_len(self.r));
+        self.selection.active = true;
+        if self.cursor_is_invisible_at_bottom() { self.focus_to_down(); }
+        if self.cursor_is_invisible_at_top() { self.focus_to_up(); }
+        self.upd = true;
+    }
+
+    /// Extends the selection to the next occurrence of the selected text or of the word
+    /// under the cursor, repeating adds the one after it.
+    fn select_next_occurrence(&mut self) {
+        let (from, to) = (self.selection.from(), self.selection.to());
+        let extending = self.selection.non_empty() &&
+            self.occurrence_origin.map_or(false, |(start, end)| start == from && end <= to);
+
+        if !extending {
+            let single_line = self.selection.non_empty() && from.0 == to.0;
+            self.occurrence_origin = if single_line { Some((from, to)) } else {
+                let line = self.code.text.line(self.r).to_string();
+                match utils::word_boundaries(&line, self.c, self.code.word_chars()) {
+                    Some((x, xto)) if x < xto => Some(((self.r, x), (self.r, xto))),
+                    _ => None,
+                }
+            };
+        }
+        let ((y, x), (yto, xto)) = match self.occurrence_origin {
+            Some(origin) => origin,
+            None => return,
+        };
+        let end = if extending { to } else { (yto, xto) };
+
+        let pattern = self.code.get_text(y, x, yto, xto);
+        let next = self.code.search(&pattern, &SearchOptions::default()).unwrap_or_default().into_iter()
+            .map(|(line, position, len)| ((line, position), (line, position + len)))
+            .find(|(start, _)| *start >= end);
+
+        let (r, c) = match next {
+            Some((_, next_end)) => next_end,
+            None if extending => { self.set_message("no more occurrences".to_string()); return; },
+            None => (yto, xto),
+        };
+        self.selection.set_start(y, x);
+        self.selection.set_end(r, c);
+        self.selection.active = true;
+        self.selection.block = false;
+        self.r = r; self.c = c;
+        if self.cursor_is_invisible_at_bottom() { self.focus_to_down(); }
+        if self.cursor_is_invisible_at_top() { self.focus_to_up(); }
+        self.upd = true;
+    }
+
+    /// Selects the text the occurrence selection started from again.
+    fn restore_occurrence_origin(&mut self) -> bool {
+        let (from, to) = (self.selection.from(), self.selection.to());
+        let ((y, x), (yto, xto)) = match self.occurrence_origin.take() {
+            Some((start, end)) if self.selection.non_empty() && start == from && end < to => (start, end),
+            _ => return false,
+        };
+        self.selection.set_start(y, x);
+        self.selection.set_end(yto, xto);
+        self.selection.active = true;
+        self.r = yto; self.c = xto;
+        if !self.cursor_is_focused() { self.focus_to_center(); }
+        self.upd = true;
+        true
+    }
+
+    /// Selects the enclosing function, repeating selects the outer one.
+    fn select_function(&mut self) {
+        let (from, to) = (self.selection.from(), self.selection.to());
+        let selected = self.selection.active && from != to;
+
+        let ranges = self.code.function_ranges_at(self.r, self.c);
+        let range = ranges.into_iter().find(|(start, end)| {
+            let range = ((start.row, start.column), (end.row, end.column));
+            !selected || range.0 <= from && range.1 >= to && range != (from, to)
+        });
+
+        match range {
+            Some((start, end)) => {
+                self.selection.set_start(start.row, start.column);
+                self.selection.set_end(end.row, end.column);
+                self.selection.active = true;
+                self.upd = true;
+            }
+            None => self.set_message("no enclosing function".to_string()),
+        }
+    }
+
     fn select_less(&mut self) {
         let prev = match self.node_path.as_mut() {
             Some(node_path) => {
@@ -2903,8 +6787,130 @@ impl Editor {
 
 }
 
-impl Drop for Editor {
+/// Restores the terminal when dropped, held by the editor from `init`.
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
     fn drop(&mut self) {
-        Self::deinit()
+        Editor::deinit()
+    }
+}
+
+#[cfg(test)]
+mod editor_occurrence_tests {
+    use crate::code::Code;
+    use super::Editor;
+
+    fn editor(text: &str) -> Editor {
+        let mut editor = Editor::new(".".to_string(), crate::config::get());
+        editor.code = Code::from_str(text);
+        editor.resize(80, 20);
+        editor
+    }
+
+    #[test]
+    fn test_select_next_occurrence() {
+        let mut editor = editor("foo bar\nfoo baz\nfoo\n");
+        editor.c = 1;
+
+        editor.select_next_occurrence(); // the word under the cursor to the next one
+        assert_eq!((editor.selection.from(), editor.selection.to()), ((0, 0), (1, 3)));
+
+        editor.select_next_occurrence();
+        assert_eq!((editor.selection.from(), editor.selection.to()), ((0, 0), (2, 3)));
+        assert_eq!((editor.r, editor.c), (2, 3));
+
+        editor.select_next_occurrence();
+        assert_eq!((editor.selection.from(), editor.selection.to()), ((0, 0), (2, 3)));
+        assert_eq!(editor.message.as_deref(), Some("no more occurrences"));
+
+        assert!(editor.restore_occurrence_origin()); // esc
+        assert_eq!((editor.selection.from(), editor.selection.to()), ((0, 0), (0, 3)));
+        assert_eq!((editor.r, editor.c), (0, 3));
+        assert!(!editor.restore_occurrence_origin());
+    }
+}
+
+#[cfg(test)]
+mod editor_text_edits_tests {
+    use crate::lsp::lsp_messages::{PositionResponse, Range, TextEdit};
+    use super::Editor;
+
+    fn edit(line: f64, character: f64, text: &str) -> TextEdit {
+        let position = PositionResponse { line, character };
+        let range = Range { start: position.clone(), end: position };
+        TextEdit { range: Some(range), replace: None, insert: None, newText: text.to_string() }
+    }
+
+    #[test]
+    fn test_sort_edits_back_to_front() {
+        let edits = vec![edit(0.0, 1.0, "a"), edit(1.0, 0.0, "b"), edit(0.0, 1.0, "c"), edit(0.0, 0.0, "d")];
+        let texts: Vec<String> = Editor::sort_edits_back_to_front(edits).into_iter()
+            .map(|e| e.newText)
+            .collect();
+        assert_eq!(texts, vec!["b", "c", "a", "d"]);
+    }
+}
+
+#[cfg(test)]
+mod editor_diagnostic_popup_tests {
+    use crate::code::Code;
+    use crate::lsp::lsp_messages::DiagnosticParams;
+    use super::Editor;
+
+    #[tokio::test]
+    async fn test_diagnostic_popup() {
+        let mut editor = Editor::new(".".to_string(), crate::config::get());
+        editor.code = Code::from_str("fn main() {\n    let x = y;\n}\n");
+        editor.code.abs_path = "/tmp/popup.rs".to_string();
+        editor.code.file_name = "popup.rs".to_string();
+        editor.resize(80, 20);
+
+        let params: DiagnosticParams = serde_json::from_value(serde_json::json!({
+            "uri": "file:///tmp/popup.rs",
+            "diagnostics": [{
+                "range": {"start": {"line": 1, "character": 12}, "end": {"line": 1, "character": 13}},
+                "severity": 1, "source": "rustc", "message": "cannot find value `y`\nnot found in this scope",
+            }],
+        })).unwrap();
+        editor.diagnostics.lock().await.insert(params.uri.clone(), params);
+        editor.hovered_diagnostic = Some(1);
+
+        editor.draw_frame().await;
+        assert!(editor.diagnostics.try_lock().is_ok()); // the popup and status line can read them
+
+        assert_eq!(editor.diagnostic_popup_lines(1, 60), vec![
+            "rustc error: cannot find value `y`".to_string(),
+            "not found in this scope".to_string(),
+        ]);
+        assert!(editor.diagnostic_popup_lines(0, 60).is_empty());
+
+        editor.minimap = true;
+        editor.upd = true;
+        editor.draw_frame().await;
+        assert_eq!(editor.error_lines(), std::collections::HashSet::from([1]));
+    }
+}
+
+#[cfg(test)]
+mod editor_status_tests {
+    use crate::code::Code;
+    use super::Editor;
+
+    #[test]
+    fn test_status_line_fits() {
+        let mut editor = Editor::new(".".to_string(), crate::config::get());
+        editor.code = Code::from_str("a\n");
+        editor.code.file_name = "一个很长的文件名字_with_a_long_ascii_tail.rs".to_string();
+        editor.resize(30, 10);
+
+        let status = editor.status_line();
+        assert!(Editor::columns(&status) <= 30);
+        assert!(status.ends_with('⛭')); // the buttons stay at the right end
+        editor.draw_status();
+
+        editor.resize(3, 10);
+        assert!(Editor::columns(&editor.status_line()) <= 3);
+        editor.draw_status();
     }
 }
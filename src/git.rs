@@ -0,0 +1,287 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Runs git in the file dir, None outside a git repo or on any git error.
+fn git(path: &str, args: &[&str]) -> Option<String> {
+    let dir = Path::new(path).parent().filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let output = Command::new("git").current_dir(dir).args(args).output().ok()?;
+    if !output.status.success() { return None; }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Blame of the file as "author, yyyy-mm-dd" per line.
+pub fn blame(path: &str) -> Option<Vec<String>> {
+    let name = Path::new(path).file_name()?.to_str()?;
+    let porcelain = git(path, &["blame", "--line-porcelain", "--", name])?;
+    Some(parse_blame(&porcelain))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffKind { Hunk, Context, Added, Removed }
+
+/// Diff line, `row` is the 0-based row of the current file it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffLine {
+    pub kind: DiffKind,
+    pub text: String,
+    pub row: usize,
+}
+
+/// Diff of the saved file against HEAD, untracked files are all added.
+/// None outside a git repo.
+pub fn diff(path: &str) -> Option<Vec<DiffLine>> {
+    let name = Path::new(path).file_name()?.to_str()?;
+    if git(path, &["ls-files", "--error-unmatch", "--", name]).is_none() {
+        git(path, &["rev-parse", "--git-dir"])?; // not a repo
+        let text = std::fs::read_to_string(path).ok()?;
+        return Some(text.lines().enumerate()
+            .map(|(row, line)| DiffLine { kind: DiffKind::Added, text: line.to_string(), row })
+            .collect());
+    }
+    let diff = git(path, &["diff", "--no-color", "--no-ext-diff", "HEAD", "--", name])?;
+    Some(parse_diff(&diff))
+}
+
+/// Diff from the old file to the new one, they do not have to be in a repo.
+pub fn diff_files(old: &Path, new: &Path) -> Option<Vec<DiffLine>> {
+    let output = Command::new("git")
+        .args(["diff", "--no-index", "--no-color", "--no-ext-diff", "--"])
+        .arg(old).arg(new)
+        .output().ok()?;
+    // exits with 1 when the files differ
+    if !matches!(output.status.code(), Some(0) | Some(1)) { return None; }
+    Some(parse_diff(&String::from_utf8(output.stdout).ok()?))
+}
+
+/// Parses unified diff of one file, file headers are skipped.
+pub fn parse_diff(diff: &str) -> Vec<DiffLine> {
+    let mut lines = vec![];
+    let mut row = 0;
+    let mut in_hunk = false;
+
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            // @@ -a,b +c,d @@
+            let start = line.split(' ').nth(2)
+                .and_then(|new| new.trim_start_matches('+').split(',').next()?.parse::<usize>().ok())
+                .unwrap_or(1);
+            row = start.saturating_sub(1);
+            in_hunk = true;
+            lines.push(DiffLine { kind: DiffKind::Hunk, text: line.to_string(), row });
+            continue;
+        }
+        if !in_hunk { continue; }
+
+        let (kind, text) = match line.chars().next() {
+            Some('+') => (DiffKind::Added, &line[1..]),
+            Some('-') => (DiffKind::Removed, &line[1..]),
+            Some(' ') => (DiffKind::Context, &line[1..]),
+            None => (DiffKind::Context, ""),
+            Some('\\') => continue, // no newline at end of file
+            _ => { in_hunk = false; continue; }
+        };
+        lines.push(DiffLine { kind, text: text.to_string(), row });
+        if kind != DiffKind::Removed { row += 1; }
+    }
+    lines
+}
+
+/// Changed region of the file, a `git diff -U0` hunk against HEAD.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+    /// First changed row of the file, for deletions the row the removed lines go before.
+    pub row: usize,
+    /// Rows of the file in the hunk, 0 for deletions.
+    pub count: usize,
+    /// HEAD lines the hunk replaced.
+    pub old: Vec<String>,
+    /// The hunk with the file header, as `git apply` takes it.
+    pub patch: String,
+}
+
+impl Hunk {
+    pub fn contains(&self, row: usize) -> bool {
+        match self.count {
+            0 => row == self.row || row + 1 == self.row,
+            count => self.row <= row && row < self.row + count,
+        }
+    }
+}
+
+/// Hunks of the saved file against HEAD, None outside a git repo.
+pub fn hunks(path: &str) -> Option<Vec<Hunk>> {
+    let name = Path::new(path).file_name()?.to_str()?;
+    let diff = git(path, &["diff", "--no-color", "--no-ext-diff", "-U0", "HEAD", "--", name])?;
+    Some(parse_hunks(&diff))
+}
+
+/// Parses `git diff -U0` output of one file.
+pub fn parse_hunks(diff: &str) -> Vec<Hunk> {
+    let mut header = String::new();
+    let mut hunks: Vec<Hunk> = vec![];
+
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            // @@ -a,b +c,d @@, d is 1 if omitted and c is the row before a deletion
+            let mut new = line.split(' ').nth(2).unwrap_or("+0").trim_start_matches('+').split(',');
+            let start: usize = new.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+            let count: usize = new.next().and_then(|s| s.parse().ok()).unwrap_or(1);
+            let row = if count == 0 { start } else { start.saturating_sub(1) };
+            hunks.push(Hunk { row, count, old: vec![], patch: format!("{}{}\n", header, line) });
+            continue;
+        }
+        match hunks.last_mut() {
+            None => { header.push_str(line); header.push('\n'); }
+            Some(hunk) => {
+                if let Some(old) = line.strip_prefix('-') { hunk.old.push(old.to_string()); }
+                hunk.patch.push_str(line);
+                hunk.patch.push('\n');
+            }
+        }
+    }
+    hunks
+}
+
+/// Applies the hunk to the index, errors with the git message.
+pub fn stage(path: &str, hunk: &Hunk) -> Result<(), String> {
+    // patch paths are relative to the repo root
+    let root = git(path, &["rev-parse", "--show-toplevel"]).ok_or("not in a git repo")?;
+    let mut child = Command::new("git").current_dir(root.trim())
+        .args(["apply", "--cached", "--unidiff-zero", "-"])
+        .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped())
+        .spawn().map_err(|e| e.to_string())?;
+
+    child.stdin.take().ok_or("no stdin")?
+        .write_all(hunk.patch.as_bytes()).map_err(|e| e.to_string())?;
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+    }
+}
+
+/// Parses `git blame --line-porcelain` output, one entry per line of the file.
+pub fn parse_blame(porcelain: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut author = "";
+    let mut time = 0;
+
+    for line in porcelain.lines() {
+        if line.starts_with('\t') {
+            lines.push(format!("{}, {}", author, date(time)));
+        } else if let Some(a) = line.strip_prefix("author ") {
+            author = if a == "Not Committed Yet" { "uncommitted" } else { a };
+        } else if let Some(t) = line.strip_prefix("author-time ") {
+            time = t.parse().unwrap_or(0);
+        }
+    }
+    lines
+}
+
+/// Formats unix seconds as yyyy-mm-dd in UTC.
+fn date(secs: i64) -> String {
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = secs.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+#[cfg(test)]
+mod git_tests {
+    use super::{date, parse_blame, parse_diff, parse_hunks, DiffKind};
+
+    #[test]
+    fn test_parse_blame() {
+        let porcelain = "\
+4f2e1a 1 1 2
+author Max
+author-mail <max@mail.com>
+author-time 1710000000
+author-tz +0100
+summary init
+filename src/main.rs
+\tfn main() {
+4f2e1a 2 2
+author Max
+author-time 1710000000
+filename src/main.rs
+\t}
+0000000 3 3 1
+author Not Committed Yet
+author-time 1720000000
+filename src/main.rs
+\t
+";
+        assert_eq!(parse_blame(porcelain), vec![
+            "Max, 2024-03-09", "Max, 2024-03-09", "uncommitted, 2024-07-03",
+        ]);
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(951782400), "2000-02-29");
+    }
+
+    #[test]
+    fn test_parse_diff() {
+        let diff = "\
+diff --git a/src/main.rs b/src/main.rs
+index 1a2b3c4..5d6e7f8 100644
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,3 +1,3 @@
+ fn main() {
+-    println!(\"hi\");
++    println!(\"hello\");
+ }
+@@ -10 +10,2 @@ fn other() {
++// added
+ }
+\\ No newline at end of file
+";
+        let lines = parse_diff(diff);
+        let kinds: Vec<_> = lines.iter().map(|l| (l.kind, l.row)).collect();
+        assert_eq!(kinds, vec![
+            (DiffKind::Hunk, 0), (DiffKind::Context, 0), (DiffKind::Removed, 1),
+            (DiffKind::Added, 1), (DiffKind::Context, 2),
+            (DiffKind::Hunk, 9), (DiffKind::Added, 9), (DiffKind::Context, 10),
+        ]);
+        assert_eq!(lines[3].text, "    println!(\"hello\");");
+    }
+
+    #[test]
+    fn test_parse_hunks() {
+        let diff = "\
+diff --git a/a.txt b/a.txt
+index 1a2b3c4..5d6e7f8 100644
+--- a/a.txt
++++ b/a.txt
+@@ -2 +2 @@
+-two
++2
+@@ -5,2 +4,0 @@ four
+-five
+-six
+@@ -9,0 +8,2 @@ eight
++nine
++ten
+";
+        let hunks = parse_hunks(diff);
+        let rows: Vec<_> = hunks.iter().map(|h| (h.row, h.count, h.old.len())).collect();
+        assert_eq!(rows, vec![(1, 1, 1), (4, 0, 2), (7, 2, 0)]);
+        assert_eq!(hunks[1].old, vec!["five", "six"]);
+        assert!(hunks[1].patch.starts_with("diff --git a/a.txt b/a.txt\n"));
+        assert!(hunks[1].patch.ends_with("@@ -5,2 +4,0 @@ four\n-five\n-six\n"));
+
+        assert!(hunks[0].contains(1) && !hunks[0].contains(2));
+        assert!(hunks[1].contains(3) && hunks[1].contains(4) && !hunks[1].contains(5));
+        assert!(hunks[2].contains(8) && !hunks[2].contains(9));
+    }
+}
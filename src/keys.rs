@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+
+use crossterm::event::{KeyCode, KeyModifiers};
+
+/// Editor commands that can be bound to keys in the `[keys]` table of config.toml.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    Save,
+    Copy,
+    Paste,
+    Cut,
+    NextOccurrence,
+    AllOccurrences,
+    Find,
+    GlobalSearch,
+    References,
+    Definition,
+    Undo,
+    CursorBack,
+    CursorForward,
+    Errors,
+    Hover,
+    Tree,
+    Completion,
+    GotoLine,
+    Rename,
+    MoveLineUp,
+    MoveLineDown,
+    SelectMore,
+    SelectLess,
+    SelectFunction,
+    SwapSelectionEnds,
+    SelectNextOccurrence,
+    WordLeft,
+    WordRight,
+    DeleteLine,
+    Comment,
+    Overwrite,
+    TextStats,
+    LineNumbers,
+    Wrap,
+    OpenByWord,
+    QuickOpen,
+    OpenFileUnderCursor,
+    Format,
+    ReopenWithEncoding,
+    Duplicate,
+    DuplicateAndComment,
+    Align,
+    CodeActions,
+    Outline,
+    Zen,
+    Blame,
+    Diff,
+    RevertHunk,
+    StageHunk,
+    RecentLines,
+    OpenConfig,
+    OpenTheme,
+    LineEndingWarnings,
+    NormalizeLineEndings,
+    RenderWhitespace,
+    ClosingContext,
+    Minimap,
+    SwitchBuffer,
+    CloseBuffer,
+    AlternateBuffer,
+}
+
+/// Action names as used in config.toml.
+pub const ACTIONS: &[(&str, Action)] = &[
+    ("quit", Action::Quit),
+    ("save", Action::Save),
+    ("copy", Action::Copy),
+    ("paste", Action::Paste),
+    ("cut", Action::Cut),
+    ("next_occurrence", Action::NextOccurrence),
+    ("all_occurrences", Action::AllOccurrences),
+    ("find", Action::Find),
+    ("global_search", Action::GlobalSearch),
+    ("references", Action::References),
+    ("definition", Action::Definition),
+    ("undo", Action::Undo),
+    ("cursor_back", Action::CursorBack),
+    ("cursor_forward", Action::CursorForward),
+    ("errors", Action::Errors),
+    ("hover", Action::Hover),
+    ("tree", Action::Tree),
+    ("completion", Action::Completion),
+    ("goto_line", Action::GotoLine),
+    ("rename", Action::Rename),
+    ("move_line_up", Action::MoveLineUp),
+    ("move_line_down", Action::MoveLineDown),
+    ("select_more", Action::SelectMore),
+    ("select_less", Action::SelectLess),
+    ("select_function", Action::SelectFunction),
+    ("swap_selection_ends", Action::SwapSelectionEnds),
+    ("select_next_occurrence", Action::SelectNextOccurrence),
+    ("word_left", Action::WordLeft),
+    ("word_right", Action::WordRight),
+    ("delete_line", Action::DeleteLine),
+    ("comment", Action::Comment),
+    ("overwrite", Action::Overwrite),
+    ("text_stats", Action::TextStats),
+    ("line_numbers", Action::LineNumbers),
+    ("wrap", Action::Wrap),
+    ("open_by_word", Action::OpenByWord),
+    ("quick_open", Action::QuickOpen),
+    ("open_file_under_cursor", Action::OpenFileUnderCursor),
+    ("format", Action::Format),
+    ("reopen_with_encoding", Action::ReopenWithEncoding),
+    ("duplicate", Action::Duplicate),
+    ("duplicate_and_comment", Action::DuplicateAndComment),
+    ("align", Action::Align),
+    ("code_actions", Action::CodeActions),
+    ("outline", Action::Outline),
+    ("zen", Action::Zen),
+    ("blame", Action::Blame),
+    ("diff", Action::Diff),
+    ("revert_hunk", Action::RevertHunk),
+    ("stage_hunk", Action::StageHunk),
+    ("recent_lines", Action::RecentLines),
+    ("open_config", Action::OpenConfig),
+    ("open_theme", Action::OpenTheme),
+    ("line_ending_warnings", Action::LineEndingWarnings),
+    ("normalize_line_endings", Action::NormalizeLineEndings),
+    ("render_whitespace", Action::RenderWhitespace),
+    ("closing_context", Action::ClosingContext),
+    ("minimap", Action::Minimap),
+    ("switch_buffer", Action::SwitchBuffer),
+    ("close_buffer", Action::CloseBuffer),
+    ("alternate_buffer", Action::AlternateBuffer),
+];
+
+/// Default bindings, an action may have several keys.
+const DEFAULT_KEYS: &[(&str, &str)] = &[
+    ("quit", "ctrl+q"),
+    ("save", "ctrl+s"),
+    ("copy", "ctrl+c"),
+    ("paste", "ctrl+v"),
+    ("cut", "ctrl+x"),
+    ("next_occurrence", "ctrl+d"),
+    ("all_occurrences", "alt+shift+l"),
+    ("find", "ctrl+f"),
+    ("references", "ctrl+r"),
+    ("definition", "ctrl+g"),
+    ("undo", "ctrl+z"),
+    ("cursor_back", "ctrl+o"),
+    ("cursor_forward", "ctrl+p"),
+    ("errors", "ctrl+e"),
+    ("hover", "ctrl+h"),
+    ("tree", "ctrl+t"),
+    ("switch_buffer", "ctrl+b"),
+    ("close_buffer", "ctrl+w"),
+    ("alternate_buffer", "alt+p"),
+    ("quick_open", "ctrl+k"),
+    ("completion", "ctrl+space"),
+    ("goto_line", "ctrl+l"),
+    ("rename", "ctrl+n"),
+    ("move_line_up", "ctrl+shift+up"),
+    ("move_line_down", "ctrl+shift+down"),
+    ("select_more", "alt+up"),
+    ("select_less", "alt+down"),
+    ("select_function", "alt+s"),
+    ("swap_selection_ends", "alt+x"),
+    ("select_next_occurrence", "alt+u"),
+    ("word_left", "alt+left"),
+    ("word_right", "alt+right"),
+    ("delete_line", "alt+backspace"),
+    ("comment", "alt+/"),
+    ("comment", "÷"), // option + / on macos
+    ("overwrite", "insert"),
+    ("text_stats", "alt+w"),
+    ("line_numbers", "alt+n"),
+    ("wrap", "alt+z"),
+    ("open_by_word", "alt+o"),
+    ("open_file_under_cursor", "alt+g"),
+    ("format", "alt+f"),
+    ("reopen_with_encoding", "alt+e"),
+    ("duplicate", "alt+shift+d"),
+    ("duplicate_and_comment", "alt+d"),
+    ("align", "alt+a"),
+    ("code_actions", "alt+q"),
+    ("outline", "alt+l"),
+    ("zen", "alt+m"),
+    ("blame", "alt+b"),
+    ("diff", "alt+c"),
+    ("revert_hunk", "alt+r"),
+    ("stage_hunk", "alt+i"),
+    ("recent_lines", "alt+j"),
+    ("open_config", "alt+,"),
+    ("open_theme", "alt+."),
+    ("line_ending_warnings", "alt+h"),
+    ("normalize_line_endings", "alt+shift+h"),
+    ("render_whitespace", "alt+v"),
+    ("closing_context", "alt+t"),
+    ("minimap", "alt+k"),
+];
+
+pub type KeyMap = HashMap<(KeyModifiers, KeyCode), Action>;
+
+pub fn action(name: &str) -> Option<Action> {
+    ACTIONS.iter().find(|(n, _)| *n == name).map(|(_, a)| *a)
+}
+
+/// Parses keys like "ctrl+s", "alt+left" or "ctrl+shift+up".
+pub fn parse_key(key: &str) -> Option<(KeyModifiers, KeyCode)> {
+    let key = key.trim().to_lowercase();
+    let (mods, name) = match key.rsplit_once('+') {
+        Some((mods, "")) => (mods.strip_suffix('+')?, "+"), // "ctrl++"
+        Some((mods, name)) => (mods, name),
+        None => ("", key.as_str()),
+    };
+
+    let mut modifiers = KeyModifiers::NONE;
+    for m in mods.split('+').filter(|m| !m.is_empty()) {
+        modifiers |= match m {
+            "ctrl" | "control" => KeyModifiers::CONTROL,
+            "alt" | "option" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return None,
+        };
+    }
+
+    let code = match name {
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "backspace" => KeyCode::Backspace,
+        "delete" => KeyCode::Delete,
+        "insert" => KeyCode::Insert,
+        "enter" => KeyCode::Enter,
+        "tab" => KeyCode::Tab,
+        "esc" => KeyCode::Esc,
+        "space" => KeyCode::Char(' '),
+        f if f.len() > 1 && f.starts_with('f') => KeyCode::F(f[1..].parse().ok()?),
+        c if c.chars().count() == 1 => {
+            let c = c.chars().next()?;
+            // terminals report shifted letters in upper case
+            if modifiers.contains(KeyModifiers::SHIFT) { KeyCode::Char(c.to_ascii_uppercase()) }
+            else { KeyCode::Char(c) }
+        }
+        _ => return None,
+    };
+
+    Some((modifiers, code))
+}
+
+/// Builds the key map from defaults and the `[keys]` config table,
+/// a configured action replaces all its default keys.
+pub fn keymap(keys: Option<&HashMap<String, String>>) -> Result<KeyMap, String> {
+    let empty = HashMap::new();
+    let keys = keys.unwrap_or(&empty);
+
+    let mut map = KeyMap::new();
+    for (name, key) in DEFAULT_KEYS {
+        if keys.contains_key(*name) { continue; }
+        let key = parse_key(key).expect("invalid default key");
+        map.insert(key, action(name).expect("unknown default action"));
+    }
+
+    let mut names: Vec<&String> = keys.keys().collect();
+    names.sort();
+    for name in names {
+        let action = action(name).ok_or_else(|| format!("unknown action '{}'", name))?;
+        let key = &keys[name];
+        let key = parse_key(key)
+            .ok_or_else(|| format!("invalid key '{}' for action '{}'", key, name))?;
+        map.insert(key, action);
+    }
+
+    Ok(map)
+}
+
+#[cfg(test)]
+mod keys_tests {
+    use std::collections::HashMap;
+    use crossterm::event::{KeyCode, KeyModifiers};
+    use super::{keymap, parse_key, Action};
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("ctrl+s"), Some((KeyModifiers::CONTROL, KeyCode::Char('s'))));
+        assert_eq!(parse_key("Alt+Left"), Some((KeyModifiers::ALT, KeyCode::Left)));
+        assert_eq!(
+            parse_key("ctrl+shift+up"),
+            Some((KeyModifiers::CONTROL | KeyModifiers::SHIFT, KeyCode::Up))
+        );
+        assert_eq!(parse_key("ctrl+space"), Some((KeyModifiers::CONTROL, KeyCode::Char(' '))));
+        assert_eq!(parse_key("shift+x"), Some((KeyModifiers::SHIFT, KeyCode::Char('X'))));
+        assert_eq!(parse_key("ctrl++"), Some((KeyModifiers::CONTROL, KeyCode::Char('+'))));
+        assert_eq!(parse_key("f5"), Some((KeyModifiers::NONE, KeyCode::F(5))));
+        assert_eq!(parse_key("hyper+s"), None);
+        assert_eq!(parse_key("ctrl+nope"), None);
+    }
+
+    #[test]
+    fn test_keymap() {
+        let map = keymap(None).unwrap();
+        assert_eq!(map.get(&(KeyModifiers::CONTROL, KeyCode::Char('s'))), Some(&Action::Save));
+
+        let keys = HashMap::from([("save".to_string(), "alt+s".to_string())]);
+        let map = keymap(Some(&keys)).unwrap();
+        assert_eq!(map.get(&(KeyModifiers::ALT, KeyCode::Char('s'))), Some(&Action::Save));
+        assert_eq!(map.get(&(KeyModifiers::CONTROL, KeyCode::Char('s'))), None);
+
+        let keys = HashMap::from([("sav".to_string(), "ctrl+s".to_string())]);
+        assert_eq!(keymap(Some(&keys)).unwrap_err(), "unknown action 'sav'");
+
+        let keys = HashMap::from([("save".to_string(), "ctrl+".to_string())]);
+        assert!(keymap(Some(&keys)).is_err());
+    }
+}
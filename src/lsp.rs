@@ -18,12 +18,16 @@ use tokio::io::{self};
 use self::lsp_messages::{
     CompletionResponse, CompletionResponse2, CompletionResult, 
     DefinitionResponse, DefinitionResult, 
-    DiagnosticParams, 
+    Diagnostic, DiagnosticParams, 
     HoverResponse, HoverResult, 
-    ReferencesResponse, ReferencesResult
+    ReferencesResponse, ReferencesResult,
+    SemanticToken, SemanticTokensResponse,
+    TextEdit, TextEditsResponse, RenameResponse, CodeActionOrCommand, CodeActionResponse,
+    DocumentSymbol, DocumentSymbolResponse, SignatureHelp, SignatureHelpResponse, PrepareRename,
 };
 
 use log2::*;
+use crate::utils;
 
 pub struct Lsp {
     lang: String,
@@ -31,9 +35,22 @@ pub struct Lsp {
     stdin_send: Option<mpsc::Sender<String>>,
     next_id: AtomicUsize,
     versions: HashMap<String, AtomicUsize>,
+    /// Content changes by path not sent yet, they go in one didChange before any other message.
+    changes: HashMap<String, Vec<Value>>,
     pending: Arc<Mutex<HashMap<usize, mpsc::Sender<String>>>>,
     ready: AtomicBool,
     opened: HashSet<String>,
+    /// Server capabilities from the initialize response.
+    capabilities: Value,
+    /// Language and status text, empty when the server is ready and idle.
+    status_send: Option<mpsc::UnboundedSender<(String, String)>>,
+}
+
+/// Short server name for messages, the file name of the command, e.g. `rust-analyzer`.
+pub fn server_name(cmd: &str) -> String {
+    let cmd = cmd.split(' ').next().unwrap_or(cmd);
+    std::path::Path::new(cmd).file_name()
+        .map_or(cmd.to_string(), |n| n.to_string_lossy().to_string())
 }
 
 impl Lsp {
@@ -44,14 +61,18 @@ impl Lsp {
             stdin_send: None,
             next_id: AtomicUsize::new(1),
             versions: HashMap::new(),
+            changes: HashMap::new(),
             pending: Arc::new(Mutex::new(HashMap::new())),
             ready: AtomicBool::new(false),
             opened: HashSet::new(),
+            capabilities: Value::Null,
+            status_send: None,
         }
     }
 
     pub fn start(&mut self, lang: &str, cmd: &str, 
-        diagnostic_updates: Option<mpsc::Sender<DiagnosticParams>>) 
+        diagnostic_updates: Option<mpsc::Sender<DiagnosticParams>>,
+        status_updates: Option<mpsc::UnboundedSender<(String, String)>>) 
         ->  io::Result<()>
     {
         // let cmd = match lsp_servers::lang2server(&lang) {
@@ -62,6 +83,7 @@ impl Lsp {
         let s: Vec<&str> = cmd.split(" ").collect();
         let cmd = s[0];
         let args = &s[1..];
+        let server = server_name(cmd);
 
         self.lang = lang.to_string();
 
@@ -83,7 +105,7 @@ impl Lsp {
         let mut stdout = child.stdout.take().unwrap();
 
         // reading from channel and write to child stdin
-        tokio::spawn(async move {
+        utils::spawn("lsp writer", async move {
             while let Some(m) = stdin_recv.recv().await {
                 debug!("-> {}", m);
                 let header = format!("Content-Length: {}\r\n\r\n", m.len());
@@ -93,11 +115,15 @@ impl Lsp {
             }
         });
 
+        self.status_send = status_updates.clone();
         let pending = self.pending.clone();
+        let server_send = self.stdin_send.clone();
+        let lang = self.lang.clone();
 
         // reading from child stdout
-        tokio::spawn(async move {
+        utils::spawn("lsp reader", async move {
             let mut reader = BufReader::new(stdout);
+            let mut progress = HashMap::new();
 
             loop {
                 let mut size = None;
@@ -127,12 +153,14 @@ impl Lsp {
 
                 let parsed_json: Value = serde_json::from_str(msg).unwrap();
 
-                if let Some(id) = parsed_json["id"].as_u64() { // response
+                let is_request = parsed_json.get("method").is_some();
+
+                if let (Some(id), false) = (parsed_json["id"].as_u64(), is_request) { // response
                     let id = id as usize;
                     if let Some(sender) = pending.lock().await.get(&id) {
                         let s = sender.clone();
                         let msg = msg.to_string();
-                        tokio::spawn(async move {
+                        utils::spawn("lsp response", async move {
                             s.send(msg).await; // send to request channel
                         });
                     } 
@@ -155,14 +183,28 @@ impl Lsp {
                                 error!("<- {:?} ", e);
                             },
                         }
-                    }   
+                    }
+                    if method.eq("window/workDoneProgress/create") {
+                        // the token is created by the server, only the answer is needed
+                        let reply = json!({"jsonrpc": "2.0", "id": parsed_json["id"], "result": null});
+                        if let Some(server_send) = server_send.as_ref() {
+                            let _ = server_send.send(reply.to_string()).await;
+                        }
+                    }
+                    if method.eq("$/progress") {
+                        let status = lsp_messages::progress_status(&parsed_json["params"], &mut progress);
+                        if let (Some(status), Some(status_send)) = (status, status_updates.as_ref()) {
+                            let status = if status.is_empty() { status } else { format!("{}: {}", server, status) };
+                            let _ = status_send.send((lang.clone(), status)); // unbounded, never blocks responses
+                        }
+                    }
                 }
                   
             }
         });
 
         // wait for child end or kill
-        tokio::spawn(async move {
+        utils::spawn("lsp process", async move {
             tokio::select! {
                 _ = child.wait() => {
                     debug!("lsp process wait done");
@@ -177,22 +219,82 @@ impl Lsp {
         Ok(())
     }
 
+    /// Asks the server to exit with `shutdown` and `exit`, so it can clean up, e.g. lock files.
+    /// Kills it if it is still running after a short wait.
+    pub async fn shutdown(&mut self) {
+        let kill_send = match self.kill_send.take() {
+            Some(kill_send) => kill_send,
+            None => return,
+        };
+
+        if self.is_ready() && !kill_send.is_closed() {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            let (tx, mut rx) = mpsc::channel::<String>(1);
+            self.add_pending(id, tx).await;
+            self.send_async(lsp_messages::shutdown(id));
+            let _ = time::timeout(Duration::from_millis(500), rx.recv()).await;
+            self.remove_pending(id).await;
+
+            if let Some(stdin_send) = &self.stdin_send {
+                let _ = stdin_send.send(lsp_messages::exit()).await;
+            }
+        }
+
+        // the receiver is dropped when the process ends
+        if time::timeout(Duration::from_millis(500), kill_send.closed()).await.is_err() {
+            let _ = kill_send.send(()).await;
+        }
+        self.ready.store(false, Ordering::SeqCst);
+    }
+
     pub async fn stop(&mut self) {
         if let Some(kill_send) = self.kill_send.take() {
             kill_send.send(()).await.expect("Failed to send kill signal");
         }
     }
 
-    fn send_async(&self, message: String) {
+    /// Sends the message after the pending changes, the server sees the current text.
+    fn send_async(&mut self, message: String) {
+        let mut messages = self.take_changes();
+        messages.push(message);
+        self.send_all(messages);
+    }
+
+    /// Sends the messages in order.
+    fn send_all(&self, messages: Vec<String>) {
+        if messages.is_empty() { return; }
         if let Some(stdin_send) = &self.stdin_send {
             let stdin_send = stdin_send.clone();
-            tokio::spawn(async move {
-                if let Err(err) = stdin_send.send(message).await {
-                    error!("Failed to send message: {:?}", err);
+            utils::spawn("lsp send", async move {
+                for message in messages {
+                    if let Err(err) = stdin_send.send(message).await {
+                        error!("Failed to send message: {:?}", err);
+                    }
                 }
             });
         }
     }
+
+    /// One didChange per path with all the pending changes of it.
+    fn take_changes(&mut self) -> Vec<String> {
+        let changes: Vec<(String, Vec<Value>)> = self.changes.drain().collect();
+        changes.into_iter()
+            .map(|(path, changes)| {
+                let version = self.get_next_version(&path);
+                lsp_messages::did_change(&path, changes, version)
+            })
+            .collect()
+    }
+
+    pub fn has_changes(&self) -> bool {
+        !self.changes.is_empty()
+    }
+
+    /// Sends the changes collected since the last message.
+    pub fn flush_changes(&mut self) {
+        let messages = self.take_changes();
+        self.send_all(messages);
+    }
     pub async fn add_pending(&mut self, id: usize, sender: mpsc::Sender<String>) {
         self.pending.lock().await.insert(id, sender);
     }
@@ -222,12 +324,33 @@ impl Lsp {
         let result = self.wait_for(id, rx).await;
         self.remove_pending(id).await;
 
+        if let Some(message) = result {
+            match serde_json::from_str::<Value>(&message) {
+                Ok(v) => self.capabilities = v["result"]["capabilities"].clone(),
+                Err(e) => debug!("lsp json parsing error {}", e),
+            }
+        }
+
+        // before initialized, after it the server may start reporting progress
+        if let Some(status_send) = &self.status_send {
+            let _ = status_send.send((self.lang.clone(), String::new()));
+        }
         self.initialized();
         tokio::time::sleep(Duration::from_millis(10)).await;
         
         self.ready.store(true, Ordering::SeqCst)
     }
 
+    pub fn capabilities(&self) -> &Value {
+        &self.capabilities
+    }
+
+    /// Capability is present and not disabled, e.g. `documentRangeFormattingProvider`.
+    pub fn supports(&self, capability: &str) -> bool {
+        let value = &self.capabilities[capability];
+        !value.is_null() && *value != Value::Bool(false)
+    }
+
     pub fn is_ready(&mut self) -> bool {
         self.ready.load(Ordering::SeqCst)
     }
@@ -246,6 +369,13 @@ impl Lsp {
         self.send_async(message);
     }
 
+    pub fn did_close(&mut self, path: &str) {
+        if !self.opened.remove(path) { return; }
+
+        let message = lsp_messages::did_close(path);
+        self.send_async(message);
+    }
+
     fn get_next_version(&mut self, path: &str) -> usize { 
         let version = self.versions.entry(path.to_string())
             .or_insert_with(|| AtomicUsize::new(0));
@@ -264,13 +394,17 @@ impl Lsp {
     ) { 
         if !self.is_ready() { return; }
 
-        let version = self.get_next_version(path);
+        let change = lsp_messages::content_change(line, character, line_end, character_end, text);
+        self.changes.entry(path.to_string()).or_default().push(change);
+    }
 
-        let message = lsp_messages::did_change(
-            line, character,
-            line_end, character_end,
-            path, text, version,
-        );
+    /// Sends the whole text, for multi-step edits where ranges are easy to get wrong.
+    pub async fn did_change_full(&mut self, path: &str, text: &str) {
+        if !self.is_ready() { return; }
+
+        self.changes.remove(path); // the full text replaces them
+        let version = self.get_next_version(path);
+        let message = lsp_messages::did_change_full(path, text, version);
         self.send_async(message);
     }
 
@@ -371,6 +505,138 @@ impl Lsp {
     }
 
 
+    /// Returns edits by file uri, collected from `changes` and `documentChanges`.
+    pub async fn rename(
+        &mut self, path: &str, line: usize, character: usize, new_name: &str
+    ) -> Option<HashMap<String, Vec<TextEdit>>> {
+        if !self.is_ready() { return None; }
+
+        let id = self.get_next_id();
+        let message = json!({
+            "id": id, "jsonrpc": "2.0", "method": "textDocument/rename",
+            "params": {
+                "textDocument": { "uri": format!("file://{}", path) },
+                "position": { "line": line, "character": character },
+                "newName": new_name,
+            }
+        });
+
+        let (tx, rx) = mpsc::channel::<String>(1);
+        self.add_pending(id, tx).await;
+        self.send_async(message.to_string());
+
+        let result = self.wait_for(id, rx).await;
+        self.remove_pending(id).await;
+
+        result.and_then(|message| {
+            serde_json::from_str::<RenameResponse>(&message)
+                .map_err(|e| debug!("lsp json parsing error {}", e))
+                .ok().and_then(|r| r.result)
+        }).map(|edit| edit.text_edits())
+    }
+
+    /// Checks the position can be renamed, servers without prepare support allow any position.
+    pub async fn prepare_rename(&mut self, path: &str, line: usize, character: usize) -> PrepareRename {
+        if !self.is_ready() { return PrepareRename::Default; }
+        if self.capabilities["renameProvider"]["prepareProvider"] != Value::Bool(true) {
+            return PrepareRename::Default;
+        }
+
+        let id = self.get_next_id();
+        let message = json!({
+            "id": id, "jsonrpc": "2.0", "method": "textDocument/prepareRename",
+            "params": {
+                "textDocument": { "uri": format!("file://{}", path) },
+                "position": { "line": line, "character": character },
+            }
+        });
+
+        let (tx, rx) = mpsc::channel::<String>(1);
+        self.add_pending(id, tx).await;
+        self.send_async(message.to_string());
+
+        let result = self.wait_for(id, rx).await;
+        self.remove_pending(id).await;
+
+        match result {
+            Some(message) => serde_json::from_str::<Value>(&message)
+                .map_err(|e| debug!("lsp json parsing error {}", e))
+                .map(|v| lsp_messages::parse_prepare_rename(&v["result"]))
+                .unwrap_or(PrepareRename::Default),
+            None => PrepareRename::Default, // no answer in time, let rename decide
+        }
+    }
+
+    pub async fn code_action(
+        &mut self, path: &str,
+        line: usize, character: usize, line_end: usize, character_end: usize,
+        diagnostics: Vec<Diagnostic>,
+    ) -> Option<Vec<CodeActionOrCommand>> {
+        if !self.is_ready() { return None; }
+
+        let id = self.get_next_id();
+        let message = json!({
+            "id": id, "jsonrpc": "2.0", "method": "textDocument/codeAction",
+            "params": {
+                "textDocument": { "uri": format!("file://{}", path) },
+                "range": {
+                    "start": { "line": line, "character": character },
+                    "end": { "line": line_end, "character": character_end },
+                },
+                "context": { "diagnostics": diagnostics },
+            }
+        });
+
+        let (tx, rx) = mpsc::channel::<String>(1);
+        self.add_pending(id, tx).await;
+        self.send_async(message.to_string());
+
+        let result = self.wait_for(id, rx).await;
+        self.remove_pending(id).await;
+
+        result.and_then(|message| {
+            serde_json::from_str::<CodeActionResponse>(&message)
+                .map_err(|e| debug!("lsp json parsing error {}", e))
+                .ok().and_then(|r| r.result)
+        })
+    }
+
+    /// Symbols of the file, nested `DocumentSymbol`s or flat `SymbolInformation`s.
+    pub async fn document_symbols(&mut self, path: &str) -> Option<Vec<DocumentSymbol>> {
+        if !self.is_ready() { return None; }
+
+        let id = self.get_next_id();
+        let message = json!({
+            "id": id, "jsonrpc": "2.0", "method": "textDocument/documentSymbol",
+            "params": {
+                "textDocument": { "uri": format!("file://{}", path) },
+            }
+        });
+
+        let (tx, rx) = mpsc::channel::<String>(1);
+        self.add_pending(id, tx).await;
+        self.send_async(message.to_string());
+
+        let result = self.wait_for(id, rx).await;
+        self.remove_pending(id).await;
+
+        result.and_then(|message| {
+            serde_json::from_str::<DocumentSymbolResponse>(&message)
+                .map_err(|e| debug!("lsp json parsing error {}", e))
+                .ok().and_then(|r| r.result)
+        })
+    }
+
+    /// Runs a `{ command, arguments }` command on the server, the result is ignored.
+    pub fn execute_command(&mut self, command: Value) {
+        let id = self.get_next_id();
+        let message = json!({
+            "id": id, "jsonrpc": "2.0", "method": "workspace/executeCommand",
+            "params": command,
+        });
+        self.send_async(message.to_string());
+    }
+
     pub async fn hover(
         &mut self, path: &str, line: usize, character: usize
     ) -> Option<HoverResult> {
@@ -398,6 +664,125 @@ impl Lsp {
                 .ok().and_then(|r| Some(r.result))
         })
     }
+
+    pub async fn signature_help(
+        &mut self, path: &str, line: usize, character: usize
+    ) -> Option<SignatureHelp> {
+        if !self.is_ready() { return None; }
+
+        let id = self.get_next_id();
+        let message = json!({
+            "id": id, "jsonrpc": "2.0", "method": "textDocument/signatureHelp",
+            "params": {
+                "textDocument": { "uri": format!("file://{}", path) },
+                "position": { "line": line, "character": character },
+            }
+        });
+
+        let (tx, rx) = mpsc::channel::<String>(1);
+        self.add_pending(id, tx).await;
+        self.send_async(message.to_string());
+
+        let result = self.wait_for(id, rx).await;
+        self.remove_pending(id).await;
+
+        result.and_then(|message| {
+            serde_json::from_str::<SignatureHelpResponse>(&message)
+                .map_err(|e| debug!("lsp json parsing error {}", e))
+                .ok().and_then(|r| r.result)
+        })
+    }
+
+    pub async fn formatting(
+        &mut self, path: &str, tab_size: usize, insert_spaces: bool
+    ) -> Option<Vec<TextEdit>> {
+        if !self.is_ready() { return None; }
+
+        let id = self.get_next_id();
+        let message = json!({
+            "id": id, "jsonrpc": "2.0", "method": "textDocument/formatting",
+            "params": {
+                "textDocument": { "uri": format!("file://{}", path) },
+                "options": { "tabSize": tab_size, "insertSpaces": insert_spaces },
+            }
+        });
+
+        self.request_text_edits(id, message).await
+    }
+
+    pub async fn range_formatting(
+        &mut self, path: &str,
+        line: usize, character: usize, line_end: usize, character_end: usize,
+        tab_size: usize, insert_spaces: bool,
+    ) -> Option<Vec<TextEdit>> {
+        if !self.is_ready() { return None; }
+
+        let id = self.get_next_id();
+        let message = json!({
+            "id": id, "jsonrpc": "2.0", "method": "textDocument/rangeFormatting",
+            "params": {
+                "textDocument": { "uri": format!("file://{}", path) },
+                "range": {
+                    "start": { "line": line, "character": character },
+                    "end": { "line": line_end, "character": character_end },
+                },
+                "options": { "tabSize": tab_size, "insertSpaces": insert_spaces },
+            }
+        });
+
+        self.request_text_edits(id, message).await
+    }
+
+    async fn request_text_edits(&mut self, id: usize, message: Value) -> Option<Vec<TextEdit>> {
+        let (tx, rx) = mpsc::channel::<String>(1);
+        self.add_pending(id, tx).await;
+        self.send_async(message.to_string());
+
+        let result = self.wait_for(id, rx).await;
+        self.remove_pending(id).await;
+
+        result.and_then(|message| {
+            serde_json::from_str::<TextEditsResponse>(&message)
+                .map_err(|e| debug!("lsp json parsing error {}", e))
+                .ok().and_then(|r| r.result)
+        })
+    }
+
+    pub async fn semantic_tokens_full(&mut self, path: &str) -> Option<Vec<SemanticToken>> {
+        if !self.is_ready() { return None; }
+
+        let legend = &self.capabilities["semanticTokensProvider"]["legend"];
+        if legend.is_null() { return None; }
+        let names = |key: &str| -> Vec<String> {
+            legend[key].as_array().map(|a| a.iter()
+                .map(|t| t.as_str().unwrap_or_default().to_string())
+                .collect()
+            ).unwrap_or_default()
+        };
+        let (types, modifiers) = (names("tokenTypes"), names("tokenModifiers"));
+
+        let id = self.get_next_id();
+        let message = json!({
+            "id": id, "jsonrpc": "2.0", "method": "textDocument/semanticTokens/full",
+            "params": {
+                "textDocument": { "uri": format!("file://{}", path) },
+            }
+        });
+
+        let (tx, rx) = mpsc::channel::<String>(1);
+        self.add_pending(id, tx).await;
+        self.send_async(message.to_string());
+
+        let result = self.wait_for(id, rx).await;
+        self.remove_pending(id).await;
+
+        result.and_then(|message| {
+            serde_json::from_str::<SemanticTokensResponse>(&message)
+                .map_err(|e| debug!("lsp json parsing error {}", e))
+                .ok().and_then(|r| r.result)
+                .map(|r| lsp_messages::decode_semantic_tokens(&r.data, &types, &modifiers))
+        })
+    }
 }
 
 #[tokio::test]
@@ -405,7 +790,7 @@ async fn test_lsp() {
     let lang = "rust";
     let mut lsp = Lsp::new();
 
-    lsp.start(lang, "rust-analyzer", None);
+    lsp.start(lang, "rust-analyzer", None, None);
     println!("after lsp start");
 
     sleep(Duration::from_secs(2)).await;
@@ -511,6 +896,7 @@ pub mod lsp_messages {
     use serde::{Deserialize, Serialize};
     use serde_json::Result;
     use serde_json::{json, Value};
+    use std::collections::HashMap;
 
     // todo, replace it to struct in the future
 
@@ -534,6 +920,9 @@ pub mod lsp_messages {
                     "version": "1.0.0"
                 },
                 "capabilities": {
+                    "window": {
+                        "workDoneProgress": true,
+                    },
                     "textDocument": {
                         "synchronization": {
                             "dynamicRegistration": true,
@@ -543,11 +932,32 @@ pub mod lsp_messages {
                                 "plaintext",
                             ]
                         },
+                        "semanticTokens": {
+                            "requests": { "full": true },
+                            "tokenTypes": [
+                                "namespace", "type", "class", "enum", "interface", "struct",
+                                "typeParameter", "parameter", "variable", "property", "enumMember",
+                                "event", "function", "method", "macro", "keyword", "modifier",
+                                "comment", "string", "number", "regexp", "operator", "decorator"
+                            ],
+                            "tokenModifiers": [
+                                "declaration", "definition", "readonly", "static", "deprecated",
+                                "abstract", "async", "modification", "documentation", "defaultLibrary"
+                            ],
+                            "formats": ["relative"],
+                        },
+                        "documentSymbol": {
+                            "hierarchicalDocumentSymbolSupport": true,
+                        },
+                        "rename": {
+                            "prepareSupport": true,
+                        },
                         "publishDiagnostics": {
                             "relatedInformation": false,
                             "versionSupport": false,
                             "codeDescriptionSupport": true,
-                            "dataSupport": true
+                            "dataSupport": true,
+                            "tagSupport": { "valueSet": [1, 2] }
                         },
                         "signatureHelp": {
                             "signatureInformation": {
@@ -582,6 +992,14 @@ pub mod lsp_messages {
         json!({"jsonrpc": "2.0","method": "initialized","params": {}}).to_string()
     }
 
+    pub fn shutdown(id: usize) -> String {
+        json!({"id": id, "jsonrpc": "2.0", "method": "shutdown"}).to_string()
+    }
+
+    pub fn exit() -> String {
+        json!({"jsonrpc": "2.0", "method": "exit"}).to_string()
+    }
+
     pub fn did_change_configuration() -> String {
         json!({
             "jsonrpc":"2.0",
@@ -601,6 +1019,19 @@ pub mod lsp_messages {
         .to_string()
     }
 
+    pub fn did_close(path: &str) -> String {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didClose",
+            "params": {
+                "textDocument": {
+                    "uri": format!("file://{}", path),
+                }
+            }
+        })
+        .to_string()
+    }
+
     pub fn did_open(lang: &str, path: &str, text: &str) -> String {
         json!({
             "jsonrpc": "2.0",
@@ -644,34 +1075,45 @@ pub mod lsp_messages {
         .to_string()
     }
 
-    pub fn did_change(
+    /// A range replaced by the text, for didChange.
+    pub fn content_change(
         line: usize,
         character: usize,
         line_end: usize,
         character_end: usize,
-        path: &str,
         text: &str,
-        version: usize,
-    ) -> String {
+    ) -> Value {
+        json!({
+            "range": {
+                "start": { "line": line, "character": character },
+                "end": { "line": line_end, "character": character_end }
+            },
+            "text": text
+        })
+    }
+
+    /// The changes are applied by the server in order.
+    pub fn did_change(path: &str, changes: Vec<Value>, version: usize) -> String {
         json!({
             "jsonrpc": "2.0",
             "method": "textDocument/didChange",
             "params": {
-                "contentChanges": [
-                {
-                    "range": {
-                        "start": {
-                            "line": line,
-                            "character": character
-                        },
-                        "end": {
-                            "line": line_end,
-                            "character": character_end
-                        }
-                    },
-                    "text": text
+                "contentChanges": changes,
+                "textDocument": {
+                    "uri": format!("file://{}", path),
+                    "version": version
                 }
-                ],
+            }
+        })
+        .to_string()
+    }
+
+    pub fn did_change_full(path: &str, text: &str, version: usize) -> String {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "contentChanges": [{ "text": text }],
                 "textDocument": {
                     "uri": format!("file://{}", path),
                     "version": version
@@ -729,6 +1171,15 @@ pub mod lsp_messages {
         pub data: Option<serde_json::Value>, 
     }
 
+    impl CompletionItem {
+        /// Range and text of the item edit, the replace range for an `InsertReplaceEdit`.
+        pub fn edit(&self) -> Option<(&Range, &str)> {
+            let edit = self.textEdit.as_ref()?;
+            let range = edit.range.as_ref().or(edit.replace.as_ref()).or(edit.insert.as_ref())?;
+            Some((range, &edit.newText))
+        }
+    }
+
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct TextEdit {
         pub range: Option<Range>,
@@ -737,12 +1188,190 @@ pub mod lsp_messages {
         pub newText: String,
     }
     #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct TextEditsResponse {
+        pub jsonrpc: String,
+        pub result: Option<Vec<TextEdit>>,
+        pub id: f64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct RenameResponse {
+        pub jsonrpc: String,
+        pub result: Option<WorkspaceEdit>,
+        pub id: f64,
+    }
+
+    /// Result of `textDocument/prepareRename`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum PrepareRename {
+        /// Range of the symbol and the name to start from if the server gives one.
+        Range(Range, Option<String>),
+        /// Renameable, the client picks the symbol.
+        Default,
+        /// Nothing to rename at the position.
+        Invalid,
+    }
+
+    /// Parses the prepareRename result: a range, a range with placeholder, defaultBehavior or null.
+    pub fn parse_prepare_rename(result: &Value) -> PrepareRename {
+        if result.get("defaultBehavior").is_some() { return PrepareRename::Default; }
+        let placeholder = result["placeholder"].as_str().map(|p| p.to_string());
+        let range = result.get("range").unwrap_or(result);
+        match serde_json::from_value::<Range>(range.clone()) {
+            Ok(range) => PrepareRename::Range(range, placeholder),
+            Err(_) => PrepareRename::Invalid,
+        }
+    }
+
+    /// Status text of a `$/progress` notification, e.g. `indexing 42%`, empty when the last one ends.
+    /// Titles come only with `begin`, so they are kept by token until `end`.
+    pub fn progress_status(params: &Value, titles: &mut HashMap<String, String>) -> Option<String> {
+        let token = match &params["token"] {
+            Value::String(s) => s.clone(),
+            Value::Null => return None,
+            token => token.to_string(),
+        };
+        let value = &params["value"];
+
+        match value["kind"].as_str()? {
+            "begin" => { titles.insert(token.clone(), value["title"].as_str().unwrap_or("").to_string()); },
+            "report" => {},
+            "end" => {
+                titles.remove(&token);
+                return Some(titles.values().next().cloned().unwrap_or_default());
+            },
+            _ => return None,
+        }
+
+        let mut status = titles.get(&token).cloned().unwrap_or_default();
+        if let Some(message) = value["message"].as_str() {
+            status = format!("{} {}", status, message).trim().to_string();
+        }
+        if let Some(percentage) = value["percentage"].as_u64() {
+            status = format!("{} {}%", status, percentage).trim().to_string();
+        }
+        Some(status)
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct WorkspaceEdit {
+        pub changes: Option<HashMap<String, Vec<TextEdit>>>,
+        pub documentChanges: Option<Vec<Value>>,
+    }
+
+    impl WorkspaceEdit {
+        /// Edits by file uri, resource operations (create, rename, delete files) are skipped.
+        pub fn text_edits(self) -> HashMap<String, Vec<TextEdit>> {
+            let mut changes = self.changes.unwrap_or_default();
+
+            for change in self.documentChanges.unwrap_or_default() {
+                if let Ok(change) = serde_json::from_value::<TextDocumentEdit>(change) {
+                    changes.entry(change.textDocument.uri).or_default().extend(change.edits);
+                }
+            }
+            changes
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct CodeActionResponse {
+        pub jsonrpc: String,
+        pub result: Option<Vec<CodeActionOrCommand>>,
+        pub id: f64,
+    }
+
+    /// Either a CodeAction or a bare Command, for a Command `command` is the name string.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct CodeActionOrCommand {
+        pub title: String,
+        pub kind: Option<String>,
+        pub edit: Option<WorkspaceEdit>,
+        pub command: Option<Value>,
+        pub arguments: Option<Vec<Value>>,
+    }
+
+    impl CodeActionOrCommand {
+        /// Command to execute after the edit, as `{ command, arguments }`.
+        pub fn command(&self) -> Option<Value> {
+            match &self.command {
+                Some(Value::String(name)) => Some(json!({
+                    "command": name, "arguments": self.arguments.clone().unwrap_or_default()
+                })),
+                Some(command @ Value::Object(_)) => Some(command.clone()),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct DocumentSymbolResponse {
+        pub jsonrpc: String,
+        pub result: Option<Vec<DocumentSymbol>>,
+        pub id: f64,
+    }
+
+    /// Either a `DocumentSymbol` with children or a flat `SymbolInformation` with location.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct DocumentSymbol {
+        pub name: String,
+        pub kind: u32,
+        pub range: Option<Range>,
+        pub selectionRange: Option<Range>,
+        pub location: Option<ReferencesResult>,
+        pub children: Option<Vec<DocumentSymbol>>,
+    }
+
+    impl DocumentSymbol {
+        /// Position of the symbol name.
+        pub fn start(&self) -> Option<&PositionResponse> {
+            self.selectionRange.as_ref()
+                .or(self.range.as_ref())
+                .or(self.location.as_ref().map(|l| &l.range))
+                .map(|r| &r.start)
+        }
+
+        pub fn kind_name(&self) -> &'static str {
+            const KINDS: [&str; 26] = [
+                "file", "module", "namespace", "package", "class", "method", "property",
+                "field", "constructor", "enum", "interface", "function", "variable", "constant",
+                "string", "number", "boolean", "array", "object", "key", "null", "enum member",
+                "struct", "event", "operator", "type parameter",
+            ];
+            KINDS.get((self.kind as usize).wrapping_sub(1)).copied().unwrap_or("symbol")
+        }
+    }
+
+    /// Symbols with their nesting depth, parents before children.
+    pub fn flatten_symbols(symbols: &[DocumentSymbol]) -> Vec<(usize, &DocumentSymbol)> {
+        fn walk<'a>(symbols: &'a [DocumentSymbol], depth: usize, out: &mut Vec<(usize, &'a DocumentSymbol)>) {
+            for symbol in symbols {
+                out.push((depth, symbol));
+                if let Some(children) = &symbol.children { walk(children, depth + 1, out); }
+            }
+        }
+        let mut out = vec![];
+        walk(symbols, 0, &mut out);
+        out
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct TextDocumentEdit {
+        pub textDocument: TextDocumentIdentifier,
+        pub edits: Vec<TextEdit>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct TextDocumentIdentifier {
+        pub uri: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
     pub struct Range {
         pub start: PositionResponse,
         pub end: PositionResponse,
     }
 
-    #[derive(Debug, Serialize, Deserialize, Clone)]
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
     pub struct PositionResponse {
         pub line: f64,
         pub character: f64,
@@ -771,8 +1400,25 @@ pub mod lsp_messages {
         pub code_description: Option<CodeDescription>,
         pub source: String,
         pub message: String,
+        /// 1 - unnecessary, 2 - deprecated
+        pub tags: Option<Vec<i32>>,
+        /// Server data, sent back as is in code action requests.
+        pub data: Option<Value>,
+        pub relatedInformation: Option<Vec<DiagnosticRelatedInformation>>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct DiagnosticRelatedInformation {
+        pub location: ReferencesResult,
+        pub message: String,
     }
 
+    pub const DIAGNOSTIC_TAG_UNNECESSARY: i32 = 1;
+    pub const DIAGNOSTIC_TAG_DEPRECATED: i32 = 2;
+
+    pub const COMPLETION_KIND_TEXT: u32 = 1;
+    pub const COMPLETION_KIND_SNIPPET: u32 = 15;
+
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct CodeDescription {
         pub href: String,
@@ -825,6 +1471,115 @@ pub mod lsp_messages {
         pub kind: String,
         pub value: String,
     }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct SignatureHelpResponse {
+        pub jsonrpc: String,
+        pub result: Option<SignatureHelp>,
+        pub id: f64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct SignatureHelp {
+        pub signatures: Vec<SignatureInformation>,
+        pub activeSignature: Option<usize>,
+        pub activeParameter: Option<usize>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct SignatureInformation {
+        pub label: String,
+        pub parameters: Option<Vec<ParameterInformation>>,
+        pub activeParameter: Option<usize>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct ParameterInformation {
+        /// Parameter text or its [start, end) utf-16 offsets in the signature label.
+        pub label: Value,
+    }
+
+    impl SignatureHelp {
+        /// Label of the active signature and char range of the active parameter in it.
+        pub fn active(&self) -> Option<(String, Option<(usize, usize)>)> {
+            let signature = self.signatures.get(self.activeSignature.unwrap_or(0))
+                .or(self.signatures.first())?;
+            let label = &signature.label;
+
+            let index = signature.activeParameter.or(self.activeParameter).unwrap_or(0);
+            let parameter = signature.parameters.as_ref().and_then(|p| p.get(index));
+
+            let range = parameter.and_then(|p| match &p.label {
+                Value::String(text) => {
+                    let start = label.find(text.as_str())?;
+                    let start = label[..start].chars().count();
+                    Some((start, start + text.chars().count()))
+                }
+                Value::Array(offsets) => {
+                    let offset = |i: usize| offsets.get(i)?.as_u64().map(|o| o as usize);
+                    let to_char = |utf16: usize| {
+                        let mut units = 0;
+                        label.chars().take_while(|c| { units += c.len_utf16(); units <= utf16 }).count()
+                    };
+                    Some((to_char(offset(0)?), to_char(offset(1)?)))
+                }
+                _ => None,
+            });
+
+            Some((label.clone(), range))
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct SemanticTokensResponse {
+        pub jsonrpc: String,
+        pub result: Option<SemanticTokensResult>,
+        pub id: f64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct SemanticTokensResult {
+        pub data: Vec<usize>,
+    }
+
+    /// Decoded semantic token, start and length in utf-16 units.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct SemanticToken {
+        pub line: usize,
+        pub start: usize,
+        pub length: usize,
+        pub token_type: String,
+        pub modifiers: Vec<String>,
+    }
+
+    /// Decodes relative encoded tokens, 5 numbers per token:
+    /// delta line, delta start, length, type index, modifiers bitset.
+    pub fn decode_semantic_tokens(
+        data: &[usize], types: &[String], modifiers: &[String]
+    ) -> Vec<SemanticToken> {
+        let mut tokens = Vec::with_capacity(data.len() / 5);
+        let (mut line, mut start) = (0, 0);
+
+        for chunk in data.chunks_exact(5) {
+            let (delta_line, delta_start, length, type_index, bits) =
+                (chunk[0], chunk[1], chunk[2], chunk[3], chunk[4]);
+
+            if delta_line > 0 { line += delta_line; start = delta_start; }
+            else { start += delta_start; }
+
+            let token_type = match types.get(type_index) {
+                Some(t) => t.clone(),
+                None => continue,
+            };
+            let modifiers = modifiers.iter().enumerate()
+                .filter(|(i, _)| bits & (1 << i) != 0)
+                .map(|(_, m)| m.clone())
+                .collect();
+
+            tokens.push(SemanticToken { line, start, length, token_type, modifiers });
+        }
+        tokens
+    }
 }
 
 
@@ -873,4 +1628,184 @@ mod tests {
         // Print the deserialized structs
         println!("{:#?}", completion_response);
     }
+
+    #[test]
+    fn test_completion_item_edit() {
+        use lsp_messages::CompletionItem;
+
+        // `std::collections::Has|` completed to HashMap, the edit starts after `::`
+        let item: CompletionItem = serde_json::from_value(json!({
+            "label": "HashMap", "kind": 22,
+            "textEdit": {
+                "range": { "start": { "line": 3, "character": 22 }, "end": { "line": 3, "character": 25 } },
+                "newText": "HashMap"
+            }
+        })).unwrap();
+        let (range, text) = item.edit().unwrap();
+        assert_eq!((range.start.character, range.end.character, text), (22.0, 25.0, "HashMap"));
+
+        // InsertReplaceEdit replaces the whole `std::fmt::Display` path
+        let item: CompletionItem = serde_json::from_value(json!({
+            "label": "std::fmt::Debug", "kind": 8,
+            "textEdit": {
+                "insert": { "start": { "line": 0, "character": 4 }, "end": { "line": 0, "character": 13 } },
+                "replace": { "start": { "line": 0, "character": 4 }, "end": { "line": 0, "character": 21 } },
+                "newText": "std::fmt::Debug"
+            }
+        })).unwrap();
+        let (range, _) = item.edit().unwrap();
+        assert_eq!((range.start.character, range.end.character), (4.0, 21.0));
+
+        let item: CompletionItem = serde_json::from_value(json!({ "label": "len", "kind": 2 })).unwrap();
+        assert!(item.edit().is_none());
+    }
+
+    #[test]
+    fn test_parse_prepare_rename() {
+        use lsp_messages::{parse_prepare_rename, PrepareRename};
+
+        let range = json!({ "start": { "line": 1, "character": 4 }, "end": { "line": 1, "character": 7 } });
+        match parse_prepare_rename(&range) {
+            PrepareRename::Range(r, None) => assert_eq!((r.start.character, r.end.character), (4.0, 7.0)),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let with_placeholder = json!({ "range": range, "placeholder": "foo" });
+        assert!(matches!(parse_prepare_rename(&with_placeholder), PrepareRename::Range(_, Some(p)) if p == "foo"));
+        assert_eq!(parse_prepare_rename(&json!({ "defaultBehavior": true })), PrepareRename::Default);
+        assert_eq!(parse_prepare_rename(&Value::Null), PrepareRename::Invalid);
+    }
+
+    #[test]
+    fn test_progress_status() {
+        use lsp_messages::progress_status;
+
+        let mut titles = HashMap::new();
+        let begin = json!({ "token": "rustAnalyzer/Indexing", "value": { "kind": "begin", "title": "Indexing" } });
+        assert_eq!(progress_status(&begin, &mut titles), Some("Indexing".to_string()));
+
+        let report = json!({ "token": "rustAnalyzer/Indexing", "value": { "kind": "report", "message": "1/24 (core)", "percentage": 42 } });
+        assert_eq!(progress_status(&report, &mut titles), Some("Indexing 1/24 (core) 42%".to_string()));
+
+        let other = json!({ "token": 7, "value": { "kind": "begin", "title": "Roots Scanned" } });
+        assert_eq!(progress_status(&other, &mut titles), Some("Roots Scanned".to_string()));
+
+        let end = json!({ "token": 7, "value": { "kind": "end" } });
+        assert_eq!(progress_status(&end, &mut titles), Some("Indexing".to_string())); // still running
+        let end = json!({ "token": "rustAnalyzer/Indexing", "value": { "kind": "end" } });
+        assert_eq!(progress_status(&end, &mut titles), Some(String::new()));
+
+        assert_eq!(progress_status(&json!({ "token": 1, "value": {} }), &mut titles), None);
+    }
+
+    #[test]
+    fn test_decode_semantic_tokens() {
+        let types = vec!["variable".to_string(), "function".to_string()];
+        let modifiers = vec!["declaration".to_string(), "mutable".to_string()];
+        let data = vec![
+            1, 4, 3, 0, 3,  // line 1, col 4
+            0, 6, 2, 1, 0,  // line 1, col 10
+            2, 2, 5, 1, 0,  // line 3, col 2
+        ];
+
+        let tokens = lsp_messages::decode_semantic_tokens(&data, &types, &modifiers);
+
+        assert_eq!(tokens.len(), 3);
+        assert_eq!((tokens[0].line, tokens[0].start, tokens[0].length), (1, 4, 3));
+        assert_eq!(tokens[0].modifiers, vec!["declaration", "mutable"]);
+        assert_eq!((tokens[1].line, tokens[1].start, &tokens[1].token_type[..]), (1, 10, "function"));
+        assert_eq!((tokens[2].line, tokens[2].start), (3, 2));
+    }
+
+    #[test]
+    fn test_code_action_response() {
+        let message = r#"{"jsonrpc":"2.0","id":7,"result":[
+            {"title":"Import `HashMap`","kind":"quickfix","edit":{"documentChanges":[
+                {"textDocument":{"uri":"file:///a.rs","version":1},"edits":[
+                    {"range":{"start":{"line":0,"character":0},"end":{"line":0,"character":0}},
+                     "newText":"use std::collections::HashMap;\n"}]},
+                {"kind":"create","uri":"file:///b.rs"}]}},
+            {"title":"Organize imports","command":"python.organize","arguments":["file:///a.py"]}
+        ]}"#;
+
+        let response: lsp_messages::CodeActionResponse = serde_json::from_str(message).unwrap();
+        let actions = response.result.unwrap();
+        assert_eq!(actions.len(), 2);
+
+        let edits = actions[0].edit.clone().unwrap().text_edits();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits["file:///a.rs"][0].newText, "use std::collections::HashMap;\n");
+        assert!(actions[0].command().is_none());
+
+        let command = actions[1].command().unwrap();
+        assert_eq!(command["command"], "python.organize");
+        assert_eq!(command["arguments"][0], "file:///a.py");
+    }
+
+    #[test]
+    fn test_document_symbol_response() {
+        let range = r#"{"start":{"line":2,"character":0},"end":{"line":9,"character":1}}"#;
+        let name_range = r#"{"start":{"line":2,"character":7},"end":{"line":2,"character":10}}"#;
+        let message = format!(r#"{{"jsonrpc":"2.0","id":3,"result":[
+            {{"name":"Foo","kind":23,"range":{range},"selectionRange":{name_range},"children":[
+                {{"name":"bar","kind":6,"range":{range},"selectionRange":{name_range}}}]}},
+            {{"name":"main","kind":12,"location":{{"uri":"file:///a.rs","range":{range}}}}}
+        ]}}"#);
+
+        let response: lsp_messages::DocumentSymbolResponse = serde_json::from_str(&message).unwrap();
+        let symbols = response.result.unwrap();
+        let flat = lsp_messages::flatten_symbols(&symbols);
+
+        let names: Vec<_> = flat.iter().map(|(depth, s)| (*depth, s.name.as_str(), s.kind_name())).collect();
+        assert_eq!(names, vec![(0, "Foo", "struct"), (1, "bar", "method"), (0, "main", "function")]);
+        assert_eq!(flat[1].1.start().unwrap().character, 7.0);
+        assert_eq!(flat[2].1.start().unwrap().line, 2.0);
+    }
+
+    #[test]
+    fn test_signature_help_response() {
+        let message = r#"{"jsonrpc":"2.0","id":5,"result":{
+            "signatures":[{"label":"fn add(a: i32, b: i32) -> i32",
+                "parameters":[{"label":[7,13]},{"label":[15,21]}]}],
+            "activeSignature":0,"activeParameter":1}}"#;
+        let response: lsp_messages::SignatureHelpResponse = serde_json::from_str(message).unwrap();
+        let (label, range) = response.result.unwrap().active().unwrap();
+        assert_eq!(label, "fn add(a: i32, b: i32) -> i32");
+        assert_eq!(range, Some((15, 21)));
+
+        let message = r#"{"jsonrpc":"2.0","id":6,"result":{
+            "signatures":[{"label":"print(value, sep)","parameters":[{"label":"value"},{"label":"sep"}],
+                "activeParameter":0}]}}"#;
+        let response: lsp_messages::SignatureHelpResponse = serde_json::from_str(message).unwrap();
+        assert_eq!(response.result.unwrap().active().unwrap().1, Some((6, 11)));
+
+        let message = r#"{"jsonrpc":"2.0","id":7,"result":null}"#;
+        let response: lsp_messages::SignatureHelpResponse = serde_json::from_str(message).unwrap();
+        assert!(response.result.is_none());
+    }
+
+    #[test]
+    fn test_did_change() {
+        let changes = vec![
+            lsp_messages::content_change(0, 1, 0, 1, "a"),
+            lsp_messages::content_change(0, 2, 0, 2, "b"),
+        ];
+        let message = lsp_messages::did_change("/a.rs", changes, 4);
+        let json: serde_json::Value = serde_json::from_str(&message).unwrap();
+        let changes = json["params"]["contentChanges"].as_array().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[1]["range"]["start"]["character"], 2);
+        assert_eq!(changes[1]["text"], "b");
+        assert_eq!(json["params"]["textDocument"]["version"], 4);
+    }
+
+    #[test]
+    fn test_did_change_full() {
+        let message = lsp_messages::did_change_full("/a.rs", "fn main() {}\n", 3);
+        let json: serde_json::Value = serde_json::from_str(&message).unwrap();
+        let change = &json["params"]["contentChanges"][0];
+        assert_eq!(change["text"], "fn main() {}\n");
+        assert!(change.get("range").is_none());
+        assert_eq!(json["params"]["textDocument"]["version"], 3);
+    }
 }
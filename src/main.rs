@@ -8,6 +8,10 @@ mod tree;
 mod tests;
 mod utils;
 mod config;
+mod keys;
+mod git;
+
+use std::io::IsTerminal;
 
 use editor::Editor;
 
@@ -28,13 +32,41 @@ async fn main() {
 
     editor.handle_panic();
 
-    match std::env::args().nth(1) {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let lang = match args.iter().position(|a| a == "--lang") {
+        Some(i) if i + 1 < args.len() => Some(args.drain(i..i+2).last().unwrap()),
+        _ => None,
+    };
+
+    // red +42 file
+    let line = match args.iter().position(|a| a.len() > 1 && a.starts_with('+') && a[1..].parse::<usize>().is_ok()) {
+        Some(i) => args.remove(i)[1..].parse::<usize>().ok(),
+        None => None,
+    };
+
+    let mut args = args.into_iter();
+    match args.next() {
         None => editor.open_left_panel(),
         Some(path) if path == "." || path == "./" =>
             editor.open_left_panel(),
-        Some(path) => {
+        Some(path) if path == "-" => { // read buffer from stdin, before raw mode
+            // not a pipe, start empty instead of waiting for ctrl+d
+            let text = match std::io::stdin().is_terminal() {
+                true => String::new(),
+                false => std::io::read_to_string(std::io::stdin()).unwrap_or_default(),
+            };
+            editor.close_left_panel();
+            editor.load_stdin(&text, lang);
+        }
+        Some(arg) => {
+            let (path, line_in_path, col) = utils::parse_file_position(&arg);
             editor.close_left_panel();
             editor.load_file(&path);
+            if let Some(line) = line_in_path.or(line) {
+                editor.set_position(line, col);
+            }
+            // red a.rs b.rs, the rest open as buffers
+            editor.add_files(args.map(|arg| utils::parse_file_position(&arg).0).collect());
         }
     }
 
@@ -2,6 +2,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
+use crate::utils;
 
 pub struct Process {
     kill_sender: Option<tokio::sync::mpsc::Sender<String>>,
@@ -34,7 +35,7 @@ impl Process {
         let args = vec![args.clone()];
         self.last_cmd = args.join(" ");
 
-        tokio::spawn(async move {
+        utils::spawn("tmux", async move {
             Command::new(&cmd).args(args)
                 .output().await.unwrap();
         });
@@ -75,7 +76,7 @@ impl Process {
         let process_lines_data = self.process_lines.clone();
         let upd_process_needed = self.upd_process.clone();
 
-        tokio::spawn(async move {
+        utils::spawn("process output", async move {
             // reading stdout task
             let reader = BufReader::new(child_stdout);
             let mut lines = reader.lines();
@@ -92,7 +93,7 @@ impl Process {
         let process_started = self.process_started.clone();
         let upd_process_needed = self.upd_process.clone();
 
-        tokio::spawn(async move {
+        utils::spawn("process", async move {
             loop {
                 tokio::select! {
                     Some(m) = kill.recv() => { // killing manually
@@ -136,7 +137,7 @@ impl Process {
         }
 
         if let Some(sender) = self.kill_sender.take() {
-            tokio::spawn(async move {
+            utils::spawn("process kill", async move {
                 sender
                     .send("".to_owned())
                     .await
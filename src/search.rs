@@ -4,12 +4,64 @@ pub struct Search {
     pub pattern: ropey::Rope,
     pub results: Vec<SearchResult>,
     pub index:usize,
+    pub replacement: ropey::Rope,
+    /// Replace line is shown, typing goes to it while `replacement_focused`.
+    pub replacing: bool,
+    pub replacement_focused: bool,
+    pub options: SearchOptions,
+    /// Why the pattern is not a valid regex.
+    pub error: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct SearchResult {
     pub line: usize,
-    pub position: usize
+    pub position: usize,
+    /// Chars of the match, regex matches differ in length.
+    pub len: usize,
+}
+
+/// How the pattern matches, toggled in the search prompt.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SearchOptions {
+    pub regex: bool,
+    pub case_insensitive: bool,
+    pub whole_word: bool,
+}
+
+impl SearchOptions {
+    /// Matches the pattern as typed, without a regex.
+    pub fn is_plain(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Regex of the pattern, escaped unless in regex mode. Errors with the reason of an invalid regex.
+    pub fn matcher(&self, pattern: &str) -> Result<regex::Regex, String> {
+        let mut re = match self.regex {
+            true => pattern.to_string(),
+            false => regex::escape(pattern),
+        };
+        if self.whole_word { re = format!(r"\b(?:{})\b", re); }
+
+        regex::RegexBuilder::new(&re)
+            .case_insensitive(self.case_insensitive)
+            .multi_line(true)
+            .build()
+            .map_err(|e| { // the last line has the reason, the rest points at it
+                let e = e.to_string();
+                e.lines().last().unwrap_or_default().trim_start_matches("error: ").to_string()
+            })
+    }
+
+    /// Marks of the modes that are on, e.g. ` [.*][Aa]`.
+    pub fn indicator(&self) -> String {
+        let mut indicator = String::new();
+        if self.regex { indicator.push_str("[.*]"); }
+        if self.case_insensitive { indicator.push_str("[Aa]"); }
+        if self.whole_word { indicator.push_str("[w]"); }
+        if !indicator.is_empty() { indicator.insert(0, ' '); }
+        indicator
+    }
 }
 
 impl Search {
@@ -18,7 +70,12 @@ impl Search {
             active: false,
             pattern: ropey::Rope::new(),
             results: Vec::new(),
-            index: 0
+            index: 0,
+            replacement: ropey::Rope::new(),
+            replacing: false,
+            replacement_focused: false,
+            options: SearchOptions::default(),
+            error: None,
         }
     }
 }
@@ -26,6 +83,7 @@ impl Search {
 
 pub mod search {
     use std::path::{Path, PathBuf};
+    use crate::utils::Ignore;
     use std::{fs, time};
 
     pub const IGNORE_EXTS: &[&str] = &[
@@ -34,7 +92,7 @@ pub mod search {
         "rar", "tar.gz", "7z", "exe", "msi", "bat", "sh", "so", "ttf", "otf",
     ];
 
-    pub fn read_directory_recursive(dir_path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    pub fn read_directory_recursive(dir_path: &Path, ignore: &Ignore) -> Result<Vec<PathBuf>, io::Error> {
         let mut paths = Vec::new();
 
         let entries = fs::read_dir(dir_path)?;
@@ -46,12 +104,12 @@ pub mod search {
                 None => continue,
             };
 
-            if crate::utils::IGNORE_DIRS.contains(&file_name.to_string().as_str()) {
+            if ignore.is_dir_ignored(&file_name) || ignore.is_git_ignored(&path, path.is_dir()) {
                 continue;
             }
 
             if path.is_dir() {
-                let sub_dir_result = read_directory_recursive(&path)?;
+                let sub_dir_result = read_directory_recursive(&path, ignore)?;
                 paths.extend(sub_dir_result);
             } else {
                 let file_ext = match path.extension() {
@@ -59,7 +117,7 @@ pub mod search {
                     None => continue,
                 };
 
-                if !IGNORE_EXTS.contains(&file_ext.to_string().as_str()) {
+                if !ignore.is_ext_ignored(&file_ext) {
                     paths.push(path);
                 }
             }
@@ -68,16 +126,28 @@ pub mod search {
         Ok(paths)
     }
 
+    /// Project files relative to the dir, sorted, at most `limit` of them.
+    pub fn project_files(dir: &Path, ignore: &Ignore, limit: usize) -> Result<Vec<String>, io::Error> {
+        let mut files: Vec<String> = read_directory_recursive(dir, ignore)?
+            .iter()
+            .map(|path| path.strip_prefix(dir).unwrap_or(path).to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files.truncate(limit);
+        Ok(files)
+    }
+
     #[cfg(test)]
     mod file_search_tests {
         use std::path::Path;
+        use crate::utils::Ignore;
         use super::read_directory_recursive;
 
         #[test]
         fn test_file_search() {
             let directory_path = Path::new("./");
 
-            let file_names = read_directory_recursive(&directory_path);
+            let file_names = read_directory_recursive(&directory_path, &Ignore::new());
 
             for name in file_names.unwrap() {
                 println!("{}", name.display());
@@ -88,16 +158,51 @@ pub mod search {
     use std::fs::File;
     use std::io::{self, BufRead};
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::time::Instant;
+    use regex::Regex;
 
     #[derive(Debug)]
     pub struct SearchResult {
         pub line: usize,
         pub column: usize,
-        preview: String,
+        /// Chars of the match.
+        pub len: usize,
+        /// The line of the match.
+        pub preview: String,
+    }
+
+    impl SearchResult {
+        /// Preview text before, of and after the match, without the indent and cut to `width` chars
+        /// with the match in view.
+        pub fn preview_parts(&self, width: usize) -> (String, String, String) {
+            let chars: Vec<char> = self.preview.chars().map(|c| if c == '\t' { ' ' } else { c }).collect();
+            let (from, to) = (self.column.min(chars.len()), (self.column + self.len).min(chars.len()));
+
+            let mut start = chars.iter().take_while(|c| c.is_whitespace()).count().min(from);
+            if to > start + width { start = to.saturating_sub(width).min(from); }
+            let end = (start + width).min(chars.len());
+
+            let text = |from: usize, to: usize| chars[from..to.max(from)].iter().collect::<String>();
+            (text(start, from.min(end)), text(from.min(end), to.min(end)), text(to.min(end), end))
+        }
+    }
+
+    #[test]
+    fn test_preview_parts() {
+        let result = |preview: &str, column: usize, len: usize| SearchResult {
+            line: 1, column, len, preview: preview.to_string(),
+        };
+        let parts = |before: &str, matched: &str, after: &str|
+            (before.to_string(), matched.to_string(), after.to_string());
+
+        assert_eq!(result("    let x = 1;", 8, 1).preview_parts(40), parts("let ", "x", " = 1;"));
+        assert_eq!(result("\tfoo(bar)", 5, 3).preview_parts(6), parts("oo(", "bar", "")); // match end in view
+        assert_eq!(result("abcdefgh", 2, 5).preview_parts(3), parts("", "cde", ""));
     }
 
-    fn search_on_file(file_path: &str, substring_to_find: &str) -> io::Result<Vec<SearchResult>> {
+    /// First match of each line of the file.
+    fn search_on_file(file_path: &str, matcher: &Regex) -> io::Result<Vec<SearchResult>> {
         let file = File::open(file_path)?;
         let reader = io::BufReader::new(file);
 
@@ -108,10 +213,11 @@ pub mod search {
             line_number += 1;
             let line = line_result?;
 
-            if let Some(index) = line.find(substring_to_find) {
+            if let Some(found) = matcher.find_iter(&line).find(|m| !m.is_empty()) {
                 let search_result = SearchResult {
                     line: line_number,
-                    column: index,
+                    column: line[..found.start()].chars().count(),
+                    len: found.as_str().chars().count(),
                     preview: line.clone(),
                 };
                 results.push(search_result);
@@ -146,18 +252,19 @@ pub mod search {
 
     pub fn search_in_directory(
         directory_path: &Path,
-        substring_to_find: &str,
+        matcher: &Regex,
+        ignore: &Ignore,
     ) -> io::Result<Vec<FileSearchResult>> {
         use rayon::prelude::*;
 
-        let file_paths = read_directory_recursive(directory_path)?;
+        let file_paths = read_directory_recursive(directory_path, ignore)?;
 
         file_paths
             // .into_iter()
             .par_iter()
             .map(|file_path| {
                 let path = file_path.to_str().expect("Invalid file path");
-                let search_results = search_on_file(path, substring_to_find)?;
+                let search_results = search_on_file(path, matcher)?;
                 Ok(FileSearchResult {
                     file_path: file_path.to_string_lossy().to_string(),
                     search_results,
@@ -166,6 +273,50 @@ pub mod search {
             .collect()
     }
 
+    /// Searches the files of the dir in parallel and passes the matches of each file to `found`
+    /// as soon as it is read, files that can not be read are skipped. Stops once `cancel` is set.
+    pub fn stream_search_in_directory(
+        directory_path: &Path,
+        matcher: &Regex,
+        ignore: &Ignore,
+        cancel: &AtomicBool,
+        found: impl Fn(FileSearchResult) + Sync,
+    ) -> io::Result<()> {
+        use rayon::prelude::*;
+
+        let file_paths = read_directory_recursive(directory_path, ignore)?;
+
+        file_paths.par_iter().for_each(|file_path| {
+            if cancel.load(Ordering::Relaxed) { return; }
+            let file_path = file_path.to_string_lossy().to_string();
+            match search_on_file(&file_path, matcher) {
+                Ok(search_results) if !search_results.is_empty() =>
+                    found(FileSearchResult { file_path, search_results }),
+                _ => {},
+            }
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_search_in_directory() {
+        use std::sync::Mutex;
+
+        let found = Mutex::new(vec![]);
+        let cancel = AtomicBool::new(false);
+        let matcher = Regex::new("fn stream_search_in_directory").unwrap();
+        stream_search_in_directory(Path::new("./src"), &matcher, &Ignore::new(),
+            &cancel, |fsr| found.lock().unwrap().push(fsr)).unwrap();
+        let found = found.into_inner().unwrap();
+        assert!(found.iter().any(|fsr| fsr.file_path.ends_with("search.rs")));
+
+        let cancel = AtomicBool::new(true);
+        let count = std::sync::atomic::AtomicUsize::new(0);
+        stream_search_in_directory(Path::new("./src"), &Regex::new("fn").unwrap(), &Ignore::new(),
+            &cancel, |_| { count.fetch_add(1, Ordering::Relaxed); }).unwrap();
+        assert_eq!(count.into_inner(), 0);
+    }
+
     use tokio::task;
 
     #[tokio::test]
@@ -174,10 +325,10 @@ pub mod search {
 
         // let directory_path = Path::new("/Users/max/Downloads/spark");
         let directory_path = Path::new("./");
-        let substring_to_find = "test";
+        let matcher = Regex::new("test").unwrap();
 
         let start = Instant::now();
-        let search_results = search_in_directory(directory_path, substring_to_find);
+        let search_results = search_in_directory(directory_path, &matcher, &Ignore::new());
         let elapsed = Instant::now() - start;
 
         match search_results {
@@ -329,7 +480,8 @@ mod tokio_tests {
         let path = Path::new("./");
 
         let files =
-            crate::search::search::read_directory_recursive(path).expect("can not read dir");
+            crate::search::search::read_directory_recursive(path, &crate::utils::Ignore::new())
+                .expect("can not read dir");
         println!("Len files {}", files.len());
 
         for f in files {
@@ -33,7 +33,9 @@ pub struct Selection {
     pub start: Point,
     pub end: Point,
     pub active: bool,
-    pub keep_once: bool
+    pub keep_once: bool,
+    /// Rectangle between start and end instead of the text between them.
+    pub block: bool,
 }
 
 impl Selection {
@@ -43,6 +45,7 @@ impl Selection {
             end: Point { y: -1, x: -1 },
             active: false,
             keep_once: false,
+            block: false,
         }
     }
     pub fn clean(&mut self) {
@@ -51,6 +54,7 @@ impl Selection {
         self.end.y = -1;
         self.end.x = -1;
         self.active = false;
+        self.block = false;
     }
 
     pub fn activate(&mut self) {
@@ -81,6 +85,11 @@ impl Selection {
     pub fn contains(&mut self, y: usize, x: usize) -> bool {
         if self.empty() { return false }
 
+        if self.block {
+            let (from, to, xfrom, xto) = self.block_range();
+            return from <= y && y <= to && xfrom <= x && x < xto;
+        }
+
         let p = Point {x: x as i32, y: y as i32};
 
         let result = if self.start.greater_than(&self.end) {
@@ -107,9 +116,21 @@ impl Selection {
         else { (self.end.y as usize, self.end.x as usize) }
     }
 
+    /// First and last row and the column range of a block selection, end column exclusive.
+    pub fn block_range(&self) -> (usize, usize, usize, usize) {
+        let (y, yto) = (self.start.y.min(self.end.y), self.start.y.max(self.end.y));
+        let (x, xto) = (self.start.x.min(self.end.x), self.start.x.max(self.end.x));
+        (y as usize, yto as usize, x as usize, xto as usize)
+    }
+
+    /// Exchanges the anchor and the active end, unlike `swap` which only orders them.
+    pub fn swap_ends(&mut self) {
+        std::mem::swap(&mut self.start, &mut self.end);
+    }
+
     pub fn swap(&mut self) {
         if self.start.greater_than(&self.end) {
             std::mem::swap(&mut self.start, &mut self.end);
         }
     }
-}
\ No newline at end of file
+}
@@ -218,6 +218,210 @@ mod tests_text {
     }
 }
 
+#[cfg(test)]
+mod tests_utils {
+    use crate::utils::{
+        background_task, decode, encode, fuzzy_match, fuzzy_score, parse_snippet, load_cursor_positions, load_session, parse_file_position,
+        path_boundaries, save_cursor_position, save_session, spawn, word_boundaries, find_next_word, find_prev_word, CursorPosition, Gitignore,
+        Session,
+    };
+
+    #[test]
+    fn test_gitignore() {
+        let dir = std::env::temp_dir().join("red_gitignore_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join(".gitignore"),
+            "# comment\n*.log\n!keep.log\n/build\ndocs/*.html\ncache/\n**/gen/*.rs\n").unwrap();
+        std::fs::write(dir.join("sub/.gitignore"), "local.txt\n!important.log\n").unwrap();
+        let dir = dir.canonicalize().unwrap(); // as the root is
+
+        let gitignore = Gitignore::new(&dir.join("sub")); // finds the repo root
+        let ignored = |path: &str, is_dir: bool| gitignore.is_ignored(&dir.join(path), is_dir);
+
+        assert!(ignored("a.log", false));
+        assert!(ignored("sub/b.log", false));
+        assert!(!ignored("keep.log", false));
+        assert!(!ignored("sub/important.log", false));
+        assert!(ignored("build", true));
+        assert!(!ignored("sub/build", true));
+        assert!(ignored("docs/x.html", false));
+        assert!(!ignored("docs/y/x.html", false));
+        assert!(ignored("sub/cache", true));
+        assert!(!ignored("cache", false));
+        assert!(ignored("sub/local.txt", false));
+        assert!(!ignored("local.txt", false));
+        assert!(ignored("gen/x.rs", false));
+        assert!(ignored("a/b/gen/x.rs", false));
+        assert!(!ignored("a/gen/b/x.rs", false));
+        assert!(!gitignore.is_ignored(std::path::Path::new("/outside/a.log"), false));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_word_boundaries() {
+        let line = "let tree_view = TreeView::new();";
+        assert_eq!(word_boundaries(line, 4, ""), Some((4, 13)));
+        assert_eq!(word_boundaries(line, 8, ""), Some((4, 13)));
+        assert_eq!(word_boundaries(line, 13, ""), Some((4, 13))); // right after the word
+        assert_eq!(word_boundaries(line, 16, ""), Some((16, 24)));
+        assert_eq!(word_boundaries(line, 14, ""), None);
+        assert_eq!(word_boundaries("", 0, ""), None);
+
+        let line = "color: var(--main-bg);";
+        assert_eq!(word_boundaries(line, 13, ""), Some((13, 17)));
+        assert_eq!(word_boundaries(line, 13, "-"), Some((11, 20)));
+    }
+
+    #[test]
+    fn test_word_chars() {
+        let line = "margin-top: 0;";
+        assert_eq!(find_next_word(line, 0, ""), 6);
+        assert_eq!(find_next_word(line, 0, "-"), 10);
+        assert_eq!(find_prev_word(line, 9, ""), 7);
+        assert_eq!(find_prev_word(line, 9, "-"), 0);
+    }
+
+    #[test]
+    fn test_path_boundaries() {
+        let line = "mod x; // see ../src/main-old.rs, ok";
+        assert_eq!(path_boundaries(line, 16), Some((14, 32)));
+        assert_eq!(path_boundaries(line, 32), Some((14, 32)));
+        assert_eq!(path_boundaries("import \"./a.js\";", 9), Some((8, 14)));
+        assert_eq!(path_boundaries("a  b", 2), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("héllo".as_bytes(), "utf-8"), Some("héllo".to_string()));
+        assert_eq!(decode(b"h\xe9llo", "utf-8"), None);
+        assert_eq!(decode(b"h\xe9llo", "latin1"), Some("héllo".to_string()));
+        assert_eq!(decode(b"\x80 \x93q\x94", "windows-1252"), Some("€ “q”".to_string()));
+        assert_eq!(decode(b"\xff\xfeh\x00i\x00", "utf-16le"), Some("hi".to_string()));
+        assert_eq!(decode(b"\x00h\x00i", "utf-16be"), Some("hi".to_string()));
+        assert_eq!(decode(b"\x00h\x00", "utf-16be"), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode("héllo", "latin1"), Some(b"h\xe9llo".to_vec()));
+        assert_eq!(encode("€ “q”", "windows-1252"), Some(b"\x80 \x93q\x94".to_vec()));
+        assert_eq!(encode("hi", "utf-16le"), Some(b"\xff\xfeh\x00i\x00".to_vec()));
+        assert_eq!(encode("hi", "utf-16be"), Some(b"\xfe\xff\x00h\x00i".to_vec()));
+        assert_eq!(encode("€", "latin1"), None);
+        assert_eq!(encode("\u{80}", "windows-1252"), None);
+
+        for encoding in ["windows-1252", "utf-16le", "utf-16be"] {
+            let bytes = encode("ça “va”", encoding).unwrap();
+            assert_eq!(decode(&bytes, encoding), Some("ça “va”".to_string()), "{}", encoding);
+        }
+    }
+
+    #[test]
+    fn test_fuzzy_match() {
+        assert_eq!(fuzzy_match("handle_keyboard", "hkb"), Some(vec![0, 7, 10]));
+        assert_eq!(fuzzy_match("Editor::new", "en"), Some(vec![0, 8]));
+        assert_eq!(fuzzy_match("draw", ""), Some(vec![]));
+        assert_eq!(fuzzy_match("draw", "wd"), None);
+    }
+
+    #[test]
+    fn test_parse_snippet() {
+        assert_eq!(parse_snippet("fn ${1:name}($2) {\n\t$0\n}"),
+            ("fn name() {\n\t\n}".to_string(), vec![(3, 4), (8, 0), (13, 0)]));
+        assert_eq!(parse_snippet("${2:b} ${1:a}"), ("b a".to_string(), vec![(2, 1), (0, 1), (3, 0)]));
+        assert_eq!(parse_snippet("\\$1 $"), ("$1 $".to_string(), vec![(4, 0)]));
+    }
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert_eq!(fuzzy_score("src/editor.rs", "xyz"), None);
+        let score = |text| fuzzy_score(text, "main").unwrap();
+        assert!(score("src/main.rs") > score("src/domain/mod_init.rs"));
+        assert!(score("src/domain/main.rs") > score("src/domain/mod_init.rs"));
+        assert!(score("src/main.rs") > score("src/my_admin.rs"));
+        assert!(fuzzy_score("src/EditorView.rs", "ev") > fuzzy_score("src/element.rs", "ev"));
+    }
+
+    #[test]
+    fn test_parse_file_position() {
+        let p = |path: &str, line, col| (path.to_string(), line, col);
+        assert_eq!(parse_file_position("src/nope.rs"), p("src/nope.rs", None, None));
+        assert_eq!(parse_file_position("src/nope.rs:42"), p("src/nope.rs", Some(42), None));
+        assert_eq!(parse_file_position("src/nope.rs:42:7"), p("src/nope.rs", Some(42), Some(7)));
+        assert_eq!(parse_file_position("src/nope.rs:42:7:"), p("src/nope.rs", Some(42), Some(7)));
+        assert_eq!(parse_file_position("a:b.rs:3"), p("a:b.rs", Some(3), None));
+        assert_eq!(parse_file_position("C:\\src\\nope.rs:5"), p("C:\\src\\nope.rs", Some(5), None));
+        assert_eq!(parse_file_position("C:12"), p("C:12", None, None));
+    }
+
+    #[test]
+    fn test_cursor_positions() {
+        let dir = std::env::temp_dir().join("red_sessions_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("sessions.json");
+        let file = std::env::current_dir().unwrap().join("src/main.rs").to_string_lossy().to_string();
+
+        let position = |filename: &str, row| CursorPosition {
+            filename: filename.to_string(), row, col: 1, y: 0, x: 0,
+        };
+        assert!(load_cursor_positions(&path).is_empty());
+
+        save_cursor_position(&path, position(&file, 3)).unwrap();
+        save_cursor_position(&path, position("/no/such/file.rs", 5)).unwrap();
+        save_cursor_position(&path, position(&file, 7)).unwrap();
+        assert_eq!(load_cursor_positions(&path), vec![position(&file, 7)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_session() {
+        let dir = std::env::temp_dir().join("red_session_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("session.json");
+        let cwd = std::env::current_dir().unwrap();
+        let file = |name: &str| cwd.join(name).to_string_lossy().to_string();
+
+        assert_eq!(load_session(&path, "/project"), None);
+
+        let session = Session {
+            files: vec![file("src/main.rs"), file("src/nope.rs"), file("src/utils.rs")],
+            active: file("src/nope.rs"),
+            root: file("src"),
+        };
+        save_session(&path, "/project", session).unwrap();
+        let other = Session { files: vec![file("src/main.rs")], active: file("src/main.rs"), root: file("nope") };
+        save_session(&path, "/other", other).unwrap();
+
+        // missing files are skipped, the active one falls back to the last file
+        assert_eq!(load_session(&path, "/project"), Some(Session {
+            files: vec![file("src/main.rs"), file("src/utils.rs")],
+            active: file("src/utils.rs"),
+            root: file("src"),
+        }));
+        assert_eq!(load_session(&path, "/other").unwrap().root, ""); // a missing root is dropped
+
+        save_session(&path, "/project", Session { files: vec![], active: String::new(), root: String::new() }).unwrap();
+        assert_eq!(load_session(&path, "/project"), None);
+        assert!(load_session(&path, "/other").is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spawn_background_task() {
+        assert_eq!(background_task(), None);
+        assert_eq!(spawn("named", async { background_task() }).await.unwrap(), Some("named"));
+
+        // like the lsp reader on a broken message, only the task ends
+        let broken = spawn("broken", async { serde_json::from_str::<serde_json::Value>("{").unwrap() });
+        assert!(broken.await.is_err());
+    }
+}
+
 #[cfg(test)]
 mod tests_selection {
     use crate::selection::{Point, Selection};
@@ -255,6 +459,38 @@ mod tests_selection {
         assert!(!selection.contains(1, 1));
         assert!(!selection.contains(3, 3));
     }
+
+    #[test]
+    fn test_is_selected_in_block() {
+        let mut selection = Selection::new();
+        selection.start = Point { y: 3, x: 6 };
+        selection.end = Point { y: 1, x: 2 };
+        selection.active = true;
+
+        assert!(selection.is_selected(2, 10)); // linear selection spans the middle row
+        selection.block = true;
+        assert_eq!(selection.block_range(), (1, 3, 2, 6));
+        assert!(selection.is_selected(2, 2) && selection.is_selected(3, 5));
+        assert!(!selection.is_selected(2, 10) && !selection.is_selected(2, 6) && !selection.is_selected(0, 3));
+
+        selection.clean();
+        assert!(!selection.block);
+    }
+
+    #[test]
+    fn test_swap_ends() {
+        let mut selection = Selection::new();
+        selection.start = Point { y: 1, x: 2 };
+        selection.end = Point { y: 3, x: 6 };
+
+        selection.swap_ends();
+        assert!(selection.start.equal(&Point { y: 3, x: 6 }) && selection.end.equal(&Point { y: 1, x: 2 }));
+        assert_eq!(selection.from(), (1, 2));
+        assert_eq!(selection.to(), (3, 6));
+
+        selection.swap(); // only orders, a swapped pair is back to start before end
+        assert!(selection.start.equal(&Point { y: 1, x: 2 }));
+    }
 }
 
 #[cfg(test)]
@@ -10,20 +10,26 @@ use serde::de;
 use tokio::sync::watch::error;
 
 use crate::utils;
-use crate::utils::{IGNORE_DIRS, IGNORE_FILES};
+use crate::utils::Ignore;
+use std::sync::Arc;
 use crossterm::style::{Color, SetBackgroundColor as BColor, SetForegroundColor as FColor};
 
+/// Max depth for expand all, to keep huge trees responsive.
+const EXPAND_ALL_MAX_DEPTH: usize = 8;
+
 #[derive(Debug)]
 pub struct TreeNode {
     name: String,
     fullpath: String,
     is_file: bool,
     children: Option<Vec<TreeNode>>,
+    /// Shared by all nodes of the tree.
+    ignore: Arc<Ignore>,
 }
 
 impl TreeNode {
-    pub fn new(name:String, fullpath:String, is_file: bool) -> Self {
-        Self { name, fullpath, is_file, children: None }
+    pub fn new(name:String, fullpath:String, is_file: bool, ignore: Arc<Ignore>) -> Self {
+        Self { name, fullpath, is_file, children: None, ignore }
     }
     pub fn print(&self) { println!("node {:?}", self); }
     pub fn is_file(&mut self) -> bool { self.is_file }
@@ -48,11 +54,13 @@ impl TreeNode {
             };
             let fullpath = abs_path.to_string_lossy().to_string();
 
-            if !is_file && IGNORE_DIRS.contains(&name.as_str()) { continue; }
-            if is_file && IGNORE_FILES.contains(&name.as_str()) { continue; }
+            if !is_file && self.ignore.is_dir_ignored(&name) { continue; }
+            if is_file && self.ignore.is_file_ignored(&name) { continue; }
+            if self.ignore.is_git_ignored(&abs_path, !is_file) { continue; }
 
-            if is_file { files.push(TreeNode::new(name, fullpath, is_file)); }
-            else { directories.push(TreeNode::new(name, fullpath, is_file)); }
+            let node = TreeNode::new(name, fullpath, is_file, self.ignore.clone());
+            if is_file { files.push(node); }
+            else { directories.push(node); }
         }
 
         directories.sort_by(|a, b| a.name.cmp(&b.name));
@@ -65,6 +73,50 @@ impl TreeNode {
         Ok(())
     }
 
+    /// Expands directories recursively, up to `depth` levels down.
+    pub fn expand_all(&mut self, depth: usize) -> io::Result<()> {
+        if self.is_file || depth == 0 { return Ok(()); }
+        if self.children.is_none() { self.expand()?; }
+
+        if let Some(children) = &mut self.children {
+            for child in children.iter_mut() {
+                child.expand_all(depth - 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Collapses all nested directories, keeping this node expanded.
+    pub fn collapse_all(&mut self) {
+        if let Some(children) = &mut self.children {
+            for child in children.iter_mut() {
+                child.collapse();
+            }
+        }
+    }
+
+    /// Re-reads the directory, keeping expanded subdirectories expanded.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        let old = match self.children.take() {
+            Some(old) => old,
+            None => return Ok(()),
+        };
+        self.expand()?;
+
+        let mut expanded: Vec<TreeNode> = old.into_iter()
+            .filter(|n| n.children.is_some()).collect();
+
+        if let Some(children) = &mut self.children {
+            for child in children.iter_mut() {
+                if let Some(i) = expanded.iter().position(|n| n.fullpath == child.fullpath) {
+                    child.children = expanded.swap_remove(i).children;
+                    child.refresh()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn toggle(&mut self) -> io::Result<()> {
         if self.children.is_none() {
             self.expand()?;
@@ -162,6 +214,11 @@ pub struct TreeView {
     active_file_color: Color,
 
     search: FileSearch,
+
+    /// Fullpath of the node being dragged.
+    drag_source: Option<String>,
+    /// Index of the directory under the cursor while dragging.
+    drop_target: Option<usize>,
 }
 
 impl TreeView {
@@ -175,7 +232,7 @@ impl TreeView {
             fullpath: utils::abs_file(&dir), 
             is_file: false,
             children: None,
-
+            ignore: Arc::new(Ignore::new()),
         };
 
         root.expand();
@@ -184,6 +241,7 @@ impl TreeView {
             moving: false, dir_color: Color::Reset, file_color: Color::Reset,
             active_file: String::new(), active_file_color: Color::Reset,
             search: FileSearch::new(),
+            drag_source: None, drop_target: None,
         }
     }
 
@@ -193,9 +251,14 @@ impl TreeView {
     pub fn set_file_color(&mut self, c: Color) { self.file_color = c; self.upd = true; }
     pub fn set_active_file_color(&mut self, c: Color) { self.active_file_color = c; self.upd = true; }
     pub fn set_moving(&mut self, m: bool) { self.moving = m; self.upd = true; }
-    pub fn set_selected(&mut self, i: usize) { self.selected = i + self.x; self.upd = true; }
+    pub fn set_selected(&mut self, i: usize) {
+        self.selected = i + self.x;
+        self.clamp_selected();
+        self.upd = true;
+    }
     pub fn is_moving(&mut self) -> bool { self.moving }
     pub fn is_search(&mut self) -> bool { self.search.active }
+    pub fn is_dragging(&self) -> bool { self.drag_source.is_some() }
 
     pub(crate) fn handle_up(&mut self) {
         if self.selected == 0 { return; }
@@ -225,6 +288,73 @@ impl TreeView {
         self.upd = true;
     }
 
+    /// Keeps `selected` and scroll within the visible nodes.
+    fn clamp_selected(&mut self) {
+        let last = self.root.len();
+        if self.selected > last { self.selected = last; }
+        if self.x > last { self.x = last; }
+    }
+
+    fn selected_path(&mut self) -> Option<String> {
+        self.get_selected().map(|node| node.fullpath())
+    }
+
+    fn index_of(&self, fullpath: &str) -> Option<usize> {
+        TreeNodeIterator::new(&self.root).position(|(node, _)| node.fullpath == fullpath)
+    }
+
+    /// Selects the node by path, or its closest visible parent, after the tree changed.
+    fn restore_selection(&mut self, fullpath: Option<String>) {
+        if let Some(fullpath) = fullpath {
+            let mut path = Some(Path::new(&fullpath));
+            while let Some(p) = path {
+                if let Some(index) = self.index_of(&p.to_string_lossy()) {
+                    self.selected = index;
+                    break;
+                }
+                path = p.parent();
+            }
+        }
+        self.clamp_selected();
+        self.upd = true;
+    }
+
+    /// Toggles the selected directory, keeping the selection on it.
+    pub fn toggle_selected(&mut self) {
+        let path = self.selected_path();
+        if let Some(node) = self.get_selected() {
+            node.toggle();
+        }
+        self.restore_selection(path);
+    }
+
+    /// Replaces the ignored names and rereads the tree with them.
+    pub fn set_ignore(&mut self, ignore: Ignore) {
+        fn apply(node: &mut TreeNode, ignore: &Arc<Ignore>) {
+            node.ignore = ignore.clone();
+            if let Some(children) = &mut node.children {
+                for child in children.iter_mut() { apply(child, ignore); }
+            }
+        }
+        apply(&mut self.root, &Arc::new(ignore));
+        if let Err(e) = self.refresh() { debug!("tree refresh error {}", e); }
+    }
+
+    /// Fullpath of the root dir.
+    pub fn root_path(&self) -> &str { &self.root.fullpath }
+
+    /// Shows the tree of another dir with the same ignored names.
+    pub fn set_root(&mut self, dir: &str) {
+        let name = Path::new(dir).file_name()
+            .map_or(dir.to_string(), |name| name.to_string_lossy().to_string());
+        self.root = TreeNode::new(name, utils::abs_file(dir), false, self.root.ignore.clone());
+        if let Err(e) = self.root.expand() { debug!("tree expand error {}", e); }
+        self.dir = dir.to_string();
+        self.selected = 0;
+        self.x = 0;
+        self.upd = true;
+    }
+
     pub fn expand_root(&mut self) {
         let root = &mut self.root;
         root.expand();
@@ -239,6 +369,7 @@ impl TreeView {
         let mut index = 0;
         Self::find_first_file_index(root, &mut index);
         self.selected = index;
+        self.clamp_selected();
     }
 
 
@@ -270,6 +401,9 @@ impl TreeView {
 
             if self.selected == i+ self.x { color = self.active_file_color }
 
+            let is_drop_target = self.drop_target == Some(i + self.x);
+            if is_drop_target { queue!(stdout, BColor(Color::DarkGrey)); }
+
             for i in 0..padding_left {
                 if col >= self.width-1 { break; }
                 queue!(stdout, Print(' '));
@@ -291,6 +425,7 @@ impl TreeView {
                     queue!(stdout, Print(' '));
                 }
             }
+            if is_drop_target { queue!(stdout, BColor(Color::Reset)); }
             queue!(stdout, FColor(Color::DarkGrey), Print('│'));
 
             count += 1;
@@ -363,13 +498,16 @@ impl TreeView {
     pub fn find_expand_by_fullpath(&mut self, fullpath: &str) {
         let root = &mut self.root;
         Self::find_by_fullpath_and_expand(root, fullpath);
+        self.restore_selection(Some(fullpath.to_string()));
     }
 
     pub fn find_and_toggle(&mut self, index: usize) {
         let mut count = 0;
+        let path = self.selected_path();
         let root = &mut self.root;
         let maybe_node = Self::find_by_index(root, index, &mut count);
         maybe_node.map(|node| node.toggle());
+        self.restore_selection(path);
     }
 
     fn find_by_index<'a>(node: &'a mut TreeNode, index: usize, count: &mut usize) -> Option<&'a mut TreeNode>{
@@ -447,10 +585,44 @@ impl TreeView {
         false
     }
 
+    /// Expands the selected directory (or the whole tree) recursively.
+    pub fn expand_all(&mut self) {
+        let node = match self.get_selected() {
+            Some(node) if !node.is_file => node,
+            _ => &mut self.root,
+        };
+        if let Err(e) = node.expand_all(EXPAND_ALL_MAX_DEPTH) {
+            debug!("expand all error {}", e);
+        }
+        self.clamp_selected();
+        self.upd = true;
+    }
+
+    /// Collapses the selected directory (or the whole tree) recursively.
+    pub fn collapse_all(&mut self) {
+        let path = self.selected_path();
+        let node = match self.get_selected() {
+            Some(node) if !node.is_file => node,
+            _ => &mut self.root,
+        };
+        node.collapse_all();
+        self.restore_selection(path);
+    }
+
     pub fn set_active(&mut self, fullpath: String) {
         self.active_file = fullpath;
         // todo: expand all nodes
     }
+
+    /// Marks the file active, expands the tree to it, selects and scrolls to it.
+    pub fn reveal(&mut self, fullpath: &str) {
+        self.set_active(fullpath.to_string());
+        self.find_expand_by_fullpath(fullpath);
+
+        if self.selected < self.x { self.x = self.selected; }
+        if self.selected >= self.x + self.height { self.x = self.selected + 1 - self.height; }
+        self.upd = true;
+    }
     
     pub async fn handle_mouse(&mut self, e: crossterm::event::MouseEvent) {
        match e {
@@ -467,6 +639,76 @@ impl TreeView {
        }
     }
 
+    /// Starts dragging the node at the given screen row.
+    pub fn start_drag(&mut self, row: usize) {
+        let root_path = self.root.fullpath.clone();
+        self.drag_source = self.find(row)
+            .map(|node| node.fullpath())
+            .filter(|path| *path != root_path);
+        self.drop_target = None;
+    }
+
+    /// Highlights the directory under the given screen row as a drop target.
+    pub fn drag_over(&mut self, row: usize) {
+        let index = row + self.x;
+        let source = self.drag_source.clone().unwrap_or_default();
+        let target = match self.find(row) {
+            Some(node) if !node.is_file && node.fullpath != source => Some(index),
+            _ => None,
+        };
+        if target != self.drop_target {
+            self.drop_target = target;
+            self.upd = true;
+        }
+    }
+
+    /// Finishes dragging, returns (source, target dir) if dropped on a directory.
+    pub fn end_drag(&mut self) -> Option<(String, String)> {
+        let source = self.drag_source.take();
+        let target = self.drop_target.take();
+        self.upd = true;
+
+        let mut count = 0;
+        let target = target.and_then(|index|
+            Self::find_by_index(&mut self.root, index, &mut count).map(|n| n.fullpath())
+        );
+        source.zip(target)
+    }
+
+    /// Moves a file or directory into the target directory, returns the new path.
+    pub fn move_node(&mut self, source: &str, target_dir: &str) -> io::Result<String> {
+        let source_path = Path::new(source);
+        let target_path = Path::new(target_dir);
+
+        if target_path.starts_with(source_path) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cant move directory into itself"));
+        }
+        if source_path.parent() == Some(target_path) {
+            return Ok(source.to_string());
+        }
+
+        let name = source_path.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+        let destination = target_path.join(name);
+        if destination.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "destination exists"));
+        }
+
+        fs::rename(source_path, &destination)?;
+        self.refresh()?;
+
+        Ok(destination.to_string_lossy().to_string())
+    }
+
+    /// Rereads expanded directories from disk, keeping the selection.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        let path = self.selected_path();
+        self.root.refresh()?;
+        self.restore_selection(path);
+        self.upd = true;
+        Ok(())
+    }
+
     pub fn insert_filter_char(&mut self, c: char) {
         self.search.active = true;
         self.search.pattern.insert_char(self.search.index, c);
@@ -485,7 +727,9 @@ impl TreeView {
 
             let pattern = self.search.pattern.to_string();
             if pattern.is_empty() {
+                let path = self.selected_path();
                 self.expand_root();
+                self.restore_selection(path);
             } else {
                 self.filter_files_by_pattern(&pattern);
             }
@@ -508,9 +752,11 @@ impl TreeView {
         };
     }
     pub fn clear_search(&mut self) {
+        let path = self.selected_path();
         self.search = FileSearch::new();
         self.upd = true;
         self.expand_root();
+        self.restore_selection(path);
     }
 }
 
@@ -582,6 +828,92 @@ mod tree_tests {
 
 
 
+    #[test]
+    fn test_move_node() {
+        let dir = std::env::temp_dir().join("red_tree_move_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::write(dir.join("file.txt"), "").unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        let mut tree = TreeView::new(dir.to_string_lossy().to_string());
+        let a = dir.join("a").to_string_lossy().to_string();
+        let b = dir.join("a/b").to_string_lossy().to_string();
+        let file = dir.join("file.txt").to_string_lossy().to_string();
+
+        let moved = tree.move_node(&file, &a).unwrap();
+        assert_eq!(moved, dir.join("a/file.txt").to_string_lossy());
+        assert!(dir.join("a/file.txt").exists());
+
+        assert!(tree.move_node(&a, &b).is_err());
+        assert!(tree.move_node(&a, &a).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand_collapse_all() {
+        let dir = std::env::temp_dir().join("red_tree_expand_all_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("a/b/c")).unwrap();
+        std::fs::write(dir.join("a/b/c/file.txt"), "").unwrap();
+
+        let mut tree = TreeView::new(dir.to_string_lossy().to_string());
+        assert_eq!(tree.root.len(), 1);
+
+        tree.expand_all();
+        assert_eq!(tree.root.len(), 4);
+
+        tree.selected = 4; // file.txt
+        tree.collapse_all();
+        assert_eq!(tree.root.len(), 1);
+        assert_eq!(tree.selected, 1); // moved to visible parent "a"
+        assert!(tree.get_selected().is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reveal() {
+        let dir = std::env::temp_dir().join("red_tree_reveal_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::create_dir_all(dir.join("c")).unwrap();
+        std::fs::write(dir.join("a/b/file.txt"), "").unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        let mut tree = TreeView::new(dir.to_string_lossy().to_string());
+        tree.set_height(2);
+        let file = dir.join("a/b/file.txt").to_string_lossy().to_string();
+        tree.reveal(&file);
+
+        assert_eq!(tree.selected, 3); // root, a, b, file.txt
+        assert_eq!(tree.x, 2);
+        assert_eq!(tree.get_selected().unwrap().fullpath(), file);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_set_ignore() {
+        let dir = std::env::temp_dir().join("red_tree_ignore_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("coverage")).unwrap();
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("notes.bak"), "").unwrap();
+
+        let mut tree = TreeView::new(dir.to_string_lossy().to_string());
+        assert_eq!(tree.root.len(), 3);
+
+        let mut config = crate::config::get();
+        config.ignore_dirs = Some(vec!["coverage".to_string()]);
+        config.ignore_files = Some(vec!["notes.bak".to_string()]);
+        tree.set_ignore(crate::utils::Ignore::from_config(&config));
+        assert_eq!(tree.root.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_iter() {
         // let root_node = TreeNode {
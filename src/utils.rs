@@ -1,7 +1,12 @@
 // utils.rs
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use regex::Regex;
 use crossterm::style::Color;
+use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
+use crate::config::Config;
 
 pub fn hex_to_color(hex_color: &str) -> Color {
     let hex = hex_color.trim_start_matches('#');
@@ -49,18 +54,23 @@ const WORD_BREAK_CHARS: [char; 23] = [
     '"', ':', '&', '?', '!', ';', '\t', '/', '<', '>', '\n'
 ];
 
-pub fn find_next_word(line: &str, from: usize) -> usize {
+/// Whether the char ends a word, `word_chars` are the language chars kept inside words.
+fn is_word_break(ch: &char, word_chars: &str) -> bool {
+    WORD_BREAK_CHARS.contains(ch) && !word_chars.contains(*ch)
+}
+
+pub fn find_next_word(line: &str, from: usize, word_chars: &str) -> usize {
     // Find the next word index after the specified index
     let chars: Vec<char> = line.chars().collect();
     for i in from..chars.len() {
-        if WORD_BREAK_CHARS.contains(&chars[i]) {
+        if is_word_break(&chars[i], word_chars) {
             return i;
         }
     }
     chars.len()-1
 }
 
-pub fn find_prev_word(line: &str, from: usize) -> usize {
+pub fn find_prev_word(line: &str, from: usize, word_chars: &str) -> usize {
     // Find the previous word index before the specified index
     let chars: Vec<char> = line.chars().collect();
     for i in (0..from).rev() {
@@ -69,13 +79,174 @@ pub fn find_prev_word(line: &str, from: usize) -> usize {
             None => { return 0; },
         };
 
-        if WORD_BREAK_CHARS.contains(ch) {
+        if is_word_break(ch, word_chars) {
             return i + 1;
         }
     }
     0
 }
 
+/// Char range of the identifier at or right before the column.
+pub fn word_boundaries(line: &str, col: usize, word_chars: &str) -> Option<(usize, usize)> {
+    token_boundaries(line, col, |c| c.is_alphanumeric() || *c == '_' || word_chars.contains(*c))
+}
+
+/// Char range of the file path at or right before the column, e.g. `../src/main.rs`.
+pub fn path_boundaries(line: &str, col: usize) -> Option<(usize, usize)> {
+    token_boundaries(line, col, |c| c.is_alphanumeric() || "_/.-~".contains(*c))
+}
+
+fn token_boundaries(line: &str, col: usize, is_word: impl Fn(&char) -> bool) -> Option<(usize, usize)> {
+    let chars: Vec<char> = line.chars().collect();
+    let is_word = |c: &char| is_word(c);
+
+    let col = if chars.get(col).map_or(false, is_word) { col }
+        else if col > 0 && chars.get(col - 1).map_or(false, is_word) { col - 1 }
+        else { return None };
+
+    let start = chars[..col].iter().rposition(|c| !is_word(c)).map_or(0, |i| i + 1);
+    let end = chars[col..].iter().position(|c| !is_word(c)).map_or(chars.len(), |i| col + i);
+    Some((start, end))
+}
+
+/// Char positions of the pattern chars found in order in the text, ignoring case.
+pub fn fuzzy_match(text: &str, pattern: &str) -> Option<Vec<usize>> {
+    let mut positions = vec![];
+    let mut chars = text.chars().enumerate();
+    for p in pattern.chars() {
+        let (i, _) = chars.find(|(_, c)| c.to_lowercase().eq(p.to_lowercase()))?;
+        positions.push(i);
+    }
+    Some(positions)
+}
+
+/// Expands a snippet template with `$1`, `${1}` or `${1:default}` tab stops, `$0` is the final one.
+/// Returns the text and the stops as (char offset, len) in the jump order.
+pub fn parse_snippet(template: &str) -> (String, Vec<(usize, usize)>) {
+    let mut text = String::new();
+    let mut len = 0;
+    let mut stops: Vec<(u32, usize, usize)> = vec![];
+    let mut chars = template.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek()) {
+            ('\\', Some(&next)) if next == '$' || next == '\\' || next == '}' => {
+                text.push(next); len += 1;
+                chars.next();
+            }
+            ('$', Some(&next)) if next.is_ascii_digit() || next == '{' => {
+                let braced = next == '{';
+                if braced { chars.next(); }
+                let mut number = String::new();
+                while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) { number.push(d); }
+
+                let mut default = String::new();
+                if braced {
+                    if chars.next_if_eq(&':').is_some() {
+                        while let Some(d) = chars.next_if(|d| *d != '}') { default.push(d); }
+                    }
+                    chars.next_if_eq(&'}');
+                }
+
+                let default_len = default.chars().count();
+                stops.push((number.parse().unwrap_or(0), len, default_len));
+                text.push_str(&default); len += default_len;
+            }
+            _ => { text.push(ch); len += 1; }
+        }
+    }
+
+    // $0 goes last, at the end when the template has none
+    if !stops.iter().any(|s| s.0 == 0) { stops.push((0, len, 0)); }
+    stops.sort_by_key(|s| if s.0 == 0 { u32::MAX } else { s.0 });
+    (text, stops.into_iter().map(|(_, at, len)| (at, len)).collect())
+}
+
+/// Scores a case-insensitive subsequence match of the pattern, None if it does not match.
+/// Consecutive chars, word starts and matches in the file name part of a path score higher,
+/// gaps and long texts lower.
+pub fn fuzzy_score(text: &str, pattern: &str) -> Option<i32> {
+    let chars: Vec<char> = text.chars().collect();
+    let name_start = chars.iter().rposition(|&c| c == '/' || c == '\\').map_or(0, |i| i + 1);
+
+    let score = |positions: Vec<usize>| {
+        let mut score = 0;
+        let mut prev: Option<usize> = None;
+        for i in positions {
+            score += 10;
+            let word_start = i == 0 || matches!(chars[i - 1], '/' | '\\' | '_' | '-' | '.' | ' ')
+                || (chars[i - 1].is_lowercase() && chars[i].is_uppercase());
+            if word_start { score += 15; }
+            if i >= name_start { score += 5; }
+            match prev {
+                Some(p) if i == p + 1 => score += 20,
+                Some(p) => score -= (i - p - 1).min(5) as i32,
+                None => {},
+            }
+            prev = Some(i);
+        }
+        score - chars.len() as i32 / 10
+    };
+
+    let best = score(fuzzy_match(text, pattern)?);
+    // greedy matching may spend the pattern on the dirs, try the file name alone too
+    let name: String = chars[name_start..].iter().collect();
+    let in_name = fuzzy_match(&name, pattern)
+        .map(|positions| score(positions.iter().map(|i| i + name_start).collect()));
+    Some(in_name.map_or(best, |s| s.max(best)))
+}
+
+/// Encodings offered when reopening a file with a forced encoding.
+pub const ENCODINGS: &[&str] = &["utf-8", "utf-16le", "utf-16be", "latin1", "windows-1252"];
+
+/// cp1252 characters for bytes 0x80..0x9f, the rest matches latin1.
+const CP1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+/// Decodes bytes with one of `ENCODINGS`, None if the bytes are invalid for it.
+pub fn decode(bytes: &[u8], encoding: &str) -> Option<String> {
+    match encoding {
+        "utf-8" => {
+            let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+            String::from_utf8(bytes.to_vec()).ok()
+        }
+        "utf-16le" | "utf-16be" => {
+            if bytes.len() % 2 != 0 { return None; }
+            let units: Vec<u16> = bytes.chunks(2)
+                .map(|b| if encoding == "utf-16le" { u16::from_le_bytes([b[0], b[1]]) }
+                    else { u16::from_be_bytes([b[0], b[1]]) })
+                .collect();
+            let units = units.strip_prefix(&[0xfeff]).unwrap_or(&units);
+            String::from_utf16(units).ok()
+        }
+        "latin1" => Some(bytes.iter().map(|&b| b as char).collect()),
+        "windows-1252" => Some(bytes.iter().map(|&b| match b {
+            0x80..=0x9f => CP1252_HIGH[(b - 0x80) as usize],
+            _ => b as char,
+        }).collect()),
+        _ => None,
+    }
+}
+
+/// Encodes text with one of `ENCODINGS`, None if a char has no bytes in it.
+/// utf-16 is written with a BOM, as `decode` drops it.
+pub fn encode(text: &str, encoding: &str) -> Option<Vec<u8>> {
+    match encoding {
+        "utf-8" => Some(text.as_bytes().to_vec()),
+        "utf-16le" | "utf-16be" => Some(std::iter::once(0xfeff).chain(text.encode_utf16())
+            .flat_map(|u| if encoding == "utf-16le" { u.to_le_bytes() } else { u.to_be_bytes() })
+            .collect()),
+        "latin1" => text.chars().map(|c| u8::try_from(c as u32).ok()).collect(),
+        "windows-1252" => text.chars().map(|c| match CP1252_HIGH.iter().position(|h| *h == c) {
+            Some(i) => Some(0x80 + i as u8),
+            None => u8::try_from(c as u32).ok().filter(|b| !(0x80..=0x9f).contains(b)),
+        }).collect(),
+        _ => None,
+    }
+}
+
 pub fn pad_left(str: &str, length: usize) -> String {
     format!("{:1$}", str, length)
 }
@@ -99,6 +270,187 @@ pub const IGNORE_FILES: &[&str] = &[
     ".DS_Store",
 ];
 
+/// Dirs, files and extensions skipped by the tree and global search,
+/// the defaults plus `ignore_dirs`, `ignore_files` and `ignore_exts` from config.
+#[derive(Debug, Clone)]
+pub struct Ignore {
+    dirs: Vec<String>,
+    files: Vec<String>,
+    exts: Vec<String>,
+    /// Paths of the .gitignore files, unless `gitignore` is off in config.
+    gitignore: Option<Arc<Gitignore>>,
+}
+
+impl Ignore {
+    pub fn new() -> Self {
+        let strings = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+        Self {
+            dirs: strings(IGNORE_DIRS),
+            files: strings(IGNORE_FILES),
+            exts: strings(crate::search::search::IGNORE_EXTS),
+            gitignore: None,
+        }
+    }
+
+    pub fn from_config(conf: &Config) -> Self {
+        let mut ignore = Self::new();
+        ignore.dirs.extend(conf.ignore_dirs.clone().unwrap_or_default());
+        ignore.files.extend(conf.ignore_files.clone().unwrap_or_default());
+        ignore.exts.extend(conf.ignore_exts.clone().unwrap_or_default());
+        if conf.gitignore.unwrap_or(true) {
+            ignore.gitignore = Some(Arc::new(Gitignore::new(Path::new("."))));
+        }
+        ignore
+    }
+
+    pub fn is_dir_ignored(&self, name: &str) -> bool { self.dirs.iter().any(|d| d == name) }
+    pub fn is_file_ignored(&self, name: &str) -> bool { self.files.iter().any(|f| f == name) }
+    pub fn is_ext_ignored(&self, ext: &str) -> bool { self.exts.iter().any(|e| e == ext) }
+    pub fn is_git_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.gitignore.as_ref().map_or(false, |g| g.is_ignored(path, is_dir))
+    }
+}
+
+/// Patterns of the .gitignore files of a repo, each file is read once, when a path in its dir is checked.
+#[derive(Debug)]
+pub struct Gitignore {
+    root: PathBuf,
+    /// Relative paths are taken from it.
+    current_dir: PathBuf,
+    rules: Mutex<HashMap<PathBuf, Arc<Vec<GitignoreRule>>>>,
+}
+
+#[derive(Debug)]
+struct GitignoreRule {
+    regex: Regex,
+    negate: bool,
+    dir_only: bool,
+}
+
+impl Gitignore {
+    /// Matcher for the git repo of `dir`, or for `dir` when it is not in one.
+    pub fn new(dir: &Path) -> Self {
+        let dir = dir.canonicalize().unwrap_or(dir.to_path_buf());
+        let root = dir.ancestors().find(|d| d.join(".git").exists()).unwrap_or(&dir).to_path_buf();
+        let current_dir = std::env::current_dir().and_then(|d| d.canonicalize()).unwrap_or_default();
+        Self { root, current_dir, rules: Mutex::new(HashMap::new()) }
+    }
+
+    /// The last pattern matching the path decides, patterns of deeper .gitignore files come later.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let path = self.current_dir.join(path); // keeps absolute paths
+        let components: Vec<_> = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative.components().collect(),
+            Err(_) => return false,
+        };
+
+        let mut ignored = false;
+        let mut dir = self.root.clone();
+        for i in 0..components.len() {
+            let rest: PathBuf = components[i..].iter().collect(); // relative to the .gitignore dir
+            let rest = rest.to_string_lossy();
+            for rule in self.rules_of(&dir).iter() {
+                if rule.dir_only && !is_dir { continue; }
+                if rule.regex.is_match(&rest) { ignored = !rule.negate; }
+            }
+            dir.push(components[i]);
+        }
+        ignored
+    }
+
+    fn rules_of(&self, dir: &Path) -> Arc<Vec<GitignoreRule>> {
+        let mut rules = self.rules.lock().unwrap();
+        rules.entry(dir.to_path_buf()).or_insert_with(|| {
+            let text = std::fs::read_to_string(dir.join(".gitignore")).unwrap_or_default();
+            Arc::new(text.lines().filter_map(GitignoreRule::parse).collect())
+        }).clone()
+    }
+}
+
+impl GitignoreRule {
+    /// A line of .gitignore, none for blank lines and comments.
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') { return None; }
+        let (negate, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        if line.is_empty() { return None; }
+
+        // a slash other than at the end ties the pattern to the dir of the .gitignore
+        let anchored = line.contains('/');
+        let glob = glob_to_regex(line.strip_prefix('/').unwrap_or(line));
+        let regex = match anchored {
+            true => format!("^{}$", glob),
+            false => format!("^(?:.*/)?{}$", glob),
+        };
+        Regex::new(&regex).ok().map(|regex| Self { regex, negate, dir_only })
+    }
+}
+
+/// Regex of a gitignore glob: `*` and `?` stay within a path segment, `**` crosses them.
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut re = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                if chars.get(i + 2) == Some(&'/') { // any dirs, also none
+                    re.push_str("(?:.*/)?");
+                    i += 3;
+                } else {
+                    re.push_str(".*");
+                    i += 2;
+                }
+                continue;
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => match chars[i..].iter().position(|c| *c == ']') {
+                Some(end) if end > 1 => {
+                    let class: String = chars[i + 1..i + end].iter().collect();
+                    let class = class.strip_prefix('!').map(|c| format!("^{}", c)).unwrap_or(class);
+                    re.push_str(&format!("[{}]", class.replace('\\', "\\\\").replace('[', "\\[")));
+                    i += end + 1;
+                    continue;
+                }
+                _ => re.push_str("\\["),
+            },
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    re
+}
+
+/// Splits `path:line` or `path:line:col` (1-based, as in compiler and grep output).
+/// An existing file keeps its name, a drive letter like `C:\\foo` is not a position.
+pub fn parse_file_position(arg: &str) -> (String, Option<usize>, Option<usize>) {
+    if Path::new(arg).exists() { return (arg.to_string(), None, None); }
+
+    let mut path = arg.strip_suffix(':').unwrap_or(arg);
+    let mut numbers = vec![];
+    while numbers.len() < 2 {
+        let (rest, number) = match path.rsplit_once(':') {
+            Some(split) => split,
+            None => break,
+        };
+        let is_drive = rest.len() == 1 && rest.chars().all(|c| c.is_ascii_alphabetic());
+        match number.parse::<usize>() {
+            Ok(number) if !rest.is_empty() && !is_drive => { numbers.push(number); path = rest; }
+            _ => break,
+        }
+    }
+    numbers.reverse();
+    (path.to_string(), numbers.first().copied(), numbers.get(1).copied())
+}
+
 pub fn current_dir() -> String {
     std::env::current_dir().unwrap()
         .to_string_lossy().into_owned()
@@ -114,6 +466,7 @@ pub fn current_directory_name() -> Option<String> {
 }
 
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CursorPosition {
     pub filename: String,
     pub row: usize,
@@ -138,4 +491,116 @@ impl CursorHistory {
     pub fn clear(&mut self) {
         self.positions.clear();
     }
-}
\ No newline at end of file
+}
+
+/// Max files with a remembered cursor position.
+pub const CURSOR_POSITIONS_LIMIT: usize = 10000;
+
+/// File with the last cursor positions of opened files, ~/.red/sessions.json.
+pub fn cursor_positions_path() -> Option<PathBuf> {
+    let home = std::env::var("HOME").ok()?;
+    Some(Path::new(&home).join(".red").join("sessions.json"))
+}
+
+/// Remembered cursor positions, oldest first, files that no longer exist are dropped.
+pub fn load_cursor_positions(path: &Path) -> Vec<CursorPosition> {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(_) => return vec![],
+    };
+    let positions: Vec<CursorPosition> = serde_json::from_str(&json).unwrap_or_default();
+    positions.into_iter().filter(|p| Path::new(&p.filename).exists()).collect()
+}
+
+/// Remembers the cursor position of the file, dropping the oldest over the limit.
+pub fn save_cursor_position(path: &Path, position: CursorPosition) -> std::io::Result<()> {
+    let mut positions = load_cursor_positions(path);
+    positions.retain(|p| p.filename != position.filename);
+    positions.push(position);
+    if positions.len() > CURSOR_POSITIONS_LIMIT {
+        positions.drain(..positions.len() - CURSOR_POSITIONS_LIMIT);
+    }
+
+    if let Some(dir) = path.parent() { std::fs::create_dir_all(dir)?; }
+    let json = serde_json::to_string(&positions)?;
+    std::fs::write(path, json)
+}
+
+/// Open buffers of a project dir, restored on the next start without a file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub files: Vec<String>,
+    pub active: String,
+    /// Tree root dir, empty if unknown.
+    #[serde(default)]
+    pub root: String,
+}
+
+/// File with the last session of every project dir, ~/.red/session.json.
+pub fn session_path() -> Option<PathBuf> {
+    let home = std::env::var("HOME").ok()?;
+    Some(Path::new(&home).join(".red").join("session.json"))
+}
+
+fn load_sessions(path: &Path) -> HashMap<String, Session> {
+    std::fs::read_to_string(path).ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Session of the dir with files that still exist, None if none is left.
+pub fn load_session(path: &Path, root: &str) -> Option<Session> {
+    let mut session = load_sessions(path).remove(root)?;
+    session.files.retain(|f| Path::new(f).is_file());
+    if session.files.is_empty() { return None; }
+    if !session.files.contains(&session.active) {
+        session.active = session.files.last()?.clone();
+    }
+    if !Path::new(&session.root).is_dir() { session.root.clear(); }
+    Some(session)
+}
+
+/// Replaces the session of the dir, an empty session removes it.
+pub fn save_session(path: &Path, root: &str, session: Session) -> std::io::Result<()> {
+    let mut sessions = load_sessions(path);
+    match session.files.is_empty() {
+        true => sessions.remove(root),
+        false => sessions.insert(root.to_string(), session),
+    };
+
+    if let Some(dir) = path.parent() { std::fs::create_dir_all(dir)?; }
+    let json = serde_json::to_string(&sessions)?;
+    std::fs::write(path, json)
+}
+
+tokio::task_local! {
+    /// Name of the background task being polled.
+    static BACKGROUND_TASK: &'static str;
+}
+
+/// Spawns a background task, a panic in it is logged by the panic hook and ends only the task.
+pub fn spawn<F>(name: &'static str, future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(BACKGROUND_TASK.scope(name, future))
+}
+
+/// Name of the background task running on this thread, None on the editor task.
+pub fn background_task() -> Option<&'static str> {
+    BACKGROUND_TASK.try_with(|name| *name).ok()
+}
+
+/// Process with the pid is running, always true where it can not be checked.
+pub fn process_alive(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        return Path::new(&format!("/proc/{}", pid)).exists();
+    }
+    if cfg!(unix) {
+        return std::process::Command::new("kill").args(["-0", &pid.to_string()])
+            .stderr(std::process::Stdio::null())
+            .status().map_or(true, |s| s.success());
+    }
+    true
+}